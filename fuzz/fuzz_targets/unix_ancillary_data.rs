@@ -0,0 +1,24 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The control messages received along with the first fragment of a message, which pass
+//! the file descriptors of channels and shared memory regions.
+
+#![no_main]
+
+use ipc_channel::fuzzing;
+use libfuzzer_sys::fuzz_target;
+use std::mem;
+use std::os::raw::c_int;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(fds) = fuzzing::decode_ancillary_data(data) {
+        assert!(fds.len() * mem::size_of::<c_int>() <= data.len());
+    }
+});
@@ -0,0 +1,21 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The header of the first fragment of a message, which holds the size of the message.
+
+#![no_main]
+
+use ipc_channel::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((total_size, fragment_data)) = fuzzing::decode_first_fragment(data) {
+        assert!(fragment_data.len() <= total_size);
+    }
+});
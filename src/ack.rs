@@ -0,0 +1,359 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Receiving with acknowledgements, for at-least-once processing by a pool of workers.
+//!
+//! An [AckReceiver], from [IpcReceiver::with_acks], hands out each message as a
+//! [Delivery], which the worker acknowledges with [ack] once it is done with it.
+//! A delivery that isn't acknowledged within the visibility timeout is handed out
+//! again, by whichever clone of the receiver asks first, as is a delivery dropped
+//! without being acknowledged, e.g. by a worker thread that panicked:
+//!
+//! ```
+//! # use ipc_channel::ipc;
+//! # use std::thread;
+//! # use std::time::Duration;
+//! let (tx, rx) = ipc::channel::<u32>().unwrap();
+//! let rx = rx.with_acks(Duration::from_secs(30));
+//! tx.send(42).unwrap();
+//!
+//! let worker_rx = rx.clone();
+//! let result = thread::spawn(move || {
+//!     let delivery = worker_rx.recv().unwrap();
+//!     assert_eq!(*delivery, 42);
+//!     panic!("worker failed")
+//! }).join();
+//! # assert!(result.is_err());
+//! let delivery = rx.recv().unwrap();
+//! assert_eq!(delivery.attempt(), 2);
+//! assert_eq!(delivery.ack(), 42);
+//! ```
+//!
+//! Unacknowledged messages are kept by the receivers, so a message is only handed
+//! out again within the process that received it, and is lost if that process
+//! dies. Messages embedding channels or shared memory can't be handed out twice,
+//! so they are delivered at most once.
+//!
+//! [AckReceiver]: struct.AckReceiver.html
+//! [IpcReceiver::with_acks]: ../ipc/struct.IpcReceiver.html#method.with_acks
+//! [Delivery]: struct.Delivery.html
+//! [ack]: struct.Delivery.html#method.ack
+
+use bincode;
+use ipc::{BincodeConfig, IpcReceiver, OpaqueIpcMessage};
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Receiving end of a channel handing out messages as [Delivery]s to acknowledge.
+///
+/// Clones share the channel and the deliveries to hand out again.
+///
+/// [Delivery]: struct.Delivery.html
+pub struct AckReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> where T: for<'de> Deserialize<'de> + Serialize {
+    receiver: Mutex<IpcReceiver<T>>,
+    bincode_config: BincodeConfig,
+    visibility_timeout: Duration,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    /// Deliveries not acknowledged yet, by id.
+    unacked: HashMap<u64, Unacked>,
+    /// Messages to hand out again, with the number of times they were handed out.
+    redeliveries: VecDeque<(Vec<u8>, u32)>,
+}
+
+struct Unacked {
+    /// When the message is handed out again.
+    deadline: Instant,
+    data: Vec<u8>,
+    attempt: u32,
+}
+
+impl State {
+    /// Queue the deliveries whose visibility timeout ran out to be handed out again.
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<u64> = self.unacked.iter()
+                                            .filter(|&(_, unacked)| unacked.deadline <= now)
+                                            .map(|(&id, _)| id)
+                                            .collect();
+        for id in expired {
+            self.requeue(id);
+        }
+    }
+
+    fn requeue(&mut self, id: u64) {
+        if let Some(unacked) = self.unacked.remove(&id) {
+            self.redeliveries.push_back((unacked.data, unacked.attempt));
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.unacked.values().map(|unacked| unacked.deadline).min()
+    }
+}
+
+impl<T> Shared<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl<T> AckReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    pub(crate) fn new(receiver: IpcReceiver<T>, visibility_timeout: Duration) -> AckReceiver<T> {
+        AckReceiver {
+            shared: Arc::new(Shared {
+                bincode_config: receiver.bincode_config(),
+                receiver: Mutex::new(receiver),
+                visibility_timeout,
+                state: Mutex::new(State::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Blocking receive, of a message handed out again or else of the next one.
+    ///
+    /// One clone at a time waits on the channel, the others waiting for messages to
+    /// be handed out again, or for their turn. Once all senders are gone, this waits
+    /// for the outstanding deliveries to be acknowledged, or handed out again, before
+    /// reporting the channel as closed.
+    pub fn recv(&self) -> Result<Delivery<T>, bincode::Error> {
+        loop {
+            let wait = {
+                let mut state = self.shared.lock();
+                let now = Instant::now();
+                state.expire(now);
+                if let Some((data, attempt)) = state.redeliveries.pop_front() {
+                    drop(state);
+                    return self.redeliver(data, attempt)
+                }
+                // A clone may hand out a delivery while we wait, which can time out
+                // no sooner than a visibility timeout from now.
+                let visibility_timeout = self.shared.visibility_timeout;
+                state.next_deadline().map_or(visibility_timeout, |deadline| {
+                    cmp::min(deadline.saturating_duration_since(now), visibility_timeout)
+                })
+            };
+            let receiver = match self.shared.receiver.try_lock() {
+                Ok(receiver) => receiver,
+                Err(TryLockError::WouldBlock) => {
+                    // Checking again with the state locked, so the wakeup isn't missed.
+                    let state = self.shared.lock();
+                    if state.redeliveries.is_empty() && self.shared.receiver.try_lock().is_err() {
+                        drop(self.shared.changed.wait_timeout(state, wait).unwrap());
+                    }
+                    continue
+                }
+                Err(TryLockError::Poisoned(error)) => panic!("{}", error),
+            };
+            let result = receiver.try_recv_opaque_timeout(wait).and_then(|message| {
+                deserialize(&receiver, message)
+            });
+            drop(receiver);
+            // Let the clones waiting for their turn know.
+            drop(self.shared.lock());
+            self.shared.changed.notify_all();
+            match result {
+                Ok((value, data)) => return Ok(self.track(value, data, 1)),
+                Err(ref error) if is_kind(error, ErrorKind::TimedOut) => {}
+                Err(error) => {
+                    if !is_kind(&error, ErrorKind::ConnectionReset) {
+                        return Err(error)
+                    }
+                    let state = self.shared.lock();
+                    if !state.redeliveries.is_empty() {
+                        continue
+                    }
+                    let deadline = match state.next_deadline() {
+                        Some(deadline) => deadline,
+                        None => return Err(error),
+                    };
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    drop(self.shared.changed.wait_timeout(state, wait).unwrap());
+                }
+            }
+        }
+    }
+
+    /// Non-blocking receive, of a message handed out again or else of the next one.
+    ///
+    /// Fails with `ErrorKind::WouldBlock` if all senders are gone, but there are
+    /// deliveries left to acknowledge, or if a clone is waiting on the channel.
+    pub fn try_recv(&self) -> Result<Delivery<T>, bincode::Error> {
+        {
+            let mut state = self.shared.lock();
+            state.expire(Instant::now());
+            if let Some((data, attempt)) = state.redeliveries.pop_front() {
+                drop(state);
+                return self.redeliver(data, attempt)
+            }
+        }
+        let result = match self.shared.receiver.try_lock() {
+            Ok(receiver) => {
+                receiver.try_recv_opaque().and_then(|message| deserialize(&receiver, message))
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::new(ErrorKind::WouldBlock, "a clone is receiving").into())
+            }
+            Err(TryLockError::Poisoned(error)) => panic!("{}", error),
+        };
+        match result {
+            Ok((value, data)) => Ok(self.track(value, data, 1)),
+            Err(ref error) if is_kind(error, ErrorKind::ConnectionReset) &&
+                              !self.shared.lock().unacked.is_empty() => {
+                Err(Error::new(ErrorKind::WouldBlock, "deliveries left to acknowledge").into())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The number of deliveries handed out and not acknowledged yet.
+    pub fn unacked_count(&self) -> usize {
+        self.shared.lock().unacked.len()
+    }
+
+    fn redeliver(&self, data: Vec<u8>, attempt: u32) -> Result<Delivery<T>, bincode::Error> {
+        let message = OpaqueIpcMessage::from_plain_data(
+            data.clone(), self.shared.bincode_config.has_metadata_header());
+        let (value, _) = message.deserialize_with_config(self.shared.bincode_config)?;
+        Ok(self.track(value, Some(data), attempt + 1))
+    }
+
+    /// Hand out `value`, to be handed out again from `data` unless acknowledged in time.
+    fn track(&self, value: T, data: Option<Vec<u8>>, attempt: u32) -> Delivery<T> {
+        let id = data.map(|data| {
+            let mut state = self.shared.lock();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.unacked.insert(id, Unacked {
+                deadline: Instant::now() + self.shared.visibility_timeout,
+                data,
+                attempt,
+            });
+            id
+        });
+        Delivery {
+            value: Some(value),
+            id,
+            attempt,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Deserialize a message received from `receiver`, along with a copy of it to hand out
+/// again, unless it has channels or shared memory.
+fn deserialize<T>(receiver: &IpcReceiver<T>, message: OpaqueIpcMessage)
+                  -> Result<(T, Option<Vec<u8>>), bincode::Error>
+                  where T: for<'de> Deserialize<'de> + Serialize {
+    let data = message.plain_data().map(<[u8]>::to_vec);
+    Ok((receiver.deserialize_opaque(message)?, data))
+}
+
+fn is_kind(error: &bincode::Error, kind: ErrorKind) -> bool {
+    match **error {
+        bincode::ErrorKind::Io(ref error) => error.kind() == kind,
+        _ => false,
+    }
+}
+
+impl<T> Clone for AckReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn clone(&self) -> AckReceiver<T> {
+        AckReceiver {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Debug for AckReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("AckReceiver")
+                 .field("visibility_timeout", &self.shared.visibility_timeout)
+                 .finish()
+    }
+}
+
+/// A message handed out by an [AckReceiver], dereferencing to the value.
+///
+/// Dropping it without calling [ack] hands the message out again right away.
+///
+/// [AckReceiver]: struct.AckReceiver.html
+/// [ack]: #method.ack
+pub struct Delivery<T> where T: for<'de> Deserialize<'de> + Serialize {
+    value: Option<T>,
+    /// Not set for messages that can't be handed out again.
+    id: Option<u64>,
+    attempt: u32,
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Delivery<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// Acknowledge the message, so it isn't handed out again, taking the value.
+    ///
+    /// If the visibility timeout already ran out, the message may have been
+    /// handed out again nonetheless.
+    pub fn ack(mut self) -> T {
+        if let Some(id) = self.id.take() {
+            self.shared.lock().unacked.remove(&id);
+            self.shared.changed.notify_all();
+        }
+        self.value.take().unwrap()
+    }
+
+    /// How many times the message was handed out, including this time, starting from 1.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+impl<T> Deref for Delivery<T> where T: for<'de> Deserialize<'de> + Serialize {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for Delivery<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for Delivery<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.shared.lock().requeue(id);
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+impl<T> Debug for Delivery<T> where T: for<'de> Deserialize<'de> + Serialize + Debug {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("Delivery")
+                 .field("value", &self.value)
+                 .field("attempt", &self.attempt)
+                 .finish()
+    }
+}
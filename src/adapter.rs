@@ -0,0 +1,166 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Converting between the types sent over a channel and those used on either end.
+//!
+//! [IpcReceiver::map] and [IpcReceiver::filter] wrap a receiver, converting or
+//! discarding messages as they are received, and [IpcSender::with] wraps a sender,
+//! converting values before they are sent. The conversions run on the thread
+//! receiving or sending, so none of this needs a forwarding thread:
+//!
+//! ```
+//! # use ipc_channel::ipc;
+//! let (tx, rx) = ipc::channel::<(u32, String)>().unwrap();
+//! let tx = tx.with(|name: &str| (name.len() as u32, name.to_owned()));
+//! let rx = rx.map(|(_, name)| name);
+//! tx.send("Hello").unwrap();
+//! assert_eq!(rx.recv().unwrap(), "Hello");
+//! ```
+//!
+//! [IpcReceiver::map]: ../ipc/struct.IpcReceiver.html#method.map
+//! [IpcReceiver::filter]: ../ipc/struct.IpcReceiver.html#method.filter
+//! [IpcSender::with]: ../ipc/struct.IpcSender.html#method.with
+
+use bincode;
+use ipc::{IpcReceiver, IpcSender};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+
+/// A receiver converting each message with a closure, from [IpcReceiver::map].
+///
+/// [IpcReceiver::map]: ../ipc/struct.IpcReceiver.html#method.map
+pub struct MapReceiver<T, F> where T: for<'de> Deserialize<'de> + Serialize {
+    receiver: IpcReceiver<T>,
+    map: F,
+}
+
+impl<T, U, F> MapReceiver<T, F> where T: for<'de> Deserialize<'de> + Serialize, F: Fn(T) -> U {
+    pub(crate) fn new(receiver: IpcReceiver<T>, map: F) -> MapReceiver<T, F> {
+        MapReceiver { receiver, map }
+    }
+
+    /// Blocking receive.
+    pub fn recv(&self) -> Result<U, bincode::Error> {
+        self.receiver.recv().map(&self.map)
+    }
+
+    /// Non-blocking receive.
+    pub fn try_recv(&self) -> Result<U, bincode::Error> {
+        self.receiver.try_recv().map(&self.map)
+    }
+
+    /// Take back the receiver, dropping the closure.
+    pub fn into_inner(self) -> IpcReceiver<T> {
+        self.receiver
+    }
+}
+
+impl<T, F> Debug for MapReceiver<T, F> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.pad("MapReceiver { .. }")
+    }
+}
+
+/// A receiver discarding the messages a predicate rejects, from [IpcReceiver::filter].
+///
+/// [IpcReceiver::filter]: ../ipc/struct.IpcReceiver.html#method.filter
+pub struct FilterReceiver<T, P> where T: for<'de> Deserialize<'de> + Serialize {
+    receiver: IpcReceiver<T>,
+    predicate: P,
+}
+
+impl<T, P> FilterReceiver<T, P> where T: for<'de> Deserialize<'de> + Serialize,
+                                      P: Fn(&T) -> bool {
+    pub(crate) fn new(receiver: IpcReceiver<T>, predicate: P) -> FilterReceiver<T, P> {
+        FilterReceiver { receiver, predicate }
+    }
+
+    /// Blocking receive of the next message the predicate accepts.
+    pub fn recv(&self) -> Result<T, bincode::Error> {
+        loop {
+            let value = self.receiver.recv()?;
+            if (self.predicate)(&value) {
+                return Ok(value)
+            }
+        }
+    }
+
+    /// Non-blocking receive, discarding the messages already queued that the predicate
+    /// rejects, up to the first one it accepts.
+    pub fn try_recv(&self) -> Result<T, bincode::Error> {
+        loop {
+            let value = self.receiver.try_recv()?;
+            if (self.predicate)(&value) {
+                return Ok(value)
+            }
+        }
+    }
+
+    /// Take back the receiver, dropping the predicate.
+    pub fn into_inner(self) -> IpcReceiver<T> {
+        self.receiver
+    }
+}
+
+impl<T, P> Debug for FilterReceiver<T, P> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.pad("FilterReceiver { .. }")
+    }
+}
+
+/// A sender of `U`s, converting them with a closure before sending them, from
+/// [IpcSender::with].
+///
+/// [IpcSender::with]: ../ipc/struct.IpcSender.html#method.with
+pub struct WithSender<T, U, F> where T: Serialize {
+    sender: IpcSender<T>,
+    map: F,
+    phantom: PhantomData<fn(U)>,
+}
+
+impl<T, U, F> WithSender<T, U, F> where T: Serialize, F: Fn(U) -> T {
+    pub(crate) fn new(sender: IpcSender<T>, map: F) -> WithSender<T, U, F> {
+        WithSender {
+            sender,
+            map,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Convert `data`, and send the result.
+    pub fn send(&self, data: U) -> Result<(), bincode::Error> {
+        self.sender.send((self.map)(data))
+    }
+
+    /// Take back the sender, dropping the closure.
+    pub fn into_inner(self) -> IpcSender<T> {
+        self.sender
+    }
+}
+
+impl<T, U, F> Clone for WithSender<T, U, F> where T: Serialize, F: Clone {
+    /// Clones the sender and the closure; as with [IpcSender::clone], the new
+    /// instance gets its own sender ID.
+    ///
+    /// [IpcSender::clone]: ../ipc/struct.IpcSender.html#impl-Clone
+    fn clone(&self) -> WithSender<T, U, F> {
+        WithSender {
+            sender: self.sender.clone(),
+            map: self.map.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, U, F> Debug for WithSender<T, U, F> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.pad("WithSender { .. }")
+    }
+}
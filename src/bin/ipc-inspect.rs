@@ -0,0 +1,134 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lists the frames of a capture written by `ipc_channel::capture`.
+
+extern crate ipc_channel;
+
+use ipc_channel::capture::{CaptureFrame, CaptureReader, Direction};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+
+const USAGE: &str = "\
+usage: ipc-inspect CAPTURE [OPTIONS]
+
+Lists the messages recorded in CAPTURE, one per line.
+
+options:
+    --sent            only list sent messages
+    --received        only list received messages
+    --pid PID         only list messages captured by process PID
+    --sender ID       only list messages from the sender with hexadecimal ID
+    --payload         dump the captured payload bytes after each message";
+
+#[derive(Default)]
+struct Filter {
+    direction: Option<Direction>,
+    pid: Option<u32>,
+    sender_id: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, frame: &CaptureFrame) -> bool {
+        self.direction.iter().all(|&direction| frame.direction == direction) &&
+            self.pid.iter().all(|&pid| frame.pid == pid) &&
+            self.sender_id.iter().all(|&sender_id| frame.sender_id == sender_id)
+    }
+}
+
+fn main() {
+    let mut path = None;
+    let mut filter = Filter::default();
+    let mut dump_payload = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--sent" => filter.direction = Some(Direction::Sent),
+            "--received" => filter.direction = Some(Direction::Received),
+            "--pid" => {
+                filter.pid = Some(args.next().and_then(|pid| pid.parse().ok())
+                                             .unwrap_or_else(|| usage_error("invalid --pid")))
+            }
+            "--sender" => {
+                filter.sender_id = Some(args.next()
+                                            .and_then(|id| u64::from_str_radix(&id, 16).ok())
+                                            .unwrap_or_else(|| usage_error("invalid --sender")))
+            }
+            "--payload" => dump_payload = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => usage_error(&format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage_error("no capture given"));
+
+    if let Err(error) = inspect(&path, &filter, dump_payload) {
+        eprintln!("ipc-inspect: {}: {}", path, error);
+        process::exit(1);
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("ipc-inspect: {}\n\n{}", message, USAGE);
+    process::exit(2)
+}
+
+fn inspect(path: &str, filter: &Filter, dump_payload: bool) -> Result<(), io::Error> {
+    let reader = CaptureReader::new(BufReader::new(File::open(path)?))?;
+    for frame in reader {
+        let frame = frame?;
+        if !filter.matches(&frame) {
+            continue
+        }
+        println!("{}", describe(&frame));
+        if dump_payload {
+            for (index, line) in frame.payload.chunks(16).enumerate() {
+                println!("    {:08x}  {}", index * 16, hex_line(line));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe(frame: &CaptureFrame) -> String {
+    let mut description = format!("{}.{:09} pid {} {} sender {:x} #{}: {} bytes",
+                                  frame.timestamp.as_secs(),
+                                  frame.timestamp.subsec_nanos(),
+                                  frame.pid,
+                                  match frame.direction {
+                                      Direction::Sent => "sent",
+                                      Direction::Received => "recv",
+                                  },
+                                  frame.sender_id,
+                                  frame.sequence,
+                                  frame.payload_length);
+    if frame.channel_count > 0 {
+        description += &format!(", {} channels", frame.channel_count);
+    }
+    if !frame.shared_memory_lengths.is_empty() {
+        let lengths: Vec<String> =
+            frame.shared_memory_lengths.iter().map(|length| length.to_string()).collect();
+        description += &format!(", shared memory [{}]", lengths.join(", "));
+    }
+    description
+}
+
+// Hex bytes followed by their printable ASCII characters, like `hexdump -C`.
+fn hex_line(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let text: String = bytes.iter().map(|&byte| {
+        if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }
+    }).collect();
+    format!("{:<47}  |{}|", hex.join(" "), text)
+}
@@ -0,0 +1,144 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encoding byte vectors in one go rather than byte by byte.
+//!
+//! Serde can't tell a `Vec<u8>` from any other vector, so bincode encodes one a
+//! byte at a time, and messages dominated by large buffers such as images spend
+//! most of their time doing so. Byte fields marked with
+//! `#[serde(with = "ipc_channel::bytes")]`, or of the [ByteBuf] type, are copied
+//! into the message as a whole instead.
+//!
+//! The encoding is the same either way, so marking fields doesn't change the
+//! protocol: peers built without the marks can still read the messages.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::bytes::ByteBuf;
+//! # use ipc_channel::ipc;
+//! let (tx, rx) = ipc::channel().unwrap();
+//! tx.send((640u32, 480u32, ByteBuf::from(vec![0; 640 * 480]))).unwrap();
+//! let (width, height, pixels): (u32, u32, ByteBuf) = rx.recv().unwrap();
+//! assert_eq!(pixels.len(), (width * height) as usize);
+//! ```
+//!
+//! [ByteBuf]: struct.ByteBuf.html
+
+use ipc::WireCompatible;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+
+/// Serialize `bytes` in one go, for fields marked `#[serde(with = "ipc_channel::bytes")]`.
+pub fn serialize<T, S>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+                       where T: AsRef<[u8]> + ?Sized, S: Serializer {
+    serializer.serialize_bytes(bytes.as_ref())
+}
+
+/// Deserialize bytes serialized by [serialize] or as a `Vec<u8>`.
+///
+/// [serialize]: fn.serialize.html
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+                              where T: From<Vec<u8>>, D: Deserializer<'de> {
+    deserializer.deserialize_byte_buf(ByteBufVisitor).map(T::from)
+}
+
+struct ByteBufVisitor;
+
+impl<'de> de::Visitor<'de> for ByteBufVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("bytes")
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> where E: de::Error {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> where E: de::Error {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Vec<u8>, A::Error> where A: de::SeqAccess<'de> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// A `Vec<u8>` serialized in one go.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteBuf(pub Vec<u8>);
+
+impl ByteBuf {
+    /// The bytes, as a plain vector.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for ByteBuf {
+    fn from(bytes: Vec<u8>) -> ByteBuf {
+        ByteBuf(bytes)
+    }
+}
+
+impl From<ByteBuf> for Vec<u8> {
+    fn from(bytes: ByteBuf) -> Vec<u8> {
+        bytes.0
+    }
+}
+
+/// Byte buffers are serialized like the vectors they hold, so channels of one can
+/// be cast to channels of the other.
+impl WireCompatible<Vec<u8>> for ByteBuf {}
+
+impl WireCompatible<ByteBuf> for Vec<u8> {}
+
+impl AsRef<[u8]> for ByteBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for ByteBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for ByteBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Debug for ByteBuf {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt(formatter)
+    }
+}
+
+impl Serialize for ByteBuf {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        deserialize(deserializer)
+    }
+}
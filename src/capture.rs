@@ -0,0 +1,329 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Recording the messages this process sends and receives, for debugging protocol issues.
+//!
+//! While a capture is running, every message sent with [IpcSender::send] or
+//! [IpcSender::send_raw], and every message received on an [IpcReceiver] or
+//! [IpcReceiverSet], is written to the capture as a frame. Captures can be read back with
+//! [CaptureReader], or listed with the `ipc-inspect` binary shipped with the crate:
+//!
+//! ```text
+//! ipc-inspect capture.ipccap --received --sender 2a --payload
+//! ```
+//!
+//! # Format
+//!
+//! All integers are little-endian. A capture starts with a 16 byte header:
+//!
+//! | Offset | Size | Field                                             |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 8    | magic, `b"IPCCAPT\0"`                             |
+//! | 8      | 2    | format version, 1                                 |
+//! | 10     | 2    | reserved, 0                                       |
+//! | 12     | 4    | snapshot length: payload bytes kept in each frame |
+//!
+//! One frame per message follows, each made of a 48 byte frame header, the
+//! lengths of the attached shared memory regions, and the start of the payload:
+//!
+//! | Offset | Size            | Field                                                |
+//! |--------|-----------------|------------------------------------------------------|
+//! | 0      | 8               | timestamp, in nanoseconds since the Unix epoch       |
+//! | 8      | 1               | direction: 0 sent, 1 received                        |
+//! | 9      | 3               | reserved, 0                                          |
+//! | 12     | 4               | id of the capturing process                          |
+//! | 16     | 8               | [sender ID] of the message                           |
+//! | 24     | 8               | sequence number of the message                       |
+//! | 32     | 4               | payload length                                       |
+//! | 36     | 4               | captured payload length, at most the snapshot length |
+//! | 40     | 2               | number of channels attached                          |
+//! | 42     | 2               | number of shared memory regions attached             |
+//! | 44     | 4               | reserved, 0                                          |
+//! | 48     | 8 per region    | lengths of the shared memory regions                 |
+//! |        | captured length | leading bytes of the payload                         |
+//!
+//! The payload is the message as serialized by bincode, without the message
+//! header; the contents of channels and shared memory regions aren't captured.
+//! Messages of channels without the [metadata header] are recorded with a sender
+//! ID and sequence number of 0.
+//! As with pcap's snapshot length, a snapshot length of 0 records the metadata only.
+//!
+//! [IpcSender::send]: ../ipc/struct.IpcSender.html#method.send
+//! [IpcSender::send_raw]: ../ipc/struct.IpcSender.html#method.send_raw
+//! [IpcReceiver]: ../ipc/struct.IpcReceiver.html
+//! [IpcReceiverSet]: ../ipc/struct.IpcReceiverSet.html
+//! [CaptureReader]: struct.CaptureReader.html
+//! [sender ID]: ../ipc/struct.IpcSender.html#method.sender_id
+//! [metadata header]: ../ipc/struct.BincodeConfig.html#method.metadata_header
+
+use ipc::IpcMessageMetadata;
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"IPCCAPT\0";
+const VERSION: u16 = 1;
+const FILE_HEADER_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = 48;
+
+struct Sink {
+    writer: Box<dyn Write + Send>,
+    snapshot_length: u32,
+}
+
+lazy_static! {
+    static ref SINK: Mutex<Option<Sink>> = Mutex::new(None);
+}
+
+// Whether `SINK` is set, checked first so messages aren't slowed down by the lock
+// when not capturing.
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// Whether a message was sent or received by the capturing process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Start capturing messages to `writer`, keeping up to `snapshot_length`
+/// bytes of each payload.
+///
+/// Replaces any running capture. If writing a frame fails, the capture stops, as it
+/// does for a message the format can't describe: one with a payload of 4 GiB or more,
+/// or with more than 65535 channels or shared memory regions attached.
+pub fn start_capture<W>(mut writer: W, snapshot_length: u32) -> Result<(), Error>
+                        where W: Write + Send + 'static {
+    let mut header = [0; FILE_HEADER_SIZE];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..10].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&snapshot_length.to_le_bytes());
+    writer.write_all(&header)?;
+    *SINK.lock().unwrap() = Some(Sink {
+        writer: Box::new(writer),
+        snapshot_length,
+    });
+    CAPTURING.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stop capturing, flushing the writer and handing it back. Returns `None` if no
+/// capture was running, or it stopped after an error.
+pub fn stop_capture() -> Option<Box<dyn Write + Send>> {
+    CAPTURING.store(false, Ordering::SeqCst);
+    let mut sink = SINK.lock().unwrap().take()?;
+    sink.writer.flush().ok()?;
+    Some(sink.writer)
+}
+
+pub(crate) fn is_capturing() -> bool {
+    CAPTURING.load(Ordering::Relaxed)
+}
+
+/// Record a message, if a capture is running.
+pub(crate) fn record<I>(direction: Direction,
+                        metadata: IpcMessageMetadata,
+                        payload: &[u8],
+                        channel_count: usize,
+                        shared_memory_lengths: I)
+                        where I: ExactSizeIterator<Item = usize> {
+    if !is_capturing() {
+        return
+    }
+    let mut sink = SINK.lock().unwrap();
+    let failed = match *sink {
+        Some(ref mut sink) => {
+            let frame = encode_frame(direction,
+                                     metadata,
+                                     payload,
+                                     sink.snapshot_length,
+                                     channel_count,
+                                     shared_memory_lengths);
+            frame.and_then(|frame| sink.writer.write_all(&frame)).is_err()
+        }
+        None => false,
+    };
+    if failed {
+        CAPTURING.store(false, Ordering::SeqCst);
+        *sink = None;
+    }
+}
+
+fn encode_frame<I>(direction: Direction,
+                   metadata: IpcMessageMetadata,
+                   payload: &[u8],
+                   snapshot_length: u32,
+                   channel_count: usize,
+                   shared_memory_lengths: I)
+                   -> Result<Vec<u8>, Error>
+                   where I: ExactSizeIterator<Item = usize> {
+    let too_large = |_| Error::new(ErrorKind::InvalidInput, "message too large to capture");
+    let payload_length = u32::try_from(payload.len()).map_err(too_large)?;
+    let captured = cmp::min(payload_length, snapshot_length);
+    let channel_count = u16::try_from(channel_count).map_err(too_large)?;
+    let region_count = u16::try_from(shared_memory_lengths.len()).map_err(too_large)?;
+    let timestamp = UNIX_EPOCH.elapsed().unwrap_or_default();
+    let mut frame = Vec::with_capacity(
+        FRAME_HEADER_SIZE + 8 * shared_memory_lengths.len() + captured as usize);
+    frame.extend_from_slice(&(timestamp.as_nanos() as u64).to_le_bytes());
+    frame.push(match direction {
+        Direction::Sent => 0,
+        Direction::Received => 1,
+    });
+    frame.extend_from_slice(&[0; 3]);
+    frame.extend_from_slice(&process::id().to_le_bytes());
+    frame.extend_from_slice(&metadata.sender_id().to_le_bytes());
+    frame.extend_from_slice(&metadata.sequence().to_le_bytes());
+    frame.extend_from_slice(&payload_length.to_le_bytes());
+    frame.extend_from_slice(&captured.to_le_bytes());
+    frame.extend_from_slice(&channel_count.to_le_bytes());
+    frame.extend_from_slice(&region_count.to_le_bytes());
+    frame.extend_from_slice(&[0; 4]);
+    for length in shared_memory_lengths {
+        frame.extend_from_slice(&(length as u64).to_le_bytes());
+    }
+    frame.extend_from_slice(&payload[..captured as usize]);
+    Ok(frame)
+}
+
+/// A message recorded in a capture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureFrame {
+    /// Time since the Unix epoch.
+    pub timestamp: Duration,
+    pub direction: Direction,
+    /// Id of the capturing process.
+    pub pid: u32,
+    pub sender_id: u64,
+    pub sequence: u64,
+    /// Length of the whole payload, of which `payload` holds the start.
+    pub payload_length: u32,
+    pub channel_count: u16,
+    pub shared_memory_lengths: Vec<u64>,
+    pub payload: Vec<u8>,
+}
+
+/// Reads the frames of a capture, as an iterator.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::capture::CaptureReader;
+/// # let capture: &[u8] = b"IPCCAPT\0\x01\0\0\0\0\0\0\0";
+/// let reader = CaptureReader::new(capture).unwrap();
+/// for frame in reader {
+///     let frame = frame.unwrap();
+///     println!("{:x} #{}: {} bytes", frame.sender_id, frame.sequence, frame.payload_length);
+/// }
+/// ```
+pub struct CaptureReader<R> {
+    reader: R,
+    snapshot_length: u32,
+}
+
+impl<R> CaptureReader<R> where R: Read {
+    /// Check the capture header, failing with `ErrorKind::InvalidData` if this
+    /// isn't a capture of a supported version.
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>, Error> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not an ipc-channel capture"))
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unsupported capture version {}", version)))
+        }
+        Ok(CaptureReader {
+            reader,
+            snapshot_length: u32::from_le_bytes([header[12], header[13], header[14], header[15]]),
+        })
+    }
+
+    /// The number of payload bytes kept in each frame.
+    pub fn snapshot_length(&self) -> u32 {
+        self.snapshot_length
+    }
+
+    fn read_frame(&mut self) -> Result<Option<CaptureFrame>, Error> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        // Only a frame that is missing entirely means the capture ended cleanly.
+        let mut filled = 0;
+        while filled < FRAME_HEADER_SIZE {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(count) => filled += count,
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let u32_at = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&header[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let u64_at = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&header[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let direction = match header[8] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            direction => {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("invalid frame direction {}", direction)))
+            }
+        };
+        let mut shared_memory_lengths = Vec::with_capacity(u16_at(42) as usize);
+        for _ in 0..u16_at(42) {
+            let mut length = [0; 8];
+            self.reader.read_exact(&mut length)?;
+            shared_memory_lengths.push(u64::from_le_bytes(length));
+        }
+        // The captured length comes from the capture, so check it rather than allocate
+        // whatever it says, and read the payload as it comes.
+        let captured = u32_at(36);
+        if captured > u32_at(32) || captured > self.snapshot_length {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("invalid captured payload length {}", captured)))
+        }
+        let mut payload = vec![];
+        (&mut self.reader).take(u64::from(captured)).read_to_end(&mut payload)?;
+        if payload.len() != captured as usize {
+            return Err(ErrorKind::UnexpectedEof.into())
+        }
+        Ok(Some(CaptureFrame {
+            timestamp: Duration::from_nanos(u64_at(0)),
+            direction,
+            pid: u32_at(12),
+            sender_id: u64_at(16),
+            sequence: u64_at(24),
+            payload_length: u32_at(32),
+            channel_count: u16_at(40),
+            shared_memory_lengths,
+            payload,
+        }))
+    }
+}
+
+impl<R> Iterator for CaptureReader<R> where R: Read {
+    type Item = Result<CaptureFrame, Error>;
+
+    fn next(&mut self) -> Option<Result<CaptureFrame, Error>> {
+        self.read_frame().transpose()
+    }
+}
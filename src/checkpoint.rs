@@ -0,0 +1,56 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Experimental support for resuming after the process was checkpointed and
+//! restored, e.g. with CRIU, or by cloning the virtual machine it runs in.
+//!
+//! The checkpointing tool restores the descriptors of channels, and the channels
+//! between restored processes keep working. What it can't restore is what lives
+//! outside the processes: the socket files [IpcOneShotServer]s listen on may be
+//! gone, e.g. with the temporary directory of another machine, and several copies
+//! of a process restored from the same checkpoint would create senders with the
+//! same [sender IDs]. Call [restored] in each restored process, before using any
+//! channel, to fix both up:
+//!
+//! ```no_run
+//! # use ipc_channel::checkpoint;
+//! let rebound = checkpoint::restored().unwrap();
+//! println!("{} one-shot servers bound anew", rebound);
+//! ```
+//!
+//! Channels to processes that weren't restored along with this one are closed,
+//! as for a peer that died. Mach ports don't survive a checkpoint, so [restored]
+//! fails on macOS; the in-process backend has nothing to restore.
+//!
+//! [IpcOneShotServer]: ../ipc/struct.IpcOneShotServer.html
+//! [sender IDs]: ../ipc/struct.IpcSender.html#method.sender_id
+//! [restored]: fn.restored.html
+
+use ipc;
+use platform;
+use std::io::Error;
+
+/// To be called in a process restored from a checkpoint, before using any channel.
+///
+/// Binds the one-shot servers whose socket file is gone anew at the same path,
+/// so clients can still connect with the name they were given, and returns how
+/// many were. Senders created from now on get IDs distinct from those of other
+/// copies of the process; those created before the checkpoint keep theirs.
+///
+/// Fails with `ErrorKind::Unsupported` on macOS, and with `ErrorKind::PermissionDenied`
+/// in [constrained mode], where sockets can't be bound. The directory of a socket is
+/// created anew for the user alone; if there is one already, which another user may
+/// have put there, it only serves if it is the user's own and closed to others, and
+/// this fails with `ErrorKind::PermissionDenied` otherwise.
+///
+/// [constrained mode]: ../ipc/fn.enter_constrained_mode.html
+pub fn restored() -> Result<usize, Error> {
+    ipc::salt_sender_ids();
+    platform::rebind_one_shot_servers()
+}
@@ -0,0 +1,373 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sending large, mostly unchanged values as differences from the previous one.
+//!
+//! A [DeltaSender] encodes each value, and sends what changed in the encoding since
+//! the value before: the blocks that differ when the encodings are the same length,
+//! or what lies between their common start and end otherwise. The [DeltaReceiver]
+//! applies the differences to the encoding it kept.
+//!
+//! Every so often, and whenever the differences would be about as large, the value is
+//! sent whole instead, as a snapshot. A receiver that can't apply the differences, as
+//! it hasn't received the value they apply to, skips them and asks for a snapshot;
+//! the sender sends one with its next value.
+//!
+//! Values are encoded with bincode's default options, so they can't carry
+//! channels or shared memory regions.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::delta;
+//! let (mut tx, mut rx) = delta::channel::<Vec<u32>>().unwrap();
+//! let mut pixels = vec![0; 100_000];
+//! tx.send(&pixels).unwrap();
+//! assert_eq!(rx.recv().unwrap(), pixels);
+//!
+//! pixels[50_000] = 1;
+//! tx.send(&pixels).unwrap();
+//! assert!(tx.last_frame_len() < 100);
+//! assert_eq!(rx.recv().unwrap(), pixels);
+//! ```
+//!
+//! [DeltaSender]: struct.DeltaSender.html
+//! [DeltaReceiver]: struct.DeltaReceiver.html
+
+use bincode;
+use bytes::ByteBuf;
+use ipc::{self, IpcReceiver, IpcSender};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp;
+use std::fmt::{self, Debug, Formatter};
+use std::io::Error;
+use std::marker::PhantomData;
+
+/// The number of values sent between snapshots by default.
+pub const DEFAULT_SNAPSHOT_INTERVAL: u32 = 64;
+
+// Changes are found in blocks of this many bytes, when the lengths match.
+const BLOCK_SIZE: usize = 32;
+
+// The encoded size of an operation, to weigh differences against snapshots.
+const OPERATION_SIZE: usize = 24;
+
+/// A value sent: its generation, that of the value the differences apply to, or
+/// none for snapshots, then the differences.
+///
+/// The new encoding is made by taking, for each operation, the `length` bytes of
+/// the previous one at `offset`, then the next `inserted` bytes of the insertions.
+type Frame = (u64, Option<u64>, Vec<Operation>, ByteBuf);
+
+/// `(offset, length, inserted)`, as described for `Frame`.
+type Operation = (u64, u64, u64);
+
+/// Create a sender and a receiver of values sent as differences.
+pub fn channel<T>() -> Result<(DeltaSender<T>, DeltaReceiver<T>), Error>
+                  where T: for<'de> Deserialize<'de> + Serialize {
+    let (frame_sender, frame_receiver) = ipc::channel()?;
+    let (resync_sender, resync_receiver) = ipc::channel()?;
+    Ok((DeltaSender {
+        frames: frame_sender,
+        resyncs: resync_receiver,
+        previous: None,
+        generation: 0,
+        since_snapshot: 0,
+        snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        last_frame_len: 0,
+        phantom: PhantomData,
+    }, DeltaReceiver {
+        frames: frame_receiver,
+        resyncs: resync_sender,
+        previous: None,
+        resyncing: false,
+        phantom: PhantomData,
+    }))
+}
+
+/// The sending end of a [delta channel](index.html).
+///
+/// Senders can't be cloned, as each keeps the value it sent last. One sent to
+/// another process starts over with a snapshot.
+pub struct DeltaSender<T> {
+    frames: IpcSender<Frame>,
+    resyncs: IpcReceiver<()>,
+    // The encoding of the value sent last, none to send a snapshot next.
+    previous: Option<Vec<u8>>,
+    generation: u64,
+    since_snapshot: u32,
+    snapshot_interval: u32,
+    last_frame_len: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T> DeltaSender<T> where T: Serialize {
+    /// Send `value`, as its differences from the value sent before if that's smaller.
+    pub fn send(&mut self, value: &T) -> Result<(), bincode::Error> {
+        let encoded = bincode::serialize(value)?;
+        // Any request for a snapshot is answered with this one.
+        let mut snapshot = self.previous.is_none() ||
+                           self.since_snapshot >= self.snapshot_interval;
+        while self.resyncs.try_recv().is_ok() {
+            snapshot = true;
+        }
+        let generation = self.generation + 1;
+        let differences = match self.previous {
+            Some(ref previous) if !snapshot => {
+                let (operations, inserted) = diff(previous, &encoded);
+                let len = operations.len() * OPERATION_SIZE + inserted.len();
+                if len < encoded.len() {
+                    Some((operations, inserted, len))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let frame = match differences {
+            Some((operations, inserted, len)) => {
+                self.last_frame_len = len;
+                self.since_snapshot += 1;
+                (generation, Some(self.generation), operations, ByteBuf(inserted))
+            }
+            None => {
+                self.last_frame_len = encoded.len();
+                self.since_snapshot = 0;
+                (generation, None, vec![], ByteBuf(encoded.clone()))
+            }
+        };
+        self.frames.send(frame)?;
+        self.previous = Some(encoded);
+        self.generation = generation;
+        Ok(())
+    }
+
+    /// Send a snapshot after `interval` values sent as differences, rather than
+    /// after [DEFAULT_SNAPSHOT_INTERVAL].
+    ///
+    /// [DEFAULT_SNAPSHOT_INTERVAL]: constant.DEFAULT_SNAPSHOT_INTERVAL.html
+    pub fn with_snapshot_interval(mut self, interval: u32) -> DeltaSender<T> {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// Send the next value as a snapshot.
+    pub fn force_snapshot(&mut self) {
+        self.previous = None;
+    }
+
+    /// The number of values sent so far.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The size of the last value sent, as differences or whole, to gauge the savings.
+    pub fn last_frame_len(&self) -> usize {
+        self.last_frame_len
+    }
+}
+
+impl<T> Debug for DeltaSender<T> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("DeltaSender")
+                 .field("generation", &self.generation)
+                 .field("snapshot_interval", &self.snapshot_interval)
+                 .finish()
+    }
+}
+
+impl<T> Serialize for DeltaSender<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.frames, &self.resyncs, self.generation, self.snapshot_interval)
+            .serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for DeltaSender<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (frames, resyncs, generation, snapshot_interval) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(DeltaSender {
+            frames,
+            resyncs,
+            previous: None,
+            generation,
+            since_snapshot: 0,
+            snapshot_interval,
+            last_frame_len: 0,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// The receiving end of a [delta channel](index.html).
+///
+/// One sent to another process skips values until the next snapshot.
+pub struct DeltaReceiver<T> {
+    frames: IpcReceiver<Frame>,
+    resyncs: IpcSender<()>,
+    // The encoding of the value received last, and its generation.
+    previous: Option<(u64, Vec<u8>)>,
+    // Whether a snapshot was asked for, and not received yet.
+    resyncing: bool,
+    phantom: PhantomData<T>,
+}
+
+impl<T> DeltaReceiver<T> where T: for<'de> Deserialize<'de> {
+    /// Wait for the next value that can be rebuilt.
+    pub fn recv(&mut self) -> Result<T, bincode::Error> {
+        loop {
+            let frame = self.frames.recv()?;
+            if let Some(value) = self.apply(frame) {
+                return value
+            }
+        }
+    }
+
+    /// Like [recv], failing with `ErrorKind::WouldBlock` rather than waiting if there is
+    /// no value that can be rebuilt.
+    ///
+    /// [recv]: #method.recv
+    pub fn try_recv(&mut self) -> Result<T, bincode::Error> {
+        loop {
+            let frame = self.frames.try_recv()?;
+            if let Some(value) = self.apply(frame) {
+                return value
+            }
+        }
+    }
+
+    /// Rebuild the value of `frame`, or ask for a snapshot if it can't be.
+    fn apply(&mut self, (generation, base, operations, inserted): Frame)
+             -> Option<Result<T, bincode::Error>> {
+        let encoded = match base {
+            None => Some(inserted.0),
+            Some(base) => match self.previous {
+                Some((previous_generation, ref previous)) if previous_generation == base => {
+                    patch(previous, &operations, &inserted)
+                }
+                _ => None,
+            },
+        };
+        let encoded = match encoded {
+            Some(encoded) => encoded,
+            None => {
+                self.previous = None;
+                if !self.resyncing {
+                    // The sender may be gone, but there might be values left to receive.
+                    let _ = self.resyncs.send(());
+                    self.resyncing = true;
+                }
+                return None
+            }
+        };
+        self.resyncing = false;
+        let value = bincode::deserialize(&encoded);
+        self.previous = Some((generation, encoded));
+        Some(value)
+    }
+
+    /// The number of the value received last, counting those sent from 1.
+    pub fn generation(&self) -> Option<u64> {
+        self.previous.as_ref().map(|&(generation, _)| generation)
+    }
+}
+
+impl<T> Debug for DeltaReceiver<T> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("DeltaReceiver")
+                 .field("generation", &self.previous.as_ref().map(|&(generation, _)| generation))
+                 .field("resyncing", &self.resyncing)
+                 .finish()
+    }
+}
+
+impl<T> Serialize for DeltaReceiver<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.frames, &self.resyncs).serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for DeltaReceiver<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (frames, resyncs) = Deserialize::deserialize(deserializer)?;
+        Ok(DeltaReceiver {
+            frames,
+            resyncs,
+            previous: None,
+            resyncing: false,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// The operations and insertions making `new` out of `previous`.
+fn diff(previous: &[u8], new: &[u8]) -> (Vec<Operation>, Vec<u8>) {
+    let mut operations = vec![];
+    let mut inserted = vec![];
+    if previous.len() != new.len() {
+        // Whatever changed shifted the rest, so only the common start and end are kept.
+        let prefix = previous.iter().zip(new).take_while(|&(a, b)| a == b).count();
+        let max_suffix = cmp::min(previous.len(), new.len()) - prefix;
+        let suffix = previous.iter().rev()
+                             .zip(new.iter().rev())
+                             .take(max_suffix)
+                             .take_while(|&(a, b)| a == b)
+                             .count();
+        inserted.extend_from_slice(&new[prefix..new.len() - suffix]);
+        operations.push((0, prefix as u64, inserted.len() as u64));
+        operations.push(((previous.len() - suffix) as u64, suffix as u64, 0));
+        return (operations, inserted)
+    }
+    // The start of the bytes to keep since the last change.
+    let mut kept = 0;
+    let mut offset = 0;
+    while offset < new.len() {
+        let end = cmp::min(offset + BLOCK_SIZE, new.len());
+        if previous[offset..end] == new[offset..end] {
+            offset = end;
+            continue
+        }
+        // Take in the following changed blocks too.
+        let start = offset;
+        offset = end;
+        while offset < new.len() {
+            let end = cmp::min(offset + BLOCK_SIZE, new.len());
+            if previous[offset..end] == new[offset..end] {
+                break
+            }
+            offset = end;
+        }
+        inserted.extend_from_slice(&new[start..offset]);
+        operations.push((kept as u64, (start - kept) as u64, (offset - start) as u64));
+        kept = offset;
+    }
+    if kept < new.len() {
+        operations.push((kept as u64, (new.len() - kept) as u64, 0));
+    }
+    (operations, inserted)
+}
+
+/// Apply `operations` to `previous`, or none if they don't fit.
+fn patch(previous: &[u8], operations: &[Operation], inserted: &[u8]) -> Option<Vec<u8>> {
+    let mut new = Vec::with_capacity(previous.len());
+    let mut insertions = inserted;
+    for &(offset, length, inserted_length) in operations {
+        let start = offset as usize;
+        let end = start.checked_add(length as usize)?;
+        new.extend_from_slice(previous.get(start..end)?);
+        if inserted_length as usize > insertions.len() {
+            return None
+        }
+        let (insertion, rest) = insertions.split_at(inserted_length as usize);
+        new.extend_from_slice(insertion);
+        insertions = rest;
+    }
+    Some(new)
+}
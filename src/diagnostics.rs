@@ -0,0 +1,152 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A report of the IPC resources this process holds, e.g. to attach to bug reports.
+//!
+//! Where [events] tell about resources as they come and go, [dump] takes stock of
+//! those alive right now, whenever it is called: the channel endpoints, one-shot
+//! servers and shared memory regions the process holds open, with what the OS
+//! can tell about them, along with the receiver sets and routes of this crate.
+//!
+//! ```
+//! # use ipc_channel::diagnostics;
+//! # use ipc_channel::ipc;
+//! let (_tx, _rx) = ipc::channel::<u32>().unwrap();
+//! let report = diagnostics::dump().unwrap();
+//! println!("{}", report);
+//! ```
+//!
+//! On the unix backend the report is built from the descriptors open in the
+//! process, so it lists channels whichever part of the process created them, and
+//! both ends of a channel whose ends are both held. The in-process backend lists
+//! one-shot servers only, its channels and regions not being OS resources; on
+//! macOS, [dump] fails with `ErrorKind::Unsupported`.
+//!
+//! [events]: ../events/index.html
+//! [dump]: fn.dump.html
+
+use ipc;
+use platform;
+use registry;
+use std::fmt::{self, Display, Formatter};
+use std::io::Error;
+use std::process;
+
+/// The IPC resources alive in a process, as returned by [dump].
+///
+/// [dump]: fn.dump.html
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IpcDiagnostics {
+    pub pid: u32,
+    pub channels: Vec<ChannelDiagnostics>,
+    pub servers: Vec<ServerDiagnostics>,
+    pub shared_memory_regions: Vec<SharedMemoryDiagnostics>,
+    /// The bytes of shared memory accounted to the process, as with
+    /// [ipc::shared_memory_in_use].
+    ///
+    /// [ipc::shared_memory_in_use]: ../ipc/fn.shared_memory_in_use.html
+    pub shared_memory_in_use: usize,
+    /// The number of receiver sets alive, including those of routers.
+    pub receiver_sets: usize,
+    /// The number of receivers handed to routers, whose channels are still open.
+    pub routed_receivers: usize,
+}
+
+/// A channel endpoint: a sender or receiver, or both ends of a channel passed
+/// around as an opaque one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelDiagnostics {
+    /// The descriptor of the endpoint.
+    pub handle: Option<i64>,
+    /// The name of the server the channel was connected to, for senders connected
+    /// with `IpcSender::connect()` and [bootstrap] channels.
+    ///
+    /// [bootstrap]: ../process/fn.bootstrap_sender.html
+    pub label: Option<String>,
+    /// The process that created the other end, or connected it to a server.
+    pub peer_pid: Option<u32>,
+    /// The bytes of the messages queued to be received from the endpoint.
+    pub queued_bytes: Option<usize>,
+}
+
+/// A one-shot server waiting for its client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerDiagnostics {
+    pub handle: Option<i64>,
+    pub name: String,
+}
+
+/// A mapping of a shared memory region; clones of a region are mapped separately.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SharedMemoryDiagnostics {
+    pub handle: Option<i64>,
+    pub length: usize,
+}
+
+/// Take stock of the IPC resources alive in this process.
+///
+/// The resources are listed one after the other, so those created or dropped
+/// meanwhile by other threads may or may not be.
+pub fn dump() -> Result<IpcDiagnostics, Error> {
+    let (receiver_sets, routed_receivers) = {
+        let registry = registry::lock();
+        (registry.receiver_sets, registry.routed_receivers)
+    };
+    let mut report = IpcDiagnostics {
+        pid: process::id(),
+        shared_memory_in_use: ipc::shared_memory_in_use(),
+        receiver_sets,
+        routed_receivers,
+        ..IpcDiagnostics::default()
+    };
+    platform::diagnose(&mut report)?;
+    Ok(report)
+}
+
+impl Display for IpcDiagnostics {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        writeln!(formatter, "IPC resources of process {}:", self.pid)?;
+        writeln!(formatter, "  {} channel endpoints", self.channels.len())?;
+        for channel in &self.channels {
+            write!(formatter, "    {}", Handle(channel.handle))?;
+            if let Some(ref label) = channel.label {
+                write!(formatter, " to {}", label)?;
+            }
+            if let Some(peer_pid) = channel.peer_pid {
+                write!(formatter, ", peer {}", peer_pid)?;
+            }
+            if let Some(queued_bytes) = channel.queued_bytes {
+                write!(formatter, ", {} bytes queued", queued_bytes)?;
+            }
+            writeln!(formatter)?;
+        }
+        writeln!(formatter, "  {} one-shot servers", self.servers.len())?;
+        for server in &self.servers {
+            writeln!(formatter, "    {} at {}", Handle(server.handle), server.name)?;
+        }
+        writeln!(formatter, "  {} shared memory mappings, {} bytes in use",
+                 self.shared_memory_regions.len(), self.shared_memory_in_use)?;
+        for region in &self.shared_memory_regions {
+            writeln!(formatter, "    {} of {} bytes", Handle(region.handle), region.length)?;
+        }
+        writeln!(formatter, "  {} receiver sets", self.receiver_sets)?;
+        write!(formatter, "  {} routed receivers", self.routed_receivers)
+    }
+}
+
+struct Handle(Option<i64>);
+
+impl Display for Handle {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match self.0 {
+            Some(handle) => write!(formatter, "#{}", handle),
+            None => write!(formatter, "-"),
+        }
+    }
+}
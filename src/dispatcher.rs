@@ -0,0 +1,368 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Handling the messages of one channel on several threads.
+//!
+//! The [router] runs every handler on its single thread, so a handler doing real
+//! work holds up all the routes. A [Dispatcher] receives the messages of one channel
+//! on a thread of its own, and hands them to a pool of worker threads: each worker
+//! has a queue, which messages are added to in turn, and a worker whose queue is
+//! empty steals from the back of the others', so a slow message doesn't hold up the
+//! ones queued behind it. Messages are numbered with a [DispatchId] as they are
+//! received, by which their completion can be awaited.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::dispatcher::{DispatchId, Dispatcher};
+//! # use ipc_channel::ipc;
+//! # use std::sync::atomic::{AtomicUsize, Ordering};
+//! # use std::sync::Arc;
+//! let (tx, rx) = ipc::channel::<usize>().unwrap();
+//! let total = Arc::new(AtomicUsize::new(0));
+//! let handler_total = total.clone();
+//! let dispatcher = Dispatcher::new(rx, 4, move |_, length| {
+//!     handler_total.fetch_add(length, Ordering::SeqCst);
+//! }).unwrap();
+//! for length in 1..=10 {
+//!     tx.send(length).unwrap();
+//! }
+//! assert!(dispatcher.wait(DispatchId::from(9)));
+//! dispatcher.wait_idle();
+//! assert_eq!(total.load(Ordering::SeqCst), 55);
+//! ```
+//!
+//! [router]: ../router/index.html
+//! [Dispatcher]: struct.Dispatcher.html
+//! [DispatchId]: struct.DispatchId.html
+
+use ipc::{self, IpcReceiver, IpcSender, TypedReceiverSet, TypedSelectionResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use threads;
+
+/// The number of a message handed out by a [Dispatcher], counting from 0 in the
+/// order the messages were received.
+///
+/// [Dispatcher]: struct.Dispatcher.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DispatchId(u64);
+
+impl From<u64> for DispatchId {
+    fn from(id: u64) -> DispatchId {
+        DispatchId(id)
+    }
+}
+
+impl From<DispatchId> for u64 {
+    fn from(id: DispatchId) -> u64 {
+        id.0
+    }
+}
+
+/// The messages of a channel, handled on a pool of worker threads.
+///
+/// The receiving thread is named with the `dispatcher` role, and the workers with
+/// the `dispatcher-worker` role, as set with [threads::set_thread_config]. Messages
+/// that can't be deserialized are dropped. Handlers that panic don't take their
+/// worker down: their message counts as complete, and as [panicked].
+///
+/// Dropping the dispatcher stops receiving, waits for the messages being handled,
+/// and drops those still queued; [wait_idle] first to have them all handled.
+///
+/// [threads::set_thread_config]: ../threads/fn.set_thread_config.html
+/// [panicked]: #method.panicked
+/// [wait_idle]: #method.wait_idle
+pub struct Dispatcher<T> {
+    shared: Arc<Shared<T>>,
+    stop_sender: IpcSender<()>,
+    receiving: Option<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared<T> {
+    // One queue per worker, each taken from the front by its worker, and from the
+    // back by the others when theirs are empty.
+    queues: Vec<Mutex<VecDeque<(DispatchId, T)>>>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    received: u64,
+    // The messages in the queues, which idle workers wait for.
+    queued: usize,
+    // The messages below this have all been handled.
+    completed_below: u64,
+    // The messages handled above `completed_below`.
+    completed_above: HashSet<u64>,
+    panicked: u64,
+    closed: bool,
+    stopping: bool,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn dispatch(&self, message: T) {
+        let mut state = self.lock();
+        let id = DispatchId(state.received);
+        state.received += 1;
+        let worker = (id.0 % self.queues.len() as u64) as usize;
+        self.queues[worker].lock().unwrap().push_back((id, message));
+        state.queued += 1;
+        self.changed.notify_all();
+    }
+
+    /// Take a message from the queue of `worker`, or steal one from another.
+    fn take(&self, worker: usize) -> Option<(DispatchId, T)> {
+        let count = self.queues.len();
+        let message = self.queues[worker].lock().unwrap().pop_front().or_else(|| {
+            (1..count).filter_map(|offset| {
+                self.queues[(worker + offset) % count].lock().unwrap().pop_back()
+            }).next()
+        });
+        if message.is_some() {
+            self.lock().queued -= 1;
+        }
+        message
+    }
+
+    fn work<F>(&self, worker: usize, handler: &F) where F: Fn(DispatchId, T) {
+        loop {
+            if let Some((id, message)) = self.take(worker) {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| handler(id, message)));
+                self.complete(id, result.is_err());
+                continue
+            }
+            let state = self.lock();
+            if state.stopping || (state.closed && state.queued == 0) {
+                return
+            }
+            if state.queued == 0 {
+                drop(self.changed.wait(state).unwrap());
+            } else {
+                // Another worker took the message, but hasn't counted it yet.
+                drop(state);
+                thread::yield_now();
+            }
+        }
+    }
+
+    fn complete(&self, id: DispatchId, panicked: bool) {
+        let mut state = self.lock();
+        if panicked {
+            state.panicked += 1;
+        }
+        if id.0 == state.completed_below {
+            state.completed_below += 1;
+            loop {
+                let next = state.completed_below;
+                if !state.completed_above.remove(&next) {
+                    break
+                }
+                state.completed_below += 1;
+            }
+        } else {
+            state.completed_above.insert(id.0);
+        }
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl State {
+    fn is_complete(&self, id: DispatchId) -> bool {
+        id.0 < self.completed_below || self.completed_above.contains(&id.0)
+    }
+
+    fn completed(&self) -> u64 {
+        self.completed_below + self.completed_above.len() as u64
+    }
+}
+
+impl<T> Dispatcher<T> where T: for<'de> Deserialize<'de> + Serialize + Send + 'static {
+    /// Receive the messages of `receiver`, handling each with `handler` on one of
+    /// `workers` threads.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if there are no workers.
+    pub fn new<F>(receiver: IpcReceiver<T>, workers: usize, handler: F)
+                  -> Result<Dispatcher<T>, Error>
+                  where F: Fn(DispatchId, T) + Send + Sync + 'static {
+        if workers == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "a dispatcher needs workers"))
+        }
+        let (stop_sender, stop_receiver) = ipc::channel()?;
+        let mut receiver_set = TypedReceiverSet::new()?;
+        let stop_id = receiver_set.add(stop_receiver, |()| None)?;
+        receiver_set.add(receiver, Some)?;
+
+        // Threads that started are stopped by dropping this if the others can't be.
+        let mut dispatcher = Dispatcher {
+            shared: Arc::new(Shared {
+                queues: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+                state: Mutex::new(State {
+                    received: 0,
+                    queued: 0,
+                    completed_below: 0,
+                    completed_above: HashSet::new(),
+                    panicked: 0,
+                    closed: false,
+                    stopping: false,
+                }),
+                changed: Condvar::new(),
+            }),
+            stop_sender,
+            receiving: None,
+            workers: Vec::with_capacity(workers),
+        };
+        let handler = Arc::new(handler);
+        for worker in 0..workers {
+            let shared = dispatcher.shared.clone();
+            let handler = handler.clone();
+            dispatcher.workers.push(spawn("dispatcher-worker", move || {
+                shared.work(worker, &*handler)
+            })?);
+        }
+        let shared = dispatcher.shared.clone();
+        dispatcher.receiving = Some(spawn("dispatcher", move || {
+            receive(&shared, receiver_set, stop_id)
+        })?);
+        Ok(dispatcher)
+    }
+}
+
+impl<T> Dispatcher<T> {
+    /// Whether the message `id` has been handled.
+    pub fn is_complete(&self, id: DispatchId) -> bool {
+        self.shared.lock().is_complete(id)
+    }
+
+    /// Wait for the message `id` to be handled, returning whether it was; it isn't
+    /// if the channel closes before the message is received.
+    pub fn wait(&self, id: DispatchId) -> bool {
+        let mut state = self.shared.lock();
+        loop {
+            if state.is_complete(id) {
+                return true
+            }
+            if state.closed && id.0 >= state.received {
+                return false
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Wait for the messages received so far to be handled.
+    pub fn wait_idle(&self) {
+        let mut state = self.shared.lock();
+        let received = state.received;
+        while state.completed_below < received {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// The number of messages received so far.
+    pub fn received(&self) -> u64 {
+        self.shared.lock().received
+    }
+
+    /// The number of messages received, but not handled yet.
+    pub fn in_flight(&self) -> u64 {
+        let state = self.shared.lock();
+        state.received - state.completed()
+    }
+
+    /// The number of messages whose handler panicked.
+    pub fn panicked(&self) -> u64 {
+        self.shared.lock().panicked
+    }
+
+    /// Whether the channel was closed, all its senders being gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl<T> Drop for Dispatcher<T> {
+    fn drop(&mut self) {
+        // The receiving thread is gone already if the channel was closed.
+        let _ = self.stop_sender.send(());
+        if let Some(receiving) = self.receiving.take() {
+            let _ = receiving.join();
+        }
+        self.shared.lock().stopping = true;
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<T> Debug for Dispatcher<T> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        let state = self.shared.lock();
+        formatter.debug_struct("Dispatcher")
+                 .field("workers", &self.workers.len())
+                 .field("received", &state.received)
+                 .field("completed", &state.completed())
+                 .finish()
+    }
+}
+
+fn receive<T>(shared: &Shared<T>, mut receiver_set: TypedReceiverSet<Option<T>>, stop_id: u64) {
+    loop {
+        let results = match receiver_set.select() {
+            Ok(results) => results,
+            Err(_) => return shared.close(),
+        };
+        for result in results {
+            match result {
+                TypedSelectionResult::MessageReceived(_, Some(message)) => {
+                    shared.dispatch(message)
+                }
+                TypedSelectionResult::MessageReceived(..) => return,
+                TypedSelectionResult::DeserializationFailed(..) => {}
+                TypedSelectionResult::ChannelClosed(id) => {
+                    if id != stop_id {
+                        return shared.close()
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Spawn a thread for `role`, configured as set with `threads::set_thread_config`.
+fn spawn<F>(role: &str, run: F) -> Result<JoinHandle<()>, Error>
+            where F: FnOnce() + Send + 'static {
+    let (started_sender, started_receiver) = mpsc::channel();
+    let thread = threads::builder(role).spawn(move || {
+        let result = threads::configure_current_thread();
+        let started = result.is_ok();
+        started_sender.send(result).unwrap();
+        if started {
+            run()
+        }
+    })?;
+    started_receiver.recv().unwrap()?;
+    Ok(thread)
+}
@@ -0,0 +1,283 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Handing frames from a producer to a consumer through two shared memory buffers.
+//!
+//! The [DoubleBuffer] writes each frame into the back buffer, then flips: it makes
+//! the back buffer the front one, in a word of shared memory, and sends the
+//! [DoubleBufferReader] a notification carrying the frame's generation. The reader
+//! switches to the new front buffer when it receives one, and tells the producer,
+//! which only then writes into the buffer the reader left. So readers never see a
+//! frame being written, and frames are never copied through the channel.
+//!
+//! Before writing a frame, the producer waits for the reader to release the buffer
+//! it is about to overwrite, so a slow reader holds the producer back: `send` blocks
+//! until the reader has received the previous frame. Both ends can be sent to other
+//! processes.
+//!
+//! Double buffers are unavailable on macOS, where [received shared memory] doesn't
+//! see the producer's later writes.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::double_buffer;
+//! let (mut producer, mut reader) = double_buffer::channel(1024).unwrap();
+//! producer.send(b"first frame").unwrap();
+//! assert_eq!(reader.recv().unwrap(), b"first frame");
+//! producer.send(b"second frame").unwrap();
+//! assert_eq!(reader.recv().unwrap(), b"second frame");
+//! assert_eq!(reader.generation(), 2);
+//! ```
+//!
+//! [DoubleBuffer]: struct.DoubleBuffer.html
+//! [DoubleBufferReader]: struct.DoubleBufferReader.html
+//! [received shared memory]: ../ipc/struct.IpcSharedMemory.html#sharing-between-processes
+
+use bincode;
+use ipc::{self, IpcReceiver, IpcSender, IpcSharedMemory};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use words::{self, WORD_SIZE};
+
+/// Create a producer and a reader of frames of up to `capacity` bytes. Fails with
+/// `ErrorKind::OutOfMemory` rather than exceed the [shared memory quota].
+///
+/// [shared memory quota]: ../ipc/fn.set_shared_memory_quota.html
+pub fn channel(capacity: usize) -> Result<(DoubleBuffer, DoubleBufferReader), Error> {
+    let size = words::slot_words(capacity)
+                   .checked_mul(WORD_SIZE)
+                   .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "capacity too large"))?;
+    let shared = Shared {
+        // The generation of the front frame; its buffer is the generation's parity.
+        front: IpcSharedMemory::try_from_byte(0, WORD_SIZE)?,
+        buffers: [IpcSharedMemory::try_from_byte(0, size)?,
+                  IpcSharedMemory::try_from_byte(0, size)?],
+    };
+    let (flip_sender, flip_receiver) = ipc::channel()?;
+    let (release_sender, release_receiver) = ipc::channel()?;
+    Ok((DoubleBuffer {
+        shared: shared.clone(),
+        flips: flip_sender,
+        releases: release_receiver,
+        generation: 0,
+        released: 0,
+    }, DoubleBufferReader {
+        shared,
+        flips: flip_receiver,
+        releases: release_sender,
+        generation: 0,
+    }))
+}
+
+#[derive(Clone)]
+struct Shared {
+    front: IpcSharedMemory,
+    buffers: [IpcSharedMemory; 2],
+}
+
+impl Shared {
+    fn new(front: IpcSharedMemory, buffers: [IpcSharedMemory; 2]) -> Option<Shared> {
+        let size = buffers[0].len();
+        if front.len() != WORD_SIZE || size < WORD_SIZE || size & (WORD_SIZE - 1) != 0 ||
+           buffers[1].len() != size {
+            return None
+        }
+        Some(Shared { front, buffers })
+    }
+
+    fn front_generation(&self) -> &AtomicU64 {
+        &self.front.atomic_u64s(0, 1).expect("invalid double buffer")[0]
+    }
+
+    fn buffer(&self, generation: u64) -> &IpcSharedMemory {
+        &self.buffers[(generation & 1) as usize]
+    }
+
+    /// The buffer of `generation`: the frame's length, then the frame.
+    fn words(&self, generation: u64) -> &[AtomicU64] {
+        let buffer = self.buffer(generation);
+        buffer.atomic_u64s(0, buffer.len() / WORD_SIZE).expect("invalid double buffer")
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffers[0].len() - WORD_SIZE
+    }
+}
+
+impl Serialize for Shared {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.front, &self.buffers[0], &self.buffers[1]).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Shared {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (front, first, second) = Deserialize::deserialize(deserializer)?;
+        Shared::new(front, [first, second]).ok_or_else(|| {
+            de::Error::custom("invalid double buffer")
+        })
+    }
+}
+
+/// The producing end of a [double buffer](index.html).
+pub struct DoubleBuffer {
+    shared: Shared,
+    flips: IpcSender<u64>,
+    releases: IpcReceiver<u64>,
+    /// The generation of the front frame.
+    generation: u64,
+    /// The generation the reader last switched to.
+    released: u64,
+}
+
+impl DoubleBuffer {
+    /// Write `frame` into the back buffer and flip, first waiting for the reader to
+    /// leave that buffer if it is still reading it.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the frame is larger than the capacity,
+    /// and once the reader is gone.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), bincode::Error> {
+        if frame.len() > self.shared.capacity() {
+            return Err(Error::new(ErrorKind::InvalidInput, "frame too large").into())
+        }
+        while self.released < self.generation {
+            self.released = self.releases.recv()?;
+        }
+        let generation = self.generation + 1;
+        words::store_bytes(self.shared.words(generation), frame);
+        self.shared.front_generation().store(generation, Ordering::Release);
+        self.generation = generation;
+        self.flips.send(generation)
+    }
+
+    /// The number of frames sent so far.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The largest frame that fits in a buffer.
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+}
+
+impl Debug for DoubleBuffer {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("DoubleBuffer")
+                 .field("generation", &self.generation)
+                 .field("capacity", &self.capacity())
+                 .finish()
+    }
+}
+
+impl Serialize for DoubleBuffer {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.shared, &self.flips, &self.releases, self.generation, self.released)
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DoubleBuffer {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (shared, flips, releases, generation, released) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(DoubleBuffer {
+            shared,
+            flips,
+            releases,
+            generation,
+            released,
+        })
+    }
+}
+
+/// The reading end of a [double buffer](index.html).
+pub struct DoubleBufferReader {
+    shared: Shared,
+    flips: IpcReceiver<u64>,
+    releases: IpcSender<u64>,
+    /// The generation of the frame being read.
+    generation: u64,
+}
+
+impl DoubleBufferReader {
+    /// Wait for a frame newer than the current one, then switch to the latest.
+    ///
+    /// Fails once the producer is gone.
+    pub fn recv(&mut self) -> Result<&[u8], bincode::Error> {
+        loop {
+            let generation = self.flips.recv()?;
+            if generation > self.generation {
+                return self.switch()
+            }
+        }
+    }
+
+    /// Like [recv], failing with `ErrorKind::WouldBlock` rather than waiting if there is
+    /// no newer frame.
+    ///
+    /// [recv]: #method.recv
+    pub fn try_recv(&mut self) -> Result<&[u8], bincode::Error> {
+        loop {
+            let generation = self.flips.try_recv()?;
+            if generation > self.generation {
+                return self.switch()
+            }
+        }
+    }
+
+    /// Read the front buffer, and let the producer write the other one.
+    fn switch(&mut self) -> Result<&[u8], bincode::Error> {
+        self.generation = self.shared.front_generation().load(Ordering::Acquire);
+        // The producer is gone if this fails, but the frame is complete.
+        let _ = self.releases.send(self.generation);
+        Ok(self.front())
+    }
+
+    /// The frame last received, or an empty one before any.
+    pub fn front(&self) -> &[u8] {
+        let len = self.shared.words(self.generation)[0].load(Ordering::Relaxed) as usize;
+        &self.shared.buffer(self.generation)[WORD_SIZE..][..cmp::min(len, self.shared.capacity())]
+    }
+
+    /// The generation of the front frame, counting the frames sent from 1.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Debug for DoubleBufferReader {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("DoubleBufferReader")
+                 .field("generation", &self.generation)
+                 .finish()
+    }
+}
+
+impl Serialize for DoubleBufferReader {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.shared, &self.flips, &self.releases, self.generation).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DoubleBufferReader {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (shared, flips, releases, generation) = Deserialize::deserialize(deserializer)?;
+        Ok(DoubleBufferReader {
+            shared,
+            flips,
+            releases,
+            generation,
+        })
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Observing the channels, servers and shared memory regions of this process as they
+//! come and go, for diagnostics tooling.
+//!
+//! Handlers passed to [subscribe] are called with an [IpcEvent] on the thread the
+//! event happened on, right after it happened, so they should be quick. They may use
+//! channels themselves; the events that causes are reported too. With no
+//! subscriptions, reporting costs an atomic load.
+//!
+//! Unlike a [capture], events don't cover messages, only the endpoints carried by
+//! them.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::events::{self, IpcEvent};
+//! # use ipc_channel::ipc;
+//! # use std::sync::atomic::{AtomicUsize, Ordering};
+//! # use std::sync::Arc;
+//! let created = Arc::new(AtomicUsize::new(0));
+//! let counter = created.clone();
+//! let subscription = events::subscribe(move |event| {
+//!     if let IpcEvent::ChannelCreated { .. } = *event {
+//!         counter.fetch_add(1, Ordering::SeqCst);
+//!     }
+//! });
+//! let _channel = ipc::channel::<u32>().unwrap();
+//! events::unsubscribe(subscription);
+//! assert!(created.load(Ordering::SeqCst) >= 1);
+//! ```
+//!
+//! [subscribe]: fn.subscribe.html
+//! [IpcEvent]: enum.IpcEvent.html
+//! [capture]: ../capture/index.html
+
+use capture::Direction;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Something that happened to the channels, servers or shared memory of this process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpcEvent {
+    /// A channel was created, with a sender of this [sender ID], or none for
+    /// byte channels.
+    ///
+    /// [sender ID]: ../ipc/struct.IpcSender.html#method.sender_id
+    ChannelCreated { sender_id: Option<u64> },
+    /// A sender or receiver was serialized into a message to send, or deserialized
+    /// from one received.
+    ChannelTransferred { direction: Direction, endpoint: Endpoint },
+    /// A receiver found all the senders of its channel gone, when receiving or in an
+    /// [IpcReceiverSet]. Receiving again reports it again.
+    ///
+    /// [IpcReceiverSet]: ../ipc/struct.IpcReceiverSet.html
+    ChannelClosed,
+    /// A one-shot server started listening, under this name, or none for servers
+    /// adopted from systemd.
+    ServerRegistered { name: Option<String> },
+    /// A one-shot server accepted a client and its first message.
+    ServerAccepted { name: Option<String> },
+    /// A shared memory region was created or received.
+    SharedMemoryMapped { length: usize },
+    /// The last handle in this process to a shared memory region was dropped.
+    SharedMemoryUnmapped { length: usize },
+}
+
+/// Which end of a channel an [IpcEvent] is about.
+///
+/// [IpcEvent]: enum.IpcEvent.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Sender,
+    Receiver,
+}
+
+/// Identifies a handler registered with [subscribe].
+///
+/// [subscribe]: fn.subscribe.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Handler = Arc<dyn Fn(&IpcEvent) + Send + Sync>;
+
+lazy_static! {
+    static ref HANDLERS: Mutex<Vec<(SubscriptionId, Handler)>> = Mutex::new(vec![]);
+}
+
+// Whether `HANDLERS` is non-empty, checked first so events cost no lock when
+// nobody listens.
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+static NEXT_SUBSCRIPTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Call `handler` with every event from now on, until [unsubscribe]d.
+///
+/// [unsubscribe]: fn.unsubscribe.html
+pub fn subscribe<F>(handler: F) -> SubscriptionId
+                    where F: Fn(&IpcEvent) + Send + Sync + 'static {
+    let id = SubscriptionId(NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::Relaxed));
+    let mut handlers = HANDLERS.lock().unwrap();
+    handlers.push((id, Arc::new(handler)));
+    SUBSCRIBED.store(true, Ordering::SeqCst);
+    id
+}
+
+/// Stop calling the handler registered as `id`. Returns whether it was registered.
+///
+/// A call in progress on another thread may still complete after this returns.
+pub fn unsubscribe(id: SubscriptionId) -> bool {
+    let mut handlers = HANDLERS.lock().unwrap();
+    let count = handlers.len();
+    handlers.retain(|&(handler_id, _)| handler_id != id);
+    SUBSCRIBED.store(!handlers.is_empty(), Ordering::SeqCst);
+    handlers.len() != count
+}
+
+/// Report `event` to the subscribed handlers, if any.
+pub(crate) fn emit<F>(event: F) where F: FnOnce() -> IpcEvent {
+    if !SUBSCRIBED.load(Ordering::Relaxed) {
+        return
+    }
+    // Handlers are called without the lock held, so they can use channels.
+    let handlers: Vec<Handler> = HANDLERS.lock().unwrap().iter().map(|(_, handler)| {
+        handler.clone()
+    }).collect();
+    let event = event();
+    for handler in handlers {
+        handler(&event);
+    }
+}
+
+/// Report a sender or receiver sent or received in a message.
+pub(crate) fn transferred(direction: Direction, endpoint: Endpoint) {
+    emit(|| IpcEvent::ChannelTransferred { direction, endpoint })
+}
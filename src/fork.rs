@@ -0,0 +1,78 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hooks for processes that `fork()` without `exec()`ing afterwards.
+//!
+//! A forked child inherits every channel of the parent, but only the thread
+//! that called `fork()`. Call [prepare] right before forking, then [parent] in
+//! the parent and [child] in the child:
+//!
+//! ```no_run
+//! # extern crate ipc_channel;
+//! # extern crate libc;
+//! # use ipc_channel::fork;
+//! # fn main() {
+//! fork::prepare();
+//! match unsafe { libc::fork() } {
+//!     0 => {
+//!         fork::child();
+//!         // ...
+//!     }
+//!     _ => fork::parent(),
+//! }
+//! # }
+//! ```
+//!
+//! [child] restarts the thread of the global [ROUTER], so routes can be added
+//! in the child. Routes added before the fork, to any router, are only serviced
+//! in the parent: [child] closes the child's copies of their receivers.
+//!
+//! Receivers the child must not read from, as the parent keeps using them,
+//! should be passed to [invalidate] in the child: otherwise both processes
+//! would compete for the same messages.
+//!
+//! [prepare]: fn.prepare.html
+//! [parent]: fn.parent.html
+//! [child]: fn.child.html
+//! [invalidate]: fn.invalidate.html
+//! [ROUTER]: ../router/struct.ROUTER.html
+
+use ipc::IpcReceiver;
+use router;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+
+/// To be called right before `fork()`.
+///
+/// Takes the locks of global state, so the child doesn't inherit them held by
+/// another thread. Must be followed by [parent] or [child] on the same thread.
+///
+/// [parent]: fn.parent.html
+/// [child]: fn.child.html
+pub fn prepare() {
+    router::prepare_fork();
+}
+
+/// To be called in the parent after `fork()`.
+pub fn parent() {
+    router::finish_fork(false);
+}
+
+/// To be called in the child after `fork()`, before using any channel.
+pub fn child() {
+    router::finish_fork(true);
+}
+
+/// In a forked child, detach `receiver` from the channel it shares with the
+/// parent. Further receives fail as if all senders were gone; the parent is
+/// not affected.
+pub fn invalidate<T>(receiver: &IpcReceiver<T>) -> Result<(), Error>
+                     where T: for<'de> Deserialize<'de> + Serialize {
+    receiver.invalidate()
+}
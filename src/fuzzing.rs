@@ -0,0 +1,30 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Entry points for fuzzing the decoding of what peers send, with the `fuzzing` feature.
+//!
+//! A peer can send anything on a channel, so the decoding of frames received from it
+//! must not trust them. These are the decoders used when receiving, as functions over
+//! byte slices, needing no live channels or file descriptors; the targets in the
+//! `fuzz` directory run them under `cargo fuzz`, e.g. with
+//!
+//! ```text
+//! cargo +nightly fuzz run unix_first_fragment
+//! ```
+//!
+//! Only the decoders of the Unix socket backend are exposed so far. The Mach backend
+//! decodes messages in place, in the buffers the kernel fills; the inprocess backend
+//! doesn't decode anything, as messages never leave the process.
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub use platform::fuzzing::{decode_ancillary_data, decode_first_fragment};
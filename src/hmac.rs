@@ -0,0 +1,213 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! HMAC-SHA-256 (RFC 2104, FIPS 180-4), used to authenticate messages on keyed channels.
+//!
+//! This is a small self-contained implementation, checked against the test vectors
+//! of FIPS 180-4 and RFC 4231, so keyed channels don't pull in crypto dependencies
+//! the crate couldn't otherwise do without.
+
+use bincode;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+
+/// Size of the tag appended to each message.
+pub(crate) const TAG_SIZE: usize = 32;
+
+const BLOCK_SIZE: usize = 64;
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Incremental SHA-256.
+#[derive(Clone)]
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK_SIZE],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub(crate) fn new() -> Sha256 {
+        Sha256 {
+            state: INITIAL_STATE,
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let count = (BLOCK_SIZE - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len == BLOCK_SIZE {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> [u8; TAG_SIZE] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+        let mut digest = [0; TAG_SIZE];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; BLOCK_SIZE]) {
+        let mut schedule = [0u32; 64];
+        for (word, chunk) in schedule.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7) ^ schedule[i - 15].rotate_right(18) ^
+                (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17) ^ schedule[i - 2].rotate_right(19) ^
+                (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16].wrapping_add(s0)
+                                          .wrapping_add(schedule[i - 7])
+                                          .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*constant)
+                         .wrapping_add(*word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+/// A shared secret keying a channel.
+///
+/// The hash states after absorbing the inner and outer key pads are kept,
+/// rather than the key itself.
+#[derive(Clone)]
+pub(crate) struct HmacKey {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacKey {
+    pub(crate) fn new(key: &[u8]) -> HmacKey {
+        let mut block = [0; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            let mut hash = Sha256::new();
+            hash.update(key);
+            block[..TAG_SIZE].copy_from_slice(&hash.finish());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        inner.update(&block.iter().map(|byte| byte ^ 0x36).collect::<Vec<u8>>());
+        outer.update(&block.iter().map(|byte| byte ^ 0x5c).collect::<Vec<u8>>());
+        HmacKey {
+            inner,
+            outer,
+        }
+    }
+
+    /// The plain HMAC of `data`, for checking against test vectors.
+    #[cfg(test)]
+    pub(crate) fn sign(&self, data: &[u8]) -> [u8; TAG_SIZE] {
+        self.sign_parts(data, &[])
+    }
+
+    /// The tag of a message: its data, followed by the number of channels and the
+    /// lengths of the shared memory regions sent along, so an attacker can't swap,
+    /// add or drop attachments without the tag failing to match.
+    pub(crate) fn sign_message<I>(&self, data: &[u8], channel_count: usize, region_lengths: I)
+                                  -> [u8; TAG_SIZE]
+                                  where I: IntoIterator<Item = usize> {
+        let region_lengths = region_lengths.into_iter().collect::<Vec<_>>();
+        let mut attachments = Vec::with_capacity(16 + region_lengths.len() * 8);
+        attachments.extend_from_slice(&(channel_count as u64).to_le_bytes());
+        attachments.extend_from_slice(&(region_lengths.len() as u64).to_le_bytes());
+        for length in region_lengths {
+            attachments.extend_from_slice(&(length as u64).to_le_bytes());
+        }
+        self.sign_parts(data, &attachments)
+    }
+
+    fn sign_parts(&self, data: &[u8], attachments: &[u8]) -> [u8; TAG_SIZE] {
+        let mut inner = self.inner.clone();
+        inner.update(data);
+        inner.update(attachments);
+        let mut outer = self.outer.clone();
+        outer.update(&inner.finish());
+        outer.finish()
+    }
+
+    /// Check and strip the tag at the end of `data`, signed with [sign_message] along
+    /// with the attachments received.
+    ///
+    /// [sign_message]: #method.sign_message
+    pub(crate) fn open<I>(&self, data: &mut Vec<u8>, channel_count: usize, region_lengths: I)
+                          -> Result<(), bincode::Error>
+                          where I: IntoIterator<Item = usize> {
+        if data.len() >= TAG_SIZE {
+            let len = data.len() - TAG_SIZE;
+            let expected = self.sign_message(&data[..len], channel_count, region_lengths);
+            // Compare in constant time, so the time taken doesn't reveal the correct prefix.
+            let difference = expected.iter().zip(&data[len..]).fold(0, |acc, (a, b)| acc | (a ^ b));
+            if difference == 0 {
+                data.truncate(len);
+                return Ok(())
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "message authentication failed").into())
+    }
+}
+
+impl Debug for HmacKey {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.write_str("HmacKey")
+    }
+}
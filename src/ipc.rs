@@ -9,21 +9,66 @@
 
 use platform::{self, OsIpcChannel, OsIpcReceiver, OsIpcReceiverSet, OsIpcSender};
 use platform::{OsIpcOneShotServer, OsIpcSelectionResult, OsIpcSharedMemory, OsOpaqueIpcChannel};
+use platform::OsIpcWeakSender;
+use platform::OsIpcPeerCredentials;
+pub use platform::PeerDied;
+pub use platform::{ReceiveBufferPoolStats, receive_buffer_pool_stats, set_receive_buffer_pool};
+pub use platform::{BufferAllocator, set_buffer_allocator};
+/// Also available as `ipc::diagnostics`, next to the resources it reports on.
+pub use diagnostics;
+use ack::AckReceiver;
+use adapter::{FilterReceiver, MapReceiver, WithSender};
+use oneshot::{self, IpcOneshotReceiver, IpcOneshotSender};
+use rate_limit::RateLimitedSender;
+use registry;
+use router::QosClass;
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+use watch::{self, IpcWatchReceiver, IpcWatchSender};
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+use platform::{OsIpcLocalMessage, OsIpcLocalPayload};
 
-use bincode;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::cell::RefCell;
-use std::cmp::min;
+use bincode::{self, Options};
+use crossbeam_channel::Sender;
+use capture::{self, Direction};
+use events::{self, Endpoint, IpcEvent};
+use hmac::{self, HmacKey};
+use rand;
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+use std::any::{Any, TypeId};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error as StdError;
+use std::cmp::{self, min};
 use std::fmt::{self, Debug, Formatter};
-use std::io::Error;
+use std::io::{self, Error};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::Deref;
+use std::process;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(feature = "async")]
 use futures::{Async, Poll, Stream};
 #[cfg(feature = "async")]
 use std::io::ErrorKind;
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(all(feature = "evented", not(feature = "force-inprocess"), any(target_os = "linux",
+                                                                     target_os = "openbsd",
+                                                                     target_os = "freebsd",
+                                                                     target_os = "illumos",
+                                                                     target_os = "solaris")))]
+use mio::{self, Evented, unix::EventedFd};
 
 thread_local! {
     static OS_IPC_CHANNELS_FOR_DESERIALIZATION: RefCell<Vec<OsOpaqueIpcChannel>> =
@@ -40,6 +85,39 @@ thread_local! {
     static OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION: RefCell<Vec<OsIpcSharedMemory>> =
         RefCell::new(Vec::new())
 }
+thread_local! {
+    // Mappings of the copies of sensitive regions among those, wiped unless sent.
+    static SENSITIVE_COPIES_FOR_SERIALIZATION: RefCell<Vec<OsIpcSharedMemory>> =
+        const { RefCell::new(Vec::new()) }
+}
+thread_local! {
+    // Set when deserializing a shared memory region fails on the quota, so the error
+    // can be handed back typed rather than as serde's custom string error.
+    static SHARED_MEMORY_QUOTA_ERROR: Cell<Option<ShmQuotaExceeded>> = const { Cell::new(None) }
+}
+
+// A global count used to create unique sender IDs
+static SENDER_ID_COUNT: AtomicU64 = AtomicU64::new(0);
+// Mixed into the pid of sender IDs once restored from a checkpoint.
+static SENDER_ID_SALT: AtomicU32 = AtomicU32::new(0);
+
+/// Create an ID for a new [IpcSender] instance.
+///
+/// The ID combines the current pid with a per-process counter,
+/// so it can tell apart senders from different processes feeding the same receiver.
+///
+/// [IpcSender]: struct.IpcSender.html
+fn new_sender_id() -> u64 {
+    let pid = process::id() ^ SENDER_ID_SALT.load(Ordering::Relaxed);
+    (u64::from(pid) << 32) | (SENDER_ID_COUNT.fetch_add(1, Ordering::Relaxed) & 0xffff_ffff)
+}
+
+/// Salt the IDs of the senders created from now on, so that copies of a process
+/// restored from the same checkpoint, which share its pid and counter, don't
+/// create senders with the same IDs.
+pub(crate) fn salt_sender_ids() {
+    SENDER_ID_SALT.store(rand::random(), Ordering::Relaxed);
+}
 
 /// Create a connected [IpcSender] and [IpcReceiver] that
 /// transfer messages of a given type privided by type `T`
@@ -73,15 +151,54 @@ thread_local! {
 /// [IpcReceiver]: struct.IpcReceiver.html
 pub fn channel<T>() -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
                   where T: for<'de> Deserialize<'de> + Serialize {
-    let (os_sender, os_receiver) = platform::channel()?;
+    channel_with_options(ChannelOptions::default())
+}
+
+/// Like [channel], sizing the OS buffers of the channel as set in `options`.
+///
+/// When a receiver falls behind, senders block once the buffers are full, so bursts
+/// of large messages block less with larger buffers.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::ipc::{self, ChannelOptions};
+/// let options = ChannelOptions::new().send_buffer(4 * 1024 * 1024);
+/// let (tx, rx) = ipc::channel_with_options(options).unwrap();
+/// tx.send(vec![0u8; 64 * 1024]).unwrap();
+/// # assert_eq!(rx.recv().unwrap().len(), 64 * 1024);
+/// ```
+///
+/// [channel]: fn.channel.html
+pub fn channel_with_options<T>(options: ChannelOptions)
+                               -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
+                               where T: for<'de> Deserialize<'de> + Serialize {
+    let (os_sender, os_receiver) =
+        platform::channel_with_buffer_sizes(options.send_buffer, options.recv_buffer)?;
+    let bincode_config = BincodeConfig {
+        metadata: options.metadata,
+        ..BincodeConfig::default()
+    };
     let ipc_receiver = IpcReceiver {
         os_receiver: os_receiver,
+        sequence_checker: RefCell::new(None),
+        transaction_parts: RefCell::default(),
+        expired: RefCell::default(),
+        dead_letters: None,
+        bincode_config,
+        hmac_key: None,
+        nonblocking: false,
         phantom: PhantomData,
     };
     let ipc_sender = IpcSender {
         os_sender: os_sender,
+        sender_id: new_sender_id(),
+        next_sequence: Cell::new(0),
+        bincode_config,
+        hmac_key: None,
         phantom: PhantomData,
     };
+    events::emit(|| IpcEvent::ChannelCreated { sender_id: Some(ipc_sender.sender_id) });
     Ok((ipc_sender, ipc_receiver))
 }
 
@@ -114,16 +231,203 @@ pub fn channel<T>() -> Result<(IpcSender<T>, IpcReceiver<T>),Error>
 /// [IpcBytesReceiver]: struct.IpcBytesReceiver.html
 /// [IpcBytesSender]: struct.IpcBytesSender.html
 pub fn bytes_channel() -> Result<(IpcBytesSender, IpcBytesReceiver),Error> {
-    let (os_sender, os_receiver) = platform::channel()?;
+    bytes_channel_with_options(ChannelOptions::default())
+}
+
+/// Like [bytes_channel], sizing the OS buffers of the channel as set in `options`.
+///
+/// [bytes_channel]: fn.bytes_channel.html
+pub fn bytes_channel_with_options(options: ChannelOptions)
+                                  -> Result<(IpcBytesSender, IpcBytesReceiver),Error> {
+    let (os_sender, os_receiver) =
+        platform::channel_with_buffer_sizes(options.send_buffer, options.recv_buffer)?;
     let ipc_bytes_receiver = IpcBytesReceiver {
         os_receiver: os_receiver,
     };
     let ipc_bytes_sender = IpcBytesSender {
         os_sender: os_sender,
     };
+    events::emit(|| IpcEvent::ChannelCreated { sender_id: None });
     Ok((ipc_bytes_sender, ipc_bytes_receiver))
 }
 
+/// Sizes of the OS buffers of a channel, for [channel_with_options] and
+/// [bytes_channel_with_options]. Buffers not set keep the platform defaults.
+///
+/// - With Unix sockets, these are the `SO_SNDBUF` of the sending socket and the
+///   `SO_RCVBUF` of the receiving one. Messages are split into packets of the default
+///   send buffer size regardless, so a smaller send buffer fails with
+///   `ErrorKind::InvalidInput`. The kernel caps both, e.g. Linux at
+///   `net.core.wmem_max` and `net.core.rmem_max`.
+/// - Mach queues messages at the receiving port, by count, so the receive buffer sets
+///   the queue limit to the number of messages of the out-of-line threshold size,
+///   64 KiB unless set, it holds, and there is no send buffer. Ports have the largest
+///   limit by default.
+/// - In-process channels are unbounded, and ignore these.
+///
+/// The options also tell whether the ends of a typed channel send and expect a
+/// [metadata header].
+///
+/// [channel_with_options]: fn.channel_with_options.html
+/// [bytes_channel_with_options]: fn.bytes_channel_with_options.html
+/// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelOptions {
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+    metadata: bool,
+}
+
+impl ChannelOptions {
+    /// Options keeping the platform defaults.
+    pub fn new() -> ChannelOptions {
+        ChannelOptions::default()
+    }
+
+    /// Size the buffer of the sending end to `bytes`.
+    pub fn send_buffer(mut self, bytes: usize) -> ChannelOptions {
+        self.send_buffer = Some(bytes);
+        self
+    }
+
+    /// Size the buffer of the receiving end to `bytes`.
+    pub fn recv_buffer(mut self, bytes: usize) -> ChannelOptions {
+        self.recv_buffer = Some(bytes);
+        self
+    }
+
+    /// Set up both ends with a [BincodeConfig] sending the [metadata header];
+    /// bytes channels ignore this.
+    ///
+    /// [BincodeConfig]: struct.BincodeConfig.html
+    /// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+    pub fn metadata_header(mut self) -> ChannelOptions {
+        self.metadata = true;
+        self
+    }
+}
+
+/// Set the payload size, in bytes, from which messages are sent as
+/// out-of-line memory on macOS: the kernel then maps the pages into the
+/// receiver instead of copying them into the message.
+///
+/// This is off until set, while the path waits for wider testing on macOS:
+/// only payloads too large for an inline message are sent out-of-line then,
+/// after copying them into shared memory.
+#[cfg(all(not(feature = "force-inprocess"), target_os = "macos"))]
+pub fn set_out_of_line_threshold(threshold: usize) {
+    platform::set_out_of_line_threshold(threshold)
+}
+
+/// Helpers for tests running on the in-process backend, with the `test-support`
+/// feature.
+#[cfg(all(feature = "test-support", any(feature = "force-inprocess", target_os = "windows",
+                                        target_os = "android", target_os = "ios")))]
+pub mod inprocess {
+    use platform;
+
+    /// Forget the names of all [IpcOneShotServer]s, as if they were all dropped, so
+    /// nothing registered by one test is left behind for the next one. Connecting to
+    /// a forgotten name fails with `ErrorKind::NotFound`, as does accepting on a
+    /// server created before.
+    ///
+    /// Servers are forgotten when dropped anyway, so this is only needed to clean
+    /// up after servers that are never dropped, e.g. kept in statics. As the names are
+    /// shared by the whole process, this breaks tests running concurrently in other
+    /// threads that use one-shot servers.
+    ///
+    /// [IpcOneShotServer]: ../struct.IpcOneShotServer.html
+    pub fn reset() {
+        platform::reset_one_shot_servers()
+    }
+}
+
+/// Stop creating sockets and shared memory objects in the filesystem, so the
+/// process can then restrict itself, e.g. with OpenBSD's
+/// `pledge("stdio sendfd recvfd")`, and keep using channels.
+///
+/// `reserved_shared_memory` objects backing shared memory regions are created
+/// now; each [IpcSharedMemory] created afterwards, and each message received with
+/// [IpcReceiver::recv_bulk], uses one up. Once none are left,
+/// [IpcSharedMemory::try_from_bytes] and [IpcSharedMemory::try_from_byte] fail,
+/// receiving in bulk fails with `ErrorKind::OutOfMemory`, and the infallible
+/// constructors panic. With the `memfd` feature on Linux, nothing needs reserving.
+///
+/// [IpcOneShotServer]s can't be created or connected to anymore, failing with
+/// `ErrorKind::PermissionDenied`: set up channels beforehand, or hand them to
+/// child processes as inherited file descriptors, using the `IntoRawFd` and
+/// `FromRawFd` implementations of [IpcSender] and [IpcReceiver]. As descriptors are
+/// closed on `exec()`, call `prepare_for_inheritance()` on the endpoints to hand over
+/// first.
+///
+/// [IpcSharedMemory]: struct.IpcSharedMemory.html
+/// [IpcReceiver::recv_bulk]: struct.IpcReceiver.html#method.recv_bulk
+/// [IpcSharedMemory::try_from_bytes]: struct.IpcSharedMemory.html#method.try_from_bytes
+/// [IpcSharedMemory::try_from_byte]: struct.IpcSharedMemory.html#method.try_from_byte
+/// [IpcOneShotServer]: struct.IpcOneShotServer.html
+/// [IpcSender]: struct.IpcSender.html
+/// [IpcReceiver]: struct.IpcReceiver.html
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub fn enter_constrained_mode(reserved_shared_memory: usize) -> Result<(), Error> {
+    Ok(platform::enter_constrained_mode(reserved_shared_memory)?)
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+/// Create a watch channel, holding the most recently published value.
+///
+/// Unlike a regular channel, sending doesn't queue: each [send] replaces the
+/// current value, and receivers that didn't look in the meantime only see the
+/// latest one. The value lives in shared memory, so it must fit in
+/// `max_value_size` bytes once serialized, and can't embed channels or shared memory.
+/// Fails with `ErrorKind::OutOfMemory` rather than exceed the [shared memory quota].
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::ipc;
+/// let (tx, rx) = ipc::watch(0u32, 8).unwrap();
+/// tx.send(1).unwrap();
+/// tx.send(2).unwrap();
+/// assert!(rx.has_changed());
+/// assert_eq!(rx.borrow().unwrap(), 2);
+/// assert!(!rx.has_changed());
+/// ```
+///
+/// [send]: ../watch/struct.IpcWatchSender.html#method.send
+/// [shared memory quota]: fn.set_shared_memory_quota.html
+pub fn watch<T>(initial_value: T, max_value_size: usize)
+                -> Result<(IpcWatchSender<T>, IpcWatchReceiver<T>), bincode::Error>
+                where T: for<'de> Deserialize<'de> + Serialize {
+    watch::channel(initial_value, max_value_size)
+}
+
+/// Create a channel that carries exactly one message, typically a reply.
+///
+/// Both ends are consumed by their operation, so the underlying channel is
+/// torn down as soon as the message is delivered. The ends are bare OS channel
+/// endpoints, without the sender ID, sequence numbers and receive state of
+/// regular channels, so creating and transferring them costs no more than the
+/// channel itself.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::ipc;
+/// let (reply_tx, reply_rx) = ipc::oneshot().unwrap();
+/// reply_tx.send("Done".to_owned()).unwrap();
+/// assert_eq!(reply_rx.recv().unwrap(), "Done");
+/// ```
+pub fn oneshot<T>() -> Result<(IpcOneshotSender<T>, IpcOneshotReceiver<T>), Error>
+                  where T: for<'de> Deserialize<'de> + Serialize {
+    oneshot::channel()
+}
+
 /// Receiving end of a channel using serialized messages.
 ///
 /// # Examples
@@ -194,21 +498,629 @@ pub fn bytes_channel() -> Result<(IpcBytesSender, IpcBytesReceiver),Error> {
 #[derive(Debug)]
 pub struct IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
     os_receiver: OsIpcReceiver,
+    sequence_checker: RefCell<Option<SequenceChecker>>,
+    /// The messages of a transaction that follow the one last received.
+    transaction_parts: RefCell<VecDeque<OpaqueIpcMessage>>,
+    expired: RefCell<ExpiredMessages>,
+    dead_letters: Option<Sender<DeadLetter>>,
+    bincode_config: BincodeConfig,
+    hmac_key: Option<Arc<HmacKey>>,
+    /// Set by `set_nonblocking()`.
+    nonblocking: bool,
     phantom: PhantomData<T>,
 }
 
 impl<T> IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
     /// Blocking receive.
     pub fn recv(&self) -> Result<T, bincode::Error> {
-        let (data, os_ipc_channels, os_ipc_shared_memory_regions) = self.os_receiver.recv()?;
-        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions).to()
+        Ok(self.recv_with_metadata()?.0)
     }
 
     /// Non-blocking receive
     pub fn try_recv(&self) -> Result<T, bincode::Error> {
-        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
-            self.os_receiver.try_recv()?;
-        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions).to()
+        Ok(self.try_recv_with_metadata()?.0)
+    }
+
+    /// Receive, waiting at most `duration` for a message, and failing with
+    /// `ErrorKind::TimedOut` if none arrives in time.
+    pub fn try_recv_timeout(&self, duration: Duration) -> Result<T, bincode::Error> {
+        let deadline = Instant::now() + duration;
+        Ok(self.receive(|os_receiver| os_receiver.try_recv_timeout(remaining(deadline)))?.0)
+    }
+
+    /// Blocking receive, also returning the [metadata] the message was sent with.
+    ///
+    /// [metadata]: struct.IpcMessageMetadata.html
+    pub fn recv_with_metadata(&self) -> Result<(T, IpcMessageMetadata), bincode::Error> {
+        if self.nonblocking {
+            return self.try_recv_with_metadata()
+        }
+        self.receive(OsIpcReceiver::recv)
+    }
+
+    /// Non-blocking receive, also returning the [metadata] the message was sent with.
+    ///
+    /// [metadata]: struct.IpcMessageMetadata.html
+    pub fn try_recv_with_metadata(&self) -> Result<(T, IpcMessageMetadata), bincode::Error> {
+        self.receive(OsIpcReceiver::try_recv)
+    }
+
+    /// Switch `recv()`, `recv_with_metadata()` and `recv_opaque()` to non-blocking mode,
+    /// in which they fail as `try_recv()` does instead of waiting when there is no
+    /// message: with `ErrorKind::WouldBlock`, or on macOS, where this is a receive with a
+    /// zero timeout, `ErrorKind::TimedOut`. This suits loops polling several sources in
+    /// turn, which can then hand the receiver to code written for blocking receives.
+    ///
+    /// As with sequence checking, the mode is not carried over when the receiver is
+    /// sent to another process, or added to an [IpcReceiverSet].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, mut rx) = ipc::channel::<u32>().unwrap();
+    /// rx.set_nonblocking(true);
+    /// assert!(rx.recv().is_err());
+    /// tx.send(42).unwrap();
+    /// assert_eq!(rx.recv().unwrap(), 42);
+    /// ```
+    ///
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// The number of senders left for this receiver, including those in messages not
+    /// yet received, if the platform can tell; without waiting.
+    ///
+    /// The inprocess backend counts them exactly. Elsewhere, senders may be in other
+    /// processes, and only their absence is known: this is `Some(0)` once they are all
+    /// gone, and `None` before. Either way, a sender may be dropped right after this
+    /// returns, or a new one connected, if this is the receiver of a one-shot server.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel::<u32>().unwrap();
+    /// assert_ne!(rx.sender_count(), Some(0));
+    /// drop(tx);
+    /// assert_eq!(rx.sender_count(), Some(0));
+    /// ```
+    pub fn sender_count(&self) -> Option<usize> {
+        self.os_receiver.sender_count()
+    }
+
+    /// Enable or disable validation of message [sequence] numbers.
+    ///
+    /// When enabled, `recv()` and friends check that the messages from each sender
+    /// instance arrive in order, starting from the first message that sender sent.
+    /// A violation is reported as an `ErrorKind::Io` error wrapping a [SequenceError]:
+    /// after a gap, the message that revealed it is returned by the next receive call;
+    /// duplicate messages are discarded.
+    ///
+    /// Checking should be enabled before any messages are received.
+    /// It is not carried over when the receiver is sent to another process,
+    /// or added to an [IpcReceiverSet]. Sequence numbers travel in the [metadata
+    /// header]: on channels without it, receiving fails with `ErrorKind::InvalidInput`.
+    ///
+    /// [sequence]: struct.IpcMessageMetadata.html#method.sequence
+    /// [SequenceError]: enum.SequenceError.html
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    /// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+    pub fn set_sequence_checking(&mut self, enabled: bool) {
+        *self.sequence_checker.borrow_mut() = if enabled {
+            Some(SequenceChecker::new())
+        } else {
+            None
+        };
+    }
+
+    /// The number of messages this receiver discarded, as they were sent with
+    /// [IpcSender::send_with_ttl] and expired before being received.
+    ///
+    /// [IpcSender::send_with_ttl]: struct.IpcSender.html#method.send_with_ttl
+    pub fn expired_count(&self) -> u64 {
+        self.expired.borrow().count
+    }
+
+    /// Call `handler` with the metadata of each message this receiver discards as expired,
+    /// replacing the previous handler.
+    ///
+    /// Like the count of expired messages, the handler is kept by [cast], but not by
+    /// [try_duplicate], nor carried along when the receiver is sent to another process.
+    ///
+    /// [cast]: #method.cast
+    /// [try_duplicate]: #method.try_duplicate
+    pub fn set_expiry_handler(&mut self, handler: ExpiryHandler) {
+        self.expired.borrow_mut().handler = Some(handler);
+    }
+
+    /// Send the messages this receiver discards as expired, and those exceeding its
+    /// [size limit] or the [shared memory quota], to `dead_letters`, rather than drop them.
+    ///
+    /// Receiving a message that exceeds a limit still fails as without dead letters.
+    /// On Linux and the BSDs, messages whose shared memory exceeds the quota are
+    /// refused before their regions are mapped, so they don't reach the dead letters.
+    /// The setting is kept by [cast] and [try_duplicate], but not carried along when the
+    /// receiver is sent to another process.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{self, BincodeConfig, DeadLetterReason};
+    /// # extern crate crossbeam_channel;
+    /// # extern crate ipc_channel;
+    /// # fn main() {
+    /// let (dead_letter_tx, dead_letter_rx) = crossbeam_channel::unbounded();
+    /// let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    /// let mut rx = rx.with_bincode_config(BincodeConfig::new().limit(64));
+    /// rx.set_dead_letter_sender(dead_letter_tx);
+    /// tx.send(vec![0; 1024]).unwrap();
+    /// assert!(rx.recv().is_err());
+    /// let dead_letter = dead_letter_rx.recv().unwrap();
+    /// assert_eq!(dead_letter.reason, DeadLetterReason::LimitExceeded);
+    /// assert_eq!(dead_letter.message.to::<Vec<u8>>().unwrap().len(), 1024);
+    /// # }
+    /// ```
+    ///
+    /// [size limit]: struct.BincodeConfig.html#method.limit
+    /// [shared memory quota]: fn.set_shared_memory_quota.html
+    /// [cast]: #method.cast
+    /// [try_duplicate]: #method.try_duplicate
+    pub fn set_dead_letter_sender(&mut self, dead_letters: Sender<DeadLetter>) {
+        self.dead_letters = Some(dead_letters);
+    }
+
+    /// Watch process `pid`, usually the one holding the senders, so that once it
+    /// exits, receiving fails with [PeerDied] instead of waiting, and an
+    /// [IpcReceiverSet] this receiver is then added to reports it closed, with
+    /// the [peer death] to tell why. Messages the peer sent before exiting are
+    /// still received first. A peer that exits while another process holds a
+    /// sender is reported all the same.
+    ///
+    /// The exit status is only known if the peer is a child of this process,
+    /// and wasn't waited for yet; it is then left for `wait()` to collect.
+    ///
+    /// Uses a pidfd on Linux, and kqueue's `EVFILT_PROC` on macOS and the BSDs.
+    /// Fails with `ErrorKind::Unsupported` on the in-process backend, and with
+    /// the `ENOSYS` OS error on illumos, Solaris, and Linux before 5.3.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ipc_channel::ipc::{self, PeerDied};
+    /// # use std::process::Command;
+    /// let (tx, mut rx) = ipc::channel::<String>().unwrap();
+    /// # drop(tx);
+    /// let child = Command::new("worker").spawn().unwrap();
+    /// rx.watch_peer(child.id()).unwrap();
+    /// match rx.recv() {
+    ///     Ok(message) => println!("{}", message),
+    ///     Err(error) => match PeerDied::from_error(&error) {
+    ///         Some(peer_died) => println!("worker died: {:?}", peer_died.exit_status),
+    ///         None => println!("receiving failed: {}", error),
+    ///     },
+    /// }
+    /// ```
+    ///
+    /// [PeerDied]: struct.PeerDied.html
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    /// [peer death]: struct.IpcReceiverSet.html#method.take_peer_death
+    pub fn watch_peer(&mut self, pid: u32) -> Result<(), Error> {
+        self.os_receiver.watch_peer(pid)?;
+        Ok(())
+    }
+
+    /// Use `config` to deserialize the messages received on this channel.
+    ///
+    /// The senders must use the same [BincodeConfig]. The setting is not
+    /// carried along when the receiver is sent to another process, nor does it
+    /// apply to messages the receiver hands over to the [ROUTER] or an
+    /// [IpcReceiverSet], which use the default configuration.
+    ///
+    /// [BincodeConfig]: struct.BincodeConfig.html
+    /// [ROUTER]: ../router/struct.ROUTER.html
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    pub fn with_bincode_config(mut self, config: BincodeConfig) -> IpcReceiver<T> {
+        self.bincode_config = config;
+        self
+    }
+
+    /// Only accept messages authenticated with the shared secret `key`,
+    /// as sent by an [IpcSender] keyed with [IpcSender::with_hmac_key].
+    ///
+    /// Each message must end with an HMAC-SHA-256 tag of its contents; receiving a
+    /// message with a missing or incorrect tag fails with `ErrorKind::InvalidData`.
+    /// The tag covers the data, along with the number of channels and the lengths of
+    /// the shared memory regions sent with it. Enable [sequence checking] to also
+    /// detect replayed or dropped messages.
+    ///
+    /// The key is not carried along when the receiver is sent to another process. It
+    /// does apply once the receiver is handed over to the [ROUTER] or added to an
+    /// [IpcReceiverSet], which drop the messages failing authentication.
+    ///
+    /// [IpcSender]: struct.IpcSender.html
+    /// [IpcSender::with_hmac_key]: struct.IpcSender.html#method.with_hmac_key
+    /// [sequence checking]: #method.set_sequence_checking
+    /// [ROUTER]: ../router/struct.ROUTER.html
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    pub fn with_hmac_key(mut self, key: &[u8]) -> IpcReceiver<T> {
+        self.hmac_key = Some(Arc::new(HmacKey::new(key)));
+        self
+    }
+
+    /// Blocking receive, keeping the message in its receive buffer.
+    ///
+    /// The returned [IpcMessageRef] can then be deserialized into a type borrowing
+    /// from the buffer, such as `&str` or `&[u8]` in place of `String` or `Vec<u8>`,
+    /// avoiding copies of large messages that are only inspected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel().unwrap();
+    /// tx.send(("Hello".to_owned(), 42)).unwrap();
+    /// let mut message = rx.recv_ref().unwrap();
+    /// let (greeting, answer): (&str, u32) = message.get().unwrap();
+    /// assert_eq!((greeting, answer), ("Hello", 42));
+    /// ```
+    ///
+    /// [IpcMessageRef]: struct.IpcMessageRef.html
+    pub fn recv_ref(&self) -> Result<IpcMessageRef<T>, bincode::Error> {
+        Ok(IpcMessageRef {
+            message: self.recv_opaque()?,
+            bincode_config: self.bincode_config,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Blocking receive of the raw bytes of a message, bypassing serde.
+    ///
+    /// Typically used for messages sent with [IpcSender::send_raw].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{self, IpcRawChannel, IpcSharedMemory};
+    /// let (tx, rx) = ipc::channel::<()>().unwrap();
+    /// let (sub_tx, sub_rx) = ipc::channel::<u32>().unwrap();
+    /// tx.send_raw(b"raw", vec![IpcRawChannel::Sender(sub_tx.to_opaque())],
+    ///             vec![IpcSharedMemory::from_byte(7, 4)]).unwrap();
+    /// let message = rx.recv_raw().unwrap();
+    /// assert_eq!(message.data, b"raw");
+    /// assert_eq!(&message.shared_memory_regions[0][..], [7; 4]);
+    /// let sub_tx = message.channels.into_iter().next().unwrap().to_sender().to::<u32>();
+    /// sub_tx.send(42).unwrap();
+    /// assert_eq!(sub_rx.recv().unwrap(), 42);
+    /// ```
+    ///
+    /// [IpcSender::send_raw]: struct.IpcSender.html#method.send_raw
+    pub fn recv_raw(&self) -> Result<IpcRawMessage, bincode::Error> {
+        let message = self.recv_opaque()?;
+        let (metadata, _, data) = message.header()?;
+        let data = data.to_vec();
+        Ok(IpcRawMessage {
+            data,
+            metadata,
+            channels: message.os_ipc_channels.into_iter().map(|os_channel| {
+                OpaqueIpcChannel {
+                    os_channel,
+                }
+            }).collect(),
+            shared_memory_regions: message.os_ipc_shared_memory_regions.into_iter().map(|region| {
+                let os_shared_memory = region.expect("received shared memory was taken");
+                Ok(IpcSharedMemory {
+                    _charge: Some(charge_shared_memory(os_shared_memory.len(), true)?),
+                    os_shared_memory,
+                    wipe: None,
+                })
+            }).collect::<Result<_, ShmQuotaExceeded>>()?,
+        })
+    }
+
+    /// Blocking receive, leaving the message in the [opaque] form.
+    ///
+    /// The message can be kept around, then deserialized with [to], or passed on
+    /// as is with [IpcSender::forward], so a broker only needs to know the types of
+    /// the messages it looks into. Sequence checking and the HMAC key of this
+    /// receiver apply as with `recv()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel::<String>().unwrap();
+    /// let (forward_tx, forward_rx) = ipc::channel::<String>().unwrap();
+    /// tx.send("Hello".to_owned()).unwrap();
+    /// let message = rx.recv_opaque().unwrap();
+    /// forward_tx.forward(message).unwrap();
+    /// assert_eq!(forward_rx.recv().unwrap(), "Hello");
+    /// ```
+    ///
+    /// [opaque]: struct.OpaqueIpcMessage.html
+    /// [to]: struct.OpaqueIpcMessage.html#method.to
+    /// [IpcSender::forward]: struct.IpcSender.html#method.forward
+    pub fn recv_opaque(&self) -> Result<OpaqueIpcMessage, bincode::Error> {
+        if self.nonblocking {
+            return self.try_recv_opaque()
+        }
+        self.receive_message(OsIpcReceiver::recv)
+    }
+
+    /// Non-blocking receive, leaving the message in the [opaque] form.
+    ///
+    /// [opaque]: struct.OpaqueIpcMessage.html
+    pub fn try_recv_opaque(&self) -> Result<OpaqueIpcMessage, bincode::Error> {
+        self.receive_message(OsIpcReceiver::try_recv)
+    }
+
+    /// Like `try_recv_timeout()`, leaving the message in the opaque form.
+    pub(crate) fn try_recv_opaque_timeout(&self, duration: Duration)
+                                          -> Result<OpaqueIpcMessage, bincode::Error> {
+        let deadline = Instant::now() + duration;
+        self.receive_message(|os_receiver| os_receiver.try_recv_timeout(remaining(deadline)))
+    }
+
+    /// Deserialize a message received in the opaque form as `recv()` would have.
+    pub(crate) fn deserialize_opaque(&self, message: OpaqueIpcMessage)
+                                     -> Result<T, bincode::Error> {
+        Ok(self.deserialize_message(message)?.0)
+    }
+
+    pub(crate) fn bincode_config(&self) -> BincodeConfig {
+        self.bincode_config
+    }
+
+    /// Switch to receiving [deliveries] that must be acknowledged; those that aren't
+    /// acknowledged within `visibility_timeout` are handed out again. See the [ack] module.
+    ///
+    /// [deliveries]: ../ack/struct.Delivery.html
+    /// [ack]: ../ack/index.html
+    pub fn with_acks(self, visibility_timeout: Duration) -> AckReceiver<T> {
+        AckReceiver::new(self, visibility_timeout)
+    }
+
+    /// Wrap the receiver to convert each message with `f` as it is received, e.g.
+    /// from the wire type to the one used internally. See the [adapter] module.
+    ///
+    /// [adapter]: ../adapter/index.html
+    pub fn map<U, F>(self, f: F) -> MapReceiver<T, F> where F: Fn(T) -> U {
+        MapReceiver::new(self, f)
+    }
+
+    /// Wrap the receiver to discard the messages `predicate` rejects as they are received.
+    pub fn filter<P>(self, predicate: P) -> FilterReceiver<T, P> where P: Fn(&T) -> bool {
+        FilterReceiver::new(self, predicate)
+    }
+
+    fn receive<F, E>(&self, os_receive: F) -> Result<(T, IpcMessageMetadata), bincode::Error>
+                     where F: Fn(&OsIpcReceiver) -> Result<(Vec<u8>,
+                                                                Vec<OsOpaqueIpcChannel>,
+                                                                Vec<OsIpcSharedMemory>), E>,
+                           E: Into<bincode::Error> {
+        self.deserialize_message(self.receive_message(os_receive)?)
+    }
+
+    /// Deserialize a received message, sending it to the dead letters instead if
+    /// it exceeds a limit.
+    fn deserialize_message(&self, message: OpaqueIpcMessage)
+                           -> Result<(T, IpcMessageMetadata), bincode::Error> {
+        let dead_letters = match self.dead_letters {
+            Some(ref dead_letters) => dead_letters,
+            None => return message.deserialize_with_config(self.bincode_config),
+        };
+        match message.deserialize_or_return_with_config(self.bincode_config) {
+            Ok(result) => Ok(result),
+            Err((message, error)) => {
+                let exceeds_limit = match *error {
+                    bincode::ErrorKind::SizeLimit => true,
+                    _ => ShmQuotaExceeded::from_error(&error).is_some(),
+                };
+                if exceeds_limit {
+                    drop(dead_letters.send(DeadLetter {
+                        reason: DeadLetterReason::LimitExceeded,
+                        message,
+                    }));
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn receive_message<F, E>(&self, os_receive: F) -> Result<OpaqueIpcMessage, bincode::Error>
+                             where F: Fn(&OsIpcReceiver) -> Result<(Vec<u8>,
+                                                                    Vec<OsOpaqueIpcChannel>,
+                                                                    Vec<OsIpcSharedMemory>), E>,
+                                   E: Into<bincode::Error> {
+        loop {
+            let message = self.receive_message_once(&os_receive)?;
+            if let Some(message) = self.discard_if_expired(message)? {
+                return Ok(message)
+            }
+        }
+    }
+
+    fn receive_message_once<F, E>(&self, os_receive: &F)
+                                  -> Result<OpaqueIpcMessage, bincode::Error>
+                                  where F: Fn(&OsIpcReceiver) -> Result<(Vec<u8>,
+                                                                         Vec<OsOpaqueIpcChannel>,
+                                                                         Vec<OsIpcSharedMemory>),
+                                                                        E>,
+                                        E: Into<bincode::Error> {
+        let mut sequence_checker = self.sequence_checker.borrow_mut();
+        let pending = sequence_checker.as_mut().and_then(|checker| checker.pending.take());
+        let part = self.transaction_parts.borrow_mut().pop_front();
+        let message = match pending.or(part) {
+            Some(message) => message,
+            None => {
+                let (mut data, os_ipc_channels, os_ipc_shared_memory_regions) =
+                    os_receive(&self.os_receiver).map_err(|error| report_closed(error.into()))?;
+                if let Some(ref key) = self.hmac_key {
+                    key.open(&mut data,
+                             os_ipc_channels.len(),
+                             os_ipc_shared_memory_regions.iter().map(|region| region.len()))?;
+                }
+                let message = OpaqueIpcMessage::new(data,
+                                                    os_ipc_channels,
+                                                    os_ipc_shared_memory_regions,
+                                                    self.bincode_config.metadata);
+                self.first_transaction_part(message)?
+            }
+        };
+        match *sequence_checker {
+            Some(ref mut checker) => checker.check(message),
+            None => Ok(message),
+        }
+    }
+
+    /// Whether messages of a [transaction] are left to be received.
+    ///
+    /// [transaction]: struct.IpcSender.html#method.transaction
+    pub(crate) fn has_transaction_parts(&self) -> bool {
+        !self.transaction_parts.borrow().is_empty()
+    }
+
+    /// Split `message` if it is a [transaction], returning its first message and
+    /// keeping the others for the next receive calls.
+    ///
+    /// [transaction]: struct.IpcSender.html#method.transaction
+    fn first_transaction_part(&self, message: OpaqueIpcMessage)
+                              -> Result<OpaqueIpcMessage, bincode::Error> {
+        let mut parts = message.split()?.into_iter();
+        let first = parts.next().ok_or_else(|| {
+            Error::new(io::ErrorKind::InvalidData, "empty transaction")
+        })?;
+        record_received(&first);
+        for part in parts {
+            record_received(&part);
+            self.transaction_parts.borrow_mut().push_back(part);
+        }
+        Ok(first)
+    }
+
+    /// Hand `message` back unless it expired, in which case count it, call the
+    /// expiry handler and send it to the dead letters instead.
+    fn discard_if_expired(&self, mut message: OpaqueIpcMessage)
+                          -> Result<Option<OpaqueIpcMessage>, bincode::Error> {
+        let metadata = message.metadata()?;
+        if !metadata.is_expired() {
+            return Ok(Some(message))
+        }
+        match self.dead_letters {
+            Some(ref dead_letters) => {
+                drop(dead_letters.send(DeadLetter {
+                    reason: DeadLetterReason::Expired,
+                    message,
+                }))
+            }
+            None => platform::recycle_buffer(mem::take(&mut message.data)),
+        }
+        let mut expired = self.expired.borrow_mut();
+        expired.count += 1;
+        if let Some(ref mut handler) = expired.handler {
+            handler(metadata)
+        }
+        Ok(None)
+    }
+
+    /// Reinterpret the receiver as receiving values of type `U`,
+    /// which can be read from the serialized form of `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel::<String>().unwrap();
+    /// tx.send("Hello".to_owned()).unwrap();
+    /// let rx = rx.cast::<Vec<u8>>();
+    /// assert_eq!(rx.recv().unwrap(), b"Hello");
+    /// ```
+    pub fn cast<U>(self) -> IpcReceiver<U>
+                   where U: for<'de> Deserialize<'de> + Serialize, T: WireCompatible<U> {
+        self.cast_unchecked()
+    }
+
+    /// Like [cast], without checking the types are compatible.
+    ///
+    /// This can't cause undefined behaviour, but if the serialized forms differ,
+    /// receiving fails or produces meaningless values.
+    ///
+    /// [cast]: #method.cast
+    pub fn cast_unchecked<U>(self) -> IpcReceiver<U>
+                             where U: for<'de> Deserialize<'de> + Serialize {
+        IpcReceiver {
+            os_receiver: self.os_receiver,
+            sequence_checker: self.sequence_checker,
+            transaction_parts: self.transaction_parts,
+            expired: self.expired,
+            dead_letters: self.dead_letters,
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key,
+            nonblocking: self.nonblocking,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Another receiver for the same channel, e.g. for another thread, or to send to
+    /// another process, to share the work of servicing it.
+    ///
+    /// Each message goes to exactly one of the receivers, whichever asks first.
+    /// The duplicate has the same [BincodeConfig] and [HMAC key], but doesn't
+    /// [check sequences], as each receiver only sees some of the messages.
+    /// The channel is closed for both once all senders are gone.
+    ///
+    /// Fails with `ErrorKind::Unsupported` on macOS, where a Mach port has a single
+    /// receive right.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// # use std::thread;
+    /// let (tx, rx) = ipc::channel::<u32>().unwrap();
+    /// # if let Ok(other_rx) = rx.try_duplicate() {
+    /// let worker = thread::spawn(move || other_rx.recv().unwrap());
+    /// tx.send(1).unwrap();
+    /// tx.send(2).unwrap();
+    /// let mut received = vec![rx.recv().unwrap(), worker.join().unwrap()];
+    /// received.sort();
+    /// assert_eq!(received, [1, 2]);
+    /// # }
+    /// ```
+    ///
+    /// [BincodeConfig]: struct.BincodeConfig.html
+    /// [HMAC key]: #method.with_hmac_key
+    /// [check sequences]: #method.set_sequence_checking
+    pub fn try_duplicate(&self) -> Result<IpcReceiver<T>, Error> {
+        Ok(IpcReceiver {
+            os_receiver: self.os_receiver.try_duplicate()?,
+            sequence_checker: RefCell::new(None),
+            transaction_parts: RefCell::default(),
+            expired: RefCell::default(),
+            dead_letters: self.dead_letters.clone(),
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key.clone(),
+            nonblocking: false,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Let processes this one `exec()`s from now on inherit the receiver's socket.
+    ///
+    /// Like every descriptor the crate creates or receives, the socket is otherwise
+    /// closed on `exec()`, so programs spawned by other code don't keep channels open.
+    /// This is for handing the receiver to a program spawned by other means than a
+    /// `Command`, which would find it by its [raw descriptor]; processes spawned by
+    /// other threads meanwhile inherit it too.
+    ///
+    /// Fails with `ErrorKind::Unsupported` on macOS and with the in-process backend,
+    /// where channels can't be inherited.
+    ///
+    /// [raw descriptor]: #impl-AsRawFd
+    pub fn prepare_for_inheritance(&self) -> Result<(), Error> {
+        self.os_receiver.prepare_for_inheritance()
     }
 
     /// Erase the type of the channel.
@@ -217,8 +1129,78 @@ impl<T> IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
     pub fn to_opaque(self) -> OpaqueIpcReceiver {
         OpaqueIpcReceiver {
             os_receiver: self.os_receiver,
+            metadata: self.bincode_config.metadata,
+            hmac_key: self.hmac_key,
+        }
+    }
+
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    pub(crate) fn invalidate(&self) -> Result<(), Error> {
+        Ok(self.os_receiver.invalidate()?)
+    }
+}
+
+impl<T> IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize + 'static {
+    /// Blocking receive, taking a value sent with [IpcSender::send_local] as is,
+    /// without deserializing it.
+    ///
+    /// Other messages are deserialized as with `recv()`, as are all messages
+    /// while [sequence checking] is enabled or the receiver has an [HMAC key].
+    ///
+    /// [IpcSender::send_local]: struct.IpcSender.html#method.send_local
+    /// [sequence checking]: #method.set_sequence_checking
+    /// [HMAC key]: #method.with_hmac_key
+    pub fn recv_local(&self) -> Result<T, bincode::Error> {
+        self.receive_local(true)
+    }
+
+    /// Non-blocking receive, taking a value sent with [IpcSender::send_local] as is.
+    ///
+    /// [IpcSender::send_local]: struct.IpcSender.html#method.send_local
+    pub fn try_recv_local(&self) -> Result<T, bincode::Error> {
+        self.receive_local(false)
+    }
+
+    #[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+    fn receive_local(&self, blocking: bool) -> Result<T, bincode::Error> {
+        if self.sequence_checker.borrow().is_some() || self.hmac_key.is_some() ||
+                !self.transaction_parts.borrow().is_empty() {
+            return if blocking { self.recv() } else { self.try_recv() }
+        }
+        let type_id = TypeId::of::<T>();
+        loop {
+            let message = if blocking {
+                self.os_receiver.recv_local(type_id)
+            } else {
+                self.os_receiver.try_recv_local(type_id)
+            }?;
+            match message {
+                OsIpcLocalMessage::Local(value) => return Ok(*value.downcast().unwrap()),
+                OsIpcLocalMessage::Serialized(data,
+                                              os_ipc_channels,
+                                              os_ipc_shared_memory_regions) => {
+                    let message = OpaqueIpcMessage::new(data,
+                                                        os_ipc_channels,
+                                                        os_ipc_shared_memory_regions,
+                                                        self.bincode_config.metadata);
+                    let message = self.first_transaction_part(message)?;
+                    if let Some(message) = self.discard_if_expired(message)? {
+                        return Ok(self.deserialize_message(message)?.0)
+                    }
+                }
+            }
         }
     }
+
+    #[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+                    target_os = "ios")))]
+    fn receive_local(&self, blocking: bool) -> Result<T, bincode::Error> {
+        if blocking { self.recv() } else { self.try_recv() }
+    }
 }
 
 #[cfg(feature = "async")]
@@ -259,8 +1241,27 @@ impl Stream for IpcBytesReceiver {
     }
 }
 
-impl<'de, T> Deserialize<'de> for IpcReceiver<T> where T: for<'dde> Deserialize<'dde> + Serialize {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+/// Callback run by a receiver for each message it discards as expired:
+/// see [IpcReceiver::set_expiry_handler].
+///
+/// [IpcReceiver::set_expiry_handler]: struct.IpcReceiver.html#method.set_expiry_handler
+pub type ExpiryHandler = Box<dyn FnMut(IpcMessageMetadata) + Send>;
+
+/// Messages a receiver discarded as expired.
+#[derive(Default)]
+struct ExpiredMessages {
+    count: u64,
+    handler: Option<ExpiryHandler>,
+}
+
+impl Debug for ExpiredMessages {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("ExpiredMessages").field("count", &self.count).finish()
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcReceiver<T> where T: for<'dde> Deserialize<'dde> + Serialize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
         let index: usize = Deserialize::deserialize(deserializer)?;
         let os_receiver =
             OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
@@ -268,8 +1269,16 @@ impl<'de, T> Deserialize<'de> for IpcReceiver<T> where T: for<'dde> Deserialize<
                 // of bounds. We should return an `Err` result instead.
                 os_ipc_channels_for_deserialization.borrow_mut()[index].to_receiver()
             });
+        events::transferred(Direction::Received, Endpoint::Receiver);
         Ok(IpcReceiver {
             os_receiver: os_receiver,
+            sequence_checker: RefCell::new(None),
+            transaction_parts: RefCell::default(),
+            expired: RefCell::default(),
+            dead_letters: None,
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
+            nonblocking: false,
             phantom: PhantomData,
         })
     }
@@ -285,10 +1294,127 @@ impl<T> Serialize for IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serial
                                                                               .consume()));
             index
         });
+        events::transferred(Direction::Sent, Endpoint::Receiver);
         index.serialize(serializer)
     }
 }
 
+/// Tracks the expected sequence number of every sender feeding a receiver.
+#[derive(Debug)]
+struct SequenceChecker {
+    expected: HashMap<u64, u64>,
+    /// Message that revealed a gap, to be delivered on the next receive.
+    pending: Option<OpaqueIpcMessage>,
+}
+
+impl SequenceChecker {
+    fn new() -> SequenceChecker {
+        SequenceChecker {
+            expected: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    fn check(&mut self, message: OpaqueIpcMessage) -> Result<OpaqueIpcMessage, bincode::Error> {
+        if !message.metadata {
+            return Err(Error::new(io::ErrorKind::InvalidInput,
+                                  "checking sequences needs the metadata header").into())
+        }
+        let metadata = message.metadata()?;
+        let expected = self.expected.entry(metadata.sender_id).or_insert(0);
+        if metadata.sequence < *expected {
+            return Err(SequenceError::Duplicate {
+                sender_id: metadata.sender_id,
+                sequence: metadata.sequence,
+            }.into())
+        }
+        if metadata.sequence > *expected {
+            let error = SequenceError::Gap {
+                sender_id: metadata.sender_id,
+                expected: *expected,
+                received: metadata.sequence,
+            };
+            *expected = metadata.sequence;
+            self.pending = Some(message);
+            return Err(error.into())
+        }
+        *expected += 1;
+        Ok(message)
+    }
+}
+
+/// Marker for types whose serialized form can be deserialized as `T`.
+///
+/// Used by [IpcSender::cast] and [IpcReceiver::cast] to reinterpret a channel
+/// without a forwarding thread. Implement it for wrapper types that serialize
+/// exactly like the type they wrap.
+///
+/// [IpcSender::cast]: struct.IpcSender.html#method.cast
+/// [IpcReceiver::cast]: struct.IpcReceiver.html#method.cast
+pub trait WireCompatible<T> {}
+
+impl<T> WireCompatible<T> for T {}
+
+/// Strings are serialized as their UTF-8 bytes.
+impl WireCompatible<Vec<u8>> for String {}
+
+/// Error reported by a receiver with [sequence checking] enabled.
+///
+/// It is returned wrapped in an `ErrorKind::Io` error of kind `InvalidData`;
+/// use [from_error] to extract it.
+///
+/// [sequence checking]: struct.IpcReceiver.html#method.set_sequence_checking
+/// [from_error]: #method.from_error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SequenceError {
+    /// Messages from the sender were lost or reordered:
+    /// the received sequence number is larger than the expected one.
+    Gap {
+        sender_id: u64,
+        expected: u64,
+        received: u64,
+    },
+    /// A message from the sender was received more than once.
+    Duplicate {
+        sender_id: u64,
+        sequence: u64,
+    },
+}
+
+impl SequenceError {
+    /// Extract the `SequenceError` from an error returned by a receive call, if any.
+    pub fn from_error(error: &bincode::Error) -> Option<&SequenceError> {
+        match **error {
+            bincode::ErrorKind::Io(ref error) => {
+                error.get_ref().and_then(|error| error.downcast_ref::<SequenceError>())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SequenceError::Gap { sender_id, expected, received } => {
+                write!(formatter, "sequence gap from sender {:x}: expected {}, received {}",
+                       sender_id, expected, received)
+            }
+            SequenceError::Duplicate { sender_id, sequence } => {
+                write!(formatter, "duplicate message {} from sender {:x}", sequence, sender_id)
+            }
+        }
+    }
+}
+
+impl StdError for SequenceError {}
+
+impl From<SequenceError> for bincode::Error {
+    fn from(sequence_error: SequenceError) -> Self {
+        Error::new(io::ErrorKind::InvalidData, sequence_error).into()
+    }
+}
+
 /// Sending end of a channel using serialized messages.
 ///
 ///
@@ -312,13 +1438,24 @@ impl<T> Serialize for IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serial
 #[derive(Debug)]
 pub struct IpcSender<T> where T: Serialize {
     os_sender: OsIpcSender,
+    sender_id: u64,
+    next_sequence: Cell<u64>,
+    bincode_config: BincodeConfig,
+    hmac_key: Option<Arc<HmacKey>>,
     phantom: PhantomData<T>,
 }
 
 impl<T> Clone for IpcSender<T> where T: Serialize {
+    /// Clones the sender; the new instance gets its own [sender_id].
+    ///
+    /// [sender_id]: #method.sender_id
     fn clone(&self) -> IpcSender<T> {
         IpcSender {
             os_sender: self.os_sender.clone(),
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key.clone(),
             phantom: PhantomData,
         }
     }
@@ -332,37 +1469,559 @@ impl<T> IpcSender<T> where T: Serialize {
     pub fn connect(name: String) -> Result<IpcSender<T>,Error> {
         Ok(IpcSender {
             os_sender: OsIpcSender::connect(name)?,
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Hand a copy of this sender to the child processes spawned by `command`,
+    /// which get it back with [from_env] from their environment variable `var_name`.
+    ///
+    /// On the Unix socket backend, the child inherits the socket as a file descriptor,
+    /// whose number the variable holds, and the copy is kept open in this process until
+    /// `command` is dropped. As Mach ports can't be inherited, on macOS the variable
+    /// holds a bootstrap name and a random token instead, with which children get the
+    /// sender from a thread serving it until `command` is dropped. Elsewhere this fails
+    /// with `ErrorKind::Unsupported`: use [process::spawn] to connect to children there.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ipc_channel::ipc::{self, IpcSender};
+    /// # use std::process::Command;
+    /// // In the parent:
+    /// let (tx, rx) = ipc::channel::<String>().unwrap();
+    /// let mut command = Command::new("child");
+    /// tx.to_env("MY_APP_SENDER", &mut command).unwrap();
+    /// let child = command.spawn().unwrap();
+    /// drop((command, tx));
+    /// println!("{}", rx.recv().unwrap());
+    ///
+    /// // In the child:
+    /// let tx = IpcSender::<String>::from_env("MY_APP_SENDER").unwrap();
+    /// tx.send("Hello".to_owned()).unwrap();
+    /// ```
+    ///
+    /// [from_env]: #method.from_env
+    /// [process::spawn]: ../process/fn.spawn.html
+    pub fn to_env(&self, var_name: &str, command: &mut process::Command) -> Result<(),Error> {
+        self.os_sender.to_env(var_name, command)
+    }
+
+    /// In a child process, take over the sender handed down with [to_env] in
+    /// the environment variable `var_name`.
+    ///
+    /// The environment is left as it is, as changing it isn't safe while other threads
+    /// run. On the Unix socket backend, the inherited socket is marked close-on-exec,
+    /// so it can only be taken over once, and isn't passed on to this process' own
+    /// children; on macOS, each call looks up a new copy of the sender, for as long as
+    /// the parent serves it. Fails with `ErrorKind::NotFound` if the variable isn't set,
+    /// and `ErrorKind::InvalidData` if it doesn't name an inherited sender, e.g. a
+    /// descriptor that isn't a socket.
+    ///
+    /// [to_env]: #method.to_env
+    pub fn from_env(var_name: &str) -> Result<IpcSender<T>,Error> {
+        Ok(IpcSender {
+            os_sender: OsIpcSender::from_env(var_name)?,
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Let processes this one `exec()`s from now on inherit the sender's socket.
+    ///
+    /// Like every descriptor the crate creates or receives, the socket is otherwise
+    /// closed on `exec()`, so programs spawned by other code don't keep channels open.
+    /// [to_env] hands a sender to one command alone, and should be preferred; this is
+    /// for programs spawned by other means, which find the sender by its
+    /// [raw descriptor]. The socket is shared with clones of the sender, and
+    /// processes spawned by other threads meanwhile inherit it too.
+    ///
+    /// Fails with `ErrorKind::Unsupported` on macOS and with the in-process backend,
+    /// where channels can't be inherited.
+    ///
+    /// [to_env]: #method.to_env
+    /// [raw descriptor]: #impl-AsRawFd
+    pub fn prepare_for_inheritance(&self) -> Result<(),Error> {
+        self.os_sender.prepare_for_inheritance()
+    }
+
+    /// ID identifying this sender instance.
+    ///
+    /// Every message sent through this instance carries the ID,
+    /// which receivers can obtain from [IpcMessageMetadata::sender_id].
+    /// IDs are assigned whenever a sender is created, cloned, connected, or received
+    /// from another channel; so each clone of a sender has a distinct ID.
+    ///
+    /// [IpcMessageMetadata::sender_id]: struct.IpcMessageMetadata.html#method.sender_id
+    pub fn sender_id(&self) -> u64 {
+        self.sender_id
+    }
+
+    /// Whether any receiver for this sender is left, without waiting; so producers can
+    /// skip work nobody would receive the result of. Receivers in messages not yet
+    /// received count, and as one may be dropped right after this returns, `send()` can
+    /// still fail after this returns `true`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel::<u32>().unwrap();
+    /// assert!(tx.is_connected());
+    /// drop(rx);
+    /// assert!(!tx.is_connected());
+    /// ```
+    pub fn is_connected(&self) -> bool {
+        self.os_sender.is_connected()
+    }
+
+    /// Have messages from this sender handled at least at the urgency of `class`, or,
+    /// with `None`, at that of the sending thread alone.
+    ///
+    /// On macOS, messages always carry the QoS class of the thread sending them, so
+    /// the receiving thread is raised to it while handling them, and messages sent
+    /// into an [IpcReceiverSet] are serviced ahead of less urgent ones; this makes
+    /// them more urgent still, e.g. for user interface traffic sent from background
+    /// threads. Clones of the sender keep the override. Fails with
+    /// `ErrorKind::Unsupported` on other platforms, which have no message priorities.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// # use ipc_channel::router::QosClass;
+    /// # use std::io::ErrorKind;
+    /// let (tx, _rx) = ipc::channel::<u32>().unwrap();
+    /// if let Err(error) = tx.set_qos_override(Some(QosClass::UserInteractive)) {
+    ///     assert_eq!(error.kind(), ErrorKind::Unsupported);
+    /// }
+    /// ```
+    ///
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    pub fn set_qos_override(&self, class: Option<QosClass>) -> Result<(), Error> {
+        self.os_sender.set_qos_override(class)
+    }
+
+    /// A [WeakIpcSender] for this sender, which doesn't keep the channel open.
+    ///
+    /// Fails with `ErrorKind::Unsupported` on macOS, as Mach has no weak send rights.
+    ///
+    /// [WeakIpcSender]: struct.WeakIpcSender.html
+    pub fn downgrade(&self) -> Result<WeakIpcSender<T>, Error> {
+        Ok(WeakIpcSender {
+            os_sender: self.os_sender.downgrade()?,
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key.clone(),
             phantom: PhantomData,
         })
     }
 
+    /// Use `config` to serialize the messages sent through this instance.
+    ///
+    /// The receiver must use the same [BincodeConfig]. The setting is kept by
+    /// clones, but not carried along when the sender is sent to another process.
+    ///
+    /// [BincodeConfig]: struct.BincodeConfig.html
+    pub fn with_bincode_config(mut self, config: BincodeConfig) -> IpcSender<T> {
+        self.bincode_config = config;
+        self
+    }
+
+    /// Authenticate the messages sent through this instance with the shared secret `key`,
+    /// for a receiver keyed with [IpcReceiver::with_hmac_key].
+    ///
+    /// The key is kept by clones, but not carried along when the sender is sent
+    /// to another process.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel().unwrap();
+    /// let rx = rx.with_hmac_key(b"secret");
+    /// tx.clone().with_hmac_key(b"secret").send(42).unwrap();
+    /// assert_eq!(rx.recv().unwrap(), 42);
+    /// tx.send(43).unwrap();
+    /// assert!(rx.recv().is_err());
+    /// ```
+    ///
+    /// [IpcReceiver::with_hmac_key]: struct.IpcReceiver.html#method.with_hmac_key
+    pub fn with_hmac_key(mut self, key: &[u8]) -> IpcSender<T> {
+        self.hmac_key = Some(Arc::new(HmacKey::new(key)));
+        self
+    }
+
+    /// Wrap the sender to send `U`s, converting each with `f` before sending it,
+    /// e.g. from the type used internally to the wire type. See the [adapter] module.
+    ///
+    /// [adapter]: ../adapter/index.html
+    pub fn with<U, F>(self, f: F) -> WithSender<T, U, F> where F: Fn(U) -> T {
+        WithSender::new(self, f)
+    }
+
+    /// Limit the sender to `rate` messages per second, allowing bursts of up to
+    /// `burst` messages. See the [rate_limit] module.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` or `burst` is zero.
+    ///
+    /// [rate_limit]: ../rate_limit/index.html
+    pub fn rate_limited(self, rate: u32, burst: u32) -> RateLimitedSender<T> {
+        RateLimitedSender::new(self, rate, burst)
+    }
+
     /// Send data accross the channel to the receiver.
     pub fn send(&self, data: T) -> Result<(), bincode::Error> {
-        let mut bytes = Vec::with_capacity(4096);
-        OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
-            OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(
-                    |os_ipc_shared_memory_regions_for_serialization| {
-                let old_os_ipc_channels =
-                    mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(), Vec::new());
-                let old_os_ipc_shared_memory_regions =
-                    mem::replace(&mut *os_ipc_shared_memory_regions_for_serialization.borrow_mut(),
-                                 Vec::new());
-                let os_ipc_shared_memory_regions;
-                let os_ipc_channels;
-                {
-                    bincode::serialize_into(&mut bytes, &data)?;
-                    os_ipc_channels =
-                        mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(),
-                                     old_os_ipc_channels);
-                    os_ipc_shared_memory_regions = mem::replace(
-                        &mut *os_ipc_shared_memory_regions_for_serialization.borrow_mut(),
-                        old_os_ipc_shared_memory_regions);
-                };
-                Ok(self.os_sender.send(&bytes[..], os_ipc_channels, os_ipc_shared_memory_regions)?)
-            })
+        self.send_with_expiry(data, None)
+    }
+
+    /// Send data that is only worth receiving within `ttl` from now.
+    ///
+    /// The deadline travels in the [metadata header], so this fails with
+    /// `ErrorKind::InvalidInput` on channels without it.
+    ///
+    /// Receivers discard the message if it is still queued once `ttl` has passed,
+    /// keeping [count] of the messages they discard. As both ends compare the
+    /// deadline to the system clock, this assumes the clocks of the processes agree.
+    /// Messages handed over to the [ROUTER] or an [IpcReceiverSet] aren't discarded;
+    /// check [IpcMessageMetadata::is_expired] there.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{self, ChannelOptions};
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// let options = ChannelOptions::new().metadata_header();
+    /// let (tx, rx) = ipc::channel_with_options(options).unwrap();
+    /// tx.send_with_ttl("stale".to_owned(), Duration::from_millis(1)).unwrap();
+    /// thread::sleep(Duration::from_millis(10));
+    /// tx.send_with_ttl("fresh".to_owned(), Duration::from_secs(60)).unwrap();
+    /// assert_eq!(rx.recv().unwrap(), "fresh");
+    /// assert_eq!(rx.expired_count(), 1);
+    /// ```
+    ///
+    /// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+    /// [count]: struct.IpcReceiver.html#method.expired_count
+    /// [ROUTER]: ../router/struct.ROUTER.html
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    /// [IpcMessageMetadata::is_expired]: struct.IpcMessageMetadata.html#method.is_expired
+    pub fn send_with_ttl(&self, data: T, ttl: Duration) -> Result<(), bincode::Error> {
+        if !self.bincode_config.metadata {
+            return Err(Error::new(io::ErrorKind::InvalidInput,
+                                  "TTLs need the metadata header").into())
+        }
+        let expiry = UNIX_EPOCH.elapsed().unwrap_or_default() + ttl;
+        self.send_with_expiry(data, Some(expiry.as_nanos() as u64))
+    }
+
+    /// Send a message that `write` serializes straight into the buffer handed to the
+    /// OS, e.g. to send a `T` made of borrowed parts without building one first.
+    ///
+    /// Bincode lays out structs, tuples and sequences as their fields one after the
+    /// other, so serializing the fields of a `T` in order serializes the `T`; the
+    /// receiver fails to deserialize the message otherwise. The size limit of the
+    /// [BincodeConfig] applies to the message as a whole.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// let (tx, rx) = ipc::channel::<(String, Vec<u32>)>().unwrap();
+    /// let (name, samples) = ("latency", [3, 1, 4]);
+    /// tx.send_with(|writer| {
+    ///     writer.serialize(name)?;
+    ///     writer.serialize(&samples[..])
+    /// }).unwrap();
+    /// assert_eq!(rx.recv().unwrap(), ("latency".to_owned(), vec![3, 1, 4]));
+    /// ```
+    ///
+    /// [BincodeConfig]: struct.BincodeConfig.html
+    pub fn send_with<F>(&self, write: F) -> Result<(), bincode::Error>
+                        where F: FnOnce(&mut IpcMessageWriter) -> Result<(), bincode::Error> {
+        let config = self.bincode_config;
+        self.send_frame(None, |buffer| {
+            let mut writer = IpcMessageWriter {
+                buffer,
+                config,
+                written: 0,
+            };
+            write(&mut writer)?;
+            match config.limit {
+                Some(limit) if writer.written > limit => {
+                    Err(Box::new(bincode::ErrorKind::SizeLimit))
+                }
+                _ => Ok(()),
+            }
         })
     }
 
+    fn send_with_expiry(&self, data: T, expiry: Option<u64>) -> Result<(), bincode::Error> {
+        let config = self.bincode_config;
+        self.send_frame(expiry, |buffer| config.serialize_into(buffer, &data))
+    }
+
+    /// Send a message whose payload `serialize` writes after the header.
+    fn send_frame<F>(&self, expiry: Option<u64>, serialize: F) -> Result<(), bincode::Error>
+                     where F: FnOnce(&mut MessageBuffer) -> Result<(), bincode::Error> {
+        let mut buffer = MessageBuffer::new();
+        let (sequence, metadata) = self.next_metadata(expiry);
+        if self.bincode_config.metadata {
+            metadata.write(&mut buffer)?;
+        }
+        let payload_start = buffer.bytes().len();
+        let (os_ipc_channels, os_ipc_shared_memory_regions, sensitive_copies) =
+            collect_attachments(|| serialize(&mut buffer))?;
+        capture::record(Direction::Sent,
+                        metadata,
+                        &buffer.bytes()[payload_start..],
+                        os_ipc_channels.len(),
+                        os_ipc_shared_memory_regions.iter().map(|region| region.len()));
+        if let Some(ref key) = self.hmac_key {
+            let tag = key.sign_message(buffer.bytes(),
+                                       os_ipc_channels.len(),
+                                       os_ipc_shared_memory_regions.iter().map(|region| {
+                                           region.len()
+                                       }));
+            io::Write::write_all(&mut buffer, &tag)?;
+        }
+        match buffer.heap {
+            Some(bytes) => {
+                self.os_sender.send_vec(bytes, os_ipc_channels, os_ipc_shared_memory_regions)?
+            }
+            None => {
+                self.os_sender.send(&buffer.inline[..buffer.len],
+                                    os_ipc_channels,
+                                    os_ipc_shared_memory_regions)?
+            }
+        }
+        sensitive_copies.sent();
+        self.next_sequence.set(sequence + 1);
+        Ok(())
+    }
+
+    /// The sequence number of the next message, and the metadata to send it with:
+    /// none but the expiry if the channel doesn't carry the metadata header.
+    fn next_metadata(&self, expiry: Option<u64>) -> (u64, IpcMessageMetadata) {
+        let sequence = self.next_sequence.get();
+        if !self.bincode_config.metadata {
+            return (sequence, IpcMessageMetadata::default())
+        }
+        (sequence, IpcMessageMetadata {
+            sender_id: self.sender_id,
+            sequence,
+            expiry,
+        })
+    }
+
+    /// Start a transaction: messages pushed to it are sent together by [commit],
+    /// so the receiver gets either all of them or, if the commit fails, none.
+    ///
+    /// The messages are sent as one OS message, so no other message from any sender
+    /// ends up among them. The receiver hands them out one by one, each with its
+    /// own sequence number, and the [ROUTER] and [IpcReceiverSet]s pass them on
+    /// one after the other. A receiver that is sent to another process or added to
+    /// a set between the messages of a transaction loses those it didn't return yet.
+    ///
+    /// Transactions need the [metadata header]: committing fails with
+    /// `ErrorKind::InvalidInput` on channels without it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{self, ChannelOptions};
+    /// let options = ChannelOptions::new().metadata_header();
+    /// let (tx, rx) = ipc::channel_with_options(options).unwrap();
+    /// let mut transaction = tx.transaction();
+    /// transaction.push("debit".to_owned()).unwrap();
+    /// transaction.push("credit".to_owned()).unwrap();
+    /// transaction.commit().unwrap();
+    /// assert_eq!(rx.recv().unwrap(), "debit");
+    /// assert_eq!(rx.recv().unwrap(), "credit");
+    /// ```
+    ///
+    /// [commit]: struct.IpcTransaction.html#method.commit
+    /// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+    /// [ROUTER]: ../router/struct.ROUTER.html
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    pub fn transaction(&self) -> IpcTransaction<'_, T> {
+        IpcTransaction {
+            sender: self,
+            parts: vec![],
+            os_ipc_channels: vec![],
+            os_ipc_shared_memory_regions: vec![],
+            sensitive_copies: SensitiveCopies::default(),
+        }
+    }
+
+    /// Send bytes that are already serialized, bypassing serde,
+    /// together with channels and shared memory regions.
+    ///
+    /// The message is received with [IpcReceiver::recv_raw]; it can only be received
+    /// with `recv()` if `data` happens to be the bincode serialization of `T`.
+    ///
+    /// [IpcReceiver::recv_raw]: struct.IpcReceiver.html#method.recv_raw
+    pub fn send_raw(&self,
+                    data: &[u8],
+                    channels: Vec<IpcRawChannel>,
+                    shared_memory_regions: Vec<IpcSharedMemory>)
+                    -> Result<(), bincode::Error> {
+        let mut bytes = Vec::with_capacity(data.len() + 16 + hmac::TAG_SIZE);
+        let (sequence, metadata) = self.next_metadata(None);
+        if self.bincode_config.metadata {
+            metadata.write(&mut bytes)?;
+        }
+        bytes.extend_from_slice(data);
+        capture::record(Direction::Sent,
+                        metadata,
+                        data,
+                        channels.len(),
+                        shared_memory_regions.iter().map(|region| region.len()));
+        if let Some(ref key) = self.hmac_key {
+            let tag = key.sign_message(&bytes,
+                                       channels.len(),
+                                       shared_memory_regions.iter().map(|region| region.len()));
+            bytes.extend_from_slice(&tag);
+        }
+        let os_ipc_channels = channels.into_iter().map(|channel| {
+            match channel {
+                IpcRawChannel::Sender(sender) => OsIpcChannel::Sender(sender.os_sender),
+                IpcRawChannel::Receiver(receiver) => OsIpcChannel::Receiver(receiver.os_receiver),
+            }
+        }).collect();
+        let mut sensitive_copies = SensitiveCopies::default();
+        let os_ipc_shared_memory_regions = shared_memory_regions.into_iter().map(|region| {
+            match region.wipe {
+                // Don't let the receiver see the region wiped along with ours.
+                Some(_) => {
+                    let copy = OsIpcSharedMemory::from_bytes(&region);
+                    sensitive_copies.0.push(copy.clone());
+                    copy
+                }
+                None => region.os_shared_memory,
+            }
+        }).collect();
+        self.os_sender.send_vec(bytes, os_ipc_channels, os_ipc_shared_memory_regions)?;
+        sensitive_copies.sent();
+        self.next_sequence.set(sequence + 1);
+        Ok(())
+    }
+
+    /// Send a message received in the [opaque] form on as is, without deserializing
+    /// and serializing it again, along with its channels and shared memory regions.
+    ///
+    /// The message keeps the [metadata] of its original sender, so a receiver
+    /// checking sequences sees the original sender's numbering; this sender's
+    /// numbering doesn't advance. That is, if both channels carry the [metadata
+    /// header]: otherwise, the header is dropped, or the message gets this sender's.
+    /// If this sender has an [HMAC key], it signs the message. Nothing checks that
+    /// the message is a serialized `T`.
+    ///
+    /// # Panics
+    ///
+    /// If a failed attempt to deserialize the message took some of its shared memory
+    /// regions out of it.
+    ///
+    /// [opaque]: struct.OpaqueIpcMessage.html
+    /// [metadata]: struct.IpcMessageMetadata.html
+    /// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+    /// [HMAC key]: #method.with_hmac_key
+    pub fn forward(&self, message: OpaqueIpcMessage) -> Result<(), bincode::Error> {
+        let (mut metadata, flags, payload_len) = {
+            let (metadata, flags, payload) = message.header()?;
+            (metadata, flags, payload.len())
+        };
+        let OpaqueIpcMessage {
+            data: mut bytes,
+            os_ipc_channels,
+            os_ipc_shared_memory_regions,
+            metadata: has_metadata,
+        } = message;
+        let mut next_sequence = None;
+        match (has_metadata, self.bincode_config.metadata) {
+            (true, false) => {
+                if flags & TRANSACTION_FLAG != 0 {
+                    return Err(Error::new(io::ErrorKind::InvalidInput,
+                                          "transactions need the metadata header").into())
+                }
+                let header_len = bytes.len() - payload_len;
+                bytes.drain(..header_len);
+            }
+            (false, true) => {
+                let (sequence, sender_metadata) = self.next_metadata(None);
+                let mut header = vec![];
+                sender_metadata.write(&mut header)?;
+                bytes.splice(..0, header);
+                metadata = sender_metadata;
+                next_sequence = Some(sequence + 1);
+            }
+            _ => {}
+        }
+        if capture::is_capturing() {
+            capture::record(Direction::Sent,
+                            metadata,
+                            &bytes[bytes.len() - payload_len..],
+                            os_ipc_channels.len(),
+                            os_ipc_shared_memory_regions.iter().map(|region| {
+                                region.as_ref().map_or(0, |region| region.len())
+                            }));
+        }
+        if let Some(ref key) = self.hmac_key {
+            let tag = key.sign_message(&bytes,
+                                       os_ipc_channels.len(),
+                                       os_ipc_shared_memory_regions.iter().map(|region| {
+                                           region.as_ref().map_or(0, |region| region.len())
+                                       }));
+            bytes.extend_from_slice(&tag);
+        }
+        let os_ipc_channels = os_ipc_channels.into_iter().map(|mut os_channel| {
+            os_channel.to_channel()
+        }).collect();
+        let os_ipc_shared_memory_regions = os_ipc_shared_memory_regions.into_iter().map(|region| {
+            region.expect("received shared memory was taken")
+        }).collect();
+        self.os_sender.send_vec(bytes, os_ipc_channels, os_ipc_shared_memory_regions)?;
+        if let Some(next_sequence) = next_sequence {
+            self.next_sequence.set(next_sequence);
+        }
+        Ok(())
+    }
+
+    /// Reinterpret the sender as sending values of type `U`,
+    /// which the receiving end can read as `T`.
+    ///
+    /// The sender keeps its [sender_id] and sequence numbering.
+    ///
+    /// [sender_id]: #method.sender_id
+    pub fn cast<U>(self) -> IpcSender<U> where U: Serialize + WireCompatible<T> {
+        self.cast_unchecked()
+    }
+
+    /// Like [cast], without checking the types are compatible.
+    ///
+    /// This can't cause undefined behaviour, but if the serialized forms differ,
+    /// receiving the messages fails or produces meaningless values.
+    ///
+    /// [cast]: #method.cast
+    pub fn cast_unchecked<U>(self) -> IpcSender<U> where U: Serialize {
+        IpcSender {
+            os_sender: self.os_sender,
+            sender_id: self.sender_id,
+            next_sequence: self.next_sequence,
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key,
+            phantom: PhantomData,
+        }
+    }
+
     pub fn to_opaque(self) -> OpaqueIpcSender {
         OpaqueIpcSender {
             os_sender: self.os_sender,
@@ -370,11 +2029,175 @@ impl<T> IpcSender<T> where T: Serialize {
     }
 }
 
+/// Messages staged to be sent together, from [IpcSender::transaction].
+///
+/// Dropping the transaction without committing it discards the messages.
+///
+/// [IpcSender::transaction]: struct.IpcSender.html#method.transaction
+pub struct IpcTransaction<'a, T> where T: Serialize + 'a {
+    sender: &'a IpcSender<T>,
+    parts: Vec<TransactionPart>,
+    os_ipc_channels: Vec<OsIpcChannel>,
+    os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>,
+    sensitive_copies: SensitiveCopies,
+}
+
+impl<'a, T> IpcTransaction<'a, T> where T: Serialize {
+    /// Stage a message, serializing it right away.
+    pub fn push(&mut self, data: T) -> Result<(), bincode::Error> {
+        self.push_unchecked(&data)
+    }
+
+    /// Stage a value of any type, e.g. for the sub-channels of a mux,
+    /// which share the sender.
+    pub(crate) fn push_unchecked<U>(&mut self, data: &U) -> Result<(), bincode::Error>
+                                    where U: Serialize {
+        let mut payload = vec![];
+        let (os_ipc_channels, os_ipc_shared_memory_regions, mut sensitive_copies) =
+            serialize_with_attachments(data, &mut payload, self.sender.bincode_config)?;
+        self.parts.push((payload,
+                         os_ipc_channels.len() as u32,
+                         os_ipc_shared_memory_regions.len() as u32));
+        self.os_ipc_channels.extend(os_ipc_channels);
+        self.os_ipc_shared_memory_regions.extend(os_ipc_shared_memory_regions);
+        self.sensitive_copies.0.append(&mut sensitive_copies.0);
+        Ok(())
+    }
+
+    /// The number of messages staged.
+    pub fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Whether no message is staged.
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Send the staged messages. Committing an empty transaction sends nothing.
+    pub fn commit(self) -> Result<(), bincode::Error> {
+        if self.parts.is_empty() {
+            return Ok(())
+        }
+        let sender = self.sender;
+        if !sender.bincode_config.metadata {
+            return Err(Error::new(io::ErrorKind::InvalidInput,
+                                  "transactions need the metadata header").into())
+        }
+        let sequence = sender.next_sequence.get();
+        let metadata = IpcMessageMetadata {
+            sender_id: sender.sender_id,
+            sequence,
+            expiry: None,
+        };
+        let size = self.parts.iter().map(|part| part.0.len() + 16).sum::<usize>();
+        let mut bytes = platform::take_buffer(size + 24 + hmac::TAG_SIZE);
+        metadata.write_with_flags(&mut bytes, TRANSACTION_FLAG)?;
+        bincode::serialize_into(&mut bytes, &self.parts)?;
+        if capture::is_capturing() {
+            let mut regions = &self.os_ipc_shared_memory_regions[..];
+            for (index, &(ref payload, channels, region_count)) in self.parts.iter().enumerate() {
+                let (part_regions, rest) = regions.split_at(region_count as usize);
+                regions = rest;
+                capture::record(Direction::Sent,
+                                IpcMessageMetadata {
+                                    sequence: sequence + index as u64,
+                                    ..metadata
+                                },
+                                payload,
+                                channels as usize,
+                                part_regions.iter().map(|region| region.len()));
+            }
+        }
+        if let Some(ref key) = sender.hmac_key {
+            let tag = key.sign_message(&bytes,
+                                       self.os_ipc_channels.len(),
+                                       self.os_ipc_shared_memory_regions.iter().map(|region| {
+                                           region.len()
+                                       }));
+            bytes.extend_from_slice(&tag);
+        }
+        sender.os_sender.send_vec(bytes, self.os_ipc_channels, self.os_ipc_shared_memory_regions)?;
+        self.sensitive_copies.sent();
+        sender.next_sequence.set(sequence + self.parts.len() as u64);
+        Ok(())
+    }
+}
+
+impl<'a, T> Debug for IpcTransaction<'a, T> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcTransaction").field("len", &self.parts.len()).finish()
+    }
+}
+
+impl<T> IpcSender<T> where T: Serialize + Send + 'static {
+    /// Send data across the channel, moving it to the receiver as is rather
+    /// than serializing it, if both ends are in this process: that is, with
+    /// the in-process backend. Elsewhere, or if the sender has an [HMAC key],
+    /// this is the same as `send()`.
+    ///
+    /// The value is only serialized after all if it isn't received with
+    /// [IpcReceiver::recv_local], as the type it was sent as.
+    ///
+    /// [IpcReceiver::recv_local]: struct.IpcReceiver.html#method.recv_local
+    /// [HMAC key]: #method.with_hmac_key
+    #[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+    pub fn send_local(&self, data: T) -> Result<(), bincode::Error> {
+        if self.hmac_key.is_some() {
+            return self.send(data)
+        }
+        let mut bytes = Vec::new();
+        let (sequence, metadata) = self.next_metadata(None);
+        if self.bincode_config.metadata {
+            metadata.write(&mut bytes)?;
+        }
+        self.os_sender.send_local(bytes, Box::new(LocalPayload(data, self.bincode_config)))?;
+        self.next_sequence.set(sequence + 1);
+        Ok(())
+    }
+
+    #[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+                    target_os = "ios")))]
+    pub fn send_local(&self, data: T) -> Result<(), bincode::Error> {
+        self.send(data)
+    }
+}
+
+/// A value sent with [IpcSender::send_local].
+///
+/// [IpcSender::send_local]: struct.IpcSender.html#method.send_local
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+struct LocalPayload<T>(T, BincodeConfig);
+
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+impl<T> OsIpcLocalPayload for LocalPayload<T> where T: Serialize + Send + 'static {
+    fn payload_type_id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(self.0)
+    }
+
+    fn serialize(self: Box<Self>, data: &mut Vec<u8>)
+                 -> Result<(Vec<OsIpcChannel>, Vec<OsIpcSharedMemory>), bincode::Error> {
+        let (os_ipc_channels, os_ipc_shared_memory_regions, sensitive_copies) =
+            serialize_with_attachments(&self.0, data, self.1)?;
+        // The message is queued by then, with the copies handed over to the receiver.
+        sensitive_copies.sent();
+        Ok((os_ipc_channels, os_ipc_shared_memory_regions))
+    }
+}
+
 impl<'de, T> Deserialize<'de> for IpcSender<T> where T: Serialize {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
         let os_sender = deserialize_os_ipc_sender(deserializer)?;
         Ok(IpcSender {
             os_sender: os_sender,
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
             phantom: PhantomData,
         })
     }
@@ -386,6 +2209,54 @@ impl<T> Serialize for IpcSender<T> where T: Serialize {
     }
 }
 
+/// A reference to an [IpcSender] that doesn't keep its channel open, from
+/// [IpcSender::downgrade]; so caches and registries can hold on to reply endpoints,
+/// and the receiver still gets `ChannelClosed` once the real senders are gone.
+///
+/// [upgrade] gives a sender as long as the sender this was made from, or a clone of it,
+/// is alive. The [BincodeConfig] and HMAC key of that sender are kept. Weak senders
+/// can't be sent to other processes.
+///
+/// [IpcSender]: struct.IpcSender.html
+/// [IpcSender::downgrade]: struct.IpcSender.html#method.downgrade
+/// [upgrade]: #method.upgrade
+/// [BincodeConfig]: struct.BincodeConfig.html
+#[derive(Debug)]
+pub struct WeakIpcSender<T> where T: Serialize {
+    os_sender: OsIpcWeakSender,
+    bincode_config: BincodeConfig,
+    hmac_key: Option<Arc<HmacKey>>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> WeakIpcSender<T> where T: Serialize {
+    /// A sender for the channel, with a new [sender_id]; or `None` if the senders this
+    /// refers to are all gone.
+    ///
+    /// [sender_id]: struct.IpcSender.html#method.sender_id
+    pub fn upgrade(&self) -> Option<IpcSender<T>> {
+        Some(IpcSender {
+            os_sender: self.os_sender.upgrade()?,
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key.clone(),
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<T> Clone for WeakIpcSender<T> where T: Serialize {
+    fn clone(&self) -> WeakIpcSender<T> {
+        WeakIpcSender {
+            os_sender: self.os_sender.clone(),
+            bincode_config: self.bincode_config,
+            hmac_key: self.hmac_key.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 /// Collection of [IpcReceiver]s moved into the set; thus creating a common
 /// (and exclusive) endpoint for receiving messages on any of the added
 /// channels.
@@ -422,6 +2293,18 @@ impl<T> Serialize for IpcSender<T> where T: Serialize {
 /// [IpcReceiver]: struct.IpcReceiver.html
 pub struct IpcReceiverSet {
     os_receiver_set: OsIpcReceiverSet,
+    /// Priorities of the receivers added with one other than 0.
+    priorities: HashMap<u64, i32>,
+    /// The receivers whose messages start with a metadata header.
+    with_metadata: HashSet<u64>,
+    /// The keys of the receivers whose messages must be authenticated.
+    hmac_keys: HashMap<u64, Arc<HmacKey>>,
+    /// The exits of watched peers the receivers were reported closed for.
+    peer_deaths: HashMap<u64, PeerDied>,
+    /// Events held back for ones of a higher priority, with the number of
+    /// selections they were held back for.
+    deferred: Vec<(IpcSelectionResult, u32)>,
+    starvation_limit: Option<u32>,
 }
 
 impl IpcReceiverSet {
@@ -433,58 +2316,440 @@ impl IpcReceiverSet {
     /// [add]: #method.add
     /// [IpcReceiverSet]: struct.IpcReceiverSet.html
     pub fn new() -> Result<IpcReceiverSet,Error> {
-        Ok(IpcReceiverSet {
+        let receiver_set = IpcReceiverSet {
             os_receiver_set: OsIpcReceiverSet::new()?,
-        })
+            priorities: HashMap::new(),
+            with_metadata: HashSet::new(),
+            hmac_keys: HashMap::new(),
+            peer_deaths: HashMap::new(),
+            deferred: vec![],
+            starvation_limit: None,
+        };
+        registry::lock().receiver_sets += 1;
+        Ok(receiver_set)
     }
 
     /// Add and consume the [IpcReceiver] to the set of receivers to be polled.
     /// [IpcReceiver]: struct.IpcReceiver.html
     pub fn add<T>(&mut self, receiver: IpcReceiver<T>) -> Result<u64,Error>
                   where T: for<'de> Deserialize<'de> + Serialize {
-        Ok(self.os_receiver_set.add(receiver.os_receiver)?)
+        self.add_opaque(receiver.to_opaque())
     }
 
     /// Add an [OpaqueIpcReceiver] to the set of receivers to be polled.
     /// [OpaqueIpcReceiver]: struct.OpaqueIpcReceiver.html
     pub fn add_opaque(&mut self, receiver: OpaqueIpcReceiver) -> Result<u64,Error> {
-        Ok(self.os_receiver_set.add(receiver.os_receiver)?)
+        let id = self.os_receiver_set.add(receiver.os_receiver)?;
+        if receiver.metadata {
+            self.with_metadata.insert(id);
+        }
+        if let Some(key) = receiver.hmac_key {
+            self.hmac_keys.insert(id, key);
+        }
+        Ok(id)
     }
 
-    /// Wait for IPC messages received on any of the receivers in the set. The
-    /// method will return multiple events. An event may be either a message
-    /// received or a channel closed event.
+    /// Like [add], with a priority: while events of receivers with a higher priority
+    /// are pending, selecting holds back the others, so e.g. control channels
+    /// preempt bulk data channels. Receivers added with [add] have priority 0.
     ///
-    /// [IpcReceiver]: struct.IpcReceiver.html
-    pub fn select(&mut self) -> Result<Vec<IpcSelectionResult>,Error> {
-        let results = self.os_receiver_set.select()?;
-        Ok(results.into_iter().map(|result| {
-            match result {
-                OsIpcSelectionResult::DataReceived(os_receiver_id,
-                                                   data,
-                                                   os_ipc_channels,
-                                                   os_ipc_shared_memory_regions) => {
-                    IpcSelectionResult::MessageReceived(os_receiver_id, OpaqueIpcMessage {
-                        data: data,
-                        os_ipc_channels: os_ipc_channels,
-                        os_ipc_shared_memory_regions:
-                            os_ipc_shared_memory_regions.into_iter().map(
-                                |os_ipc_shared_memory_region| {
-                                    Some(os_ipc_shared_memory_region)
-                                }).collect(),
-                    })
-                }
-                OsIpcSelectionResult::ChannelClosed(os_receiver_id) => {
-                    IpcSelectionResult::ChannelClosed(os_receiver_id)
+    /// Events are held back for as long as higher-priority ones keep coming, unless
+    /// a [starvation limit] is set. The events of a receiver stay in order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{self, IpcReceiverSet};
+    /// let (_control_tx, control_rx) = ipc::channel::<String>().unwrap();
+    /// let (_bulk_tx, bulk_rx) = ipc::channel::<Vec<u8>>().unwrap();
+    /// let mut rx_set = IpcReceiverSet::new().unwrap();
+    /// rx_set.add_with_priority(control_rx, 10).unwrap();
+    /// rx_set.add(bulk_rx).unwrap();
+    /// // Hand out bulk data held back for 10 selections in a row anyway.
+    /// rx_set.set_starvation_limit(Some(10));
+    /// ```
+    ///
+    /// [add]: #method.add
+    /// [starvation limit]: #method.set_starvation_limit
+    pub fn add_with_priority<T>(&mut self, receiver: IpcReceiver<T>, priority: i32)
+                                -> Result<u64,Error>
+                                where T: for<'de> Deserialize<'de> + Serialize {
+        self.add_opaque_with_priority(receiver.to_opaque(), priority)
+    }
+
+    /// Like [add_with_priority], for an [OpaqueIpcReceiver].
+    ///
+    /// [add_with_priority]: #method.add_with_priority
+    /// [OpaqueIpcReceiver]: struct.OpaqueIpcReceiver.html
+    pub fn add_opaque_with_priority(&mut self, receiver: OpaqueIpcReceiver, priority: i32)
+                                    -> Result<u64,Error> {
+        let id = self.add_opaque(receiver)?;
+        if priority != 0 {
+            self.priorities.insert(id, priority);
+        }
+        Ok(id)
+    }
+
+    /// Hand out events held back for higher-priority ones once they were held back
+    /// for `selections` selections, after the higher-priority events; `None`, the
+    /// default, holds them back indefinitely.
+    pub fn set_starvation_limit(&mut self, selections: Option<u32>) {
+        self.starvation_limit = selections;
+    }
+
+    /// If the receiver `id` was reported closed because the peer it [watches] exited,
+    /// the pid and exit status of the peer. Each is only returned once.
+    ///
+    /// [watches]: struct.IpcReceiver.html#method.watch_peer
+    pub fn take_peer_death(&mut self, id: u64) -> Option<PeerDied> {
+        self.peer_deaths.remove(&id)
+    }
+
+    /// Report the receivers whose watched peer died as closed, keeping the reason.
+    #[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+                  target_os = "ios")))]
+    fn record_peer_death(&mut self, result: OsIpcSelectionResult) -> OsIpcSelectionResult {
+        match result {
+            OsIpcSelectionResult::PeerDied(id, pid, exit_status) => {
+                self.peer_deaths.insert(id, PeerDied { pid, exit_status });
+                OsIpcSelectionResult::ChannelClosed(id)
+            }
+            result => result,
+        }
+    }
+
+    #[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios"))]
+    fn record_peer_death(&mut self, result: OsIpcSelectionResult) -> OsIpcSelectionResult {
+        result
+    }
+
+    fn priority(&self, result: &IpcSelectionResult) -> i32 {
+        self.priorities.get(&result.receiver_id()).cloned().unwrap_or(0)
+    }
+
+    /// The events to hand out among `results` and those held back before, in order
+    /// of priority, holding back the others.
+    fn prioritize(&mut self, results: Vec<OsIpcSelectionResult>) -> Vec<IpcSelectionResult> {
+        let results = results.into_iter()
+                             .map(|result| self.record_peer_death(result))
+                             .collect::<Vec<_>>();
+        let results = results.into_iter().flat_map(|result| {
+            IpcSelectionResult::from_os(result, &self.with_metadata, &self.hmac_keys)
+        }).collect::<Vec<_>>();
+        for result in &results {
+            if let IpcSelectionResult::ChannelClosed(id) = *result {
+                self.with_metadata.remove(&id);
+                self.hmac_keys.remove(&id);
+            }
+        }
+        if self.priorities.is_empty() && self.deferred.is_empty() {
+            return results
+        }
+        let mut pending = mem::take(&mut self.deferred);
+        pending.extend(results.into_iter().map(|result| (result, 0)));
+        let top_priority = pending.iter().map(|(result, _)| self.priority(result)).max();
+        let mut selected = vec![];
+        for (result, deferrals) in pending {
+            let priority = self.priority(&result);
+            if Some(priority) == top_priority ||
+               self.starvation_limit.iter().any(|&limit| deferrals >= limit) {
+                selected.push((priority, result))
+            } else {
+                self.deferred.push((result, deferrals + 1))
+            }
+        }
+        // Stable, so the events of each receiver stay in order.
+        selected.sort_by_key(|&(priority, _)| cmp::Reverse(priority));
+        for (_, result) in &selected {
+            if let IpcSelectionResult::ChannelClosed(id) = *result {
+                self.priorities.remove(&id);
+            }
+        }
+        selected.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Wait for IPC messages received on any of the receivers in the set. The
+    /// method will return multiple events. An event may be either a message
+    /// received or a channel closed event.
+    ///
+    /// [IpcReceiver]: struct.IpcReceiver.html
+    pub fn select(&mut self) -> Result<Vec<IpcSelectionResult>,Error> {
+        // Events held back can be handed out without waiting.
+        let results = if self.deferred.is_empty() {
+            self.os_receiver_set.select()?
+        } else {
+            self.os_receiver_set.try_select()?
+        };
+        Ok(self.prioritize(results))
+    }
+
+    /// Like [select], returning no events rather than waiting if none is pending.
+    ///
+    /// [select]: #method.select
+    pub fn try_select(&mut self) -> Result<Vec<IpcSelectionResult>,Error> {
+        let results = self.os_receiver_set.try_select()?;
+        Ok(self.prioritize(results))
+    }
+
+    /// Like [select], returning no events if none arrives within `duration`.
+    ///
+    /// [select]: #method.select
+    pub fn select_timeout(&mut self, duration: Duration) -> Result<Vec<IpcSelectionResult>,Error> {
+        let results = if self.deferred.is_empty() {
+            self.os_receiver_set.select_timeout(duration)?
+        } else {
+            self.os_receiver_set.try_select()?
+        };
+        Ok(self.prioritize(results))
+    }
+
+    /// Turn the set into an [IpcSelectionStream] of its events, for use in an event loop.
+    ///
+    /// [IpcSelectionStream]: struct.IpcSelectionStream.html
+    #[cfg(feature = "async")]
+    pub fn into_stream(self) -> IpcSelectionStream {
+        IpcSelectionStream {
+            receiver_set: self,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// The events of an [IpcReceiverSet], one at a time, without blocking.
+///
+/// As with the `Stream` of an [IpcReceiver], `poll()` doesn't arrange for the
+/// task to be woken once an event is pending; drive it from the event loop's
+/// reactor instead. On Linux and the BSDs, the set's file descriptor, from
+/// `as_raw_fd()`, becomes readable while events are pending, so it can be
+/// registered with the reactor. The stream never ends, as receivers can be
+/// added at any time through [get_mut].
+///
+/// [IpcReceiverSet]: struct.IpcReceiverSet.html
+/// [IpcReceiver]: struct.IpcReceiver.html
+/// [get_mut]: #method.get_mut
+#[cfg(feature = "async")]
+pub struct IpcSelectionStream {
+    receiver_set: IpcReceiverSet,
+    /// Events of the last selection not handed out yet.
+    pending: VecDeque<IpcSelectionResult>,
+}
+
+#[cfg(feature = "async")]
+impl IpcSelectionStream {
+    /// The set, e.g. to add receivers to it.
+    pub fn get_mut(&mut self) -> &mut IpcReceiverSet {
+        &mut self.receiver_set
+    }
+
+    /// The set back, along with the events the stream hasn't handed out yet.
+    pub fn into_inner(self) -> (IpcReceiverSet, Vec<IpcSelectionResult>) {
+        (self.receiver_set, self.pending.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "async")]
+impl Stream for IpcSelectionStream {
+    type Item = IpcSelectionResult;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.pending.is_empty() {
+            self.pending.extend(self.receiver_set.try_select()?);
+        }
+        match self.pending.pop_front() {
+            Some(result) => Ok(Async::Ready(Some(result))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Converts a message of one receiver of a [TypedReceiverSet] into the set's event type.
+///
+/// [TypedReceiverSet]: struct.TypedReceiverSet.html
+type MessageConverter<E> = Box<dyn Fn(OpaqueIpcMessage) -> Result<E, bincode::Error> + Send>;
+
+/// An [IpcReceiverSet] handing out messages already deserialized, and converted into
+/// one type for all its receivers, such as an enum with a variant per receiver.
+///
+/// Each receiver is added with the conversion of its messages; `select()` then
+/// deserializes messages with the type and [BincodeConfig] of the receiver they
+/// arrived on, so they can't be deserialized as the wrong type.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::ipc::{self, TypedReceiverSet, TypedSelectionResult};
+/// #[derive(Debug, PartialEq)]
+/// enum Event {
+///     Resize(u32, u32),
+///     Title(String),
+/// }
+///
+/// let (resize_tx, resize_rx) = ipc::channel().unwrap();
+/// let (title_tx, title_rx) = ipc::channel().unwrap();
+/// let mut set = TypedReceiverSet::new().unwrap();
+/// set.add(resize_rx, |(width, height)| Event::Resize(width, height)).unwrap();
+/// let title_id = set.add(title_rx, Event::Title).unwrap();
+///
+/// title_tx.send("Untitled".to_owned()).unwrap();
+/// match set.select().unwrap().remove(0) {
+///     TypedSelectionResult::MessageReceived(id, event) => {
+///         assert_eq!((id, event), (title_id, Event::Title("Untitled".to_owned())));
+///     }
+///     _ => panic!("unexpected event"),
+/// }
+/// # drop(resize_tx);
+/// ```
+///
+/// [IpcReceiverSet]: struct.IpcReceiverSet.html
+/// [BincodeConfig]: struct.BincodeConfig.html
+pub struct TypedReceiverSet<E> {
+    receiver_set: IpcReceiverSet,
+    converters: HashMap<u64, MessageConverter<E>>,
+}
+
+impl<E> TypedReceiverSet<E> {
+    /// Create an empty set.
+    pub fn new() -> Result<TypedReceiverSet<E>, Error> {
+        Ok(TypedReceiverSet {
+            receiver_set: IpcReceiverSet::new()?,
+            converters: HashMap::new(),
+        })
+    }
+
+    /// Add `receiver` to the set, its messages being handed out as `convert` makes them.
+    pub fn add<T, F>(&mut self, receiver: IpcReceiver<T>, convert: F) -> Result<u64, Error>
+                     where T: for<'de> Deserialize<'de> + Serialize,
+                           F: Fn(T) -> E + Send + 'static {
+        let bincode_config = receiver.bincode_config;
+        let id = self.receiver_set.add(receiver)?;
+        self.converters.insert(id, Box::new(move |message: OpaqueIpcMessage| {
+            let (value, _) = message.deserialize_with_config(bincode_config)?;
+            Ok(convert(value))
+        }));
+        Ok(id)
+    }
+
+    /// Like [IpcReceiverSet::add_with_priority], converting messages as with [add].
+    ///
+    /// [IpcReceiverSet::add_with_priority]: struct.IpcReceiverSet.html#method.add_with_priority
+    /// [add]: #method.add
+    pub fn add_with_priority<T, F>(&mut self,
+                                   receiver: IpcReceiver<T>,
+                                   priority: i32,
+                                   convert: F)
+                                   -> Result<u64, Error>
+                                   where T: for<'de> Deserialize<'de> + Serialize,
+                                         F: Fn(T) -> E + Send + 'static {
+        let id = self.add(receiver, convert)?;
+        if priority != 0 {
+            self.receiver_set.priorities.insert(id, priority);
+        }
+        Ok(id)
+    }
+
+    /// Why the receiver `id` was reported closed, as with
+    /// [IpcReceiverSet::take_peer_death].
+    ///
+    /// [IpcReceiverSet::take_peer_death]: struct.IpcReceiverSet.html#method.take_peer_death
+    pub fn take_peer_death(&mut self, id: u64) -> Option<PeerDied> {
+        self.receiver_set.take_peer_death(id)
+    }
+
+    /// Wait for events on any of the receivers, as with [IpcReceiverSet::select].
+    ///
+    /// [IpcReceiverSet::select]: struct.IpcReceiverSet.html#method.select
+    pub fn select(&mut self) -> Result<Vec<TypedSelectionResult<E>>, Error> {
+        let results = self.receiver_set.select()?;
+        Ok(self.convert(results))
+    }
+
+    /// Like [select], returning no events rather than waiting if there are none.
+    ///
+    /// [select]: #method.select
+    pub fn try_select(&mut self) -> Result<Vec<TypedSelectionResult<E>>, Error> {
+        let results = self.receiver_set.try_select()?;
+        Ok(self.convert(results))
+    }
+
+    /// Like [select], returning no events if none arrives within `duration`.
+    ///
+    /// [select]: #method.select
+    pub fn select_timeout(&mut self, duration: Duration)
+                          -> Result<Vec<TypedSelectionResult<E>>, Error> {
+        let results = self.receiver_set.select_timeout(duration)?;
+        Ok(self.convert(results))
+    }
+
+    fn convert(&mut self, results: Vec<IpcSelectionResult>) -> Vec<TypedSelectionResult<E>> {
+        results.into_iter().map(|result| match result {
+            IpcSelectionResult::MessageReceived(id, message) => {
+                match self.converters[&id](message) {
+                    Ok(event) => TypedSelectionResult::MessageReceived(id, event),
+                    Err(error) => TypedSelectionResult::DeserializationFailed(id, error),
                 }
             }
-        }).collect())
+            IpcSelectionResult::ChannelClosed(id) => {
+                self.converters.remove(&id);
+                TypedSelectionResult::ChannelClosed(id)
+            }
+        }).collect()
+    }
+}
+
+impl<E> Debug for TypedReceiverSet<E> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("TypedReceiverSet")
+                 .field("receivers", &self.converters.len())
+                 .finish()
+    }
+}
+
+/// An event of a [TypedReceiverSet], like an [IpcSelectionResult] with the message
+/// converted.
+///
+/// [TypedReceiverSet]: struct.TypedReceiverSet.html
+/// [IpcSelectionResult]: enum.IpcSelectionResult.html
+#[derive(Debug)]
+pub enum TypedSelectionResult<E> {
+    /// A message received on the receiver identified by the `u64` value, converted.
+    MessageReceived(u64, E),
+    /// A message received on the receiver identified by the `u64` value couldn't be
+    /// deserialized as the receiver's type. The receiver stays in the set.
+    DeserializationFailed(u64, bincode::Error),
+    /// The channel of the receiver identified by the `u64` value has been closed.
+    ChannelClosed(u64),
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl AsRawFd for IpcReceiverSet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.os_receiver_set.as_raw_fd()
     }
 }
 
 /// Shared memory descriptor that will be made accessible to the receiver
 /// of an IPC message that contains the discriptor.
 ///
+/// # Sharing between processes
+///
+/// On the Unix socket and in-process backends, a received region maps the very
+/// memory that was sent, so writes made after sending are seen on both ends. macOS
+/// sends regions as copy-on-write copies instead: a received region starts out with
+/// the sender's contents, but doesn't share later writes. The [ring], [watch],
+/// [state] and [double_buffer] channels, which rely on such writes, are therefore
+/// unavailable there.
+///
+/// [ring]: ../ring/index.html
+/// [watch]: ../watch/index.html
+/// [state]: ../state/index.html
+/// [double_buffer]: ../double_buffer/index.html
+///
 /// # Examples
 /// ```
 /// # use ipc_channel::ipc::{self, IpcSharedMemory};
@@ -495,11 +2760,206 @@ impl IpcReceiverSet {
 /// # let rx_shmem = rx.recv().unwrap();
 /// # assert_eq!(shmem, rx_shmem);
 /// ```
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone)]
 pub struct IpcSharedMemory {
     os_shared_memory: OsIpcSharedMemory,
+    /// For sensitive regions, wipes the region when the last clone in this process is dropped.
+    wipe: Option<Arc<WipeOnDrop>>,
+    /// The region's share of the [shared memory quota], released with the last clone.
+    ///
+    /// [shared memory quota]: fn.set_shared_memory_quota.html
+    _charge: Option<Arc<QuotaCharge>>,
+}
+
+impl Debug for IpcSharedMemory {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        let mut debug = formatter.debug_struct("IpcSharedMemory");
+        match self.wipe {
+            Some(_) => debug.field("os_shared_memory", &format_args!("<sensitive>")),
+            None => debug.field("os_shared_memory", &self.os_shared_memory),
+        }.finish()
+    }
+}
+
+impl PartialEq for IpcSharedMemory {
+    fn eq(&self, other: &IpcSharedMemory) -> bool {
+        self.os_shared_memory == other.os_shared_memory
+    }
+}
+
+/// A mapping of a sensitive region, wiping it when dropped.
+struct WipeOnDrop(OsIpcSharedMemory);
+
+impl Drop for WipeOnDrop {
+    fn drop(&mut self) {
+        self.0.wipe();
+    }
+}
+
+// Bytes of shared memory currently accounted to this process.
+static SHARED_MEMORY_IN_USE: AtomicUsize = AtomicUsize::new(0);
+// The limit on `SHARED_MEMORY_IN_USE`, `usize::MAX` meaning none.
+static SHARED_MEMORY_QUOTA: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Limit the total size of the shared memory regions alive in this process, or
+/// lift the limit with `None`, the default.
+///
+/// Regions count from when they are created or received until the last clone
+/// of the handle is dropped. Once the quota would be exceeded,
+/// [IpcSharedMemory::try_from_bytes] and [IpcSharedMemory::try_from_byte] fail,
+/// and so does receiving a message with shared memory attached, with an error
+/// wrapping [ShmQuotaExceeded]; the regions are unmapped rather than handed over,
+/// so a misbehaving peer can't exhaust the address space with attachments. On Linux
+/// and the BSDs, the regions of a message are checked against the quota before they
+/// are even mapped, and [IpcReceiverSet]s drop the messages refused.
+/// The infallible constructors and [IpcReceiver::recv_bulk] are counted, but
+/// never refused. Lowering the quota below the amount in use refuses new
+/// regions until enough have been dropped.
+///
+/// [IpcSharedMemory::try_from_bytes]: struct.IpcSharedMemory.html#method.try_from_bytes
+/// [IpcSharedMemory::try_from_byte]: struct.IpcSharedMemory.html#method.try_from_byte
+/// [ShmQuotaExceeded]: struct.ShmQuotaExceeded.html
+/// [IpcReceiver::recv_bulk]: struct.IpcReceiver.html#method.recv_bulk
+/// [IpcReceiverSet]: struct.IpcReceiverSet.html
+pub fn set_shared_memory_quota(quota: Option<usize>) {
+    SHARED_MEMORY_QUOTA.store(quota.unwrap_or(usize::MAX), Ordering::SeqCst);
+}
+
+/// The total size in bytes of the shared memory regions alive in this process.
+pub fn shared_memory_in_use() -> usize {
+    SHARED_MEMORY_IN_USE.load(Ordering::SeqCst)
+}
+
+/// Bytes accounted to the shared memory in use, released when dropped.
+#[derive(Debug)]
+struct QuotaCharge(usize);
+
+impl Drop for QuotaCharge {
+    fn drop(&mut self) {
+        SHARED_MEMORY_IN_USE.fetch_sub(self.0, Ordering::SeqCst);
+        events::emit(|| IpcEvent::SharedMemoryUnmapped { length: self.0 });
+    }
+}
+
+/// Fail if regions `length` bytes long in all would exceed the quota, without
+/// charging them, so received regions can be refused before they are mapped.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub(crate) fn check_shared_memory_quota(length: usize) -> Result<(), ShmQuotaExceeded> {
+    let quota = SHARED_MEMORY_QUOTA.load(Ordering::SeqCst);
+    let in_use = SHARED_MEMORY_IN_USE.load(Ordering::SeqCst);
+    if in_use.saturating_add(length) > quota {
+        return Err(ShmQuotaExceeded {
+            requested: length,
+            in_use,
+            quota,
+        })
+    }
+    Ok(())
+}
+
+fn charge_shared_memory(length: usize, enforce: bool)
+                        -> Result<Arc<QuotaCharge>, ShmQuotaExceeded> {
+    if !enforce {
+        SHARED_MEMORY_IN_USE.fetch_add(length, Ordering::SeqCst);
+        events::emit(|| IpcEvent::SharedMemoryMapped { length });
+        return Ok(Arc::new(QuotaCharge(length)))
+    }
+    let quota = SHARED_MEMORY_QUOTA.load(Ordering::SeqCst);
+    let mut in_use = SHARED_MEMORY_IN_USE.load(Ordering::SeqCst);
+    loop {
+        if in_use.saturating_add(length) > quota {
+            return Err(ShmQuotaExceeded {
+                requested: length,
+                in_use,
+                quota,
+            })
+        }
+        match SHARED_MEMORY_IN_USE.compare_exchange_weak(in_use, in_use + length,
+                                                         Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {
+                events::emit(|| IpcEvent::SharedMemoryMapped { length });
+                return Ok(Arc::new(QuotaCharge(length)))
+            }
+            Err(actual) => in_use = actual,
+        }
+    }
+}
+
+/// The error of a region refused in constrained mode, as no reserved shared memory
+/// object is left.
+fn no_reserved_shared_memory(length: usize) -> ShmQuotaExceeded {
+    let in_use = shared_memory_in_use();
+    ShmQuotaExceeded {
+        requested: length,
+        in_use,
+        quota: in_use,
+    }
+}
+
+/// A shared memory region was refused, as it would exceed the
+/// [shared memory quota].
+///
+/// In [constrained mode], creating a region also fails with this error once no
+/// reserved shared memory object is left, with the `quota` set to the bytes in use.
+///
+/// Receive calls return it wrapped in an `ErrorKind::Io` error of kind
+/// `OutOfMemory`; use [from_error] to extract it.
+///
+/// [shared memory quota]: fn.set_shared_memory_quota.html
+/// [constrained mode]: fn.enter_constrained_mode.html
+/// [from_error]: #method.from_error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShmQuotaExceeded {
+    /// Size of the refused region, or of all the regions of a refused message.
+    pub requested: usize,
+    /// Bytes in use when the region was refused.
+    pub in_use: usize,
+    /// The quota in force then, as set with [set_shared_memory_quota].
+    ///
+    /// [set_shared_memory_quota]: fn.set_shared_memory_quota.html
+    pub quota: usize,
+}
+
+impl ShmQuotaExceeded {
+    /// Extract the `ShmQuotaExceeded` from an error returned by a receive call, if any.
+    pub fn from_error(error: &bincode::Error) -> Option<&ShmQuotaExceeded> {
+        match **error {
+            bincode::ErrorKind::Io(ref error) => {
+                error.get_ref().and_then(|error| error.downcast_ref::<ShmQuotaExceeded>())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ShmQuotaExceeded {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "shared memory quota exceeded: {} bytes requested, {} of {} in use",
+               self.requested, self.in_use, self.quota)
+    }
+}
+
+impl StdError for ShmQuotaExceeded {}
+
+impl From<ShmQuotaExceeded> for Error {
+    fn from(quota_error: ShmQuotaExceeded) -> Self {
+        Error::new(io::ErrorKind::OutOfMemory, quota_error)
+    }
+}
+
+impl From<ShmQuotaExceeded> for bincode::Error {
+    fn from(quota_error: ShmQuotaExceeded) -> Self {
+        Error::from(quota_error).into()
+    }
 }
 
+// Set in the serialized index of sensitive regions, so receivers wipe them too.
+const SENSITIVE_INDEX_FLAG: u64 = 1 << 63;
+
 impl Deref for IpcSharedMemory {
     type Target = [u8];
 
@@ -511,17 +2971,34 @@ impl Deref for IpcSharedMemory {
 
 impl<'de> Deserialize<'de> for IpcSharedMemory {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        let index: usize = Deserialize::deserialize(deserializer)?;
+        let index: u64 = Deserialize::deserialize(deserializer)?;
         let os_shared_memory = OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
             |os_ipc_shared_memory_regions_for_deserialization| {
-                // FIXME(pcwalton): This could panic if the data was corrupt and the index was out
-                // of bounds. We should return an `Err` result instead.
-                mem::replace(
-                    &mut os_ipc_shared_memory_regions_for_deserialization.borrow_mut()[index],
-                    None).unwrap()
-            });
+                // Corrupt data may refer to a region the message doesn't have, or one
+                // already handed to another value.
+                os_ipc_shared_memory_regions_for_deserialization
+                    .borrow_mut()
+                    .get_mut((index & !SENSITIVE_INDEX_FLAG) as usize)
+                    .and_then(Option::take)
+                    .ok_or_else(|| {
+                        de::Error::custom(format!("no shared memory region at index {}",
+                                                  index & !SENSITIVE_INDEX_FLAG))
+                    })
+            })?;
+        let charge = match charge_shared_memory(os_shared_memory.len(), true) {
+            Ok(charge) => charge,
+            Err(quota_error) => {
+                SHARED_MEMORY_QUOTA_ERROR.with(|error| error.set(Some(quota_error)));
+                return Err(de::Error::custom(quota_error))
+            }
+        };
+        if index & SENSITIVE_INDEX_FLAG != 0 {
+            return Ok(IpcSharedMemory::sensitive(os_shared_memory, charge))
+        }
         Ok(IpcSharedMemory {
             os_shared_memory: os_shared_memory,
+            wipe: None,
+            _charge: Some(charge),
         })
     }
 }
@@ -532,124 +3009,1116 @@ impl Serialize for IpcSharedMemory {
             |os_ipc_shared_memory_regions_for_serialization| {
                 let mut os_ipc_shared_memory_regions_for_serialization =
                     os_ipc_shared_memory_regions_for_serialization.borrow_mut();
-                let index = os_ipc_shared_memory_regions_for_serialization.len();
-                os_ipc_shared_memory_regions_for_serialization.push(self.os_shared_memory
-                                                                        .clone());
-                index
+                let index = os_ipc_shared_memory_regions_for_serialization.len() as u64;
+                match self.wipe {
+                    // Send a copy, so the receiver doesn't see it wiped along with ours.
+                    Some(_) => {
+                        let copy = OsIpcSharedMemory::from_bytes(self);
+                        SENSITIVE_COPIES_FOR_SERIALIZATION.with(|sensitive_copies| {
+                            sensitive_copies.borrow_mut().push(copy.clone())
+                        });
+                        os_ipc_shared_memory_regions_for_serialization.push(copy);
+                        index | SENSITIVE_INDEX_FLAG
+                    }
+                    None => {
+                        os_ipc_shared_memory_regions_for_serialization.push(self.os_shared_memory
+                                                                                .clone());
+                        index
+                    }
+                }
             });
         index.serialize(serializer)
     }
-}
+}
+
+impl IpcSharedMemory {
+    /// Create shared memory initialized with the bytes provided.
+    pub fn from_bytes(bytes: &[u8]) -> IpcSharedMemory {
+        IpcSharedMemory {
+            os_shared_memory: OsIpcSharedMemory::from_bytes(bytes),
+            wipe: None,
+            _charge: charge_shared_memory(bytes.len(), false).ok(),
+        }
+    }
+
+    /// Create a chunk of shared memory that is filled with the byte
+    /// provided.
+    pub fn from_byte(byte: u8, length: usize) -> IpcSharedMemory {
+        IpcSharedMemory {
+            os_shared_memory: OsIpcSharedMemory::from_byte(byte, length),
+            wipe: None,
+            _charge: charge_shared_memory(length, false).ok(),
+        }
+    }
+
+    /// Create a zeroed chunk of shared memory, whose pages are only allocated
+    /// once first written to, by whichever process maps it.
+    ///
+    /// Unlike with [from_byte], which writes to every page, the OS places each page
+    /// on the NUMA node of the first thread to touch it, so a consumer on another
+    /// NUMA node than the producer can touch the pages it works on first. See also
+    /// [with_numa_node].
+    ///
+    /// [from_byte]: #method.from_byte
+    /// [with_numa_node]: #method.with_numa_node
+    pub fn zeroed(length: usize) -> IpcSharedMemory {
+        IpcSharedMemory {
+            os_shared_memory: OsIpcSharedMemory::zeroed(length),
+            wipe: None,
+            _charge: charge_shared_memory(length, false).ok(),
+        }
+    }
+
+    /// Like [from_bytes], but fails rather than exceed the [shared memory quota],
+    /// or, in [constrained mode], once no reserved shared memory object is left.
+    ///
+    /// [from_bytes]: #method.from_bytes
+    /// [shared memory quota]: fn.set_shared_memory_quota.html
+    /// [constrained mode]: fn.enter_constrained_mode.html
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<IpcSharedMemory, ShmQuotaExceeded> {
+        let charge = charge_shared_memory(bytes.len(), true)?;
+        let os_shared_memory = match OsIpcSharedMemory::try_from_bytes(bytes) {
+            Ok(os_shared_memory) => os_shared_memory,
+            Err(_) => {
+                drop(charge);
+                return Err(no_reserved_shared_memory(bytes.len()))
+            }
+        };
+        Ok(IpcSharedMemory {
+            os_shared_memory,
+            wipe: None,
+            _charge: Some(charge),
+        })
+    }
+
+    /// Like [from_byte], but fails rather than exceed the [shared memory quota],
+    /// or, in [constrained mode], once no reserved shared memory object is left.
+    ///
+    /// [from_byte]: #method.from_byte
+    /// [shared memory quota]: fn.set_shared_memory_quota.html
+    /// [constrained mode]: fn.enter_constrained_mode.html
+    pub fn try_from_byte(byte: u8, length: usize) -> Result<IpcSharedMemory, ShmQuotaExceeded> {
+        let charge = charge_shared_memory(length, true)?;
+        let os_shared_memory = match OsIpcSharedMemory::try_from_byte(byte, length) {
+            Ok(os_shared_memory) => os_shared_memory,
+            Err(_) => {
+                drop(charge);
+                return Err(no_reserved_shared_memory(length))
+            }
+        };
+        Ok(IpcSharedMemory {
+            os_shared_memory,
+            wipe: None,
+            _charge: Some(charge),
+        })
+    }
+
+    /// Create shared memory for secrets such as key material, initialized with
+    /// the bytes provided.
+    ///
+    /// The region is overwritten with zeros before being unmapped, once this
+    /// handle and all its clones are dropped. Sending the region transfers a copy,
+    /// which the receiving process wipes the same way, so neither side sees the
+    /// other's copy wiped; the copy is wiped right away if the message isn't sent.
+    /// Regions sent with [IpcSender::send_raw] are received as ordinary shared
+    /// memory. Debug output doesn't show the contents.
+    ///
+    /// The caller should wipe `bytes` itself.
+    ///
+    /// [IpcSender::send_raw]: struct.IpcSender.html#method.send_raw
+    pub fn new_sensitive(bytes: &[u8]) -> IpcSharedMemory {
+        let charge = charge_shared_memory(bytes.len(), false).unwrap();
+        IpcSharedMemory::sensitive(OsIpcSharedMemory::from_bytes(bytes), charge)
+    }
+
+    /// Whether the region was created with [new_sensitive], or received as such.
+    ///
+    /// [new_sensitive]: #method.new_sensitive
+    pub fn is_sensitive(&self) -> bool {
+        self.wipe.is_some()
+    }
+
+    /// Lock the region into RAM, so it is never paged out to disk, e.g. for secrets
+    /// or latency-critical buffers.
+    ///
+    /// The lock applies to the pages of this handle's mapping, and lasts until it is
+    /// dropped; receivers of the region must lock their mapping themselves.
+    /// Fails with `ErrorKind::OutOfMemory` if this would exceed the process'
+    /// `RLIMIT_MEMLOCK`, and with `ErrorKind::Unsupported` on the in-process backend.
+    pub fn lock(&self) -> Result<(), Error> {
+        self.os_shared_memory.lock()
+    }
+
+    /// Prefer NUMA node `node` for the pages of the region, moving those already
+    /// allocated on other nodes there, e.g. to the node the consumer of the region
+    /// is pinned to. The preference is the region's, so pages first touched by any
+    /// process mapping it are allocated there too, while the node has memory left.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if there's no such node, and with
+    /// `ErrorKind::Unsupported` on platforms other than Linux, and with the
+    /// in-process backend.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ipc_channel::ipc::IpcSharedMemory;
+    /// let table = IpcSharedMemory::zeroed(64 << 20).with_numa_node(1).unwrap();
+    /// ```
+    pub fn with_numa_node(self, node: usize) -> Result<IpcSharedMemory, Error> {
+        self.os_shared_memory.set_numa_node(node)?;
+        Ok(self)
+    }
+
+    /// The number of pages of the region allocated on each NUMA node, indexed by
+    /// node, for diagnostics. Pages not touched yet aren't counted.
+    ///
+    /// Fails as [with_numa_node] does where NUMA placement isn't supported.
+    ///
+    /// [with_numa_node]: #method.with_numa_node
+    pub fn numa_nodes(&self) -> Result<Vec<usize>, Error> {
+        self.os_shared_memory.numa_nodes()
+    }
+
+    /// A new region the contents of this one are copied into, there and then, which
+    /// can be sent elsewhere while this region keeps being updated, e.g. through
+    /// [atomic_u32s]. Copies of sensitive regions are sensitive too.
+    ///
+    /// This is a plain copy, with nothing copy-on-write about it: the whole region
+    /// is copied up front, byte by byte, without any synchronization. A copy taken
+    /// while other processes write to the region may catch some of their writes but
+    /// not others, even within a value; synchronize with them, e.g. with an
+    /// [IpcMutex], for a consistent view.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::IpcSharedMemory;
+    /// # use std::sync::atomic::Ordering;
+    /// let table = IpcSharedMemory::from_byte(0, 64);
+    /// table.atomic_u32s(0, 1).unwrap()[0].store(1, Ordering::SeqCst);
+    /// let copy = table.racy_copy();
+    /// table.atomic_u32s(0, 1).unwrap()[0].store(2, Ordering::SeqCst);
+    /// assert_eq!(copy.atomic_u32s(0, 1).unwrap()[0].load(Ordering::SeqCst), 1);
+    /// ```
+    ///
+    /// [atomic_u32s]: #method.atomic_u32s
+    /// [IpcMutex]: ../sync/struct.IpcMutex.html
+    pub fn racy_copy(&self) -> IpcSharedMemory {
+        let charge = charge_shared_memory(self.len(), false).unwrap();
+        let os_shared_memory = OsIpcSharedMemory::from_bytes(self);
+        if self.is_sensitive() {
+            return IpcSharedMemory::sensitive(os_shared_memory, charge)
+        }
+        IpcSharedMemory {
+            os_shared_memory,
+            wipe: None,
+            _charge: Some(charge),
+        }
+    }
+
+    /// Send a copy of the region over `sender`, which can be taken back from every
+    /// process that received it with the returned handle, e.g. to cut a misbehaving
+    /// peer off from a sensitive buffer.
+    ///
+    /// The copy is a region of its own, shared through the handle until revoked, so
+    /// later writes to this region don't reach the receivers. Sensitive regions are
+    /// sent as ordinary ones, revoking taking the place of wiping.
+    ///
+    /// Revoking only works where the receivers map the very region that was sent, as
+    /// with the Unix socket and in-process backends. On macOS, where each receiver
+    /// gets a copy of its own, this fails with `ErrorKind::Unsupported` without
+    /// sending anything.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{self, IpcSharedMemory};
+    /// let (tx, rx) = ipc::channel().unwrap();
+    /// let secret = IpcSharedMemory::from_bytes(b"hunter2");
+    /// let mut grant = secret.send_revocable(&tx).unwrap();
+    /// let received = rx.recv().unwrap();
+    /// assert_eq!(&received[..], b"hunter2");
+    /// grant.revoke().unwrap();
+    /// assert_eq!(&received[..], &[0; 7]);
+    /// ```
+    pub fn send_revocable(&self, sender: &IpcSender<IpcSharedMemory>)
+                          -> Result<RevocationHandle, bincode::Error> {
+        OsIpcSharedMemory::check_revocable()?;
+        let grant = IpcSharedMemory {
+            os_shared_memory: OsIpcSharedMemory::from_bytes(self),
+            wipe: None,
+            _charge: charge_shared_memory(self.len(), false).ok(),
+        };
+        sender.send(grant.clone())?;
+        Ok(RevocationHandle {
+            grant: Some(grant),
+        })
+    }
+
+    fn sensitive(os_shared_memory: OsIpcSharedMemory, charge: Arc<QuotaCharge>)
+                 -> IpcSharedMemory {
+        IpcSharedMemory {
+            wipe: Some(Arc::new(WipeOnDrop(os_shared_memory.clone()))),
+            os_shared_memory,
+            _charge: Some(charge),
+        }
+    }
+
+    /// View `count` consecutive `AtomicU32` values starting at byte `offset`
+    /// of the region.
+    ///
+    /// Clones of the region observe the same values, and so do the mappings of
+    /// it received by other processes where those [share the region], so these
+    /// can be used for cross-process counters and flags there.
+    ///
+    /// Returns `None` if the range does not fit in the region, or if `offset`
+    /// is not suitably aligned for `AtomicU32`.
+    ///
+    /// [share the region]: #sharing-between-processes
+    pub fn atomic_u32s(&self, offset: usize, count: usize) -> Option<&[AtomicU32]> {
+        unsafe { self.atomics(offset, count) }
+    }
+
+    /// View `count` consecutive `AtomicU64` values starting at byte `offset`
+    /// of the region.
+    ///
+    /// See [atomic_u32s] for details.
+    ///
+    /// [atomic_u32s]: #method.atomic_u32s
+    pub fn atomic_u64s(&self, offset: usize, count: usize) -> Option<&[AtomicU64]> {
+        unsafe { self.atomics(offset, count) }
+    }
+
+    /// Reinterpret part of the region as a slice of `A`.
+    ///
+    /// Only sound for atomic integer types: they have the same in-memory
+    /// representation as the underlying integers, and all mutation goes
+    /// through atomic operations.
+    unsafe fn atomics<A>(&self, offset: usize, count: usize) -> Option<&[A]> {
+        if count == 0 {
+            return Some(&[])
+        }
+        let size = count.checked_mul(mem::size_of::<A>())?;
+        let end = offset.checked_add(size)?;
+        if end > self.len() {
+            return None
+        }
+        let start = self.as_ptr().add(offset);
+        if start as usize & (mem::align_of::<A>() - 1) != 0 {
+            return None
+        }
+        Some(slice::from_raw_parts(start as *const A, count))
+    }
+}
+
+/// A region sent with [IpcSharedMemory::send_revocable], which can be revoked
+/// from every process that received it.
+///
+/// Revoking releases the pages of the region, so that every mapping of it reads
+/// zeros from then on, rather than faulting: receivers keep a valid mapping, but
+/// of nothing. Linux punches a hole in the backing file; the other backends that
+/// support revoking overwrite the contents in place. Dropping the handle leaves
+/// the grant as it is.
+///
+/// [IpcSharedMemory::send_revocable]: struct.IpcSharedMemory.html#method.send_revocable
+#[derive(Debug)]
+pub struct RevocationHandle {
+    grant: Option<IpcSharedMemory>,
+}
+
+impl RevocationHandle {
+    /// The region the receivers map, while not revoked, through which the
+    /// contents can still be updated, e.g. with [IpcSharedMemory::atomic_u32s].
+    ///
+    /// [IpcSharedMemory::atomic_u32s]: struct.IpcSharedMemory.html#method.atomic_u32s
+    pub fn region(&self) -> Option<&IpcSharedMemory> {
+        self.grant.as_ref()
+    }
+
+    /// Whether the grant was revoked.
+    pub fn is_revoked(&self) -> bool {
+        self.grant.is_none()
+    }
+
+    /// Take the region back from every process it was sent to. Revoking again
+    /// does nothing.
+    pub fn revoke(&mut self) -> Result<(), Error> {
+        if let Some(ref grant) = self.grant {
+            grant.os_shared_memory.revoke()?;
+        }
+        self.grant = None;
+        Ok(())
+    }
+}
+
+impl Drop for IpcReceiverSet {
+    fn drop(&mut self) {
+        registry::lock().receiver_sets -= 1;
+    }
+}
+
+/// Result for readable events returned from [IpcReceiverSet::select].
+///
+/// [IpcReceiverSet::select]: struct.IpcReceiverSet.html#method.select
+pub enum IpcSelectionResult {
+    /// A message received from the [IpcReceiver] in the [opaque] form,
+    /// identified by the `u64` value.
+    ///
+    /// [IpcReceiver]: struct.IpcReceiver.html
+    /// [opaque]: struct.OpaqueIpcMessage.html
+    MessageReceived(u64, OpaqueIpcMessage),
+    /// The channel has been closed for the [IpcReceiver] identified by the `u64` value.
+    /// This includes receivers whose [watched peer] exited, as told by
+    /// [IpcReceiverSet::take_peer_death].
+    ///
+    /// [IpcReceiver]: struct.IpcReceiver.html
+    /// [watched peer]: struct.IpcReceiver.html#method.watch_peer
+    /// [IpcReceiverSet::take_peer_death]: struct.IpcReceiverSet.html#method.take_peer_death
+    ChannelClosed(u64),
+}
+
+impl IpcSelectionResult {
+    fn receiver_id(&self) -> u64 {
+        match *self {
+            IpcSelectionResult::MessageReceived(id, _) |
+            IpcSelectionResult::ChannelClosed(id) => id,
+        }
+    }
+
+    /// The events for `result`: a [transaction] is received as one event per message.
+    ///
+    /// [transaction]: struct.IpcSender.html#method.transaction
+    fn from_os(result: OsIpcSelectionResult,
+               with_metadata: &HashSet<u64>,
+               hmac_keys: &HashMap<u64, Arc<HmacKey>>)
+               -> Vec<IpcSelectionResult> {
+        let result = match result {
+            OsIpcSelectionResult::DataReceived(os_receiver_id,
+                                               mut data,
+                                               os_ipc_channels,
+                                               os_ipc_shared_memory_regions) => {
+                if let Some(key) = hmac_keys.get(&os_receiver_id) {
+                    let region_lengths = os_ipc_shared_memory_regions.iter().map(|region| {
+                        region.len()
+                    });
+                    // There is no error to report a forged message with among the
+                    // events, so it is dropped, along with its attachments.
+                    if key.open(&mut data, os_ipc_channels.len(), region_lengths).is_err() {
+                        return vec![]
+                    }
+                }
+                let message = OpaqueIpcMessage::new(data,
+                                                    os_ipc_channels,
+                                                    os_ipc_shared_memory_regions,
+                                                    with_metadata.contains(&os_receiver_id));
+                // A malformed transaction is handed out as is, failing to deserialize.
+                let messages = match message.transaction_parts() {
+                    Ok(Some(parts)) => message.split_into(parts),
+                    _ => vec![message],
+                };
+                return messages.into_iter().map(|message| {
+                    record_received(&message);
+                    IpcSelectionResult::MessageReceived(os_receiver_id, message)
+                }).collect()
+            }
+            OsIpcSelectionResult::ChannelClosed(os_receiver_id) => {
+                events::emit(|| IpcEvent::ChannelClosed);
+                IpcSelectionResult::ChannelClosed(os_receiver_id)
+            }
+            #[cfg(not(any(feature = "force-inprocess", target_os = "windows",
+                          target_os = "android", target_os = "ios")))]
+            OsIpcSelectionResult::PeerDied(..) => unreachable!("peer deaths are recorded first"),
+        };
+        vec![result]
+    }
+
+    /// Helper method to move the value out of the [IpcSelectionResult] if it
+    /// is [MessageReceived].
+    ///
+    /// # Panics
+    ///
+    /// If the result is [ChannelClosed] this call will panic.
+    ///
+    /// [IpcSelectionResult]: enum.IpcSelectionResult.html
+    /// [MessageReceived]: enum.IpcSelectionResult.html#variant.MessageReceived
+    /// [ChannelClosed]: enum.IpcSelectionResult.html#variant.ChannelClosed
+    pub fn unwrap(self) -> (u64, OpaqueIpcMessage) {
+        match self {
+            IpcSelectionResult::MessageReceived(id, message) => (id, message),
+            IpcSelectionResult::ChannelClosed(id) => {
+                panic!("IpcSelectionResult::unwrap(): channel {} closed", id)
+            }
+        }
+    }
+}
+
+/// Structure used to represent a raw message from an [IpcSender].
+///
+/// Use the [to] method to deserialize the raw result into the requested type.
+///
+/// [IpcSender]: struct.IpcSender.html
+/// [to]: #method.to
+pub struct OpaqueIpcMessage {
+    data: Vec<u8>,
+    os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+    os_ipc_shared_memory_regions: Vec<Option<OsIpcSharedMemory>>,
+    /// Whether `data` starts with a metadata header.
+    metadata: bool,
+}
+
+impl Debug for OpaqueIpcMessage {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match String::from_utf8(self.data.clone()) {
+            Ok(string) => string.chars().take(256).collect::<String>().fmt(formatter),
+            Err(..) => self.data[0..min(self.data.len(), 256)].fmt(formatter),
+        }
+    }
+}
+
+impl OpaqueIpcMessage {
+    fn new(data: Vec<u8>,
+           os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+           os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>,
+           metadata: bool)
+           -> OpaqueIpcMessage {
+        OpaqueIpcMessage {
+            data: data,
+            os_ipc_channels: os_ipc_channels,
+            os_ipc_shared_memory_regions:
+                os_ipc_shared_memory_regions.into_iter()
+                                            .map(|os_ipc_shared_memory_region| {
+                    Some(os_ipc_shared_memory_region)
+                }).collect(),
+            metadata,
+        }
+    }
+
+    /// A message made of `data` alone, without channels or shared memory regions,
+    /// starting with a metadata header if `metadata` is set.
+    pub(crate) fn from_plain_data(data: Vec<u8>, metadata: bool) -> OpaqueIpcMessage {
+        OpaqueIpcMessage::new(data, vec![], vec![], metadata)
+    }
+
+    /// Whether the data starts with a metadata header.
+    pub(crate) fn has_metadata(&self) -> bool {
+        self.metadata
+    }
+
+    /// The data of the message, if it has no channels or shared memory regions:
+    /// then, `from_plain_data()` makes the same message from a copy.
+    pub(crate) fn plain_data(&self) -> Option<&[u8]> {
+        if self.os_ipc_channels.is_empty() && self.os_ipc_shared_memory_regions.is_empty() {
+            Some(&self.data)
+        } else {
+            None
+        }
+    }
+
+    /// Metadata the message was sent with; for a message received on a channel
+    /// without the [metadata header], a sender ID and sequence number of 0.
+    ///
+    /// [metadata header]: struct.BincodeConfig.html#method.metadata_header
+    pub fn metadata(&self) -> Result<IpcMessageMetadata, bincode::Error> {
+        Ok(self.header()?.0)
+    }
+
+    /// The serialized payload, following the metadata.
+    pub(crate) fn payload(&self) -> Result<&[u8], bincode::Error> {
+        Ok(self.header()?.2)
+    }
+
+    /// The metadata and flags of the message, and the payload following them.
+    fn header(&self) -> Result<(IpcMessageMetadata, u64, &[u8]), bincode::Error> {
+        let mut reader = &self.data[..];
+        if !self.metadata {
+            return Ok((IpcMessageMetadata::default(), 0, reader))
+        }
+        let (metadata, flags) = IpcMessageMetadata::read_with_flags(&mut reader)?;
+        Ok((metadata, flags, reader))
+    }
+
+    /// The messages a [transaction] was made of, in order, each with its own metadata;
+    /// any other message is returned as is.
+    ///
+    /// Messages handed out by an [IpcReceiverSet] or the [ROUTER] are already split.
+    ///
+    /// [transaction]: struct.IpcSender.html#method.transaction
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    /// [ROUTER]: ../router/struct.ROUTER.html
+    pub fn split(self) -> Result<Vec<OpaqueIpcMessage>, bincode::Error> {
+        match self.transaction_parts()? {
+            Some(parts) => Ok(self.split_into(parts)),
+            None => Ok(vec![self]),
+        }
+    }
+
+    /// The header and parts of a transaction, checking they match the channels and
+    /// shared memory regions of the message; none if it isn't a transaction.
+    fn transaction_parts(&self) -> Result<Option<TransactionParts>, bincode::Error> {
+        let (metadata, flags, reader) = self.header()?;
+        if flags & TRANSACTION_FLAG == 0 {
+            return Ok(None)
+        }
+        let parts: Vec<TransactionPart> = bincode::deserialize(reader)?;
+        let channel_count: u64 = parts.iter().map(|&(_, channels, _)| u64::from(channels)).sum();
+        let region_count: u64 = parts.iter().map(|&(_, _, regions)| u64::from(regions)).sum();
+        if channel_count != self.os_ipc_channels.len() as u64 ||
+                region_count != self.os_ipc_shared_memory_regions.len() as u64 {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                                  "transaction doesn't match its attachments").into())
+        }
+        Ok(Some((metadata, parts)))
+    }
+
+    fn split_into(mut self, (metadata, parts): TransactionParts) -> Vec<OpaqueIpcMessage> {
+        let mut os_ipc_channels = self.os_ipc_channels.drain(..);
+        let mut os_ipc_shared_memory_regions = self.os_ipc_shared_memory_regions.drain(..);
+        let messages = parts.into_iter().enumerate().map(|(index, (payload, channels, regions))| {
+            let part_metadata = IpcMessageMetadata {
+                sender_id: metadata.sender_id,
+                sequence: metadata.sequence + index as u64,
+                expiry: None,
+            };
+            let mut data = platform::take_buffer(payload.len() + 16);
+            part_metadata.write(&mut data).unwrap();
+            data.extend_from_slice(&payload);
+            OpaqueIpcMessage {
+                data,
+                os_ipc_channels: os_ipc_channels.by_ref().take(channels as usize).collect(),
+                os_ipc_shared_memory_regions:
+                    os_ipc_shared_memory_regions.by_ref().take(regions as usize).collect(),
+                metadata: true,
+            }
+        }).collect();
+        platform::recycle_buffer(mem::take(&mut self.data));
+        messages
+    }
+
+    /// Deserialize the raw data in the contained message into the inferred type.
+    pub fn to<T>(self) -> Result<T, bincode::Error> where T: for<'de> Deserialize<'de> + Serialize {
+        Ok(self.to_with_metadata()?.0)
+    }
+
+    /// Deserialize the raw data in the contained message into the inferred type,
+    /// also returning the metadata the message was sent with.
+    pub fn to_with_metadata<T>(self) -> Result<(T, IpcMessageMetadata), bincode::Error>
+                               where T: for<'de> Deserialize<'de> + Serialize {
+        self.deserialize_with_config(BincodeConfig::default())
+    }
+
+    /// Like `to()`, handing the message back along with the error if deserializing fails.
+    ///
+    /// Channels and shared memory regions the failed attempt had already taken
+    /// out of the message are lost.
+    pub(crate) fn deserialize_or_return<T>(self)
+                                           -> Result<T, (OpaqueIpcMessage, bincode::Error)>
+                                           where T: for<'de> Deserialize<'de> + Serialize {
+        Ok(self.deserialize_or_return_with_config(BincodeConfig::default())?.0)
+    }
+
+    fn deserialize_or_return_with_config<T>(mut self, config: BincodeConfig)
+                                            -> Result<(T, IpcMessageMetadata),
+                                                      (OpaqueIpcMessage, bincode::Error)>
+                                            where T: for<'de> Deserialize<'de> + Serialize {
+        match self.deserialize(config) {
+            Ok(result) => {
+                platform::recycle_buffer(mem::take(&mut self.data));
+                Ok(result)
+            }
+            Err(error) => Err((self, error)),
+        }
+    }
+
+    /// The message `value` would be received as, e.g. to pass on a value that couldn't
+    /// be delivered as a [DeadLetter]. Its channels and shared memory regions go to the
+    /// message.
+    ///
+    /// [DeadLetter]: struct.DeadLetter.html
+    pub(crate) fn from_value<T>(value: &T) -> Result<OpaqueIpcMessage, bincode::Error>
+                                where T: Serialize {
+        let mut data = Vec::new();
+        let (os_ipc_channels, os_ipc_shared_memory_regions, sensitive_copies) =
+            serialize_with_attachments(value, &mut data, BincodeConfig::default())?;
+        sensitive_copies.sent();
+        let os_ipc_channels = os_ipc_channels.into_iter()
+                                             .map(OsOpaqueIpcChannel::from_channel)
+                                             .collect::<Result<Vec<_>, _>>()?;
+        Ok(OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions, false))
+    }
+
+    /// Reuse the buffer of a message that is done with.
+    pub(crate) fn recycle(mut self) {
+        platform::recycle_buffer(mem::take(&mut self.data));
+    }
+
+    pub(crate) fn deserialize_with_config<T>(mut self, config: BincodeConfig)
+                                             -> Result<(T, IpcMessageMetadata), bincode::Error>
+                                             where T: for<'de> Deserialize<'de> + Serialize {
+        let result = self.deserialize(config);
+        platform::recycle_buffer(mem::take(&mut self.data));
+        result
+    }
+
+    /// Deserialize the message, handing over its channels and shared memory regions
+    /// to the values embedding them; `T` may borrow from the message data.
+    fn deserialize<'a, T>(&'a mut self, config: BincodeConfig)
+                          -> Result<(T, IpcMessageMetadata), bincode::Error>
+                          where T: Deserialize<'a> {
+        let OpaqueIpcMessage {
+            ref data,
+            ref mut os_ipc_channels,
+            ref mut os_ipc_shared_memory_regions,
+            metadata,
+        } = *self;
+        OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+            OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
+                    |os_ipc_shared_memory_regions_for_deserialization| {
+                mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(),
+                          os_ipc_channels);
+                mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
+                          os_ipc_shared_memory_regions);
+                let mut reader = &data[..];
+                let header = if metadata {
+                    IpcMessageMetadata::read_with_flags(&mut reader)
+                } else {
+                    Ok((IpcMessageMetadata::default(), 0))
+                };
+                let result = header.and_then(|(metadata, flags)| {
+                    if flags & TRANSACTION_FLAG != 0 {
+                        return Err(Error::new(io::ErrorKind::InvalidData,
+                                              "transactions must be split first").into())
+                    }
+                    Ok((config.deserialize(reader)?, metadata))
+                });
+                mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
+                          os_ipc_shared_memory_regions);
+                mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(),
+                          os_ipc_channels);
+                /* Error check comes after doing cleanup,
+                 * since we need the cleanup both in the success and the error cases. */
+                match SHARED_MEMORY_QUOTA_ERROR.with(Cell::take) {
+                    Some(quota_error) if result.is_err() => Err(quota_error.into()),
+                    _ => Ok(result?),
+                }
+            })
+        })
+    }
+}
+
+/// A message that couldn't be delivered, sent to the dead letters set with
+/// [IpcReceiver::set_dead_letter_sender] or [RouterProxy::set_dead_letter_sender].
+///
+/// [IpcReceiver::set_dead_letter_sender]: struct.IpcReceiver.html#method.set_dead_letter_sender
+/// [RouterProxy::set_dead_letter_sender]:
+/// ../router/struct.RouterProxy.html#method.set_dead_letter_sender
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub reason: DeadLetterReason,
+    /// The message. Those the router couldn't deliver were deserialized first, so they
+    /// are serialized anew from the value, with its channels and shared memory regions.
+    pub message: OpaqueIpcMessage,
+}
+
+/// Why a message became a [DeadLetter].
+///
+/// [DeadLetter]: struct.DeadLetter.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeadLetterReason {
+    /// The message was sent with a TTL, which ran out before it was received.
+    Expired,
+    /// The message exceeded the size limit of the receiver, or the shared memory quota.
+    LimitExceeded,
+    /// The router couldn't deliver the message, as the receiving end of its route is
+    /// gone, or its bounded queue was full.
+    Undeliverable,
+}
+
+/// A channel endpoint to send with [IpcSender::send_raw].
+///
+/// [IpcSender::send_raw]: struct.IpcSender.html#method.send_raw
+#[derive(Debug)]
+pub enum IpcRawChannel {
+    Sender(OpaqueIpcSender),
+    Receiver(OpaqueIpcReceiver),
+}
+
+/// A message received with [IpcReceiver::recv_raw].
+///
+/// [IpcReceiver::recv_raw]: struct.IpcReceiver.html#method.recv_raw
+#[derive(Debug)]
+pub struct IpcRawMessage {
+    /// The payload, without the metadata header.
+    pub data: Vec<u8>,
+    /// Metadata the message was sent with.
+    pub metadata: IpcMessageMetadata,
+    /// The channels sent along with the payload, in order.
+    pub channels: Vec<OpaqueIpcChannel>,
+    /// The shared memory regions sent along with the payload, in order.
+    pub shared_memory_regions: Vec<IpcSharedMemory>,
+}
+
+/// A received channel endpoint, of a kind only known to the application.
+#[derive(Debug)]
+pub struct OpaqueIpcChannel {
+    os_channel: OsOpaqueIpcChannel,
+}
+
+impl OpaqueIpcChannel {
+    /// Interpret the endpoint as a sender.
+    pub fn to_sender(mut self) -> OpaqueIpcSender {
+        OpaqueIpcSender {
+            os_sender: self.os_channel.to_sender(),
+        }
+    }
+
+    /// Interpret the endpoint as a receiver.
+    pub fn to_receiver(mut self) -> OpaqueIpcReceiver {
+        OpaqueIpcReceiver {
+            os_receiver: self.os_channel.to_receiver(),
+            metadata: false,
+            hmac_key: None,
+        }
+    }
+}
+
+/// A message received with [IpcReceiver::recv_ref], still in its receive buffer.
+///
+/// [IpcReceiver::recv_ref]: struct.IpcReceiver.html#method.recv_ref
+pub struct IpcMessageRef<T> {
+    message: OpaqueIpcMessage,
+    bincode_config: BincodeConfig,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcMessageRef<T> {
+    /// Metadata the message was sent with.
+    pub fn metadata(&self) -> Result<IpcMessageMetadata, bincode::Error> {
+        self.message.metadata()
+    }
+
+    /// Deserialize the message into `U`, borrowing from the receive buffer.
+    ///
+    /// `U` must have the same serialized form as the channel's type `T`,
+    /// e.g. `&str` for `String`. Channels and shared memory embedded in the
+    /// message are handed over on the first call: later calls fail to
+    /// deserialize them.
+    pub fn get<'a, U>(&'a mut self) -> Result<U, bincode::Error> where U: Deserialize<'a> {
+        Ok(self.message.deserialize(self.bincode_config)?.0)
+    }
+}
+
+impl<T> Debug for IpcMessageRef<T> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        self.message.fmt(formatter)
+    }
+}
+
+/// The time left until `deadline`, for timed receives that may have to wait again
+/// after discarding expired messages.
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Report a receive error meaning all the senders are gone as a closed channel.
+fn report_closed(error: bincode::Error) -> bincode::Error {
+    if let bincode::ErrorKind::Io(ref error) = *error {
+        if error.kind() == io::ErrorKind::ConnectionReset {
+            events::emit(|| IpcEvent::ChannelClosed);
+        }
+    }
+    error
+}
+
+/// Record a freshly received message, if a capture is running.
+fn record_received(message: &OpaqueIpcMessage) {
+    if !capture::is_capturing() {
+        return
+    }
+    if let Ok((metadata, _, reader)) = message.header() {
+        capture::record(Direction::Received,
+                        metadata,
+                        reader,
+                        message.os_ipc_channels.len(),
+                        message.os_ipc_shared_memory_regions.iter().map(|region| {
+                            region.as_ref().map_or(0, |region| region.len())
+                        }));
+    }
+}
+
+/// Information about a message, sent along with the payload on typed channels.
+///
+/// Obtained with [IpcReceiver::recv_with_metadata] or [OpaqueIpcMessage::metadata].
+///
+/// [IpcReceiver::recv_with_metadata]: struct.IpcReceiver.html#method.recv_with_metadata
+/// [OpaqueIpcMessage::metadata]: struct.OpaqueIpcMessage.html#method.metadata
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IpcMessageMetadata {
+    sender_id: u64,
+    sequence: u64,
+    /// Deadline of a message sent with a TTL, in nanoseconds since the Unix epoch.
+    expiry: Option<u64>,
+}
+
+// Set in the sequence number of the header when the expiry follows it.
+const EXPIRY_FLAG: u64 = 1 << 63;
+// Set in the sequence number of the header when the payload holds the messages
+// of a transaction, as a list of `TransactionPart`s, rather than a single value.
+const TRANSACTION_FLAG: u64 = 1 << 62;
+
+/// The payload of a message in a transaction, with the number of channels and
+/// shared memory regions it takes from those of the transaction.
+type TransactionPart = (Vec<u8>, u32, u32);
+
+/// The header of a transaction, with its parts.
+type TransactionParts = (IpcMessageMetadata, Vec<TransactionPart>);
+
+impl IpcMessageMetadata {
+    /// The [sender_id] of the sender instance the message came from.
+    ///
+    /// [sender_id]: struct.IpcSender.html#method.sender_id
+    pub fn sender_id(&self) -> u64 {
+        self.sender_id
+    }
+
+    /// Position of the message among all messages sent by the same sender instance,
+    /// starting from 0.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// When the message stops being worth receiving, if it was sent with
+    /// [IpcSender::send_with_ttl].
+    ///
+    /// [IpcSender::send_with_ttl]: struct.IpcSender.html#method.send_with_ttl
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expiry.map(|expiry| UNIX_EPOCH + Duration::from_nanos(expiry))
+    }
+
+    /// Whether the message was sent with a TTL which has run out.
+    pub fn is_expired(&self) -> bool {
+        self.expiry.iter().any(|&expiry| {
+            UNIX_EPOCH.elapsed().unwrap_or_default().as_nanos() >= u128::from(expiry)
+        })
+    }
+
+    /// Write the metadata as the message header preceding the payload.
+    fn write<W>(&self, writer: W) -> Result<(), bincode::Error> where W: io::Write {
+        self.write_with_flags(writer, 0)
+    }
+
+    /// Write the message header, with `flags` telling what kind of payload follows.
+    fn write_with_flags<W>(&self, mut writer: W, flags: u64) -> Result<(), bincode::Error>
+                           where W: io::Write {
+        match self.expiry {
+            None => bincode::serialize_into(writer, &(self.sender_id, self.sequence | flags)),
+            Some(expiry) => {
+                bincode::serialize_into(&mut writer,
+                                        &(self.sender_id, self.sequence | EXPIRY_FLAG | flags))?;
+                bincode::serialize_into(writer, &expiry)
+            }
+        }
+    }
+
+    /// Read the message header, along with the flags telling what kind of payload follows.
+    fn read_with_flags(reader: &mut &[u8]) -> Result<(IpcMessageMetadata, u64), bincode::Error> {
+        let (sender_id, sequence): (u64, u64) = bincode::deserialize_from(&mut *reader)?;
+        let expiry = if sequence & EXPIRY_FLAG != 0 {
+            Some(bincode::deserialize_from(reader)?)
+        } else {
+            None
+        };
+        let metadata = IpcMessageMetadata {
+            sender_id,
+            sequence: sequence & !(EXPIRY_FLAG | TRANSACTION_FLAG),
+            expiry,
+        };
+        Ok((metadata, sequence & TRANSACTION_FLAG))
+    }
+}
+
+/// How message payloads are encoded by bincode, set per channel with
+/// [IpcSender::with_bincode_config] and [IpcReceiver::with_bincode_config].
+///
+/// The default matches `bincode::serialize`: fixed size little-endian integers
+/// and no size limit. The config also tells whether the payloads follow a
+/// [metadata header]; this is off by default, so that messages keep the layout
+/// of the peers that predate it. The header itself always uses the default encoding.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::ipc::{self, BincodeConfig};
+/// let config = BincodeConfig::new().limit(1024).varint_encoding().big_endian();
+/// let (tx, rx) = ipc::channel::<Vec<u64>>().unwrap();
+/// let (tx, rx) = (tx.with_bincode_config(config), rx.with_bincode_config(config));
+/// tx.send(vec![1, 2, 3]).unwrap();
+/// assert_eq!(rx.recv().unwrap(), [1, 2, 3]);
+/// assert!(tx.send(vec![0; 1024]).is_err());
+/// ```
+///
+/// [IpcSender::with_bincode_config]: struct.IpcSender.html#method.with_bincode_config
+/// [IpcReceiver::with_bincode_config]: struct.IpcReceiver.html#method.with_bincode_config
+/// [metadata header]: #method.metadata_header
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BincodeConfig {
+    limit: Option<u64>,
+    varint: bool,
+    big_endian: bool,
+    metadata: bool,
+}
+
+/// Evaluate `$body` with `$options` bound to the `bincode::Options` matching `$config`.
+macro_rules! with_bincode_options {
+    ($config:expr, $options:ident => $body:expr) => {{
+        let config: BincodeConfig = $config;
+        let options = bincode::DefaultOptions::new().allow_trailing_bytes();
+        match config.limit {
+            None => with_bincode_options!(@ints config, options.with_no_limit(), $options => $body),
+            Some(limit) => {
+                with_bincode_options!(@ints config, options.with_limit(limit), $options => $body)
+            }
+        }
+    }};
+    (@ints $config:expr, $base:expr, $options:ident => $body:expr) => {
+        match ($config.varint, $config.big_endian) {
+            (false, false) => {
+                let $options = $base.with_fixint_encoding().with_little_endian();
+                $body
+            }
+            (false, true) => {
+                let $options = $base.with_fixint_encoding().with_big_endian();
+                $body
+            }
+            (true, false) => {
+                let $options = $base.with_varint_encoding().with_little_endian();
+                $body
+            }
+            (true, true) => {
+                let $options = $base.with_varint_encoding().with_big_endian();
+                $body
+            }
+        }
+    };
+}
+
+impl BincodeConfig {
+    /// The default configuration.
+    pub fn new() -> BincodeConfig {
+        BincodeConfig::default()
+    }
+
+    /// Fail to send or receive payloads larger than `bytes`.
+    ///
+    /// On the receiving end, this also bounds the memory allocated
+    /// while deserializing corrupt or malicious messages.
+    pub fn limit(mut self, bytes: u64) -> BincodeConfig {
+        self.limit = Some(bytes);
+        self
+    }
+
+    /// Encode integers with a variable number of bytes, so small values take less space.
+    pub fn varint_encoding(mut self) -> BincodeConfig {
+        self.varint = true;
+        self
+    }
 
-impl IpcSharedMemory {
-    /// Create shared memory initialized with the bytes provided.
-    pub fn from_bytes(bytes: &[u8]) -> IpcSharedMemory {
-        IpcSharedMemory {
-            os_shared_memory: OsIpcSharedMemory::from_bytes(bytes),
-        }
+    /// Encode integers with their full size; the default.
+    pub fn fixint_encoding(mut self) -> BincodeConfig {
+        self.varint = false;
+        self
     }
 
-    /// Create a chunk of shared memory that is filled with the byte
-    /// provided.
-    pub fn from_byte(byte: u8, length: usize) -> IpcSharedMemory {
-        IpcSharedMemory {
-            os_shared_memory: OsIpcSharedMemory::from_byte(byte, length),
-        }
+    /// Encode integers most significant byte first.
+    pub fn big_endian(mut self) -> BincodeConfig {
+        self.big_endian = true;
+        self
     }
-}
 
-/// Result for readable events returned from [IpcReceiverSet::select].
-///
-/// [IpcReceiverSet::select]: struct.IpcReceiverSet.html#method.select
-pub enum IpcSelectionResult {
-    /// A message received from the [IpcReceiver] in the [opaque] form,
-    /// identified by the `u64` value.
-    ///
-    /// [IpcReceiver]: struct.IpcReceiver.html
-    /// [opaque]: struct.OpaqueIpcMessage.html
-    MessageReceived(u64, OpaqueIpcMessage),
-    /// The channel has been closed for the [IpcReceiver] identified by the `u64` value.
-    /// [IpcReceiver]: struct.IpcReceiver.html
-    ChannelClosed(u64),
-}
+    /// Encode integers least significant byte first; the default.
+    pub fn little_endian(mut self) -> BincodeConfig {
+        self.big_endian = false;
+        self
+    }
 
-impl IpcSelectionResult {
-    /// Helper method to move the value out of the [IpcSelectionResult] if it
-    /// is [MessageReceived].
+    /// Precede each payload with a header holding its [IpcMessageMetadata].
     ///
-    /// # Panics
+    /// Sender IDs, sequence numbers, TTLs and transactions need the header:
+    /// without it, messages report a sender ID and sequence number of 0 and no
+    /// expiry, and [IpcSender::send_with_ttl] and [IpcTransaction::commit] fail
+    /// with `ErrorKind::InvalidInput`. Peers built before the header was introduced
+    /// can't receive messages carrying it, nor send them.
     ///
-    /// If the result is [ChannelClosed] this call will panic.
+    /// [IpcMessageMetadata]: struct.IpcMessageMetadata.html
+    /// [IpcSender::send_with_ttl]: struct.IpcSender.html#method.send_with_ttl
+    /// [IpcTransaction::commit]: struct.IpcTransaction.html#method.commit
+    pub fn metadata_header(mut self) -> BincodeConfig {
+        self.metadata = true;
+        self
+    }
+
+    /// Whether payloads follow a metadata header.
+    pub(crate) fn has_metadata_header(self) -> bool {
+        self.metadata
+    }
+
+    /// Serialize `value` into `writer`, failing with `bincode::ErrorKind::SizeLimit` if
+    /// it exceeds the limit.
     ///
-    /// [IpcSelectionResult]: enum.IpcSelectionResult.html
-    /// [MessageReceived]: enum.IpcSelectionResult.html#variant.MessageReceived
-    /// [ChannelClosed]: enum.IpcSelectionResult.html#variant.ChannelClosed
-    pub fn unwrap(self) -> (u64, OpaqueIpcMessage) {
-        match self {
-            IpcSelectionResult::MessageReceived(id, message) => (id, message),
-            IpcSelectionResult::ChannelClosed(id) => {
-                panic!("IpcSelectionResult::unwrap(): channel {} closed", id)
-            }
+    /// Unlike `Options::serialize_into`, this checks the limit as the bytes are written
+    /// rather than in a first pass, as serializing channels and one-shot senders has
+    /// side effects that mustn't happen twice.
+    fn serialize_into<W, T>(self, writer: W, value: &T) -> Result<(), bincode::Error>
+                            where W: io::Write, T: Serialize + ?Sized {
+        let limit = match self.limit {
+            None => return with_bincode_options!(self, options => {
+                options.serialize_into(writer, value)
+            }),
+            Some(limit) => limit,
+        };
+        let mut writer = LimitedWriter {
+            writer,
+            remaining: limit,
+            exceeded: false,
+        };
+        let unlimited = BincodeConfig { limit: None, ..self };
+        let result = with_bincode_options!(unlimited, options => {
+            options.serialize_into(&mut writer, value)
+        });
+        match result {
+            Err(_) if writer.exceeded => Err(Box::new(bincode::ErrorKind::SizeLimit)),
+            result => result,
         }
     }
-}
 
-/// Structure used to represent a raw message from an [IpcSender].
-///
-/// Use the [to] method to deserialize the raw result into the requested type.
-///
-/// [IpcSender]: struct.IpcSender.html
-/// [to]: #method.to
-pub struct OpaqueIpcMessage {
-    data: Vec<u8>,
-    os_ipc_channels: Vec<OsOpaqueIpcChannel>,
-    os_ipc_shared_memory_regions: Vec<Option<OsIpcSharedMemory>>,
+    fn deserialize<'a, T>(self, bytes: &'a [u8]) -> Result<T, bincode::Error>
+                          where T: Deserialize<'a> {
+        // Unlike `Options::deserialize`, the deserializer applies the limit to slices too.
+        with_bincode_options!(self, options => {
+            T::deserialize(&mut bincode::Deserializer::from_slice(bytes, options))
+        })
+    }
 }
 
-impl Debug for OpaqueIpcMessage {
-    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
-        match String::from_utf8(self.data.clone()) {
-            Ok(string) => string.chars().take(256).collect::<String>().fmt(formatter),
-            Err(..) => self.data[0..min(self.data.len(), 256)].fmt(formatter),
-        }
-    }
+/// Writer failing once more than `remaining` bytes are written to it.
+struct LimitedWriter<W> {
+    writer: W,
+    remaining: u64,
+    exceeded: bool,
 }
 
-impl OpaqueIpcMessage {
-    fn new(data: Vec<u8>,
-           os_ipc_channels: Vec<OsOpaqueIpcChannel>,
-           os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
-           -> OpaqueIpcMessage {
-        OpaqueIpcMessage {
-            data: data,
-            os_ipc_channels: os_ipc_channels,
-            os_ipc_shared_memory_regions:
-                os_ipc_shared_memory_regions.into_iter()
-                                            .map(|os_ipc_shared_memory_region| {
-                    Some(os_ipc_shared_memory_region)
-                }).collect(),
+impl<W> io::Write for LimitedWriter<W> where W: io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(Error::new(io::ErrorKind::WriteZero, "size limit exceeded"))
         }
+        let written = self.writer.write(buf)?;
+        self.remaining -= written as u64;
+        Ok(written)
     }
 
-    /// Deserialize the raw data in the contained message into the inferred type.
-    pub fn to<T>(mut self) -> Result<T, bincode::Error> where T: for<'de> Deserialize<'de> + Serialize {
-        OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
-            OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(
-                    |os_ipc_shared_memory_regions_for_deserialization| {
-                mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_channels);
-                mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_shared_memory_regions);
-                let result = bincode::deserialize(&self.data[..]);
-                mem::swap(&mut *os_ipc_shared_memory_regions_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_shared_memory_regions);
-                mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(),
-                          &mut self.os_ipc_channels);
-                /* Error check comes after doing cleanup,
-                 * since we need the cleanup both in the success and the error cases. */
-                Ok(result?)
-            })
-        })
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
     }
 }
 
@@ -662,6 +4131,10 @@ impl OpaqueIpcSender {
     pub fn to<'de, T>(self) -> IpcSender<T> where T: Deserialize<'de> + Serialize {
         IpcSender {
             os_sender: self.os_sender,
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
             phantom: PhantomData,
         }
     }
@@ -685,6 +4158,181 @@ impl Serialize for OpaqueIpcSender {
 #[derive(Debug)]
 pub struct OpaqueIpcReceiver {
     os_receiver: OsIpcReceiver,
+    /// Whether the messages start with a metadata header, as the typed receiver
+    /// this was made from expected.
+    metadata: bool,
+    /// The key of the typed receiver this was made from, checked by the
+    /// [IpcReceiverSet] it is added to.
+    ///
+    /// [IpcReceiverSet]: struct.IpcReceiverSet.html
+    hmac_key: Option<Arc<HmacKey>>,
+}
+
+impl OpaqueIpcReceiver {
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    pub(crate) fn as_raw_fd(&self) -> RawFd {
+        self.os_receiver.as_raw_fd()
+    }
+
+    pub fn to<T>(self) -> IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+        IpcReceiver {
+            os_receiver: self.os_receiver,
+            sequence_checker: RefCell::new(None),
+            transaction_parts: RefCell::default(),
+            expired: RefCell::default(),
+            dead_letters: None,
+            bincode_config: BincodeConfig {
+                metadata: self.metadata,
+                ..BincodeConfig::default()
+            },
+            hmac_key: self.hmac_key,
+            nonblocking: false,
+            phantom: PhantomData,
+        }
+    }
+}
+
+// Channels can be handed to child processes as inherited file descriptors,
+// without a named one-shot server, once prepared for inheritance.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> AsRawFd for IpcSender<T> where T: Serialize {
+    fn as_raw_fd(&self) -> RawFd {
+        self.os_sender.as_raw_fd()
+    }
+}
+
+/// Returns a duplicate of the descriptor, which may be shared with clones of the sender.
+/// Like all descriptors of the crate, it is closed on `exec()` unless the sender was
+/// [prepared for inheritance].
+///
+/// [prepared for inheritance]: struct.IpcSender.html#method.prepare_for_inheritance
+///
+/// # Panics
+///
+/// If duplicating the descriptor fails.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> IntoRawFd for IpcSender<T> where T: Serialize {
+    fn into_raw_fd(self) -> RawFd {
+        self.os_sender.into_raw_fd().expect("failed to duplicate the sender's descriptor")
+    }
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> FromRawFd for IpcSender<T> where T: Serialize {
+    unsafe fn from_raw_fd(fd: RawFd) -> IpcSender<T> {
+        IpcSender {
+            os_sender: OsIpcSender::from_raw_fd(fd),
+            sender_id: new_sender_id(),
+            next_sequence: Cell::new(0),
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> AsRawFd for IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn as_raw_fd(&self) -> RawFd {
+        self.os_receiver.as_raw_fd()
+    }
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> IntoRawFd for IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn into_raw_fd(self) -> RawFd {
+        self.os_receiver.into_raw_fd()
+    }
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> FromRawFd for IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    unsafe fn from_raw_fd(fd: RawFd) -> IpcReceiver<T> {
+        OpaqueIpcReceiver {
+            os_receiver: OsIpcReceiver::from_raw_fd(fd),
+            metadata: false,
+            hmac_key: None,
+        }.to()
+    }
+}
+
+/// Lets a receiver be registered with a `mio::Poll`, with the `evented` feature, to
+/// wait for messages in an existing event loop rather than with an [IpcReceiverSet].
+///
+/// The receiver is readable when a message arrives, or once all senders are gone.
+/// Messages of a [transaction] after the first are queued in the receiver itself,
+/// so with edge-triggered registrations, receive with `try_recv()` until it fails
+/// with `ErrorKind::WouldBlock`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ipc_channel;
+/// # extern crate mio;
+/// # use ipc_channel::ipc;
+/// # use mio::{Events, Poll, PollOpt, Ready, Token};
+/// # fn main() {
+/// let (tx, rx) = ipc::channel::<u32>().unwrap();
+/// let poll = Poll::new().unwrap();
+/// poll.register(&rx, Token(7), Ready::readable(), PollOpt::level()).unwrap();
+/// tx.send(42).unwrap();
+///
+/// let mut events = Events::with_capacity(16);
+/// poll.poll(&mut events, None).unwrap();
+/// assert_eq!(events.iter().next().unwrap().token(), Token(7));
+/// assert_eq!(rx.try_recv().unwrap(), 42);
+/// # }
+/// ```
+///
+/// [IpcReceiverSet]: struct.IpcReceiverSet.html
+/// [transaction]: struct.IpcSender.html#method.transaction
+#[cfg(all(feature = "evented", not(feature = "force-inprocess"), any(target_os = "linux",
+                                                                     target_os = "openbsd",
+                                                                     target_os = "freebsd",
+                                                                     target_os = "illumos",
+                                                                     target_os = "solaris")))]
+impl<T> Evented for IpcReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready,
+                opts: mio::PollOpt) -> Result<(), Error> {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready,
+                  opts: mio::PollOpt) -> Result<(), Error> {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> Result<(), Error> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
 }
 
 /// A server associated with a given name.
@@ -722,21 +4370,125 @@ pub struct OpaqueIpcReceiver {
 /// [IpcSender]: struct.IpcSender.html
 pub struct IpcOneShotServer<T> {
     os_server: OsIpcOneShotServer,
+    /// Reported in the server's events; servers from `from_listen_fds()` have none.
+    name: Option<String>,
     phantom: PhantomData<T>,
 }
 
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+impl<T> AsRawFd for IpcOneShotServer<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.os_server.as_raw_fd()
+    }
+}
+
+/// Lets a server be registered with a `mio::Poll`, with the `evented` feature. The
+/// server is readable once a client connects, and [accept] then returns without
+/// waiting for the connection, though still waiting for the first message.
+///
+/// [accept]: #method.accept
+#[cfg(all(feature = "evented", not(feature = "force-inprocess"), any(target_os = "linux",
+                                                                     target_os = "openbsd",
+                                                                     target_os = "freebsd",
+                                                                     target_os = "illumos",
+                                                                     target_os = "solaris")))]
+impl<T> Evented for IpcOneShotServer<T> {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready,
+                opts: mio::PollOpt) -> Result<(), Error> {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready,
+                  opts: mio::PollOpt) -> Result<(), Error> {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> Result<(), Error> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
+}
+
 impl<T> IpcOneShotServer<T> where T: for<'de> Deserialize<'de> + Serialize {
     pub fn new() -> Result<(IpcOneShotServer<T>, String),Error> {
         let (os_server, name) = OsIpcOneShotServer::new()?;
+        events::emit(|| IpcEvent::ServerRegistered { name: Some(name.clone()) });
         Ok((IpcOneShotServer {
             os_server: os_server,
+            name: Some(name.clone()),
             phantom: PhantomData,
         }, name))
     }
 
+    /// Adopt the listening sockets systemd passed to this process with socket
+    /// activation, in the order they are configured in the `.socket` unit, which
+    /// must have `ListenSequentialPacket=`. Clients connect with the socket's path.
+    ///
+    /// Returns no servers if the process wasn't socket-activated, or if the sockets
+    /// were adopted already. Fails with `ErrorKind::InvalidData` if `$LISTEN_FDS`
+    /// isn't a valid count, and with `ErrorKind::InvalidInput` if a passed socket
+    /// isn't a listening seqpacket socket; all sockets are checked before any is
+    /// adopted, so they are all left open then. The `LISTEN_*` environment variables
+    /// are left as they are, as changing the environment isn't safe while other
+    /// threads run: `$LISTEN_PID` names this process, so children don't adopt the
+    /// sockets. Only Linux and the BSDs support this; otherwise, this fails with
+    /// `ErrorKind::Unsupported`.
+    pub fn from_listen_fds() -> Result<Vec<IpcOneShotServer<T>>,Error> {
+        Ok(OsIpcOneShotServer::from_listen_fds()?.into_iter().map(|os_server| {
+            events::emit(|| IpcEvent::ServerRegistered { name: None });
+            IpcOneShotServer {
+                os_server,
+                name: None,
+                phantom: PhantomData,
+            }
+        }).collect())
+    }
+
     pub fn accept(self) -> Result<(IpcReceiver<T>,T), bincode::Error> {
-        let (os_receiver, data, os_channels, os_shared_memory_regions) =
-            self.os_server.accept()?;
+        IpcOneShotServer::finish_accept(self.name, self.os_server.accept()?)
+    }
+
+    /// Like `accept()`, only accepting a client whose credentials `policy` approves.
+    ///
+    /// Rejected clients are dropped, and the server waits for the next one. On Linux
+    /// and the BSDs, `policy` is consulted when a client connects, before reading
+    /// anything it sent. With the in-process backend, clients are always this process,
+    /// so `policy` is consulted once, before accepting anyone: if it rejects this
+    /// process, no client could ever be accepted, and this fails with
+    /// `ErrorKind::PermissionDenied` instead of waiting.
+    /// The Mach backend can't tell who sent the first message: `policy` is called
+    /// with unknown credentials.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
+    /// # use std::process;
+    /// let (server, name) = IpcOneShotServer::new().unwrap();
+    /// let tx = IpcSender::connect(name).unwrap();
+    /// tx.send(42).unwrap();
+    /// let (_, value): (_, u32) = server.accept_with_policy(|peer| {
+    ///     peer.pid().map_or(true, |pid| pid == process::id())
+    /// }).unwrap();
+    /// assert_eq!(value, 42);
+    /// ```
+    pub fn accept_with_policy<F>(self, mut policy: F) -> Result<(IpcReceiver<T>,T), bincode::Error>
+                                 where F: FnMut(&IpcPeerCredentials) -> bool {
+        let accepted = self.os_server.accept_with_policy(|os_credentials| {
+            policy(&IpcPeerCredentials {
+                os_credentials: *os_credentials,
+            })
+        })?;
+        IpcOneShotServer::finish_accept(self.name, accepted)
+    }
+
+    fn finish_accept(name: Option<String>,
+                     (os_receiver, data, os_channels, os_shared_memory_regions): AcceptedClient)
+                     -> Result<(IpcReceiver<T>,T), bincode::Error> {
+        events::emit(|| IpcEvent::ServerAccepted { name });
         let value = OpaqueIpcMessage {
             data: data,
             os_ipc_channels: os_channels,
@@ -744,14 +4496,54 @@ impl<T> IpcOneShotServer<T> where T: for<'de> Deserialize<'de> + Serialize {
                                                                   .map(|os_shared_memory_region| {
                 Some(os_shared_memory_region)
             }).collect(),
+            metadata: false,
         }.to()?;
         Ok((IpcReceiver {
             os_receiver: os_receiver,
+            sequence_checker: RefCell::new(None),
+            transaction_parts: RefCell::default(),
+            expired: RefCell::default(),
+            dead_letters: None,
+            bincode_config: BincodeConfig::default(),
+            hmac_key: None,
+            nonblocking: false,
             phantom: PhantomData,
         }, value))
     }
 }
 
+/// The receiver and first message of a client accepted by an `OsIpcOneShotServer`.
+type AcceptedClient = (OsIpcReceiver, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
+/// Credentials of a client connecting to an [IpcOneShotServer],
+/// passed to the policy of [IpcOneShotServer::accept_with_policy].
+///
+/// Each is `None` where the platform doesn't report it.
+///
+/// [IpcOneShotServer]: struct.IpcOneShotServer.html
+/// [IpcOneShotServer::accept_with_policy]: struct.IpcOneShotServer.html#method.accept_with_policy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpcPeerCredentials {
+    os_credentials: OsIpcPeerCredentials,
+}
+
+impl IpcPeerCredentials {
+    /// Process ID of the client.
+    pub fn pid(&self) -> Option<u32> {
+        self.os_credentials.pid
+    }
+
+    /// Effective user ID of the client.
+    pub fn uid(&self) -> Option<u32> {
+        self.os_credentials.uid
+    }
+
+    /// Effective group ID of the client.
+    pub fn gid(&self) -> Option<u32> {
+        self.os_credentials.gid
+    }
+}
+
 /// Receiving end of a channel that does not used serialized messages.
 #[derive(Debug)]
 pub struct IpcBytesReceiver {
@@ -764,7 +4556,7 @@ impl IpcBytesReceiver {
     pub fn recv(&self) -> Result<Vec<u8>, bincode::Error> {
         match self.os_receiver.recv() {
             Ok((data, _, _)) => Ok(data),
-            Err(err) => Err(err.into()),
+            Err(err) => Err(report_closed(err.into())),
         }
     }
 
@@ -772,6 +4564,34 @@ impl IpcBytesReceiver {
     pub fn try_recv(&self) -> Result<Vec<u8>, bincode::Error> {
         match self.os_receiver.try_recv() {
             Ok((data, _, _)) => Ok(data),
+            Err(err) => Err(report_closed(err.into())),
+        }
+    }
+
+    /// Receive a message sent with `IpcBytesSender::send_message()`, closing the
+    /// channel before deserializing it.
+    pub(crate) fn recv_message<T>(self) -> Result<T, bincode::Error>
+                                  where T: for<'de> Deserialize<'de> + Serialize {
+        let (data, os_ipc_channels, os_ipc_shared_memory_regions) =
+            self.os_receiver.recv().map_err(|err| report_closed(err.into()))?;
+        drop(self);
+        OpaqueIpcMessage::new(data, os_ipc_channels, os_ipc_shared_memory_regions, false).to()
+    }
+
+    /// Blocking receive of a bulk message, straight into a freshly mapped
+    /// shared memory region rather than a `Vec<u8>`.
+    ///
+    /// On Linux and the BSDs, the fragments of a large message are received
+    /// directly into the region, so the payload never hits the heap.
+    pub fn recv_bulk(&self) -> Result<IpcSharedMemory, bincode::Error> {
+        match self.os_receiver.recv_bulk() {
+            Ok((os_shared_memory, _, _)) => {
+                Ok(IpcSharedMemory {
+                    _charge: charge_shared_memory(os_shared_memory.len(), false).ok(),
+                    os_shared_memory,
+                    wipe: None,
+                })
+            }
             Err(err) => Err(err.into()),
         }
     }
@@ -786,6 +4606,7 @@ impl<'de> Deserialize<'de> for IpcBytesReceiver {
                 // of bounds. We should return an `Err` result instead.
                 os_ipc_channels_for_deserialization.borrow_mut()[index].to_receiver()
             });
+        events::transferred(Direction::Received, Endpoint::Receiver);
         Ok(IpcBytesReceiver {
             os_receiver: os_receiver,
         })
@@ -802,6 +4623,7 @@ impl Serialize for IpcBytesReceiver {
                                                                               .consume()));
             index
         });
+        events::transferred(Direction::Sent, Endpoint::Receiver);
         index.serialize(serializer)
     }
 }
@@ -840,6 +4662,217 @@ impl IpcBytesSender {
     pub fn send(&self, data: &[u8]) -> Result<(),Error> {
         self.os_sender.send(data, vec![], vec![]).map_err(|e| Error::from(e))
     }
+
+    /// Like [send], but hands `data` over to the channel, which saves a
+    /// copy when both ends are in the same process.
+    ///
+    /// [send]: #method.send
+    #[inline]
+    pub fn send_vec(&self, data: Vec<u8>) -> Result<(),Error> {
+        self.os_sender.send_vec(data, vec![], vec![]).map_err(Error::from)
+    }
+
+    /// Serialize and send `data` along with the channels and shared memory it embeds,
+    /// as a regular sender without the metadata header would, closing the channel.
+    pub(crate) fn send_message<T>(self, data: &T) -> Result<(), bincode::Error>
+                                  where T: Serialize {
+        let mut buffer = MessageBuffer::new();
+        let (os_ipc_channels, os_ipc_shared_memory_regions, sensitive_copies) =
+            serialize_with_attachments(data, &mut buffer, BincodeConfig::default())?;
+        match buffer.heap {
+            Some(bytes) => {
+                self.os_sender.send_vec(bytes, os_ipc_channels, os_ipc_shared_memory_regions)?
+            }
+            None => {
+                self.os_sender.send(&buffer.inline[..buffer.len],
+                                    os_ipc_channels,
+                                    os_ipc_shared_memory_regions)?
+            }
+        }
+        sensitive_copies.sent();
+        Ok(())
+    }
+}
+
+/// Size of the buffer messages are serialized into before resorting to the heap.
+const INLINE_MESSAGE_SIZE: usize = 256;
+
+/// Serialization buffer that keeps small messages on the stack,
+/// moving to the heap only once they outgrow it.
+struct MessageBuffer {
+    inline: [u8; INLINE_MESSAGE_SIZE],
+    len: usize,
+    heap: Option<Vec<u8>>,
+}
+
+impl MessageBuffer {
+    fn new() -> MessageBuffer {
+        MessageBuffer {
+            inline: [0; INLINE_MESSAGE_SIZE],
+            len: 0,
+            heap: None,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.heap {
+            Some(ref heap) => heap,
+            None => &self.inline[..self.len],
+        }
+    }
+}
+
+impl io::Write for MessageBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut heap) = self.heap {
+            heap.extend_from_slice(buf);
+        } else if self.len + buf.len() <= INLINE_MESSAGE_SIZE {
+            self.inline[self.len..self.len + buf.len()].copy_from_slice(buf);
+            self.len += buf.len();
+        } else {
+            let mut heap = platform::take_buffer(cmp::max(4096, self.len + buf.len()));
+            heap.extend_from_slice(&self.inline[..self.len]);
+            heap.extend_from_slice(buf);
+            self.heap = Some(heap);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the payload of a message sent with [IpcSender::send_with], straight into
+/// the buffer handed to the OS.
+///
+/// Raw bytes can be written with `io::Write` too, e.g. for a field that is already
+/// serialized.
+///
+/// [IpcSender::send_with]: struct.IpcSender.html#method.send_with
+pub struct IpcMessageWriter<'a> {
+    buffer: &'a mut MessageBuffer,
+    config: BincodeConfig,
+    written: u64,
+}
+
+impl<'a> IpcMessageWriter<'a> {
+    /// Serialize `value` after what was written so far, with the sender's
+    /// [BincodeConfig]. Channels and shared memory regions in it are sent along.
+    ///
+    /// [BincodeConfig]: struct.BincodeConfig.html
+    pub fn serialize<U>(&mut self, value: &U) -> Result<(), bincode::Error>
+                        where U: Serialize + ?Sized {
+        let config = self.config;
+        config.serialize_into(self, value)
+    }
+
+    /// The number of payload bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<'a> io::Write for IpcMessageWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written += buf.len() as u64;
+        io::Write::write_all(self.buffer, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Debug for IpcMessageWriter<'a> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcMessageWriter")
+                 .field("written", &self.written)
+                 .finish()
+    }
+}
+
+/// The channels and shared memory regions serialized into a message, with the
+/// copies made of sensitive regions to send.
+type Attachments = (Vec<OsIpcChannel>, Vec<OsIpcSharedMemory>, SensitiveCopies);
+
+/// Serialize `data` into `writer`,
+/// collecting the channels and shared memory regions embedded in it.
+fn serialize_with_attachments<T, W>(data: &T, writer: W, config: BincodeConfig)
+                                    -> Result<Attachments, bincode::Error>
+                                    where T: Serialize, W: io::Write {
+    collect_attachments(|| config.serialize_into(writer, data))
+}
+
+/// Run `serialize`, collecting the channels and shared memory regions it serializes.
+fn collect_attachments<F>(serialize: F) -> Result<Attachments, bincode::Error>
+                          where F: FnOnce() -> Result<(), bincode::Error> {
+    OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+        OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(
+                |os_ipc_shared_memory_regions_for_serialization| {
+            SENSITIVE_COPIES_FOR_SERIALIZATION.with(|sensitive_copies_for_serialization| {
+                let old_os_ipc_channels =
+                    mem::take(&mut *os_ipc_channels_for_serialization.borrow_mut());
+                let old_os_ipc_shared_memory_regions =
+                    mem::take(&mut *os_ipc_shared_memory_regions_for_serialization.borrow_mut());
+                let old_sensitive_copies =
+                    mem::take(&mut *sensitive_copies_for_serialization.borrow_mut());
+                let result = serialize();
+                let os_ipc_channels =
+                    mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(),
+                                 old_os_ipc_channels);
+                let os_ipc_shared_memory_regions = mem::replace(
+                    &mut *os_ipc_shared_memory_regions_for_serialization.borrow_mut(),
+                    old_os_ipc_shared_memory_regions);
+                // Dropped, wiping the copies, if serializing failed.
+                let sensitive_copies = SensitiveCopies(mem::replace(
+                    &mut *sensitive_copies_for_serialization.borrow_mut(),
+                    old_sensitive_copies));
+                result.map(|()| (os_ipc_channels, os_ipc_shared_memory_regions, sensitive_copies))
+            })
+        })
+    })
+}
+
+/// Mappings of the copies of sensitive regions made to send them, which wipe the
+/// copies when dropped unless [sent] is called: the message wasn't sent then, and
+/// its copies would be unmapped as is.
+///
+/// [sent]: #method.sent
+#[derive(Default)]
+struct SensitiveCopies(Vec<OsIpcSharedMemory>);
+
+impl SensitiveCopies {
+    /// Keep the copies, now that they are the receiver's.
+    fn sent(mut self) {
+        self.0.clear()
+    }
+}
+
+impl Drop for SensitiveCopies {
+    fn drop(&mut self) {
+        for copy in &self.0 {
+            copy.wipe();
+        }
+    }
+}
+
+/// Serialize `data` into `bytes`, for transports that can only carry plain bytes.
+///
+/// Fails if `data` embeds any channels or shared memory regions.
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+pub(crate) fn serialize_plain<T>(data: &T, bytes: &mut Vec<u8>) -> Result<(), bincode::Error>
+                                 where T: Serialize {
+    let (os_ipc_channels, os_ipc_shared_memory_regions, _sensitive_copies) =
+        serialize_with_attachments(data, bytes, BincodeConfig::default())?;
+    if !os_ipc_channels.is_empty() || !os_ipc_shared_memory_regions.is_empty() {
+        return Err(Error::new(io::ErrorKind::InvalidInput,
+                              "message cannot embed channels or shared memory").into())
+    }
+    Ok(())
 }
 
 fn serialize_os_ipc_sender<S>(os_ipc_sender: &OsIpcSender, serializer: S)
@@ -851,12 +4884,14 @@ fn serialize_os_ipc_sender<S>(os_ipc_sender: &OsIpcSender, serializer: S)
         os_ipc_channels_for_serialization.push(OsIpcChannel::Sender(os_ipc_sender.clone()));
         index
     });
+    events::transferred(Direction::Sent, Endpoint::Sender);
     index.serialize(serializer)
 }
 
 fn deserialize_os_ipc_sender<'de, D>(deserializer: D)
                                 -> Result<OsIpcSender, D::Error> where D: Deserializer<'de> {
     let index: usize = Deserialize::deserialize(deserializer)?;
+    events::transferred(Direction::Received, Endpoint::Sender);
     OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
         // FIXME(pcwalton): This could panic if the data was corrupt and the index was out of
         // bounds. We should return an `Err` result instead.
@@ -43,13 +43,15 @@ extern crate crossbeam_channel;
 extern crate lazy_static;
 #[cfg(all(
     not(feature = "force-inprocess"),
-    any(target_os = "linux", target_os = "openbsd", target_os = "freebsd")
+    any(target_os = "linux", target_os = "openbsd", target_os = "freebsd",
+        target_os = "illumos", target_os = "solaris")
 ))]
 extern crate fnv;
 extern crate libc;
 #[cfg(all(
     not(feature = "force-inprocess"),
-    any(target_os = "linux", target_os = "openbsd", target_os = "freebsd")
+    any(target_os = "linux", target_os = "openbsd", target_os = "freebsd",
+        target_os = "illumos", target_os = "solaris")
 ))]
 extern crate mio;
 extern crate rand;
@@ -72,10 +74,61 @@ extern crate sc;
 
 #[cfg(feature = "async")]
 extern crate futures;
+#[cfg(loom)]
+extern crate loom;
 
+pub mod ack;
+pub mod adapter;
+pub mod bytes;
+pub mod capture;
+pub mod checkpoint;
+pub mod delta;
+pub mod diagnostics;
+pub mod dispatcher;
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+pub mod double_buffer;
+pub mod events;
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub mod fork;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod hmac;
 pub mod ipc;
+pub mod mux;
+pub mod named_lock;
+pub mod oneshot;
 pub mod platform;
+pub mod pool;
+pub mod process;
+pub mod rate_limit;
+mod registry;
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+pub mod ring;
 pub mod router;
+#[cfg(feature = "test-support")]
+pub mod sim;
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+pub mod state;
+pub mod supervisor;
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+pub mod sync;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod threads;
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+pub mod watch;
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+mod words;
 
 #[cfg(test)]
 mod test;
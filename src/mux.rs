@@ -0,0 +1,709 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Many logical sub-channels multiplexed over a single OS channel.
+//!
+//! Each sub-channel is identified by a `u64` chosen by the application, and
+//! carries messages of its own type, which can embed channels and shared
+//! memory as usual. Only one file descriptor or port is used however many
+//! sub-channels there are.
+//!
+//! ```
+//! # use ipc_channel::mux;
+//! let (tx, rx) = mux::channel().unwrap();
+//! let names = tx.sub_sender::<String>(1);
+//! let ages = tx.sub_sender::<u32>(2);
+//! ages.send(29).unwrap();
+//! names.send("Patrick Walton".to_owned()).unwrap();
+//!
+//! assert_eq!(rx.sub_receiver::<String>(1).recv().unwrap(), "Patrick Walton");
+//! assert_eq!(rx.sub_receiver::<u32>(2).recv().unwrap(), 29);
+//! ```
+//!
+//! Messages are queued in the [MuxReceiver] until the matching sub-receiver
+//! asks for them, so every sub-channel that is written to should be read from.
+//!
+//! ## Flow control
+//!
+//! With [channel_with_flow_control], each sub-channel gets a window of
+//! credits: a sub-sender spends one credit per message, and blocks in
+//! [send][MuxSubSender::send] once it has sent `window` messages that the
+//! matching sub-receiver hasn't received yet. Credits travel back to the
+//! senders over a second channel. So a sub-channel nobody reads from only
+//! ever has `window` messages queued, and doesn't hold up the others.
+//! [poll_ready][MuxSubSender::poll_ready] tells whether a send would block.
+//!
+//! [MuxReceiver]: struct.MuxReceiver.html
+//! [channel_with_flow_control]: fn.channel_with_flow_control.html
+//! [MuxSubSender::send]: struct.MuxSubSender.html#method.send
+//! [MuxSubSender::poll_ready]: struct.MuxSubSender.html#method.poll_ready
+
+use bincode;
+use ipc::{self, BincodeConfig, ChannelOptions, IpcReceiver, IpcSender, OpaqueIpcMessage};
+use router::ROUTER;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Create a multiplexed channel.
+pub fn channel() -> Result<(MuxSender, MuxReceiver), Error> {
+    let (sender, receiver) = ipc::channel_with_options(options())?;
+    Ok((MuxSender { sender, credit: None }, MuxReceiver::new(receiver, None)))
+}
+
+/// Create a multiplexed channel where at most `window` messages per
+/// sub-channel can be in flight.
+///
+/// # Panics
+///
+/// If `window` is zero.
+pub fn channel_with_flow_control(window: u32) -> Result<(MuxSender, MuxReceiver), Error> {
+    assert!(window > 0, "flow control window must not be empty");
+    let (sender, receiver) = ipc::channel_with_options(options())?;
+    let (grant_sender, grant_receiver) = ipc::channel()?;
+    Ok((MuxSender { sender, credit: Some(Credit::new(window, HashMap::new(), grant_receiver)) },
+        MuxReceiver::new(receiver, Some((window, grant_sender)))))
+}
+
+/// The options of the underlying channel: transactions need the metadata header.
+fn options() -> ChannelOptions {
+    ChannelOptions::new().metadata_header()
+}
+
+/// Called with whether a sub-channel became congested, or writable again.
+pub type CongestionHandler = Box<dyn FnMut(bool) + Send>;
+
+/// Number of credits for one sub-channel, and its id.
+type Grant = (u64, u32);
+
+/// Window, credits left per sub-channel, and where grants arrive.
+type SenderCredit = (u32, HashMap<u64, u32>, IpcReceiver<Grant>);
+
+/// Sending end of a multiplexed channel, handing out [MuxSubSender]s.
+///
+/// [MuxSubSender]: struct.MuxSubSender.html
+#[derive(Clone)]
+pub struct MuxSender {
+    sender: IpcSender<()>,
+    credit: Option<Arc<Credit>>,
+}
+
+impl MuxSender {
+    /// Get a sender for sub-channel `id`.
+    pub fn sub_sender<T>(&self, id: u64) -> MuxSubSender<T> where T: Serialize {
+        MuxSubSender {
+            id,
+            sender: self.sender.clone().cast_unchecked(),
+            credit: self.credit.clone(),
+        }
+    }
+}
+
+impl MuxSender {
+    /// Start a transaction: messages pushed to it, for any sub-channels, are
+    /// sent together by [commit], so the receiving end queues either all of them
+    /// or none. See [IpcSender::transaction].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::mux;
+    /// let (tx, rx) = mux::channel().unwrap();
+    /// let mut transaction = tx.transaction();
+    /// transaction.push(1, "Patrick Walton".to_owned()).unwrap();
+    /// transaction.push(2, 29u32).unwrap();
+    /// transaction.commit().unwrap();
+    ///
+    /// assert_eq!(rx.sub_receiver::<u32>(2).recv().unwrap(), 29);
+    /// assert_eq!(rx.sub_receiver::<String>(1).recv().unwrap(), "Patrick Walton");
+    /// ```
+    ///
+    /// [commit]: struct.MuxTransaction.html#method.commit
+    /// [IpcSender::transaction]: ../ipc/struct.IpcSender.html#method.transaction
+    pub fn transaction(&self) -> MuxTransaction<'_> {
+        MuxTransaction {
+            transaction: self.sender.transaction(),
+            credit: &self.credit,
+            counts: HashMap::new(),
+        }
+    }
+}
+
+/// Messages for sub-channels of a multiplexed channel, staged to be sent
+/// together, from [MuxSender::transaction].
+///
+/// Dropping the transaction without committing it discards the messages.
+///
+/// [MuxSender::transaction]: struct.MuxSender.html#method.transaction
+pub struct MuxTransaction<'a> {
+    transaction: ipc::IpcTransaction<'a, ()>,
+    credit: &'a Option<Arc<Credit>>,
+    /// Messages staged per sub-channel.
+    counts: HashMap<u64, u32>,
+}
+
+impl<'a> MuxTransaction<'a> {
+    /// Stage a message for sub-channel `id`, serializing it right away.
+    ///
+    /// With flow control, fails with `ErrorKind::InvalidInput` if the transaction
+    /// already holds a full window of messages for the sub-channel, as it could
+    /// never be committed.
+    pub fn push<T>(&mut self, id: u64, data: T) -> Result<(), bincode::Error>
+                   where T: Serialize {
+        let count = self.counts.entry(id).or_default();
+        if let Some(ref credit) = *self.credit {
+            if *count == credit.window {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "transaction exceeds the flow control window").into())
+            }
+        }
+        self.transaction.push_unchecked(&(id, data))?;
+        *count += 1;
+        Ok(())
+    }
+
+    /// The number of messages staged.
+    pub fn len(&self) -> usize {
+        self.transaction.len()
+    }
+
+    /// Whether no message is staged.
+    pub fn is_empty(&self) -> bool {
+        self.transaction.is_empty()
+    }
+
+    /// Send the staged messages. With flow control, blocks until each
+    /// sub-channel has credits for all its messages.
+    pub fn commit(self) -> Result<(), bincode::Error> {
+        if let Some(ref credit) = *self.credit {
+            for (&id, &count) in &self.counts {
+                for _ in 0..count {
+                    credit.reserve(id, true, true)?;
+                }
+            }
+        }
+        self.transaction.commit()
+    }
+}
+
+impl<'a> Debug for MuxTransaction<'a> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("MuxTransaction").field("len", &self.len()).finish()
+    }
+}
+
+impl Debug for MuxSender {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("MuxSender")
+                 .field("sender", &self.sender)
+                 .field("window", &self.credit.as_ref().map(|credit| credit.window))
+                 .finish()
+    }
+}
+
+impl Serialize for MuxSender {
+    /// With flow control, only a sender that was not cloned, and no
+    /// sub-sender was taken from, can be sent.
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        use serde::ser::Error;
+        match self.credit {
+            None => (&self.sender, None::<SenderCredit>)
+                        .serialize(serializer),
+            Some(ref credit) => {
+                if Arc::strong_count(credit) != 1 {
+                    return Err(S::Error::custom("cannot send a MuxSender that is in use"))
+                }
+                let state = credit.state.lock().unwrap();
+                // Not routed, as the route would have a reference.
+                let receiver = credit.receiver.lock().unwrap();
+                (&self.sender, Some((credit.window, &state.available, receiver.as_ref().unwrap())))
+                    .serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MuxSender {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (sender, credit): (IpcSender<()>, Option<SenderCredit>) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(MuxSender {
+            sender: sender.with_bincode_config(BincodeConfig::new().metadata_header()),
+            credit: credit.map(|(window, available, receiver)| {
+                Credit::new(window, available, receiver)
+            }),
+        })
+    }
+}
+
+/// Credits of the sub-senders of one process.
+struct Credit {
+    window: u32,
+    /// Taken by the router once a congestion handler is set.
+    receiver: Mutex<Option<IpcReceiver<Grant>>>,
+    state: Mutex<CreditState>,
+    condvar: Condvar,
+    handlers: Mutex<HashMap<u64, CongestionHandler>>,
+}
+
+#[derive(Default)]
+struct CreditState {
+    /// Credits left per sub-channel; those not in there have a full window.
+    available: HashMap<u64, u32>,
+    /// Whether a thread is currently receiving grants.
+    reading: bool,
+    /// Whether grants are received by the router rather than by the senders.
+    routed: bool,
+    /// Whether the receiver is gone.
+    closed: bool,
+    /// Congestion changes the handlers haven't been told about yet.
+    pending: Vec<(u64, bool)>,
+}
+
+impl CreditState {
+    fn grant(&mut self, window: u32, id: u64, amount: u32) {
+        let available = self.available.entry(id).or_insert(window);
+        if *available == 0 && amount > 0 {
+            self.pending.push((id, false));
+        }
+        *available += amount;
+    }
+}
+
+impl Credit {
+    fn new(window: u32, available: HashMap<u64, u32>, receiver: IpcReceiver<Grant>)
+           -> Arc<Credit> {
+        Arc::new(Credit {
+            window,
+            receiver: Mutex::new(Some(receiver)),
+            state: Mutex::new(CreditState { available, ..CreditState::default() }),
+            condvar: Condvar::new(),
+            handlers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check whether sub-channel `id` has a credit left, waiting for one if
+    /// `blocking`, and spend it if `spend`.
+    fn reserve(&self, id: u64, blocking: bool, spend: bool) -> Result<bool, bincode::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.reading && !state.routed {
+            // Pick up pending grants, so they don't pile up in the channel.
+            state = self.receive_grants(state, false)?;
+        }
+        let result = loop {
+            {
+                let available = state.available.entry(id).or_insert(self.window);
+                if *available > 0 {
+                    if spend {
+                        *available -= 1;
+                        if *available == 0 {
+                            state.pending.push((id, true));
+                        }
+                    }
+                    break Ok(true)
+                }
+            }
+            if state.closed {
+                break Err(Error::new(ErrorKind::ConnectionReset,
+                                     "multiplexed channel closed").into())
+            }
+            if !blocking {
+                break Ok(false)
+            }
+            if state.reading || state.routed {
+                state = self.condvar.wait(state).unwrap();
+                continue
+            }
+            state = self.receive_grants(state, true)?;
+        };
+        self.notify(state);
+        result
+    }
+
+    /// Receive one grant if `blocking`, otherwise all those already sent.
+    fn receive_grants<'a>(&'a self, mut state: MutexGuard<'a, CreditState>, blocking: bool)
+                          -> Result<MutexGuard<'a, CreditState>, bincode::Error> {
+        state.reading = true;
+        drop(state);
+        let mut grants = vec![];
+        let result = {
+            let receiver = self.receiver.lock().unwrap();
+            let receiver = receiver.as_ref().expect("grants received by the router");
+            if blocking {
+                receiver.recv().map(|grant| grants.push(grant))
+            } else {
+                loop {
+                    match receiver.try_recv() {
+                        Ok(grant) => grants.push(grant),
+                        Err(ref error) if !is_closed(error) => break Ok(()),
+                        Err(error) => break Err(error),
+                    }
+                }
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        state.reading = false;
+        self.condvar.notify_all();
+        for (id, amount) in grants {
+            state.grant(self.window, id, amount);
+        }
+        match result {
+            Ok(()) => Ok(state),
+            Err(ref error) if is_closed(error) => {
+                state.closed = true;
+                Ok(state)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Tell the handlers about pending congestion changes, in order.
+    fn notify<'a>(&'a self, mut state: MutexGuard<'a, CreditState>) {
+        while !state.pending.is_empty() {
+            // Whoever holds the handlers, possibly this very thread from
+            // within a handler, will come back for the pending changes.
+            let mut handlers = match self.handlers.try_lock() {
+                Ok(handlers) => handlers,
+                Err(_) => return,
+            };
+            let pending = mem::take(&mut state.pending);
+            drop(state);
+            for (id, congested) in pending {
+                if let Some(handler) = handlers.get_mut(&id) {
+                    handler(congested);
+                }
+            }
+            drop(handlers);
+            state = self.state.lock().unwrap();
+        }
+    }
+
+    /// Have the router receive grants from now on, so handlers are told when
+    /// a sub-channel is writable again even if no sender is waiting for it.
+    fn route(credit: &Arc<Credit>) {
+        let receiver = match credit.receiver.lock().unwrap().take() {
+            Some(receiver) => receiver,
+            None => return,
+        };
+        credit.state.lock().unwrap().routed = true;
+        credit.condvar.notify_all();
+
+        let credit = CloseOnDrop(credit.clone());
+        ROUTER.add_route(receiver.to_opaque(), Box::new(move |message| {
+            let mut state = credit.0.state.lock().unwrap();
+            if let Ok((id, amount)) = message.to::<Grant>() {
+                state.grant(credit.0.window, id, amount);
+            }
+            credit.0.condvar.notify_all();
+            credit.0.notify(state);
+        }));
+    }
+}
+
+/// Marks the credits closed when the router drops the route.
+struct CloseOnDrop(Arc<Credit>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.condvar.notify_all();
+    }
+}
+
+/// Sending end of one sub-channel.
+pub struct MuxSubSender<T> where T: Serialize {
+    id: u64,
+    sender: IpcSender<(u64, T)>,
+    credit: Option<Arc<Credit>>,
+}
+
+impl<T> MuxSubSender<T> where T: Serialize {
+    /// The sub-channel this sender writes to.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Send a message. With flow control, blocks while the sub-channel's
+    /// window is full.
+    pub fn send(&self, data: T) -> Result<(), bincode::Error> {
+        if let Some(ref credit) = self.credit {
+            credit.reserve(self.id, true, true)?;
+        }
+        self.sender.send((self.id, data))
+    }
+
+    /// Whether [send] can go ahead without blocking. Always true without
+    /// flow control.
+    ///
+    /// Another sender of the same sub-channel may take the credit first.
+    ///
+    /// [send]: #method.send
+    pub fn poll_ready(&self) -> Result<bool, bincode::Error> {
+        match self.credit {
+            Some(ref credit) => credit.reserve(self.id, false, false),
+            None => Ok(true),
+        }
+    }
+
+    /// Block until [send] can go ahead without blocking.
+    ///
+    /// [send]: #method.send
+    pub fn ready(&self) -> Result<(), bincode::Error> {
+        match self.credit {
+            Some(ref credit) => credit.reserve(self.id, true, false).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Call `handler` with `true` whenever this sub-channel runs out of
+    /// credits, and with `false` when it gets some again, so producers can
+    /// hold off instead of blocking in [send]. Replaces any handler set
+    /// before for the same sub-channel. Never called without flow control.
+    ///
+    /// Credits are then received on the [ROUTER] thread, where `false` is
+    /// reported from. A handler may use the sub-senders, but must not set
+    /// handlers itself.
+    ///
+    /// [send]: #method.send
+    /// [ROUTER]: ../router/struct.ROUTER.html
+    pub fn set_congestion_handler(&self, handler: CongestionHandler) {
+        if let Some(ref credit) = self.credit {
+            credit.handlers.lock().unwrap().insert(self.id, handler);
+            Credit::route(credit);
+        }
+    }
+}
+
+impl<T> Clone for MuxSubSender<T> where T: Serialize {
+    fn clone(&self) -> MuxSubSender<T> {
+        MuxSubSender {
+            id: self.id,
+            sender: self.sender.clone(),
+            credit: self.credit.clone(),
+        }
+    }
+}
+
+impl<T> Debug for MuxSubSender<T> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("MuxSubSender").field("id", &self.id).finish()
+    }
+}
+
+/// Receiving end of a multiplexed channel, handing out [MuxSubReceiver]s.
+///
+/// Sub-receivers can be used from different threads: whichever is waiting
+/// reads from the OS channel, and queues messages meant for the others.
+///
+/// [MuxSubReceiver]: struct.MuxSubReceiver.html
+#[derive(Clone)]
+pub struct MuxReceiver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    receiver: Mutex<IpcReceiver<()>>,
+    /// Window, and where to return credits, with flow control.
+    grants: Option<(u32, Mutex<IpcSender<Grant>>)>,
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    queues: HashMap<u64, VecDeque<OpaqueIpcMessage>>,
+    /// Whether a thread is currently receiving from the OS channel.
+    reading: bool,
+    /// Whether all senders are gone.
+    closed: bool,
+    /// Messages received per sub-channel, not yet returned as credits.
+    consumed: HashMap<u64, u32>,
+}
+
+impl MuxReceiver {
+    fn new(receiver: IpcReceiver<()>, grants: Option<(u32, IpcSender<Grant>)>) -> MuxReceiver {
+        MuxReceiver {
+            shared: Arc::new(Shared {
+                receiver: Mutex::new(receiver),
+                grants: grants.map(|(window, sender)| (window, Mutex::new(sender))),
+                state: Mutex::new(State::default()),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Get a receiver for sub-channel `id`.
+    pub fn sub_receiver<T>(&self, id: u64) -> MuxSubReceiver<T>
+                           where T: for<'de> Deserialize<'de> + Serialize {
+        MuxSubReceiver {
+            id,
+            shared: self.shared.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl Debug for MuxReceiver {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("MuxReceiver").finish()
+    }
+}
+
+impl Serialize for MuxReceiver {
+    /// Only a receiver no sub-receiver was taken from can be sent.
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        use serde::ser::Error;
+        if Arc::strong_count(&self.shared) != 1 {
+            return Err(S::Error::custom("cannot send a MuxReceiver that is in use"))
+        }
+        let receiver = self.shared.receiver.lock().unwrap();
+        match self.shared.grants {
+            None => (&*receiver, None::<(u32, IpcSender<Grant>)>).serialize(serializer),
+            Some((window, ref sender)) => {
+                let sender = sender.lock().unwrap();
+                let mut state = self.shared.state.lock().unwrap();
+                for grant in state.consumed.drain() {
+                    let _ = sender.send(grant);
+                }
+                (&*receiver, Some((window, &*sender))).serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MuxReceiver {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (receiver, grants): (IpcReceiver<()>, _) = Deserialize::deserialize(deserializer)?;
+        let receiver = receiver.with_bincode_config(BincodeConfig::new().metadata_header());
+        Ok(MuxReceiver::new(receiver, grants))
+    }
+}
+
+impl Shared {
+    fn receive(&self, id: u64, blocking: bool) -> Result<OpaqueIpcMessage, bincode::Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(message) = state.queues.get_mut(&id).and_then(VecDeque::pop_front) {
+                return Ok(message)
+            }
+            if state.closed {
+                return Err(Error::new(ErrorKind::ConnectionReset,
+                                      "multiplexed channel closed").into())
+            }
+            if state.reading {
+                if !blocking {
+                    return Err(Error::new(ErrorKind::WouldBlock,
+                                          "another thread is receiving").into())
+                }
+                state = self.condvar.wait(state).unwrap();
+                continue
+            }
+
+            state.reading = true;
+            drop(state);
+            let result = {
+                let receiver = self.receiver.lock().unwrap();
+                let message = if blocking {
+                    receiver.recv_opaque()
+                } else {
+                    receiver.try_recv_opaque()
+                };
+                // Take the rest of a transaction along, to queue it all at once.
+                message.and_then(|message| {
+                    let mut messages = vec![message];
+                    while receiver.has_transaction_parts() {
+                        messages.push(receiver.try_recv_opaque()?);
+                    }
+                    Ok(messages)
+                })
+            };
+            state = self.state.lock().unwrap();
+            state.reading = false;
+            self.condvar.notify_all();
+            let messages = match result {
+                Ok(messages) => messages,
+                Err(error) => {
+                    if is_closed(&error) {
+                        state.closed = true;
+                    }
+                    return Err(error)
+                }
+            };
+            for message in messages {
+                let message_id: u64 = bincode::deserialize(message.payload()?)?;
+                state.queues.entry(message_id).or_default().push_back(message);
+            }
+        }
+    }
+
+    /// Receive a message for sub-channel `id`, returning credits if due.
+    fn receive_and_grant(&self, id: u64, blocking: bool)
+                         -> Result<OpaqueIpcMessage, bincode::Error> {
+        let message = self.receive(id, blocking)?;
+        if let Some((window, ref sender)) = self.grants {
+            let amount = {
+                let mut state = self.state.lock().unwrap();
+                let consumed = state.consumed.entry(id).or_default();
+                *consumed += 1;
+                // Return credits in batches, but early enough that the
+                // senders can keep going.
+                if *consumed < (window / 2).max(1) {
+                    return Ok(message)
+                }
+                ::std::mem::replace(consumed, 0)
+            };
+            // The senders may be gone, which isn't a concern of the receiver.
+            let _ = sender.lock().unwrap().send((id, amount));
+        }
+        Ok(message)
+    }
+}
+
+fn is_closed(error: &bincode::Error) -> bool {
+    match **error {
+        bincode::ErrorKind::Io(ref error) => error.kind() == ErrorKind::ConnectionReset,
+        _ => false,
+    }
+}
+
+/// Receiving end of one sub-channel.
+pub struct MuxSubReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    id: u64,
+    shared: Arc<Shared>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> MuxSubReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// The sub-channel this receiver reads from.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Blocking receive.
+    pub fn recv(&self) -> Result<T, bincode::Error> {
+        let message = self.shared.receive_and_grant(self.id, true)?;
+        Ok(message.to::<(u64, T)>()?.1)
+    }
+
+    /// Non-blocking receive
+    pub fn try_recv(&self) -> Result<T, bincode::Error> {
+        let message = self.shared.receive_and_grant(self.id, false)?;
+        Ok(message.to::<(u64, T)>()?.1)
+    }
+}
+
+impl<T> Debug for MuxSubReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("MuxSubReceiver").field("id", &self.id).finish()
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Deciding which of several processes becomes the server for a well-known name.
+//!
+//! Each process tries to take the [IpcNamedLock] of the name; the one that gets it
+//! starts a one-shot server and [publish]es its name under the lock, and the others
+//! [look up] that name to connect to it. The lock is released when dropped, or
+//! when its process exits, so a new server can take over from one that died.
+//!
+//! On Unix, the lock is an `flock()` on a file named after the lock in
+//! `$XDG_RUNTIME_DIR`, or the temporary directory if that isn't set. Lock files
+//! that are symbolic links, or belong to other users, e.g. as they were planted in
+//! a shared temporary directory, are refused with `ErrorKind::PermissionDenied`
+//! rather than followed or trusted. On Windows,
+//! channels only work within a process, and so do these locks.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
+//! # use ipc_channel::named_lock::IpcNamedLock;
+//! # let lock_name = format!("named-lock-doc-{}", std::process::id());
+//! match IpcNamedLock::try_lock(&lock_name).unwrap() {
+//!     Some(lock) => {
+//!         let (server, server_name) = IpcOneShotServer::<String>::new().unwrap();
+//!         lock.publish(&server_name).unwrap();
+//!         // Serve clients, keeping the lock meanwhile.
+//! #       drop(server);
+//!     }
+//!     None => {
+//!         let server_name = IpcNamedLock::published(&lock_name).unwrap().unwrap();
+//!         let sender = IpcSender::<String>::connect(server_name).unwrap();
+//! #       drop(sender);
+//!     }
+//! }
+//! ```
+//!
+//! [IpcNamedLock]: struct.IpcNamedLock.html
+//! [publish]: struct.IpcNamedLock.html#method.publish
+//! [look up]: struct.IpcNamedLock.html#method.published
+
+#[cfg(unix)]
+use libc;
+#[cfg(not(unix))]
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::env;
+use std::fmt::{self, Debug, Formatter};
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+#[cfg(unix)]
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(not(unix))]
+use std::sync::Mutex;
+
+#[cfg(not(unix))]
+lazy_static! {
+    // The locks held in this process, with what was published under each.
+    static ref LOCKS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// An exclusive claim on a name, held until dropped.
+pub struct IpcNamedLock {
+    name: String,
+    #[cfg(unix)]
+    file: File,
+}
+
+impl IpcNamedLock {
+    /// Take the lock named `name`, or return `None` if another process, or another
+    /// lock in this one, holds it.
+    ///
+    /// Names are made of ASCII letters, digits, `-`, `_` and `.`, and name the same
+    /// lock for all processes of the user. Other names fail with
+    /// `ErrorKind::InvalidInput`.
+    pub fn try_lock(name: &str) -> Result<Option<IpcNamedLock>, Error> {
+        check_name(name)?;
+        IpcNamedLock::try_lock_checked(name)
+    }
+
+    #[cfg(unix)]
+    fn try_lock_checked(name: &str) -> Result<Option<IpcNamedLock>, Error> {
+        let file = open_lock_file(OpenOptions::new().read(true)
+                                                    .write(true)
+                                                    .create(true)
+                                                    .truncate(false)
+                                                    .mode(0o600),
+                                  name)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let error = Error::last_os_error();
+            if error.kind() == ErrorKind::WouldBlock {
+                return Ok(None)
+            }
+            return Err(error)
+        }
+        // Whatever a previous holder published is stale.
+        file.set_len(0)?;
+        Ok(Some(IpcNamedLock {
+            name: name.to_owned(),
+            file,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    fn try_lock_checked(name: &str) -> Result<Option<IpcNamedLock>, Error> {
+        let mut locks = LOCKS.lock().unwrap();
+        if locks.contains_key(name) {
+            return Ok(None)
+        }
+        locks.insert(name.to_owned(), String::new());
+        Ok(Some(IpcNamedLock {
+            name: name.to_owned(),
+        }))
+    }
+
+    /// The name of the lock.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Publish `server_name` under the lock, for other processes to look up with
+    /// [published], replacing what was published before.
+    ///
+    /// [published]: #method.published
+    #[cfg(unix)]
+    pub fn publish(&self, server_name: &str) -> Result<(), Error> {
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(server_name.as_bytes())
+    }
+
+    /// Publish `server_name` under the lock, for other processes to look up with
+    /// [published], replacing what was published before.
+    ///
+    /// [published]: #method.published
+    #[cfg(not(unix))]
+    pub fn publish(&self, server_name: &str) -> Result<(), Error> {
+        LOCKS.lock().unwrap().insert(self.name.clone(), server_name.to_owned());
+        Ok(())
+    }
+
+    /// What the holder of the lock named `name` published, or `None` if nothing was,
+    /// e.g. as it has yet to start its server.
+    ///
+    /// This doesn't check the lock is held, so what was published by a holder that
+    /// died may be returned: connecting to it then fails.
+    #[cfg(unix)]
+    pub fn published(name: &str) -> Result<Option<String>, Error> {
+        check_name(name)?;
+        let mut published = String::new();
+        match open_lock_file(OpenOptions::new().read(true), name) {
+            Ok(mut file) => file.read_to_string(&mut published)?,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        Ok(Some(published).filter(|published| !published.is_empty()))
+    }
+
+    /// What the holder of the lock named `name` published, or `None` if nothing was,
+    /// e.g. as it has yet to start its server.
+    #[cfg(not(unix))]
+    pub fn published(name: &str) -> Result<Option<String>, Error> {
+        check_name(name)?;
+        Ok(LOCKS.lock().unwrap().get(name).cloned().filter(|published| !published.is_empty()))
+    }
+}
+
+#[cfg(not(unix))]
+impl Drop for IpcNamedLock {
+    fn drop(&mut self) {
+        LOCKS.lock().unwrap().remove(&self.name);
+    }
+}
+
+impl Debug for IpcNamedLock {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcNamedLock")
+                 .field("name", &self.name)
+                 .finish()
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty() && !name.starts_with('.') && name.bytes().all(|byte| {
+        byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' || byte == b'.'
+    });
+    if !valid {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid lock name {:?}", name)))
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lock_path(name: &str) -> PathBuf {
+    let directory = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+                                                  .unwrap_or_else(env::temp_dir);
+    directory.join(format!("ipc-channel-{}.lock", name))
+}
+
+/// Open the lock file of `name`, without following symbolic links, and check it is a
+/// file of the user's own.
+#[cfg(unix)]
+fn open_lock_file(options: &mut OpenOptions, name: &str) -> Result<File, Error> {
+    let file = match options.custom_flags(libc::O_NOFOLLOW).open(lock_path(name)) {
+        Err(ref error) if error.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("the lock file of {:?} is a symbolic link", name)))
+        }
+        result => result?,
+    };
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+                              format!("the lock file of {:?} isn't the user's own", name)))
+    }
+    Ok(file)
+}
@@ -0,0 +1,127 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Endpoints of one-shot channels, created with [ipc::oneshot].
+//!
+//! [ipc::oneshot]: ../ipc/fn.oneshot.html
+
+use bincode;
+use ipc::{self, IpcBytesReceiver, IpcBytesSender};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::ser::Error as SerError;
+use std::cell::Cell;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+
+pub(crate) fn channel<T>() -> Result<(IpcOneshotSender<T>, IpcOneshotReceiver<T>), Error>
+                         where T: for<'de> Deserialize<'de> + Serialize {
+    let (sender, receiver) = ipc::bytes_channel()?;
+    let sender = IpcOneshotSender {
+        sender,
+        transferred: Cell::new(false),
+        phantom: PhantomData,
+    };
+    Ok((sender, IpcOneshotReceiver { receiver, phantom: PhantomData }))
+}
+
+/// Sending end of a [one-shot channel].
+///
+/// The sender can't be cloned, and [send] consumes it, so at most one message
+/// is ever delivered. Dropping it without sending makes the receiver fail.
+///
+/// Serializing the sender, to send it to another process, transfers the right to
+/// send: serializing it again fails, and so does [send] on the original.
+///
+/// [one-shot channel]: ../ipc/fn.oneshot.html
+/// [send]: #method.send
+pub struct IpcOneshotSender<T> where T: Serialize {
+    sender: IpcBytesSender,
+    transferred: Cell<bool>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcOneshotSender<T> where T: Serialize {
+    /// Send the value, closing the channel.
+    pub fn send(self, value: T) -> Result<(), bincode::Error> {
+        if self.transferred.get() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "one-shot sender was transferred").into())
+        }
+        self.sender.send_message(&value)
+    }
+}
+
+impl<T> Debug for IpcOneshotSender<T> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcOneshotSender")
+                 .field("transferred", &self.transferred.get())
+                 .finish()
+    }
+}
+
+impl<T> Serialize for IpcOneshotSender<T> where T: Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        if self.transferred.replace(true) {
+            return Err(S::Error::custom("one-shot sender was already transferred"))
+        }
+        self.sender.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcOneshotSender<T> where T: Serialize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcOneshotSender {
+            sender: Deserialize::deserialize(deserializer)?,
+            transferred: Cell::new(false),
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// Receiving end of a [one-shot channel].
+///
+/// [one-shot channel]: ../ipc/fn.oneshot.html
+pub struct IpcOneshotReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    receiver: IpcBytesReceiver,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcOneshotReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// Block until the value is sent, and return it.
+    ///
+    /// The receiver is consumed, releasing the underlying channel as soon as the
+    /// message is in, before deserializing it. Fails if the sender was dropped
+    /// without sending.
+    pub fn recv(self) -> Result<T, bincode::Error> {
+        self.receiver.recv_message()
+    }
+}
+
+impl<T> Debug for IpcOneshotReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcOneshotReceiver").finish()
+    }
+}
+
+impl<T> Serialize for IpcOneshotReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.receiver.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcOneshotReceiver<T>
+                              where T: for<'dde> Deserialize<'dde> + Serialize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcOneshotReceiver {
+            receiver: Deserialize::deserialize(deserializer)?,
+            phantom: PhantomData,
+        })
+    }
+}
@@ -8,18 +8,30 @@
 // except according to those terms.
 
 use bincode;
-use crossbeam_channel::{self, Receiver, Select, Sender, TryRecvError};
-use std::sync::{Arc, Mutex};
+use diagnostics::{IpcDiagnostics, ServerDiagnostics};
+#[cfg(unix)]
+use libc;
+use super::OsIpcPeerCredentials;
+use router::QosClass;
+use std::any::{Any, TypeId};
+use self::sync::{Receiver, RecvTimeoutError, Select, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, Weak};
 use std::collections::hash_map::HashMap;
-use std::cell::{RefCell, Ref};
+use std::cell::{Cell, RefCell, Ref, UnsafeCell};
 use std::io::{Error, ErrorKind};
 use std::slice;
 use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use std::mem;
 use std::cmp::{PartialEq};
 use std::ops::{Deref, RangeFrom};
+use std::process;
+use std::time::Duration;
 use std::usize;
 use uuid::Uuid;
 
+mod sync;
+
 #[derive(Clone)]
 struct ServerRecord {
     sender: OsIpcSender,
@@ -29,7 +41,7 @@ struct ServerRecord {
 
 impl ServerRecord {
     fn new(sender: OsIpcSender) -> ServerRecord {
-        let (tx, rx) = crossbeam_channel::unbounded::<bool>();
+        let (tx, rx) = sync::unbounded::<bool>();
         ServerRecord {
             sender: sender,
             conn_sender: tx,
@@ -50,19 +62,74 @@ lazy_static! {
     static ref ONE_SHOT_SERVERS: Mutex<HashMap<String,ServerRecord>> = Mutex::new(HashMap::new());
 }
 
-struct ChannelMessage(Vec<u8>, Vec<OsIpcChannel>, Vec<OsIpcSharedMemory>);
+struct ChannelMessage(Vec<u8>, Vec<OsIpcChannel>, Vec<OsIpcSharedMemory>,
+                      Option<Box<dyn OsIpcLocalPayload>>);
+
+/// The data, channels and shared memory regions of a received message.
+type SerializedMessage = (Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
+impl ChannelMessage {
+    /// The message in serialized form, serializing a local payload after all.
+    fn materialize(self) -> Result<SerializedMessage, ChannelError> {
+        let ChannelMessage(mut data, mut channels, mut shared_memory_regions, payload) = self;
+        if let Some(payload) = payload {
+            let (payload_channels, payload_shared_memory_regions) =
+                payload.serialize(&mut data).map_err(ChannelError::SerializationError)?;
+            channels.extend(payload_channels);
+            shared_memory_regions.extend(payload_shared_memory_regions);
+        }
+        Ok((data, channels.into_iter().map(OsOpaqueIpcChannel::new).collect(), shared_memory_regions))
+    }
+
+    fn into_local(self, type_id: TypeId) -> Result<OsIpcLocalMessage, ChannelError> {
+        if self.3.as_ref().is_some_and(|payload| payload.payload_type_id() == type_id) {
+            let ChannelMessage(_, _, _, payload) = self;
+            return Ok(OsIpcLocalMessage::Local(payload.unwrap().into_any()))
+        }
+        let (data, channels, shared_memory_regions) = self.materialize()?;
+        Ok(OsIpcLocalMessage::Serialized(data, channels, shared_memory_regions))
+    }
+}
+
+/// A value sent as is rather than serialized, as both ends of the channel are in this process.
+pub trait OsIpcLocalPayload: Send {
+    fn payload_type_id(&self) -> TypeId;
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+
+    /// Serialize the value after all, appending it to `data`.
+    fn serialize(self: Box<Self>, data: &mut Vec<u8>)
+                 -> Result<(Vec<OsIpcChannel>, Vec<OsIpcSharedMemory>), bincode::Error>;
+}
+
+/// A message received with `recv_local()`.
+pub enum OsIpcLocalMessage {
+    Local(Box<dyn Any>),
+    Serialized(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+}
 
 pub fn channel() -> Result<(OsIpcSender, OsIpcReceiver), ChannelError> {
-    let (base_sender, base_receiver) = crossbeam_channel::unbounded::<ChannelMessage>();
+    let (base_sender, base_receiver) = sync::unbounded::<ChannelMessage>();
+    let (base_sender, receiver_token) = (Arc::new(base_sender), Arc::new(()));
     Ok((
-        OsIpcSender::new(base_sender),
-        OsIpcReceiver::new(base_receiver)
+        OsIpcSender::new(base_sender.clone(), Arc::downgrade(&receiver_token)),
+        OsIpcReceiver::new(base_receiver, receiver_token, Arc::downgrade(&base_sender))
     ))
 }
 
+/// Channels queue messages in memory without a limit, so there are no buffers to size.
+pub fn channel_with_buffer_sizes(_send_buffer: Option<usize>, _recv_buffer: Option<usize>)
+                                 -> Result<(OsIpcSender, OsIpcReceiver), ChannelError> {
+    channel()
+}
+
 #[derive(Debug)]
 pub struct OsIpcReceiver {
-    receiver: RefCell<Option<crossbeam_channel::Receiver<ChannelMessage>>>,
+    receiver: RefCell<Option<Receiver<ChannelMessage>>>,
+    /// Each receiver holds a reference, and each sender a weak one, so senders can tell
+    /// whether any receiver is left; `senders` is the same the other way around.
+    receiver_token: RefCell<Option<Arc<()>>>,
+    senders: Weak<Sender<ChannelMessage>>,
 }
 
 impl PartialEq for OsIpcReceiver {
@@ -73,12 +140,47 @@ impl PartialEq for OsIpcReceiver {
 }
 
 impl OsIpcReceiver {
-    fn new(receiver: Receiver<ChannelMessage>) -> OsIpcReceiver {
-        OsIpcReceiver { receiver: RefCell::new(Some(receiver)) }
+    fn new(receiver: Receiver<ChannelMessage>,
+           receiver_token: Arc<()>,
+           senders: Weak<Sender<ChannelMessage>>)
+           -> OsIpcReceiver {
+        OsIpcReceiver {
+            receiver: RefCell::new(Some(receiver)),
+            receiver_token: RefCell::new(Some(receiver_token)),
+            senders,
+        }
     }
 
     pub fn consume(&self) -> OsIpcReceiver {
-        OsIpcReceiver { receiver: RefCell::new(self.receiver.borrow_mut().take()) }
+        OsIpcReceiver {
+            receiver: RefCell::new(self.receiver.borrow_mut().take()),
+            receiver_token: RefCell::new(self.receiver_token.borrow_mut().take()),
+            senders: self.senders.clone(),
+        }
+    }
+
+    /// All senders are in this process, so they can be counted exactly.
+    pub fn sender_count(&self) -> Option<usize> {
+        Some(self.senders.strong_count())
+    }
+
+    /// Peers can't die separately from this process.
+    pub fn watch_peer(&self, _: u32) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "peers are threads of this process"))
+    }
+
+    /// Channels don't leave this process.
+    pub fn prepare_for_inheritance(&self) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "in-process channels can't be inherited"))
+    }
+
+    /// Another receiver for the same queue, each message going to one of them.
+    pub fn try_duplicate(&self) -> Result<OsIpcReceiver, ChannelError> {
+        Ok(OsIpcReceiver {
+            receiver: RefCell::new(self.receiver.borrow().clone()),
+            receiver_token: RefCell::new(self.receiver_token.borrow().clone()),
+            senders: self.senders.clone(),
+        })
     }
 
     pub fn recv(
@@ -87,9 +189,7 @@ impl OsIpcReceiver {
         let r = self.receiver.borrow();
         let r = r.as_ref().unwrap();
         match r.recv() {
-            Ok(ChannelMessage(d, c, s)) => {
-                Ok((d, c.into_iter().map(OsOpaqueIpcChannel::new).collect(), s))
-            }
+            Ok(message) => message.materialize(),
             Err(_) => Err(ChannelError::ChannelClosedError),
         }
     }
@@ -100,40 +200,123 @@ impl OsIpcReceiver {
         let r = self.receiver.borrow();
         let r = r.as_ref().unwrap();
         match r.try_recv() {
-            Ok(ChannelMessage(d, c, s)) => {
-                Ok((d, c.into_iter().map(OsOpaqueIpcChannel::new).collect(), s))
-            },
+            Ok(message) => message.materialize(),
             Err(e) => {
                 match e {
-                    TryRecvError::Empty => Err(ChannelError::UnknownError),
+                    TryRecvError::Empty => Err(ChannelError::WouldBlockError),
                     TryRecvError::Disconnected => Err(ChannelError::ChannelClosedError),
                 }
             }
         }
     }
+
+    /// Blocking receive, giving up with `TimedOutError` after `duration`.
+    pub fn try_recv_timeout(
+        &self,
+        duration: Duration,
+    ) -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>), ChannelError> {
+        let r = self.receiver.borrow();
+        let r = r.as_ref().unwrap();
+        match r.recv_timeout(duration) {
+            Ok(message) => message.materialize(),
+            Err(RecvTimeoutError::Timeout) => Err(ChannelError::TimedOutError),
+            Err(RecvTimeoutError::Disconnected) => Err(ChannelError::ChannelClosedError),
+        }
+    }
+
+    /// Blocking receive, keeping a value sent with `send_local()` as is if it is of the type
+    /// `type_id`.
+    pub fn recv_local(&self, type_id: TypeId) -> Result<OsIpcLocalMessage, ChannelError> {
+        let r = self.receiver.borrow();
+        let r = r.as_ref().unwrap();
+        match r.recv() {
+            Ok(message) => message.into_local(type_id),
+            Err(_) => Err(ChannelError::ChannelClosedError),
+        }
+    }
+
+    pub fn try_recv_local(&self, type_id: TypeId) -> Result<OsIpcLocalMessage, ChannelError> {
+        let r = self.receiver.borrow();
+        let r = r.as_ref().unwrap();
+        match r.try_recv() {
+            Ok(message) => message.into_local(type_id),
+            Err(TryRecvError::Empty) => Err(ChannelError::WouldBlockError),
+            Err(TryRecvError::Disconnected) => Err(ChannelError::ChannelClosedError),
+        }
+    }
+
+    /// Blocking receive of a message into shared memory. The data was never serialized out of
+    /// the process, so this is just a copy.
+    pub fn recv_bulk(
+        &self
+    ) -> Result<(OsIpcSharedMemory, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>), ChannelError> {
+        let (data, channels, shared_memory_regions) = self.recv()?;
+        Ok((OsIpcSharedMemory::from_bytes(&data), channels, shared_memory_regions))
+    }
 }
 
+/// The senders of a channel share one `Sender`, which weak senders can refer to without
+/// keeping the channel open.
 #[derive(Clone, Debug)]
 pub struct OsIpcSender {
-    sender: RefCell<Sender<ChannelMessage>>,
+    sender: Arc<Sender<ChannelMessage>>,
+    /// See `OsIpcReceiver::receiver_token`.
+    receiver_token: Weak<()>,
+    // Make sure this is `!Sync`, like the senders of the other backends.
+    nosync_marker: PhantomData<Cell<()>>,
 }
 
 impl PartialEq for OsIpcSender {
     fn eq(&self, other: &OsIpcSender) -> bool {
-        &*self.sender.borrow() as *const _ ==
-            &*other.sender.borrow() as *const _
+        Arc::ptr_eq(&self.sender, &other.sender)
     }
 }
 
 impl OsIpcSender {
-    fn new(sender: Sender<ChannelMessage>) -> OsIpcSender {
+    fn new(sender: Arc<Sender<ChannelMessage>>, receiver_token: Weak<()>) -> OsIpcSender {
         OsIpcSender {
-            sender: RefCell::new(sender),
+            sender,
+            receiver_token,
+            nosync_marker: PhantomData,
         }
     }
 
+    pub fn downgrade(&self) -> Result<OsIpcWeakSender, Error> {
+        Ok(OsIpcWeakSender {
+            sender: Arc::downgrade(&self.sender),
+            receiver_token: self.receiver_token.clone(),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.receiver_token.strong_count() > 0
+    }
+
+    pub fn set_qos_override(&self, _: Option<QosClass>) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "in-process channels don't carry a QoS class"))
+    }
+
+    /// Channels don't leave this process.
+    pub fn to_env(&self, _: &str, _: &mut process::Command) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "in-process channels can't be inherited"))
+    }
+
+    pub fn from_env(_: &str) -> Result<OsIpcSender, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "in-process channels can't be inherited"))
+    }
+
+    pub fn prepare_for_inheritance(&self) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "in-process channels can't be inherited"))
+    }
+
+    /// Fails if there is no server by that name, or it was dropped, or it already
+    /// accepted a client.
     pub fn connect(name: String) -> Result<OsIpcSender, ChannelError> {
-        let record = ONE_SHOT_SERVERS.lock().unwrap().get(&name).unwrap().clone();
+        let record = ONE_SHOT_SERVERS.lock()
+                                     .unwrap()
+                                     .get(&name)
+                                     .cloned()
+                                     .ok_or(ChannelError::NotFoundError)?;
         record.connect();
         Ok(record.sender)
     }
@@ -147,10 +330,39 @@ impl OsIpcSender {
         data: &[u8],
         ports: Vec<OsIpcChannel>,
         shared_memory_regions: Vec<OsIpcSharedMemory>,
+    ) -> Result<(), ChannelError> {
+        self.send_vec(data.to_vec(), ports, shared_memory_regions)
+    }
+
+    /// Like `send()`, but moves `data` to the receiver instead of copying it.
+    pub fn send_vec(
+        &self,
+        data: Vec<u8>,
+        ports: Vec<OsIpcChannel>,
+        shared_memory_regions: Vec<OsIpcSharedMemory>,
     ) -> Result<(), ChannelError> {
         Ok(self.sender
-            .borrow()
-            .send(ChannelMessage(data.to_vec(), ports, shared_memory_regions)).map_err(|_| ChannelError::BrokenPipeError)?)
+            .send(ChannelMessage(data, ports, shared_memory_regions, None)).map_err(|_| ChannelError::BrokenPipeError)?)
+    }
+
+    /// Send `payload` as is, along with `data`.
+    pub fn send_local(&self, data: Vec<u8>, payload: Box<dyn OsIpcLocalPayload>)
+                      -> Result<(), ChannelError> {
+        self.sender
+            .send(ChannelMessage(data, vec![], vec![], Some(payload))).map_err(|_| ChannelError::BrokenPipeError)
+    }
+}
+
+/// A sender that doesn't keep the channel open, from `OsIpcSender::downgrade()`.
+#[derive(Clone, Debug)]
+pub struct OsIpcWeakSender {
+    sender: Weak<Sender<ChannelMessage>>,
+    receiver_token: Weak<()>,
+}
+
+impl OsIpcWeakSender {
+    pub fn upgrade(&self) -> Option<OsIpcSender> {
+        Some(OsIpcSender::new(self.sender.upgrade()?, self.receiver_token.clone()))
     }
 }
 
@@ -180,6 +392,25 @@ impl OsIpcReceiverSet {
         if self.receivers.is_empty() {
             return Err(ChannelError::UnknownError);
         }
+        self.select_with_timeout(None)
+    }
+
+    /// Like `select()`, returning no results rather than waiting if no receiver is ready.
+    pub fn try_select(&mut self) -> Result<Vec<OsIpcSelectionResult>, ChannelError> {
+        self.select_with_timeout(Some(Duration::from_millis(0)))
+    }
+
+    /// Like `select()`, returning no results if no receiver is ready within `duration`.
+    pub fn select_timeout(&mut self, duration: Duration)
+                          -> Result<Vec<OsIpcSelectionResult>, ChannelError> {
+        if self.receivers.is_empty() {
+            return Err(ChannelError::UnknownError);
+        }
+        self.select_with_timeout(Some(duration))
+    }
+
+    fn select_with_timeout(&mut self, timeout: Option<Duration>)
+                           -> Result<Vec<OsIpcSelectionResult>, ChannelError> {
 
         struct Remove(usize, u64);
 
@@ -193,11 +424,17 @@ impl OsIpcReceiverSet {
             for r in &borrows {
                 select.recv(&r);
             }
-            let res = select.select();
+            let res = match timeout {
+                None => select.select(),
+                Some(timeout) => match select.select_timeout(timeout) {
+                    Ok(res) => res,
+                    Err(_) => return Ok(vec![]),
+                },
+            };
             let r_index = res.index();
             let r_id = self.receiver_ids[r_index];
-            if let Ok(ChannelMessage(data, channels, shmems)) = res.recv(&borrows[r_index as usize]) {
-                let channels = channels.into_iter().map(OsOpaqueIpcChannel::new).collect();
+            if let Ok(message) = res.recv(&borrows[r_index as usize]) {
+                let (data, channels, shmems) = message.materialize()?;
                 return Ok(vec![OsIpcSelectionResult::DataReceived(r_id, data, channels, shmems)])
             } else {
                 Remove(r_index, r_id)
@@ -227,6 +464,32 @@ impl OsIpcSelectionResult {
     }
 }
 
+/// Only the one-shot servers are listed, by name; channels and shared memory
+/// regions are plain heap objects here.
+pub fn diagnose(report: &mut IpcDiagnostics) -> Result<(), Error> {
+    let servers = ONE_SHOT_SERVERS.lock().unwrap();
+    report.servers.extend(servers.keys().map(|name| ServerDiagnostics {
+        handle: None,
+        name: name.clone(),
+    }));
+    Ok(())
+}
+
+/// One-shot servers are names within the process here, which a checkpoint keeps.
+pub fn rebind_one_shot_servers() -> Result<usize, Error> {
+    Ok(0)
+}
+
+/// Forget all one-shot servers, so nothing can connect to them anymore, and those
+/// waiting to accept a client fail.
+#[cfg(feature = "test-support")]
+pub fn reset_one_shot_servers() {
+    ONE_SHOT_SERVERS.lock().unwrap().clear();
+}
+
+/// The receiver and first message of a client accepted by an `OsIpcOneShotServer`.
+type AcceptedClient = (OsIpcReceiver, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
 pub struct OsIpcOneShotServer {
     receiver: OsIpcReceiver,
     name: String,
@@ -245,6 +508,11 @@ impl OsIpcOneShotServer {
         },name.clone()))
     }
 
+    /// There are no sockets to adopt.
+    pub fn from_listen_fds() -> Result<Vec<OsIpcOneShotServer>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "in-process channels can't be passed by systemd"))
+    }
+
     pub fn accept(
         self,
     ) -> Result<
@@ -260,12 +528,57 @@ impl OsIpcOneShotServer {
             .lock()
             .unwrap()
             .get(&self.name)
-            .unwrap()
-            .clone();
+            .cloned()
+            .ok_or(ChannelError::NotFoundError)?;
         record.accept();
-        ONE_SHOT_SERVERS.lock().unwrap().remove(&self.name).unwrap();
-        let (data, channels, shmems) = self.receiver.recv()?;
-        Ok((self.receiver, data, channels, shmems))
+        ONE_SHOT_SERVERS.lock().unwrap().remove(&self.name);
+        let receiver = self.receiver.consume();
+        let (data, channels, shmems) = receiver.recv()?;
+        Ok((receiver, data, channels, shmems))
+    }
+
+    /// Like `accept()`, if `policy` accepts the credentials of this process, which
+    /// every client shares; fails with `PermissionDeniedError` without accepting
+    /// anyone otherwise, as no client could be accepted.
+    pub fn accept_with_policy<F>(
+        self,
+        mut policy: F,
+    ) -> Result<AcceptedClient, ChannelError>
+    where
+        F: FnMut(&OsIpcPeerCredentials) -> bool,
+    {
+        if !policy(&own_credentials()) {
+            return Err(ChannelError::PermissionDeniedError);
+        }
+        self.accept()
+    }
+}
+
+impl Drop for OsIpcOneShotServer {
+    fn drop(&mut self) {
+        if let Ok(mut servers) = ONE_SHOT_SERVERS.lock() {
+            servers.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn own_credentials() -> OsIpcPeerCredentials {
+    unsafe {
+        OsIpcPeerCredentials {
+            pid: Some(process::id()),
+            uid: Some(libc::getuid()),
+            gid: Some(libc::getgid()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn own_credentials() -> OsIpcPeerCredentials {
+    OsIpcPeerCredentials {
+        pid: Some(process::id()),
+        uid: None,
+        gid: None,
     }
 }
 
@@ -300,22 +613,31 @@ impl OsOpaqueIpcChannel {
             OsIpcChannel::Receiver(_) => panic!("Opaque channel is not a sender!"),
         }
     }
+
+    /// Take the channel as whichever end it is, e.g. to send it on.
+    pub fn to_channel(&mut self) -> OsIpcChannel {
+        self.channel.borrow_mut().take().unwrap()
+    }
+
+    /// The opaque form of `channel`, as if it had been received in a message.
+    pub fn from_channel(channel: OsIpcChannel) -> Result<OsOpaqueIpcChannel,ChannelError> {
+        Ok(OsOpaqueIpcChannel::new(channel))
+    }
 }
 
+/// The bytes are cells, as `wipe()` writes to them through any clone.
 pub struct OsIpcSharedMemory {
-    ptr: *mut u8,
-    length: usize,
-    data: Arc<Vec<u8>>,
+    data: Arc<[UnsafeCell<u8>]>,
 }
 
+// Like a mapping shared with another process, a region can be read from any thread, and it's
+// up to its users not to wipe it while reading it elsewhere.
 unsafe impl Send for OsIpcSharedMemory {}
 unsafe impl Sync for OsIpcSharedMemory {}
 
 impl Clone for OsIpcSharedMemory {
     fn clone(&self) -> OsIpcSharedMemory {
         OsIpcSharedMemory {
-            ptr: self.ptr,
-            length: self.length,
             data: self.data.clone(),
         }
     }
@@ -338,40 +660,103 @@ impl Deref for OsIpcSharedMemory {
 
     #[inline]
     fn deref(&self) -> &[u8] {
-        if self.ptr.is_null() {
-            panic!("attempted to access a consumed `OsIpcSharedMemory`")
-        }
+        // `UnsafeCell<u8>` has the layout of `u8`.
         unsafe {
-            slice::from_raw_parts(self.ptr, self.length)
+            slice::from_raw_parts(self.data.as_ptr() as *const u8, self.data.len())
         }
     }
 }
 
 impl OsIpcSharedMemory {
-    pub fn from_byte(byte: u8, length: usize) -> OsIpcSharedMemory {
-        let mut v = Arc::new(vec![byte; length]);
+    fn from_vec(bytes: Vec<u8>) -> OsIpcSharedMemory {
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+        // `UnsafeCell<u8>` has the layout of `u8`.
+        let cells = unsafe { Box::from_raw(bytes as *mut [UnsafeCell<u8>]) };
         OsIpcSharedMemory {
-            ptr: Arc::get_mut(&mut v).unwrap().as_mut_ptr(),
-            length: length,
-            data: v
+            data: Arc::from(cells),
         }
     }
 
+    pub fn from_byte(byte: u8, length: usize) -> OsIpcSharedMemory {
+        OsIpcSharedMemory::from_vec(vec![byte; length])
+    }
+
+    pub fn try_from_byte(byte: u8, length: usize) -> Result<OsIpcSharedMemory,ChannelError> {
+        Ok(OsIpcSharedMemory::from_byte(byte, length))
+    }
+
+    pub fn zeroed(length: usize) -> OsIpcSharedMemory {
+        OsIpcSharedMemory::from_vec(vec![0; length])
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> OsIpcSharedMemory {
-        let mut v = Arc::new(bytes.to_vec());
-        OsIpcSharedMemory {
-            ptr: Arc::get_mut(&mut v).unwrap().as_mut_ptr(),
-            length: v.len(),
-            data: v
+        OsIpcSharedMemory::from_vec(bytes.to_vec())
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<OsIpcSharedMemory,ChannelError> {
+        Ok(OsIpcSharedMemory::from_bytes(bytes))
+    }
+
+    /// Overwrite the contents with zeros, as seen by every mapping of the region.
+    pub fn wipe(&self) {
+        // Writing through a pointer derived from the cells is allowed, unlike through one
+        // derived from plain bytes that may be borrowed elsewhere.
+        unsafe {
+            super::wipe_bytes(UnsafeCell::raw_get(self.data.as_ptr()), self.data.len())
         }
     }
+
+    pub fn check_revocable() -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Every receiver shares the same heap memory, so wiping it revokes the contents.
+    pub fn revoke(&self) -> Result<(), Error> {
+        self.wipe();
+        Ok(())
+    }
+
+    /// Regions are plain heap memory here, which `mlock()` wouldn't release when freed.
+    pub fn lock(&self) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported,
+                       "locking shared memory is not supported by the in-process backend"))
+    }
+
+    pub fn set_numa_node(&self, _: usize) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported,
+                       "NUMA placement is not supported by the in-process backend"))
+    }
+
+    pub fn numa_nodes(&self) -> Result<Vec<usize>, Error> {
+        Err(Error::new(ErrorKind::Unsupported,
+                       "NUMA placement is not supported by the in-process backend"))
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum ChannelError {
     ChannelClosedError,
     BrokenPipeError,
+    TimedOutError,
+    WouldBlockError,
+    NotFoundError,
+    /// The policy of `OsIpcOneShotServer::accept_with_policy()` rejected this process.
+    PermissionDeniedError,
     UnknownError,
+    /// Serializing a local payload failed, as it had to be received serialized after all.
+    SerializationError(bincode::Error),
+}
+
+impl PartialEq for ChannelError {
+    fn eq(&self, other: &ChannelError) -> bool {
+        match (self, other) {
+            (ChannelError::SerializationError(error),
+             ChannelError::SerializationError(other_error)) => {
+                error.to_string() == other_error.to_string()
+            }
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
+    }
 }
 
 impl ChannelError {
@@ -383,7 +768,10 @@ impl ChannelError {
 
 impl From<ChannelError> for bincode::Error {
     fn from(crossbeam_error: ChannelError) -> Self {
-        Error::from(crossbeam_error).into()
+        match crossbeam_error {
+            ChannelError::SerializationError(error) => error,
+            crossbeam_error => Error::from(crossbeam_error).into(),
+        }
     }
 }
 
@@ -396,9 +784,25 @@ impl From<ChannelError> for Error {
             ChannelError::BrokenPipeError => {
                 Error::new(ErrorKind::BrokenPipe, "crossbeam-channel receiver closed")
             }
+            ChannelError::TimedOutError => {
+                Error::new(ErrorKind::TimedOut, "crossbeam-channel receive timed out")
+            }
+            ChannelError::WouldBlockError => {
+                Error::new(ErrorKind::WouldBlock, "crossbeam-channel is empty")
+            }
+            ChannelError::NotFoundError => {
+                Error::new(ErrorKind::NotFound, "no one-shot server by that name")
+            }
+            ChannelError::PermissionDeniedError => {
+                Error::new(ErrorKind::PermissionDenied, "the policy rejected this process")
+            }
             ChannelError::UnknownError => {
                 Error::new(ErrorKind::Other, "Other crossbeam-channel error")
             }
+            ChannelError::SerializationError(error) => match *error {
+                bincode::ErrorKind::Io(error) => error,
+                error => Error::new(ErrorKind::InvalidData, error),
+            },
         }
     }
 }
@@ -0,0 +1,264 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The channels the inprocess backend is built on.
+//!
+//! These are crossbeam's, except when building with `RUSTFLAGS="--cfg loom"`:
+//! loom can't see into crossbeam, so a plain mutex and condition variable
+//! channel with the same interface is used instead, letting loom explore the
+//! interleavings of senders, receivers and receiver sets. The loom tests are
+//! run with
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --features force-inprocess --lib loom
+//! ```
+//!
+//! Under loom, channels only work inside `loom::model()`. This includes the
+//! channels of one-shot servers, whose registry is a process-wide static.
+
+#[cfg(not(loom))]
+pub use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Select, Sender, TryRecvError};
+
+#[cfg(loom)]
+pub use self::model::{unbounded, Receiver, RecvTimeoutError, Select, Sender, TryRecvError};
+
+#[cfg(loom)]
+mod model {
+    use loom::sync::{Arc, Condvar, Mutex};
+    use loom::thread;
+    use std::collections::VecDeque;
+    use std::fmt::{self, Debug, Formatter};
+    use std::time::{Duration, Instant};
+
+    struct Shared<T> {
+        state: Mutex<State<T>>,
+        changed: Condvar,
+    }
+
+    struct State<T> {
+        queue: VecDeque<T>,
+        senders: usize,
+        receivers: usize,
+    }
+
+    impl<T> State<T> {
+        fn disconnected(&self) -> bool {
+            self.senders == 0
+        }
+    }
+
+    pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                senders: 1,
+                receivers: 1,
+            }),
+            changed: Condvar::new(),
+        });
+        (Sender { shared: shared.clone() }, Receiver { shared })
+    }
+
+    #[derive(Debug)]
+    pub struct SendError<T>(pub T);
+
+    #[derive(Debug)]
+    pub struct RecvError;
+
+    pub enum TryRecvError {
+        Empty,
+        Disconnected,
+    }
+
+    pub enum RecvTimeoutError {
+        Timeout,
+        Disconnected,
+    }
+
+    pub struct Sender<T> {
+        shared: Arc<Shared<T>>,
+    }
+
+    impl<T> Sender<T> {
+        pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.receivers == 0 {
+                return Err(SendError(value))
+            }
+            state.queue.push_back(value);
+            self.shared.changed.notify_all();
+            Ok(())
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Sender<T> {
+            self.shared.state.lock().unwrap().senders += 1;
+            Sender { shared: self.shared.clone() }
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            self.shared.state.lock().unwrap().senders -= 1;
+            self.shared.changed.notify_all();
+        }
+    }
+
+    impl<T> Debug for Sender<T> {
+        fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+            formatter.pad("Sender { .. }")
+        }
+    }
+
+    pub struct Receiver<T> {
+        shared: Arc<Shared<T>>,
+    }
+
+    impl<T> Receiver<T> {
+        pub fn recv(&self) -> Result<T, RecvError> {
+            let mut state = self.shared.state.lock().unwrap();
+            loop {
+                if let Some(value) = state.queue.pop_front() {
+                    return Ok(value)
+                }
+                if state.disconnected() {
+                    return Err(RecvError)
+                }
+                state = self.shared.changed.wait(state).unwrap();
+            }
+        }
+
+        pub fn try_recv(&self) -> Result<T, TryRecvError> {
+            let mut state = self.shared.state.lock().unwrap();
+            match state.queue.pop_front() {
+                Some(value) => Ok(value),
+                None if state.disconnected() => Err(TryRecvError::Disconnected),
+                None => Err(TryRecvError::Empty),
+            }
+        }
+
+        /// Loom's condition variables never time out, so under loom this waits
+        /// for a message or the senders to be gone.
+        pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+            let deadline = Instant::now() + timeout;
+            let mut state = self.shared.state.lock().unwrap();
+            loop {
+                if let Some(value) = state.queue.pop_front() {
+                    return Ok(value)
+                }
+                if state.disconnected() {
+                    return Err(RecvTimeoutError::Disconnected)
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(RecvTimeoutError::Timeout)
+                }
+                state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+
+        fn is_ready(&self) -> bool {
+            let state = self.shared.state.lock().unwrap();
+            !state.queue.is_empty() || state.disconnected()
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Receiver<T> {
+            self.shared.state.lock().unwrap().receivers += 1;
+            Receiver { shared: self.shared.clone() }
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            let mut state = self.shared.state.lock().unwrap();
+            state.receivers -= 1;
+            if state.receivers == 0 {
+                state.queue.clear();
+            }
+        }
+    }
+
+    impl<T> Debug for Receiver<T> {
+        fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+            formatter.pad("Receiver { .. }")
+        }
+    }
+
+    /// Waits for one of several receivers, like `crossbeam_channel::Select`. This polls,
+    /// yielding to loom between rounds; receivers never share a condition variable.
+    pub struct Select<'a, T: 'a> {
+        receivers: Vec<&'a Receiver<T>>,
+    }
+
+    impl<'a, T> Select<'a, T> {
+        pub fn new() -> Select<'a, T> {
+            Select { receivers: vec![] }
+        }
+
+        pub fn recv(&mut self, receiver: &'a Receiver<T>) -> usize {
+            self.receivers.push(receiver);
+            self.receivers.len() - 1
+        }
+
+        pub fn select(&mut self) -> SelectedOperation {
+            loop {
+                if let Ok(operation) = self.try_select() {
+                    return operation
+                }
+                thread::yield_now();
+            }
+        }
+
+        pub fn try_select(&mut self) -> Result<SelectedOperation, TrySelectError> {
+            match self.receivers.iter().position(|receiver| receiver.is_ready()) {
+                Some(index) => Ok(SelectedOperation { index }),
+                None => Err(TrySelectError),
+            }
+        }
+
+        pub fn select_timeout(&mut self, timeout: Duration)
+                              -> Result<SelectedOperation, SelectTimeoutError> {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Ok(operation) = self.try_select() {
+                    return Ok(operation)
+                }
+                if Instant::now() >= deadline {
+                    return Err(SelectTimeoutError)
+                }
+                thread::yield_now();
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TrySelectError;
+
+    #[derive(Debug)]
+    pub struct SelectTimeoutError;
+
+    pub struct SelectedOperation {
+        index: usize,
+    }
+
+    impl SelectedOperation {
+        pub fn index(&self) -> usize {
+            self.index
+        }
+
+        /// The inprocess backend never clones the receivers it selects on, so nobody can
+        /// have taken the message since `select()`.
+        pub fn recv<T>(self, receiver: &Receiver<T>) -> Result<T, RecvError> {
+            receiver.try_recv().map_err(|_| RecvError)
+        }
+    }
+}
@@ -10,22 +10,34 @@
 use self::mach_sys::{kern_return_t, mach_msg_body_t, mach_msg_header_t, mach_msg_return_t};
 use self::mach_sys::{mach_msg_ool_descriptor_t, mach_msg_port_descriptor_t, mach_msg_type_name_t};
 use self::mach_sys::{mach_msg_timeout_t, mach_port_limits_t, mach_port_msgcount_t};
-use self::mach_sys::{mach_port_right_t, mach_port_t, mach_task_self_, vm_inherit_t};
+use self::mach_sys::{mach_port_right_t, mach_port_status_t, mach_port_t, mach_port_type_t};
+use self::mach_sys::{mach_task_self_, natural_t, vm_inherit_t};
 
 use bincode;
+use super::{OsIpcPeerCredentials, PeerDied, pool};
+use router::QosClass;
 use libc::{self, c_char, c_uint, c_void, size_t};
 use rand::{self, Rng};
+use diagnostics::IpcDiagnostics;
 use std::cell::Cell;
+use std::cmp;
+use std::env;
 use std::ffi::CString;
 use std::fmt::{self, Debug, Formatter};
 use std::io::{Error, ErrorKind};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::Deref;
+use std::collections::HashMap;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
 use std::ptr;
 use std::slice;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use std::usize;
+use threads;
 
 mod mach_sys;
 
@@ -58,19 +70,26 @@ const MACH_MSG_TYPE_MAKE_SEND: u8 = 20;
 const MACH_MSG_TYPE_MAKE_SEND_ONCE: u8 = 21;
 const MACH_MSG_TYPE_MOVE_RECEIVE: u8 = 16;
 const MACH_MSG_TYPE_MOVE_SEND: u8 = 17;
+const MACH_MSG_TYPE_MOVE_SEND_ONCE: u8 = 18;
+const MACH_MSG_TYPE_PORT_RECEIVE: u8 = MACH_MSG_TYPE_MOVE_RECEIVE;
 const MACH_MSG_TYPE_PORT_SEND: u8 = MACH_MSG_TYPE_MOVE_SEND;
 const MACH_MSG_VIRTUAL_COPY: c_uint = 1;
 const MACH_MSG_VM_KERNEL: kern_return_t = 0x00000400;
 const MACH_MSG_VM_SPACE: kern_return_t = 0x00001000;
 const MACH_NOTIFY_FIRST: i32 = 64;
 const MACH_NOTIFY_NO_SENDERS: i32 = MACH_NOTIFY_FIRST + 6;
+/// The ID of the messages telling a receiver that the peer it watches exited.
+const PEER_DIED_MESSAGE_ID: i32 = 0x1000;
 const MACH_PORT_LIMITS_INFO: i32 = 1;
 const MACH_PORT_NULL: mach_port_t = 0;
 const MACH_PORT_QLIMIT_LARGE: mach_port_msgcount_t = 1024;
 const MACH_PORT_QLIMIT_MAX: mach_port_msgcount_t = MACH_PORT_QLIMIT_LARGE;
+const MACH_PORT_RECEIVE_STATUS: i32 = 2;
+const MACH_PORT_RECEIVE_STATUS_COUNT: natural_t = 10;
 const MACH_PORT_RIGHT_PORT_SET: mach_port_right_t = 3;
 const MACH_PORT_RIGHT_RECEIVE: mach_port_right_t = 1;
 const MACH_PORT_RIGHT_SEND: mach_port_right_t = 0;
+const MACH_PORT_TYPE_DEAD_NAME: mach_port_type_t = 1 << 20;
 const MACH_RCV_BODY_ERROR: kern_return_t = 0x1000400c;
 const MACH_RCV_HEADER_ERROR: kern_return_t = 0x1000400b;
 const MACH_RCV_INTERRUPTED: kern_return_t = 0x10004005;
@@ -106,6 +125,8 @@ const MACH_SEND_IN_PROGRESS: kern_return_t = 0x10000001;
 const MACH_SEND_MSG: i32 = 1;
 const MACH_SEND_MSG_TOO_SMALL: kern_return_t = 0x10000008;
 const MACH_SEND_NO_BUFFER: kern_return_t = 0x1000000d;
+const MACH_SEND_OVERRIDE: i32 = 0x00000020;
+const MACH_SEND_PROPAGATE_QOS: i32 = 0x00200000;
 const MACH_SEND_TIMED_OUT: kern_return_t = 0x10000004;
 const MACH_SEND_TOO_LARGE: kern_return_t = 0x1000000e;
 const TASK_BOOTSTRAP_PORT: i32 = 4;
@@ -121,9 +142,35 @@ pub fn channel() -> Result<(OsIpcSender, OsIpcReceiver),MachError> {
     Ok((sender, receiver))
 }
 
+/// Create a channel whose port queues `recv_buffer` bytes' worth of messages.
+///
+/// Mach queues messages at the receiving port, and limits them by count rather than
+/// size, so there is no send buffer, and the queue limit is set to the number of
+/// inline messages as large as the out-of-line threshold it takes to fill
+/// `recv_buffer`. Ports otherwise get the largest queue limit already.
+pub fn channel_with_buffer_sizes(_send_buffer: Option<usize>, recv_buffer: Option<usize>)
+                                 -> Result<(OsIpcSender, OsIpcReceiver),MachError> {
+    let receiver = match recv_buffer {
+        Some(recv_buffer) => {
+            let threshold = match OUT_OF_LINE_THRESHOLD.load(Ordering::Relaxed) {
+                usize::MAX => QUEUED_MESSAGE_SIZE,
+                threshold => cmp::max(threshold, 1),
+            };
+            let messages = cmp::min(recv_buffer / threshold, MACH_PORT_QLIMIT_MAX as usize);
+            OsIpcReceiver::with_queue_limit(cmp::max(messages, 1) as mach_port_msgcount_t)?
+        }
+        None => OsIpcReceiver::new()?,
+    };
+    let sender = receiver.sender()?;
+    receiver.request_no_senders_notification()?;
+    Ok((sender, receiver))
+}
+
 #[derive(PartialEq, Debug)]
 pub struct OsIpcReceiver {
     port: Cell<mach_port_t>,
+    // The pid and exit status of the peer watched with `watch_peer()`, once it exited.
+    peer_died: Cell<Option<(u32, Option<ExitStatus>)>>,
 }
 
 impl Drop for OsIpcReceiver {
@@ -196,9 +243,13 @@ fn mach_port_extract_right(
 
 impl OsIpcReceiver {
     fn new() -> Result<OsIpcReceiver,MachError> {
+        OsIpcReceiver::with_queue_limit(MACH_PORT_QLIMIT_MAX)
+    }
+
+    fn with_queue_limit(queue_limit: mach_port_msgcount_t) -> Result<OsIpcReceiver,MachError> {
         let port = mach_port_allocate(MACH_PORT_RIGHT_RECEIVE)?;
         let limits = mach_port_limits_t {
-            mpl_qlimit: MACH_PORT_QLIMIT_MAX,
+            mpl_qlimit: queue_limit,
         };
         let os_result = unsafe {
             mach_sys::mach_port_set_attributes(mach_task_self(),
@@ -217,6 +268,7 @@ impl OsIpcReceiver {
     fn from_name(port: mach_port_t) -> OsIpcReceiver {
         OsIpcReceiver {
             port: Cell::new(port),
+            peer_died: Cell::new(None),
         }
     }
 
@@ -236,6 +288,66 @@ impl OsIpcReceiver {
         OsIpcReceiver::from_name(self.consume_port())
     }
 
+    /// The port's status only tells whether any send rights are left, not how many.
+    pub fn sender_count(&self) -> Option<usize> {
+        let mut status = mach_port_status_t::default();
+        let mut count = MACH_PORT_RECEIVE_STATUS_COUNT;
+        let os_result = unsafe {
+            mach_sys::mach_port_get_attributes(mach_task_self(),
+                                               self.port.get(),
+                                               MACH_PORT_RECEIVE_STATUS,
+                                               &mut status as *mut _ as *mut _,
+                                               &mut count)
+        };
+        if os_result == KERN_SUCCESS && status.mps_srights == 0 {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Once process `pid` exits, fail receive calls finding no message with
+    /// `MachError::PeerDied`, rather than wait.
+    ///
+    /// A thread of ours waits for the exit with kqueue's `EVFILT_PROC`, and then sends a
+    /// message to the port, with a send-once right, so the port is still notified when
+    /// there are no senders left, and the messages sent before the exit come first.
+    pub fn watch_peer(&self, pid: u32) -> Result<(), Error> {
+        let kqueue = match *PEER_WATCHER {
+            Ok(kqueue) => kqueue,
+            Err(errno) => return Err(Error::from_raw_os_error(errno)),
+        };
+        let (right, _) = mach_port_extract_right(self.extract_port(),
+                                                 MACH_MSG_TYPE_MAKE_SEND_ONCE as u32)
+            .map_err(MachError::from)?;
+        // Held until the right is recorded, so the watcher can't miss it.
+        let mut watched_peers = WATCHED_PEERS.lock().unwrap();
+        unsafe {
+            let mut change: libc::kevent = mem::zeroed();
+            change.ident = pid as libc::uintptr_t;
+            change.filter = libc::EVFILT_PROC;
+            change.flags = libc::EV_ADD | libc::EV_ONESHOT;
+            change.fflags = libc::NOTE_EXIT;
+            if libc::kevent(kqueue, &change, 1, ptr::null_mut(), 0, ptr::null()) < 0 {
+                let error = Error::last_os_error();
+                mach_sys::mach_port_deallocate(mach_task_self(), right);
+                return Err(error)
+            }
+        }
+        watched_peers.entry(pid).or_insert_with(Vec::new).push(right);
+        Ok(())
+    }
+
+    /// A port has exactly one receive right, so receivers can't be duplicated.
+    pub fn try_duplicate(&self) -> Result<OsIpcReceiver, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach ports have a single receive right"))
+    }
+
+    /// Ports don't survive `exec()`.
+    pub fn prepare_for_inheritance(&self) -> Result<(),Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach ports can't be inherited"))
+    }
+
     fn sender(&self) -> Result<OsIpcSender,MachError> {
         let port = self.port.get();
         debug_assert!(port != MACH_PORT_NULL);
@@ -324,14 +436,28 @@ impl OsIpcReceiver {
     fn recv_with_blocking_mode(&self, blocking_mode: BlockingMode)
                                -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
                                          MachError> {
-        select(self.port.get(), blocking_mode).and_then(|result| {
-            match result {
-                OsIpcSelectionResult::DataReceived(_, data, channels, shared_memory_regions) => {
-                    Ok((data, channels, shared_memory_regions))
-                }
-                OsIpcSelectionResult::ChannelClosed(_) => Err(MachError::from(MACH_NOTIFY_NO_SENDERS)),
+        let blocking_mode = match self.peer_died.get() {
+            // Only the messages the peer left behind are worth waiting for.
+            Some(_) => BlockingMode::Nonblocking,
+            None => blocking_mode,
+        };
+        match select(self.port.get(), blocking_mode) {
+            Ok(OsIpcSelectionResult::DataReceived(_, data, channels, shared_memory_regions)) => {
+                Ok((data, channels, shared_memory_regions))
             }
-        })
+            Ok(OsIpcSelectionResult::ChannelClosed(_)) => {
+                Err(MachError::from(MACH_NOTIFY_NO_SENDERS))
+            }
+            Ok(OsIpcSelectionResult::PeerDied(_, pid, exit_status)) => {
+                self.peer_died.set(Some((pid, exit_status)));
+                Err(MachError::PeerDied(pid, exit_status))
+            }
+            Err(MachError::RcvTimedOut) if self.peer_died.get().is_some() => {
+                let (pid, exit_status) = self.peer_died.get().unwrap();
+                Err(MachError::PeerDied(pid, exit_status))
+            }
+            Err(error) => Err(error),
+        }
     }
 
     pub fn recv(&self)
@@ -343,21 +469,69 @@ impl OsIpcReceiver {
                     -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),MachError> {
         self.recv_with_blocking_mode(BlockingMode::Nonblocking)
     }
+
+    /// Receive, waiting for a message at most `duration`.
+    pub fn try_recv_timeout(&self, duration: Duration)
+                            -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+                                      MachError> {
+        self.recv_with_blocking_mode(BlockingMode::Timeout(duration))
+    }
+
+    /// Blocking receive of a message into shared memory.
+    ///
+    /// Large messages already arrive out-of-line, so this only copies them once more.
+    pub fn recv_bulk(&self)
+                     -> Result<(OsIpcSharedMemory, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+                               MachError> {
+        let (data, channels, shared_memory_regions) = self.recv()?;
+        Ok((OsIpcSharedMemory::from_bytes(&data), channels, shared_memory_regions))
+    }
 }
 
 enum SendData<'a> {
     Inline(&'a [u8]),
+    /// Copied into a shared memory region, sent along with the others.
     OutOfLine(Option<OsIpcSharedMemory>),
+    /// Sent as an out-of-line descriptor pointing at the buffer itself,
+    /// which the kernel maps into the receiver copy-on-write.
+    Borrowed(&'a [u8]),
 }
 
 lazy_static! {
     static ref MAX_INLINE_SIZE: RwLock<usize> = RwLock::new(usize::MAX);
 }
 
+/// Payloads of at least this many bytes are sent out-of-line from the caller's buffer.
+/// Off until set: only payloads too large for an inline message are, as a copy.
+static OUT_OF_LINE_THRESHOLD: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The size of the messages filling the queue of a channel, absent an out-of-line threshold.
+const QUEUED_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Listing the port rights of the task, and telling ours apart, isn't implemented.
+pub fn diagnose(_: &mut IpcDiagnostics) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Unsupported, "Mach ports can't be listed for diagnostics"))
+}
+
+/// The ports of one-shot servers are the kernel's, which a checkpoint doesn't keep, and
+/// the bootstrap server's names for them can't be taken over by new ones.
+pub fn rebind_one_shot_servers() -> Result<usize, Error> {
+    Err(Error::new(ErrorKind::Unsupported, "Mach ports can't be restored from a checkpoint"))
+}
+
+/// Set the size from which payloads are sent out-of-line rather than copied into the message.
+pub fn set_out_of_line_threshold(threshold: usize) {
+    OUT_OF_LINE_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
 impl<'a> From<&'a [u8]> for SendData<'a> {
     fn from(data: &'a [u8]) -> SendData<'a> {
         let max_inline_size = *MAX_INLINE_SIZE.read().unwrap();
-        if data.len() >= max_inline_size {
+        let threshold = OUT_OF_LINE_THRESHOLD.load(Ordering::Relaxed);
+        if !data.is_empty() && data.len() >= threshold {
+            // Let the kernel remap the pages of large payloads instead of copying them twice.
+            SendData::Borrowed(data)
+        } else if data.len() >= max_inline_size {
             // Convert the data payload into a shared memory region to avoid exceeding
             // any message size limits.
             SendData::OutOfLine(Some(OsIpcSharedMemory::from_bytes(data)))
@@ -370,22 +544,29 @@ impl<'a> From<&'a [u8]> for SendData<'a> {
 impl<'a> SendData<'a> {
     fn take_shared_memory(&mut self) -> Option<OsIpcSharedMemory> {
         match *self {
-            SendData::Inline(_) => None,
             SendData::OutOfLine(ref mut data) => data.take(),
+            SendData::Inline(_) | SendData::Borrowed(_) => None,
         }
     }
 
     fn is_inline(&self) -> bool {
         match *self {
             SendData::Inline(_) => true,
-            SendData::OutOfLine(_) => false,
+            SendData::OutOfLine(_) | SendData::Borrowed(_) => false,
         }
     }
 
     fn inline_data(&self) -> &[u8] {
         match *self {
             SendData::Inline(ref data) => data,
-            SendData::OutOfLine(_) => &[],
+            SendData::OutOfLine(_) | SendData::Borrowed(_) => &[],
+        }
+    }
+
+    fn out_of_line_data(&self) -> Option<&[u8]> {
+        match *self {
+            SendData::Borrowed(data) => Some(data),
+            SendData::Inline(_) | SendData::OutOfLine(_) => None,
         }
     }
 }
@@ -399,6 +580,7 @@ pub struct OsIpcSender {
     // (Rather, senders should just be cloned, as they are shared internally anyway --
     // another layer of sharing only adds unnecessary overhead...)
     nosync_marker: PhantomData<Cell<()>>,
+    qos_override: Cell<Option<QosClass>>,
 }
 
 impl Drop for OsIpcSender {
@@ -428,6 +610,7 @@ impl Clone for OsIpcSender {
         OsIpcSender {
             port: cloned_port,
             nosync_marker: PhantomData,
+            qos_override: Cell::new(self.qos_override.get()),
         }
     }
 }
@@ -437,9 +620,80 @@ impl OsIpcSender {
         OsIpcSender {
             port: port,
             nosync_marker: PhantomData,
+            qos_override: Cell::new(None),
+        }
+    }
+
+    /// Once the receive right is gone, the send right turns into a dead name.
+    pub fn is_connected(&self) -> bool {
+        let mut port_type = 0;
+        let os_result = unsafe {
+            mach_sys::mach_port_type(mach_task_self(), self.port, &mut port_type)
+        };
+        os_result != KERN_SUCCESS || port_type & MACH_PORT_TYPE_DEAD_NAME == 0
+    }
+
+    /// Mach has no weak send rights, and once the send rights of a name are all released,
+    /// the name may be reused for another port.
+    pub fn downgrade(&self) -> Result<OsIpcWeakSender,Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach has no weak send rights"))
+    }
+
+    /// Ports don't survive `exec()`, so the children look the sender up instead: it is
+    /// served under a bootstrap name to those presenting the token stored along with
+    /// the name in `var_name`, until `command` is dropped.
+    pub fn to_env(&self, var_name: &str, command: &mut Command) -> Result<(),Error> {
+        let (server, name) = OsIpcOneShotServer::new()?;
+        let mut stop = OsIpcSender::connect(name.clone())?;
+        let token = rand::thread_rng().gen::<u64>();
+        let sender = self.clone();
+        threads::builder("env sender").spawn(move || {
+            drop(threads::configure_current_thread());
+            serve_env_sender(server, sender, token)
+        })?;
+        let guard = EnvSenderGuard(mem::replace(&mut stop.port, MACH_PORT_NULL));
+        command.env(var_name, format!("mach:{}:{}", name, token));
+        unsafe {
+            command.pre_exec(move || {
+                // Only there to be dropped along with `command`.
+                let _ = &guard;
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Look up the sender served with `to_env()`. Each call gets a new send right,
+    /// as long as the parent serves it.
+    pub fn from_env(var_name: &str) -> Result<OsIpcSender,Error> {
+        let value = env::var(var_name).map_err(|_| {
+            Error::new(ErrorKind::NotFound, format!("no sender in ${}", var_name))
+        })?;
+        let invalid = || {
+            Error::new(ErrorKind::InvalidData,
+                       format!("invalid sender in ${}: {}", var_name, value))
+        };
+        let (name, token) = value.strip_prefix("mach:")
+                                 .and_then(|rest| rest.rsplit_once(':'))
+                                 .ok_or_else(invalid)?;
+        let token = token.parse::<u64>().map_err(|_| invalid())?;
+        let server = OsIpcSender::connect(name.to_owned())?;
+        let (reply_sender, reply_receiver) = channel()?;
+        server.send(&token.to_le_bytes(), vec![OsIpcChannel::Sender(reply_sender)], vec![])?;
+        let (_, channels, _) = reply_receiver.recv()?;
+        let mut channels = channels.into_iter()
+                                   .map(|mut channel| channel.to_channel())
+                                   .collect::<Vec<_>>();
+        match (channels.pop(), channels.is_empty()) {
+            (Some(OsIpcChannel::Sender(sender)), true) => Ok(sender),
+            _ => Err(invalid()),
         }
     }
 
+    pub fn prepare_for_inheritance(&self) -> Result<(),Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach ports can't be inherited"))
+    }
+
     pub fn connect(name: String) -> Result<OsIpcSender,MachError> {
         unsafe {
             let mut bootstrap_port = 0;
@@ -465,6 +719,24 @@ impl OsIpcSender {
         usize::MAX
     }
 
+    /// Messages carry the QoS class of the sending thread, or `class` if that's more
+    /// urgent.
+    pub fn set_qos_override(&self, class: Option<QosClass>) -> Result<(),Error> {
+        self.qos_override.set(class);
+        Ok(())
+    }
+
+    /// Like `send()`, taking ownership of `data`.
+    ///
+    /// Mach copies (or remaps, for out-of-line data) the buffer in any case.
+    pub fn send_vec(&self,
+                    data: Vec<u8>,
+                    ports: Vec<OsIpcChannel>,
+                    shared_memory_regions: Vec<OsIpcSharedMemory>)
+                    -> Result<(),MachError> {
+        self.send(&data, ports, shared_memory_regions)
+    }
+
     pub fn send(&self,
                 data: &[u8],
                 ports: Vec<OsIpcChannel>,
@@ -474,9 +746,11 @@ impl OsIpcSender {
         if let Some(data) = data.take_shared_memory() {
             shared_memory_regions.push(data);
         }
+        let descriptor_count = ports.len() + shared_memory_regions.len() +
+            data.out_of_line_data().map_or(0, |_| 1);
 
         unsafe {
-            let size = Message::size_of(&data, ports.len(), shared_memory_regions.len());
+            let size = Message::size_of(&data, ports.len(), descriptor_count - ports.len());
             let message = libc::malloc(size as size_t) as *mut Message;
             (*message).header.msgh_bits = (MACH_MSG_TYPE_COPY_SEND as u32) |
                 MACH_MSGH_BITS_COMPLEX;
@@ -485,8 +759,7 @@ impl OsIpcSender {
             (*message).header.msgh_remote_port = self.port;
             (*message).header.msgh_reserved = 0;
             (*message).header.msgh_id = 0;
-            (*message).body.msgh_descriptor_count =
-                (ports.len() + shared_memory_regions.len()) as u32;
+            (*message).body.msgh_descriptor_count = descriptor_count as u32;
 
             let mut port_descriptor_dest = message.offset(1) as *mut mach_msg_port_descriptor_t;
             for outgoing_port in &ports {
@@ -513,6 +786,17 @@ impl OsIpcSender {
                 (*shared_memory_descriptor_dest).type_ = MACH_MSG_OOL_DESCRIPTOR;
                 shared_memory_descriptor_dest = shared_memory_descriptor_dest.offset(1);
             }
+            // The payload goes last, where the receiver expects it. Unlike the shared memory
+            // regions, the buffer stays ours.
+            if let Some(payload) = data.out_of_line_data() {
+                (*shared_memory_descriptor_dest).address =
+                    payload.as_ptr() as *const c_void as *mut c_void;
+                (*shared_memory_descriptor_dest).size = payload.len() as u32;
+                (*shared_memory_descriptor_dest).deallocate = 0;
+                (*shared_memory_descriptor_dest).copy = MACH_MSG_VIRTUAL_COPY as u8;
+                (*shared_memory_descriptor_dest).type_ = MACH_MSG_OOL_DESCRIPTOR;
+                shared_memory_descriptor_dest = shared_memory_descriptor_dest.offset(1);
+            }
 
             let is_inline_dest = shared_memory_descriptor_dest as *mut bool;
             *is_inline_dest = data.is_inline();
@@ -530,13 +814,21 @@ impl OsIpcSender {
                 ptr::copy_nonoverlapping(data.as_ptr(), data_dest, data_size);
             }
 
+            // The kernel raises the receiving thread to the QoS of the message while it
+            // handles it, as for messages of native frameworks. With `MACH_SEND_OVERRIDE`,
+            // the last argument carries the override rather than a notification port.
+            let (options, priority) = match self.qos_override.get() {
+                Some(class) => (MACH_SEND_MSG | MACH_SEND_PROPAGATE_QOS | MACH_SEND_OVERRIDE,
+                                mach_msg_priority(class)),
+                None => (MACH_SEND_MSG | MACH_SEND_PROPAGATE_QOS, MACH_PORT_NULL),
+            };
             let os_result = mach_sys::mach_msg(message as *mut _,
-                                               MACH_SEND_MSG,
+                                               options,
                                                (*message).header.msgh_size,
                                                0,
                                                MACH_PORT_NULL,
                                                MACH_MSG_TIMEOUT_NONE,
-                                               MACH_PORT_NULL);
+                                               priority);
             libc::free(message as *mut _);
             if os_result == MACH_SEND_TOO_LARGE && data.is_inline() {
                 let inline_data = data.inline_data();
@@ -563,6 +855,30 @@ impl OsIpcSender {
     }
 }
 
+/// The `pthread_priority_t` of `class` at its base relative priority, as taken by
+/// `MACH_SEND_OVERRIDE`.
+fn mach_msg_priority(class: QosClass) -> u32 {
+    // libpthread's QoS tiers, from 2 for background to 6 for user interactive.
+    let tier = match class {
+        QosClass::Background => 2,
+        QosClass::Utility => 3,
+        QosClass::Default => 4,
+        QosClass::UserInitiated => 5,
+        QosClass::UserInteractive => 6,
+    };
+    (1u32 << (tier - 1)) << 8 | 0xff
+}
+
+/// Senders can't be downgraded, so there are no weak senders.
+#[derive(Clone, Debug)]
+pub enum OsIpcWeakSender {}
+
+impl OsIpcWeakSender {
+    pub fn upgrade(&self) -> Option<OsIpcSender> {
+        match *self {}
+    }
+}
+
 pub enum OsIpcChannel {
     Sender(OsIpcSender),
     Receiver(OsIpcReceiver),
@@ -580,6 +896,8 @@ impl OsIpcChannel {
 #[derive(PartialEq, Debug)]
 pub struct OsOpaqueIpcChannel {
     port: mach_port_t,
+    /// Whether we hold the receive right of the port, rather than a send right.
+    is_receiver: bool,
 }
 
 impl Drop for OsOpaqueIpcChannel {
@@ -590,9 +908,10 @@ impl Drop for OsOpaqueIpcChannel {
 }
 
 impl OsOpaqueIpcChannel {
-    fn from_name(name: mach_port_t) -> OsOpaqueIpcChannel {
+    fn from_name(name: mach_port_t, disposition: u8) -> OsOpaqueIpcChannel {
         OsOpaqueIpcChannel {
             port: name,
+            is_receiver: disposition == MACH_MSG_TYPE_PORT_RECEIVE,
         }
     }
 
@@ -600,12 +919,36 @@ impl OsOpaqueIpcChannel {
         OsIpcSender {
             port: mem::replace(&mut self.port, MACH_PORT_NULL),
             nosync_marker: PhantomData,
+            qos_override: Cell::new(None),
         }
     }
 
     pub fn to_receiver(&mut self) -> OsIpcReceiver {
         OsIpcReceiver::from_name(mem::replace(&mut self.port, MACH_PORT_NULL))
     }
+
+    /// Take the channel as whichever kind of right was received, e.g. to send it on.
+    pub fn to_channel(&mut self) -> OsIpcChannel {
+        if self.is_receiver {
+            OsIpcChannel::Receiver(self.to_receiver())
+        } else {
+            OsIpcChannel::Sender(self.to_sender())
+        }
+    }
+
+    /// The opaque form of `channel`, as if it had been received in a message.
+    pub fn from_channel(channel: OsIpcChannel) -> Result<OsOpaqueIpcChannel,MachError> {
+        Ok(match channel {
+            OsIpcChannel::Sender(mut sender) => OsOpaqueIpcChannel {
+                port: mem::replace(&mut sender.port, MACH_PORT_NULL),
+                is_receiver: false,
+            },
+            OsIpcChannel::Receiver(receiver) => OsOpaqueIpcChannel {
+                port: receiver.consume_port(),
+                is_receiver: true,
+            },
+        })
+    }
 }
 
 pub struct OsIpcReceiverSet {
@@ -630,7 +973,40 @@ impl OsIpcReceiverSet {
     }
 
     pub fn select(&mut self) -> Result<Vec<OsIpcSelectionResult>,MachError> {
-        select(self.port, BlockingMode::Blocking).map(|result| vec![result])
+        let result = select(self.port, BlockingMode::Blocking)?;
+        Ok(vec![self.close_if_peer_died(result)])
+    }
+
+    /// Like `select()`, returning no results rather than waiting if no receiver is ready.
+    pub fn try_select(&mut self) -> Result<Vec<OsIpcSelectionResult>,MachError> {
+        self.select_or_time_out(BlockingMode::Nonblocking)
+    }
+
+    /// Like `select()`, returning no results if no receiver is ready within `duration`.
+    pub fn select_timeout(&mut self, duration: Duration)
+                          -> Result<Vec<OsIpcSelectionResult>,MachError> {
+        self.select_or_time_out(BlockingMode::Timeout(duration))
+    }
+
+    fn select_or_time_out(&mut self, blocking_mode: BlockingMode)
+                          -> Result<Vec<OsIpcSelectionResult>,MachError> {
+        match select(self.port, blocking_mode) {
+            Ok(result) => Ok(vec![self.close_if_peer_died(result)]),
+            Err(MachError::RcvTimedOut) => Ok(vec![]),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Drop the receiver whose peer died, as the unix backend does.
+    fn close_if_peer_died(&mut self, result: OsIpcSelectionResult) -> OsIpcSelectionResult {
+        if let OsIpcSelectionResult::PeerDied(id, ..) = result {
+            let port = id as mach_port_t;
+            if let Some(index) = self.ports.iter().position(|&receiver| receiver == port) {
+                self.ports.remove(index);
+                mach_port_mod_release(port, MACH_PORT_RIGHT_RECEIVE).unwrap();
+            }
+        }
+        result
     }
 }
 
@@ -646,6 +1022,9 @@ impl Drop for OsIpcReceiverSet {
 pub enum OsIpcSelectionResult {
     DataReceived(u64, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
     ChannelClosed(u64),
+    /// The peer watched by the receiver with this ID exited, with its pid and exit status.
+    /// The receiver was closed and removed from the set.
+    PeerDied(u64, u32, Option<ExitStatus>),
 }
 
 impl OsIpcSelectionResult {
@@ -657,6 +1036,9 @@ impl OsIpcSelectionResult {
             OsIpcSelectionResult::ChannelClosed(id) => {
                 panic!("OsIpcSelectionResult::unwrap(): receiver ID {} was closed!", id)
             }
+            OsIpcSelectionResult::PeerDied(id, pid, _) => {
+                panic!("OsIpcSelectionResult::unwrap(): peer {} of receiver ID {} died!", pid, id)
+            }
         }
     }
 }
@@ -665,6 +1047,7 @@ impl OsIpcSelectionResult {
 enum BlockingMode {
     Blocking,
     Nonblocking,
+    Timeout(Duration),
 }
 
 fn select(port: mach_port_t, blocking_mode: BlockingMode)
@@ -678,6 +1061,11 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
         let (flags, timeout) = match blocking_mode {
             BlockingMode::Blocking => (MACH_RCV_MSG | MACH_RCV_LARGE, MACH_MSG_TIMEOUT_NONE),
             BlockingMode::Nonblocking => (MACH_RCV_MSG | MACH_RCV_LARGE | MACH_RCV_TIMEOUT, 0),
+            BlockingMode::Timeout(duration) => {
+                let millis = cmp::min(duration.as_millis(),
+                                      u128::from(mach_msg_timeout_t::max_value()));
+                (MACH_RCV_MSG | MACH_RCV_LARGE | MACH_RCV_TIMEOUT, millis as mach_msg_timeout_t)
+            }
         };
         match mach_sys::mach_msg(message as *mut _,
                                  flags,
@@ -724,6 +1112,18 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
         if (*message).header.msgh_id == MACH_NOTIFY_NO_SENDERS {
             return Ok(OsIpcSelectionResult::ChannelClosed(local_port as u64))
         }
+        if (*message).header.msgh_id == PEER_DIED_MESSAGE_ID &&
+                (*message).header.msgh_size as usize == mem::size_of::<PeerDiedMessage>() {
+            let notice = ptr::read_unaligned(message as *const PeerDiedMessage);
+            // Anyone with a send right could send the ID; only our watcher knows the token.
+            if notice.token == *PEER_DIED_TOKEN {
+                let exit_status = super::unreaped_exit_status(libc::P_PID,
+                                                              notice.pid as libc::id_t);
+                return Ok(OsIpcSelectionResult::PeerDied(local_port as u64,
+                                                         notice.pid,
+                                                         exit_status))
+            }
+        }
 
         let (mut ports, mut shared_memory_regions) = (Vec::new(), Vec::new());
         let mut port_descriptor = message.offset(1) as *mut mach_msg_port_descriptor_t;
@@ -732,7 +1132,8 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
             if (*port_descriptor).type_ != MACH_MSG_PORT_DESCRIPTOR {
                 break
             }
-            ports.push(OsOpaqueIpcChannel::from_name((*port_descriptor).name));
+            ports.push(OsOpaqueIpcChannel::from_name((*port_descriptor).name,
+                                                     (*port_descriptor).disposition));
             port_descriptor = port_descriptor.offset(1);
             descriptors_remaining -= 1;
         }
@@ -756,10 +1157,14 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
                 (shared_memory_descriptor as usize);
             assert!(payload_size <= max_payload_size);
             let payload_ptr = payload_size_ptr.offset(1) as *mut u8;
-            slice::from_raw_parts(payload_ptr, payload_size).to_vec()
+            let mut payload = pool::take(payload_size);
+            payload.extend_from_slice(slice::from_raw_parts(payload_ptr, payload_size));
+            payload
         } else {
             let ool_payload = shared_memory_regions.pop().expect("Missing OOL shared memory region");
-            ool_payload.to_vec()
+            let mut payload = pool::take(ool_payload.len());
+            payload.extend_from_slice(&ool_payload);
+            payload
         };
 
         if let Some(allocated_buffer) = allocated_buffer {
@@ -773,6 +1178,118 @@ fn select(port: mach_port_t, blocking_mode: BlockingMode)
     }
 }
 
+lazy_static! {
+    /// The kqueue of the thread watching peers for `OsIpcReceiver::watch_peer()`, started
+    /// on first use, or the error starting it.
+    static ref PEER_WATCHER: Result<libc::c_int, i32> = start_peer_watcher();
+    /// The send-once rights to the receivers watching each peer, by pid.
+    static ref WATCHED_PEERS: Mutex<HashMap<u32, Vec<mach_port_t>>> = Mutex::new(HashMap::new());
+    static ref PEER_DIED_TOKEN: u64 = rand::thread_rng().gen();
+}
+
+/// The message the peer watcher sends to receivers once their peer exited.
+#[repr(C)]
+struct PeerDiedMessage {
+    header: mach_msg_header_t,
+    pid: u32,
+    token: u64,
+}
+
+fn start_peer_watcher() -> Result<libc::c_int, i32> {
+    let kqueue = unsafe { libc::kqueue() };
+    if kqueue < 0 {
+        return Err(Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL))
+    }
+    let watcher = threads::builder("peer watcher").spawn(move || {
+        drop(threads::configure_current_thread());
+        loop {
+            let mut event: libc::kevent = unsafe { mem::zeroed() };
+            let count = unsafe {
+                libc::kevent(kqueue, ptr::null(), 0, &mut event, 1, ptr::null())
+            };
+            if count < 0 && Error::last_os_error().kind() != ErrorKind::Interrupted {
+                return
+            }
+            if count < 1 {
+                continue
+            }
+            let pid = event.ident as u32;
+            let rights = WATCHED_PEERS.lock().unwrap().remove(&pid).unwrap_or_default();
+            for right in rights {
+                notify_peer_died(right, pid);
+            }
+        }
+    });
+    match watcher {
+        Ok(_) => Ok(kqueue),
+        Err(error) => {
+            unsafe {
+                libc::close(kqueue);
+            }
+            Err(error.raw_os_error().unwrap_or(libc::EAGAIN))
+        }
+    }
+}
+
+/// Tell the receiver behind the send-once right `right` that its peer `pid` exited.
+/// Messages sent with send-once rights aren't held back by full queues.
+fn notify_peer_died(right: mach_port_t, pid: u32) {
+    unsafe {
+        let mut notice: PeerDiedMessage = mem::zeroed();
+        notice.header.msgh_bits = MACH_MSG_TYPE_MOVE_SEND_ONCE as u32;
+        notice.header.msgh_size = mem::size_of::<PeerDiedMessage>() as u32;
+        notice.header.msgh_remote_port = right;
+        notice.header.msgh_local_port = MACH_PORT_NULL;
+        notice.header.msgh_id = PEER_DIED_MESSAGE_ID;
+        notice.pid = pid;
+        notice.token = *PEER_DIED_TOKEN;
+        let os_result = mach_sys::mach_msg(&mut notice.header,
+                                           MACH_SEND_MSG,
+                                           notice.header.msgh_size,
+                                           0,
+                                           MACH_PORT_NULL,
+                                           MACH_MSG_TIMEOUT_NONE,
+                                           MACH_PORT_NULL);
+        if os_result != MACH_MSG_SUCCESS {
+            // The receiver is gone, leaving a dead name.
+            mach_sys::mach_port_deallocate(mach_task_self(), right);
+        }
+    }
+}
+
+/// The receiver and first message of a client accepted by an `OsIpcOneShotServer`.
+type AcceptedClient = (OsIpcReceiver, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
+/// Hand out `sender` to those sending `token` along with a sender to reply to, until
+/// an empty message comes.
+fn serve_env_sender(server: OsIpcOneShotServer, sender: OsIpcSender, token: u64) {
+    while let Ok((data, channels, _)) = server.receiver.recv() {
+        if data.is_empty() {
+            return
+        }
+        let mut channels = channels.into_iter()
+                                   .map(|mut channel| channel.to_channel())
+                                   .collect::<Vec<_>>();
+        if let (Some(OsIpcChannel::Sender(reply_sender)), true) =
+            (channels.pop(), channels.is_empty()) {
+            if data == token.to_le_bytes() {
+                drop(reply_sender.send(&[], vec![OsIpcChannel::Sender(sender.clone())], vec![]));
+            }
+        }
+    }
+}
+
+/// Stops serving a sender handed down with `to_env()` when dropped, through the
+/// send right to the server it holds.
+struct EnvSenderGuard(mach_port_t);
+
+impl Drop for EnvSenderGuard {
+    fn drop(&mut self) {
+        let stop = OsIpcSender::from_name(self.0);
+        drop(stop.send(&[], vec![], vec![]));
+    }
+}
+
 pub struct OsIpcOneShotServer {
     receiver: OsIpcReceiver,
     name: String,
@@ -794,6 +1311,11 @@ impl OsIpcOneShotServer {
         }, name))
     }
 
+    /// There are no sockets to adopt.
+    pub fn from_listen_fds() -> Result<Vec<OsIpcOneShotServer>,Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach ports can't be passed by systemd"))
+    }
+
     pub fn accept(self) -> Result<(OsIpcReceiver,
                                    Vec<u8>,
                                    Vec<OsOpaqueIpcChannel>,
@@ -801,6 +1323,27 @@ impl OsIpcOneShotServer {
         let (bytes, channels, shared_memory_regions) = self.receiver.recv()?;
         Ok((self.receiver.consume(), bytes, channels, shared_memory_regions))
     }
+
+    /// Like `accept()`, discarding the messages of clients whose credentials
+    /// `policy` rejects.
+    ///
+    /// The one-shot server has no connection step: clients just send their first
+    /// message to the registered port, so their credentials are unknown.
+    pub fn accept_with_policy<F>(self, mut policy: F)
+                                 -> Result<AcceptedClient,MachError>
+                                 where F: FnMut(&OsIpcPeerCredentials) -> bool {
+        let credentials = OsIpcPeerCredentials {
+            pid: None,
+            uid: None,
+            gid: None,
+        };
+        loop {
+            let (bytes, channels, shared_memory_regions) = self.receiver.recv()?;
+            if policy(&credentials) {
+                return Ok((self.receiver.consume(), bytes, channels, shared_memory_regions))
+            }
+        }
+    }
 }
 
 pub struct OsIpcSharedMemory {
@@ -890,6 +1433,17 @@ impl OsIpcSharedMemory {
         }
     }
 
+    pub fn try_from_byte(byte: u8, length: usize) -> Result<OsIpcSharedMemory,MachError> {
+        Ok(OsIpcSharedMemory::from_byte(byte, length))
+    }
+
+    /// A zeroed region, whose pages the kernel only allocates once touched.
+    pub fn zeroed(length: usize) -> OsIpcSharedMemory {
+        unsafe {
+            OsIpcSharedMemory::from_raw_parts(allocate_vm_pages(length), length)
+        }
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> OsIpcSharedMemory {
         unsafe {
             let address = allocate_vm_pages(bytes.len());
@@ -897,6 +1451,42 @@ impl OsIpcSharedMemory {
             OsIpcSharedMemory::from_raw_parts(address, bytes.len())
         }
     }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<OsIpcSharedMemory,MachError> {
+        Ok(OsIpcSharedMemory::from_bytes(bytes))
+    }
+
+    /// Overwrite the contents with zeros, as seen by every mapping of the region.
+    pub fn wipe(&self) {
+        if !self.ptr.is_null() {
+            unsafe {
+                super::wipe_bytes(self.ptr, self.length)
+            }
+        }
+    }
+
+    /// Received regions are copies of the sent ones, which can't be reached from here.
+    pub fn check_revocable() -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "received Mach shared memory is a copy"))
+    }
+
+    pub fn revoke(&self) -> Result<(), Error> {
+        OsIpcSharedMemory::check_revocable()
+    }
+
+    /// Lock the pages of this mapping into RAM, so they are never paged out,
+    /// until the mapping is dropped.
+    pub fn lock(&self) -> Result<(), Error> {
+        super::lock_bytes(self.ptr, self.length)
+    }
+
+    pub fn set_numa_node(&self, _: usize) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach shared memory has no NUMA placement"))
+    }
+
+    pub fn numa_nodes(&self) -> Result<Vec<usize>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Mach shared memory has no NUMA placement"))
+    }
 }
 
 unsafe fn allocate_vm_pages(length: usize) -> *mut u8 {
@@ -1015,6 +1605,8 @@ pub enum MachError {
     SendNoBuffer,
     SendTimedOut,
     SendTooLarge,
+    /// The watched peer process exited, with its pid and exit status if known.
+    PeerDied(u32, Option<ExitStatus>),
     Unknown(mach_msg_return_t),
 }
 
@@ -1237,6 +1829,9 @@ impl From<MachError> for Error {
                 Error::new(ErrorKind::ConnectionReset,
                            "No senders exist for this port.")
             }
+            MachError::PeerDied(pid, exit_status) => {
+                Error::new(ErrorKind::ConnectionAborted, PeerDied { pid, exit_status })
+            }
             MachError::Unknown(mach_error_number) => {
                 Error::new(ErrorKind::Other,
                            format!("Unknown Mach error: {:x}", mach_error_number))
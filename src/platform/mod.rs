@@ -9,11 +9,15 @@
 
 #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
                                                 target_os = "openbsd",
-                                                target_os = "freebsd")))]
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
 mod unix;
 #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
                                                 target_os = "openbsd",
-                                                target_os = "freebsd")))]
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
 mod os {
     pub use super::unix::*;
 }
@@ -24,6 +28,18 @@ mod macos;
 mod os {
     pub use super::macos::*;
 }
+#[cfg(all(not(feature = "force-inprocess"), target_os = "macos"))]
+pub use self::os::set_out_of_line_threshold;
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub use self::os::enter_constrained_mode;
+#[cfg(all(feature = "fuzzing", not(feature = "force-inprocess"),
+          any(target_os = "linux", target_os = "openbsd", target_os = "freebsd",
+              target_os = "illumos", target_os = "solaris")))]
+pub use self::os::fuzzing;
 
 #[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
 mod inprocess;
@@ -31,10 +47,137 @@ mod inprocess;
 mod os {
     pub use super::inprocess::*;
 }
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android", target_os = "ios"))]
+pub use self::os::{OsIpcLocalMessage, OsIpcLocalPayload};
+#[cfg(all(feature = "test-support", any(feature = "force-inprocess", target_os = "windows",
+                                        target_os = "android", target_os = "ios")))]
+pub use self::os::reset_one_shot_servers;
+
+#[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios")))]
+use libc;
+#[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios")))]
+use std::io;
+#[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios")))]
+use std::mem;
+#[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios")))]
+use std::os::unix::process::ExitStatusExt;
+use bincode;
+use std::error::Error as StdError;
+use std::fmt::{self, Formatter};
+use std::process::ExitStatus;
+use std::ptr;
+use std::sync::atomic;
+
+mod pool;
+pub use self::pool::{ReceiveBufferPoolStats, receive_buffer_pool_stats, set_receive_buffer_pool};
+pub use self::pool::{BufferAllocator, set_buffer_allocator};
+pub(crate) use self::pool::{recycle as recycle_buffer, take as take_buffer};
 
 pub use self::os::{OsIpcChannel, OsIpcOneShotServer, OsIpcReceiver, OsIpcReceiverSet};
-pub use self::os::{OsIpcSelectionResult, OsIpcSender, OsIpcSharedMemory};
-pub use self::os::{OsOpaqueIpcChannel, channel};
+pub use self::os::{OsIpcSelectionResult, OsIpcSender, OsIpcSharedMemory, OsIpcWeakSender};
+pub use self::os::{OsOpaqueIpcChannel, channel, channel_with_buffer_sizes};
+pub(crate) use self::os::{diagnose, rebind_one_shot_servers};
+
+/// Overwrite `length` bytes at `ptr` with zeros, in a way the compiler can't optimize out.
+unsafe fn wipe_bytes(ptr: *mut u8, length: usize) {
+    for offset in 0..length {
+        ptr::write_volatile(ptr.add(offset), 0);
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+/// Lock the pages spanning `length` bytes at `ptr` into RAM.
+#[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios")))]
+fn lock_bytes(ptr: *mut u8, length: usize) -> Result<(), io::Error> {
+    if length == 0 || unsafe { libc::mlock(ptr as *const libc::c_void, length) } == 0 {
+        return Ok(())
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::ENOMEM) => {
+            Err(io::Error::new(io::ErrorKind::OutOfMemory,
+                               "locking the shared memory would exceed RLIMIT_MEMLOCK"))
+        }
+        Some(libc::EPERM) | Some(libc::EAGAIN) => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               "not allowed to lock the shared memory into RAM"))
+        }
+        _ => Err(error),
+    }
+}
+
+/// Credentials of the process at the other end of a connection,
+/// as far as the backend can tell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OsIpcPeerCredentials {
+    pub pid: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Error reported by a receiver watching its peer with `IpcReceiver::watch_peer()`,
+/// once the peer process exited and the messages already queued were received.
+///
+/// It is returned wrapped in an `ErrorKind::Io` error of kind `ConnectionAborted`;
+/// use [from_error] to extract it.
+///
+/// [from_error]: #method.from_error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerDied {
+    pub pid: u32,
+    /// How the peer exited: only known if it is a child of this process,
+    /// which wasn't reaped yet.
+    pub exit_status: Option<ExitStatus>,
+}
+
+impl PeerDied {
+    /// Extract the `PeerDied` from an error returned by a receive call, if any.
+    pub fn from_error(error: &bincode::Error) -> Option<&PeerDied> {
+        match **error {
+            bincode::ErrorKind::Io(ref error) => {
+                error.get_ref().and_then(|error| error.downcast_ref::<PeerDied>())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerDied {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match self.exit_status {
+            Some(exit_status) => write!(formatter, "peer {} died: {}", self.pid, exit_status),
+            None => write!(formatter, "peer {} died", self.pid),
+        }
+    }
+}
+
+impl StdError for PeerDied {}
+
+/// How the process `id` designates exited, if it is an unreaped child of this
+/// process; it is left for `wait()` to collect.
+#[cfg(not(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios")))]
+fn unreaped_exit_status(id_type: libc::idtype_t, id: libc::id_t) -> Option<ExitStatus> {
+    unsafe {
+        let mut info: libc::siginfo_t = mem::zeroed();
+        let options = libc::WEXITED | libc::WNOWAIT | libc::WNOHANG;
+        if libc::waitid(id_type, id, &mut info, options) != 0 || info.si_pid() == 0 {
+            return None
+        }
+        // Encode it the way `waitpid()` would.
+        let status = info.si_status();
+        Some(ExitStatus::from_raw(match info.si_code {
+            libc::CLD_EXITED => (status & 0xff) << 8,
+            libc::CLD_DUMPED => status | 0x80,
+            _ => status,
+        }))
+    }
+}
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,164 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A pool of message buffers, handed back once a message is deserialized,
+//! so steady-state traffic doesn't allocate on the receive path.
+
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+struct Pool {
+    buffers: Vec<Vec<u8>>,
+    max_buffers: usize,
+    max_buffer_size: usize,
+}
+
+lazy_static! {
+    static ref POOL: Mutex<Pool> = Mutex::new(Pool {
+        buffers: Vec::new(),
+        max_buffers: 8,
+        max_buffer_size: 1024 * 1024,
+    });
+}
+
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static MISSES: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref ALLOCATOR: RwLock<Option<Arc<dyn BufferAllocator>>> = RwLock::new(None);
+}
+
+// Whether `ALLOCATOR` is set, checked first so the default costs no lock.
+static CUSTOM_ALLOCATOR: AtomicBool = AtomicBool::new(false);
+
+/// Where the buffers messages are serialized and received into come from, when the
+/// pool has none to reuse, and go to when it has no room for them.
+///
+/// Buffers are plain vectors, which may be grown, or handed to the application and
+/// freed, as any other; so their memory must come from the global allocator. This is
+/// for keeping IPC buffers apart within it, e.g. in a jemalloc arena of their own,
+/// or for recycling them in a frame allocator.
+pub trait BufferAllocator: Send + Sync {
+    /// An empty buffer with room for at least `capacity` bytes.
+    fn allocate(&self, capacity: usize) -> Vec<u8>;
+
+    /// Take back a buffer the crate no longer needs. By default, it is freed.
+    fn release(&self, buffer: Vec<u8>) {
+        drop(buffer)
+    }
+}
+
+/// Take the buffers the receive buffer pool can't provide from `allocator`, and give
+/// it back those the pool can't keep; or, with `None`, use the global allocator.
+///
+/// Buffers allocated before keep going to the pool, or to the allocator in use when
+/// they are released. Set the receive buffer pool to zero buffers to have all the
+/// buffers go through the allocator.
+pub fn set_buffer_allocator(allocator: Option<Arc<dyn BufferAllocator>>) {
+    let mut current = ALLOCATOR.write().unwrap();
+    CUSTOM_ALLOCATOR.store(allocator.is_some(), Ordering::SeqCst);
+    *current = allocator;
+}
+
+fn custom_allocator() -> Option<Arc<dyn BufferAllocator>> {
+    if !CUSTOM_ALLOCATOR.load(Ordering::Relaxed) {
+        return None
+    }
+    // Cloned, so allocators can use channels themselves.
+    ALLOCATOR.read().unwrap().clone()
+}
+
+/// Keep at most `max_buffers` receive buffers for reuse, of at most
+/// `max_buffer_size` bytes each. Zero buffers disables the pool.
+///
+/// Defaults to 8 buffers of up to 1 MiB.
+pub fn set_receive_buffer_pool(max_buffers: usize, max_buffer_size: usize) {
+    let mut pool = POOL.lock().unwrap();
+    pool.max_buffers = max_buffers;
+    pool.max_buffer_size = max_buffer_size;
+    pool.buffers.retain(|buffer| buffer.capacity() <= max_buffer_size);
+    pool.buffers.truncate(max_buffers);
+}
+
+/// How often receive buffers could be taken from the pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveBufferPoolStats {
+    /// Buffers reused from the pool.
+    pub hits: usize,
+    /// Buffers that had to be allocated.
+    pub misses: usize,
+}
+
+impl ReceiveBufferPoolStats {
+    /// The share of buffers reused from the pool, between 0 and 1.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+/// Statistics of the receive buffer pool since the process started.
+pub fn receive_buffer_pool_stats() -> ReceiveBufferPoolStats {
+    ReceiveBufferPoolStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// An empty buffer with room for at least `capacity` bytes.
+pub fn take(capacity: usize) -> Vec<u8> {
+    {
+        let mut pool = POOL.lock().unwrap();
+        if let Some(index) = pool.buffers.iter().position(|buffer| buffer.capacity() >= capacity) {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return pool.buffers.swap_remove(index)
+        }
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    match custom_allocator() {
+        Some(allocator) => allocator.allocate(capacity),
+        None => Vec::with_capacity(capacity),
+    }
+}
+
+/// Hand back a buffer whose contents are no longer needed.
+pub fn recycle(buffer: Vec<u8>) {
+    if buffer.capacity() == 0 {
+        return
+    }
+    if let Some(rejected) = keep(buffer) {
+        if let Some(allocator) = custom_allocator() {
+            allocator.release(rejected)
+        }
+    }
+}
+
+/// Keep `buffer` in the pool, returning the buffer that doesn't fit, if any.
+fn keep(mut buffer: Vec<u8>) -> Option<Vec<u8>> {
+    let mut pool = POOL.lock().unwrap();
+    if buffer.capacity() > pool.max_buffer_size {
+        return Some(buffer)
+    }
+    buffer.clear();
+    if pool.buffers.len() < pool.max_buffers {
+        pool.buffers.push(buffer);
+        return None
+    }
+    // When full, prefer keeping larger buffers, which can serve any request.
+    let smallest = (0..pool.buffers.len()).min_by_key(|&index| pool.buffers[index].capacity());
+    match smallest {
+        Some(index) if pool.buffers[index].capacity() < buffer.capacity() => {
+            Some(mem::replace(&mut pool.buffers[index], buffer))
+        }
+        _ => Some(buffer),
+    }
+}
@@ -62,6 +62,28 @@ fn receiver_transfer() {
                (data, vec![], vec![]));
 }
 
+#[cfg(not(all(target_os = "macos", not(feature = "force-inprocess"))))]
+#[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
+fn receiver_duplicate() {
+    let (tx, rx) = platform::channel().unwrap();
+    let other_rx = rx.try_duplicate().unwrap();
+    let data: Vec<u8> = (0.. 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let thread = {
+        let data = data.clone();
+        thread::spawn(move || {
+            tx.send(b"small", vec![], vec![]).unwrap();
+            // Arrives whole at one receiver even if fragmented.
+            tx.send(&data, vec![], vec![]).unwrap();
+        })
+    };
+    assert_eq!(rx.recv().unwrap().0, b"small");
+    assert_eq!(other_rx.recv().unwrap().0, data);
+    thread.join().unwrap();
+    assert!(rx.recv().unwrap_err().channel_is_closed());
+    assert!(other_rx.recv().unwrap_err().channel_is_closed());
+}
+
 #[test]
 fn multisender_transfer() {
     let (super_tx, super_rx) = platform::channel().unwrap();
@@ -136,11 +158,13 @@ fn check_big_data(size: u32) {
 }
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn big_data() {
     check_big_data(1024 * 1024);
 }
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn huge_data() {
     check_big_data(1024 * 1024 * 50);
     check_big_data(1024 * 1024 * 46);
@@ -150,6 +174,7 @@ fn huge_data() {
 }
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn big_data_with_sender_transfer() {
     let (super_tx, super_rx) = platform::channel().unwrap();
     let (sub_tx, sub_rx) = platform::channel().unwrap();
@@ -209,7 +234,9 @@ fn with_n_fds(n: usize, size: usize) {
 
 // These tests only apply to platforms that need fragmentation.
 #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
-                                                target_os = "freebsd")))]
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
 mod fragment_tests {
     use platform;
     use super::with_n_fds;
@@ -288,6 +315,7 @@ fn fd_only() {
 macro_rules! create_big_data_with_n_fds {
     ($name:ident, $n:expr) => (
         #[test]
+        #[cfg_attr(miri, ignore)] // Too slow under Miri.
         fn $name() {
             let (sender_fds, receivers): (Vec<_>, Vec<_>) = (0..$n).map(|_| platform::channel().unwrap())
                                                             .map(|(tx, rx)| (OsIpcChannel::Sender(tx), rx))
@@ -333,6 +361,7 @@ create_big_data_with_n_fds!(big_data_with_5_fds, 5);
 create_big_data_with_n_fds!(big_data_with_6_fds, 6);
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn concurrent_senders() {
     let num_senders = 3;
 
@@ -522,6 +551,7 @@ fn receiver_set_medium_data() {
 }
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn receiver_set_big_data() {
     let (tx0, rx0) = platform::channel().unwrap();
     let (tx1, rx1) = platform::channel().unwrap();
@@ -612,6 +642,9 @@ fn receiver_set_concurrent() {
                     let (_, _, msg_index) = receiver_records.remove(&rx_id).unwrap();
                     assert_eq!(msg_index, messages_per_channel);
                 },
+                #[cfg(not(any(feature = "force-inprocess", target_os = "windows",
+                              target_os = "android", target_os = "ios")))]
+                platform::OsIpcSelectionResult::PeerDied(..) => unreachable!(),
             }
         }
     }
@@ -798,6 +831,14 @@ fn shared_memory_clone() {
     assert_eq!(&shmem_data_0[..], &shmem_data_1[..]);
 }
 
+#[test]
+fn shared_memory_wipe() {
+    let shmem_data_0 = OsIpcSharedMemory::from_byte(0xba, 4096);
+    let shmem_data_1 = shmem_data_0.clone();
+    shmem_data_1.wipe();
+    assert!(shmem_data_0.iter().all(|byte| *byte == 0));
+}
+
 #[test]
 fn try_recv() {
     let (tx, rx) = platform::channel().unwrap();
@@ -858,6 +899,7 @@ fn no_senders_notification_try_recv_delayed() {
 }
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn try_recv_large() {
     let (tx, rx) = platform::channel().unwrap();
     assert!(rx.try_recv().is_err());
@@ -884,6 +926,7 @@ fn try_recv_large() {
 }
 
 #[test]
+#[cfg_attr(miri, ignore)] // Too slow under Miri.
 fn try_recv_large_delayed() {
     // These settings work well on my system when doing cargo test --release.
     // Haven't found a good way to test this with non-release builds...
@@ -981,3 +1024,110 @@ mod sync_test {
         platform::OsIpcSender::test_not_sync();
     }
 }
+
+// Run with `RUSTFLAGS="--cfg loom"`, see `platform::inprocess::sync`.
+#[cfg(all(loom, any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+                    target_os = "ios")))]
+mod loom_tests {
+    use loom;
+    use platform::{self, OsIpcReceiverSet, OsIpcSelectionResult};
+
+    #[test]
+    fn loom_send_then_close() {
+        loom::model(|| {
+            let (tx, rx) = platform::channel().unwrap();
+            let thread = loom::thread::spawn(move || {
+                tx.send(b"1234", vec![], vec![]).unwrap();
+            });
+            assert_eq!(rx.recv().unwrap().0, b"1234");
+            assert!(rx.recv().unwrap_err().channel_is_closed());
+            thread.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn loom_send_to_dropped_receiver() {
+        loom::model(|| {
+            let (tx, rx) = platform::channel().unwrap();
+            let thread = loom::thread::spawn(move || drop(rx));
+            // Either the message is dropped along with the receiver, or the send fails.
+            let _ = tx.send(b"1234", vec![], vec![]);
+            thread.join().unwrap();
+            assert!(tx.send(b"1234", vec![], vec![]).is_err());
+        });
+    }
+
+    #[test]
+    fn loom_receiver_set() {
+        loom::model(|| {
+            let mut rx_set = OsIpcReceiverSet::new().unwrap();
+            let mut threads = vec![];
+            let mut ids = vec![];
+            for data in &[b"a", b"b"] {
+                let (tx, rx) = platform::channel().unwrap();
+                ids.push(rx_set.add(rx).unwrap());
+                threads.push(loom::thread::spawn(move || {
+                    tx.send(&data[..], vec![], vec![]).unwrap();
+                }));
+            }
+
+            let (mut received, mut closed) = (vec![], vec![]);
+            while closed.len() < ids.len() {
+                for result in rx_set.select().unwrap() {
+                    match result {
+                        OsIpcSelectionResult::DataReceived(id, data, _, _) => {
+                            // Each channel delivers its message before closing.
+                            assert!(!closed.contains(&id));
+                            received.push(data)
+                        }
+                        OsIpcSelectionResult::ChannelClosed(id) => closed.push(id),
+                    }
+                }
+            }
+            received.sort();
+            assert_eq!(received, vec![b"a".to_vec(), b"b".to_vec()]);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        });
+    }
+}
+
+#[cfg(all(feature = "fuzzing", target_os = "linux", not(feature = "force-inprocess")))]
+#[test]
+fn fuzzing_first_fragment() {
+    use platform::fuzzing::decode_first_fragment;
+    let mut fragment = 5usize.to_ne_bytes().to_vec();
+    fragment.extend_from_slice(b"abc");
+    assert_eq!(decode_first_fragment(&fragment).unwrap(), (5, &b"abc"[..]));
+    assert!(decode_first_fragment(&fragment[..3]).is_err());
+    let mut oversized = 2usize.to_ne_bytes().to_vec();
+    oversized.extend_from_slice(b"abc");
+    assert!(decode_first_fragment(&oversized).is_err());
+}
+
+#[cfg(all(feature = "fuzzing", target_os = "linux", not(feature = "force-inprocess")))]
+#[test]
+fn fuzzing_ancillary_data() {
+    use libc::{self, c_int, cmsghdr};
+    use platform::fuzzing::decode_ancillary_data;
+    use std::{mem, ptr, slice};
+
+    let fds: [c_int; 2] = [3, 4];
+    let data_len = mem::size_of_val(&fds) as u32;
+    // Words, so the header is aligned.
+    let mut buffer = vec![0u64; unsafe { libc::CMSG_SPACE(data_len) } as usize / 8 + 1];
+    let len = unsafe {
+        let header = buffer.as_mut_ptr() as *mut cmsghdr;
+        (*header).cmsg_len = libc::CMSG_LEN(data_len) as _;
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, libc::CMSG_DATA(header), 8);
+        libc::CMSG_SPACE(data_len) as usize
+    };
+    let control = unsafe { slice::from_raw_parts(buffer.as_ptr() as *const u8, len) };
+    assert_eq!(decode_ancillary_data(control).unwrap(), fds);
+    assert_eq!(decode_ancillary_data(&[]).unwrap(), vec![]);
+    let truncated = unsafe { libc::CMSG_LEN(data_len) } as usize - 1;
+    assert!(decode_ancillary_data(&control[..truncated]).is_err());
+}
@@ -8,26 +8,37 @@
 // except according to those terms.
 
 use bincode;
+use diagnostics::{ChannelDiagnostics, IpcDiagnostics, ServerDiagnostics, SharedMemoryDiagnostics};
+use ipc::{self, ShmQuotaExceeded};
+use super::{OsIpcPeerCredentials, PeerDied, pool};
+use router::QosClass;
 use fnv::FnvHasher;
 use libc::{self, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE, SOCK_SEQPACKET, SOL_SOCKET};
-use libc::{SO_LINGER, S_IFMT, S_IFSOCK, c_char, c_int, c_void, getsockopt};
+use libc::{SOCK_CLOEXEC, SO_LINGER, S_IFMT, S_IFSOCK, c_char, c_int, c_void, getsockopt};
 use libc::{iovec, mode_t, msghdr, off_t, recvmsg, sendmsg};
 use libc::{setsockopt, size_t, sockaddr, sockaddr_un, socketpair, socklen_t, sa_family_t};
 use std::cell::Cell;
 use std::cmp;
+use std::env;
 use std::collections::HashMap;
 use std::ffi::CString;
+use std::fs::{self, DirBuilder};
 use std::fmt::{self, Debug, Formatter};
 use std::hash::BuildHasherDefault;
 use std::io::{Error, ErrorKind};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::{Deref, RangeFrom};
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::slice;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::UNIX_EPOCH;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use std::thread;
 use mio::unix::EventedFd;
 use mio::{Poll, Token, Events, Ready, PollOpt};
@@ -35,7 +46,17 @@ use tempfile::{Builder, TempDir};
 
 const MAX_FDS_IN_CMSG: u32 = 64;
 
+#[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
 const SCM_RIGHTS: c_int = 0x01;
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+const SCM_RIGHTS: c_int = 0x1010;
+
+// Every descriptor made or received here is close-on-exec, so processes spawned by
+// other code don't inherit them; `prepare_for_inheritance()` opts endpoints out.
+#[cfg(not(target_os = "solaris"))]
+const RECV_FLAGS: c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(target_os = "solaris")]
+const RECV_FLAGS: c_int = 0;
 
 // The value Linux returns for SO_SNDBUF
 // is not the size we are actually allowed to use...
@@ -67,6 +88,13 @@ lazy_static! {
     };
 }
 
+lazy_static! {
+    /// Held while taking over a sender handed down in the environment.
+    static ref FROM_ENV_LOCK: Mutex<()> = Mutex::new(());
+    /// Whether the sockets systemd passed were adopted by `from_listen_fds()`.
+    static ref LISTEN_FDS_ADOPTED: Mutex<bool> = Mutex::new(false);
+}
+
 // The pid of the current process which is used to create unique IDs
 lazy_static! {
     static ref PID: c_int = unsafe { libc::getpid() };
@@ -75,10 +103,40 @@ lazy_static! {
 // A global count used to create unique IDs
 static SHM_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+// Set by `enter_constrained_mode()`.
+static CONSTRAINED: AtomicBool = AtomicBool::new(false);
+
+const NO_RESERVED_STORE_LEFT: &str = "no reserved shared memory left in constrained mode";
+
+lazy_static! {
+    // Unlinked shared memory objects created by `enter_constrained_mode()`,
+    // for backing regions once `shm_open()` is off limits.
+    static ref RESERVED_BACKING_STORES: Mutex<Vec<c_int>> = Mutex::new(Vec::new());
+}
+
+/// Stop touching the filesystem; see `ipc::enter_constrained_mode()`.
+pub fn enter_constrained_mode(reserved_shared_memory: usize) -> Result<(),UnixError> {
+    if cfg!(not(all(target_os="linux", feature="memfd"))) {
+        let mut reserved = RESERVED_BACKING_STORES.lock().unwrap();
+        for _ in 0..reserved_shared_memory {
+            reserved.push(BackingStore::new(0)?.into_fd());
+        }
+    }
+    CONSTRAINED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+fn check_unconstrained() -> Result<(),UnixError> {
+    if CONSTRAINED.load(Ordering::SeqCst) {
+        return Err(UnixError::Errno(libc::EPERM))
+    }
+    Ok(())
+}
+
 pub fn channel() -> Result<(OsIpcSender, OsIpcReceiver),UnixError> {
     let mut results = [0, 0];
     unsafe {
-        if socketpair(libc::AF_UNIX, SOCK_SEQPACKET, 0, &mut results[0]) >= 0 {
+        if socketpair(libc::AF_UNIX, SOCK_SEQPACKET | SOCK_CLOEXEC, 0, &mut results[0]) >= 0 {
             Ok((OsIpcSender::from_fd(results[0]), OsIpcReceiver::from_fd(results[1])))
         } else {
             Err(UnixError::last())
@@ -86,6 +144,43 @@ pub fn channel() -> Result<(OsIpcSender, OsIpcReceiver),UnixError> {
     }
 }
 
+/// Create a channel whose sending socket has a `send_buffer` of at least the
+/// default size, and whose receiving socket has a `recv_buffer`.
+///
+/// Messages are still cut into fragments of the default size, so a larger send
+/// buffer holds more of them before the sender blocks; a smaller one would let none
+/// through, and fails with `EINVAL`. The kernel may cap the sizes, as Linux does at
+/// `net.core.wmem_max` and `net.core.rmem_max`.
+pub fn channel_with_buffer_sizes(send_buffer: Option<usize>, recv_buffer: Option<usize>)
+                                 -> Result<(OsIpcSender, OsIpcReceiver),UnixError> {
+    let (sender, receiver) = channel()?;
+    if let Some(send_buffer) = send_buffer {
+        set_buffer_size(sender.fd.0, libc::SO_SNDBUF, send_buffer)?;
+        if sender.get_system_sendbuf_size()? < *SYSTEM_SENDBUF_SIZE {
+            return Err(UnixError::Errno(libc::EINVAL))
+        }
+    }
+    if let Some(recv_buffer) = recv_buffer {
+        set_buffer_size(receiver.fd.get(), libc::SO_RCVBUF, recv_buffer)?;
+    }
+    Ok((sender, receiver))
+}
+
+fn set_buffer_size(fd: c_int, option: c_int, size: usize) -> Result<(),UnixError> {
+    let size = cmp::min(size, c_int::MAX as usize) as c_int;
+    let result = unsafe {
+        setsockopt(fd,
+                   SOL_SOCKET,
+                   option,
+                   &size as *const c_int as *const c_void,
+                   mem::size_of::<c_int>() as socklen_t)
+    };
+    if result < 0 {
+        return Err(UnixError::last())
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy)]
 struct PollEntry {
     pub id: u64,
@@ -95,6 +190,8 @@ struct PollEntry {
 #[derive(PartialEq, Debug)]
 pub struct OsIpcReceiver {
     fd: Cell<c_int>,
+    // The pidfd, or kqueue, and pid of the process watched with `watch_peer()`.
+    peer: Cell<Option<(c_int, u32)>>,
 }
 
 impl Drop for OsIpcReceiver {
@@ -104,6 +201,9 @@ impl Drop for OsIpcReceiver {
                 let result = libc::close(self.fd.get());
                 assert!(thread::panicking() || result == 0);
             }
+            if let Some((pidfd, _)) = self.peer.get() {
+                libc::close(pidfd);
+            }
         }
     }
 }
@@ -112,6 +212,7 @@ impl OsIpcReceiver {
     fn from_fd(fd: c_int) -> OsIpcReceiver {
         OsIpcReceiver {
             fd: Cell::new(fd),
+            peer: Cell::new(None),
         }
     }
 
@@ -125,15 +226,232 @@ impl OsIpcReceiver {
         OsIpcReceiver::from_fd(self.consume_fd())
     }
 
+    /// Take over a receiver socket, e.g. one inherited from the parent process.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a channel socket, not owned by anything else.
+    pub unsafe fn from_raw_fd(fd: c_int) -> OsIpcReceiver {
+        OsIpcReceiver::from_fd(fd)
+    }
+
+    pub fn as_raw_fd(&self) -> c_int {
+        self.fd.get()
+    }
+
+    pub fn into_raw_fd(self) -> c_int {
+        self.consume_fd()
+    }
+
+    /// Senders are descriptors of the other end of the socket, maybe in other processes, so
+    /// they can only be counted once they are all closed.
+    pub fn sender_count(&self) -> Option<usize> {
+        if is_hung_up(self.fd.get()) {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Another receiver for the same socket. Messages go to whichever receiver asks first;
+    /// fragmented messages still arrive whole, as their followup fragments are sent over
+    /// a dedicated channel.
+    pub fn try_duplicate(&self) -> Result<OsIpcReceiver,UnixError> {
+        let fd = dup_cloexec(self.fd.get());
+        if fd < 0 {
+            return Err(UnixError::last())
+        }
+        Ok(OsIpcReceiver::from_fd(fd))
+    }
+
+    pub fn prepare_for_inheritance(&self) -> Result<(),Error> {
+        clear_cloexec(self.fd.get())
+    }
+
+    /// Once process `pid` exits, fail receive calls finding no message with
+    /// `UnixError::PeerDied`, rather than wait. Supported on Linux, with pidfds, and on
+    /// the BSDs, with kqueue.
+    pub fn watch_peer(&self, pid: u32) -> Result<(),UnixError> {
+        let pidfd = open_pidfd(pid)?;
+        if let Some((previous_pidfd, _)) = self.peer.replace(Some((pidfd, pid))) {
+            unsafe {
+                libc::close(previous_pidfd);
+            }
+        }
+        Ok(())
+    }
+
     pub fn recv(&self)
                 -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),UnixError> {
+        self.wait_for_message_or_peer()?;
         recv(self.fd.get(), BlockingMode::Blocking)
     }
 
     pub fn try_recv(&self)
                     -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),UnixError> {
-        recv(self.fd.get(), BlockingMode::Nonblocking)
+        match recv(self.fd.get(), BlockingMode::Nonblocking) {
+            Err(UnixError::Errno(errno)) if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK => {
+                match self.peer.get() {
+                    Some((pidfd, pid)) if is_readable(pidfd)? => {
+                        Err(UnixError::PeerDied(pid, peer_exit_status(pidfd, pid)))
+                    }
+                    _ => Err(UnixError::Errno(errno)),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Receive, waiting for a message at most `duration`, failing with `ETIMEDOUT` after that.
+    pub fn try_recv_timeout(&self, duration: Duration) -> Result<SerializedMessage,UnixError> {
+        let deadline = Instant::now() + duration;
+        loop {
+            match self.try_recv() {
+                Err(UnixError::Errno(errno)) if errno == libc::EAGAIN ||
+                                                errno == libc::EWOULDBLOCK => {}
+                result => return result,
+            }
+            // Another receiver of the channel may take the message first, so check again.
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(UnixError::Errno(libc::ETIMEDOUT))
+            }
+            self.wait_readable(deadline - now)?;
+        }
+    }
+
+    /// Wait at most `duration` for the socket, or the watched peer, to become readable.
+    fn wait_readable(&self, duration: Duration) -> Result<(),UnixError> {
+        let mut pollfds = [
+            libc::pollfd { fd: self.fd.get(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: -1, events: libc::POLLIN, revents: 0 },
+        ];
+        if let Some((pidfd, _)) = self.peer.get() {
+            pollfds[1].fd = pidfd;
+        }
+        // Round up, so we don't wake up just before the deadline.
+        let millis = (duration + Duration::from_nanos(999_999)).as_millis();
+        let millis = cmp::min(millis, c_int::MAX as u128);
+        if unsafe { libc::poll(pollfds.as_mut_ptr(), 2, millis as c_int) } < 0 {
+            match UnixError::last() {
+                UnixError::Errno(libc::EINTR) => {}
+                error => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocking receive of a message reassembled straight into shared memory.
+    pub fn recv_bulk(&self)
+                     -> Result<(OsIpcSharedMemory, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+                               UnixError> {
+        self.wait_for_message_or_peer()?;
+        recv_bulk(self.fd.get(), BlockingMode::Blocking)
+    }
+
+    /// If watching the peer, wait until the socket is readable, failing if the peer exits first.
+    fn wait_for_message_or_peer(&self) -> Result<(),UnixError> {
+        let (pidfd, pid) = match self.peer.get() {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let mut pollfds = [
+            libc::pollfd { fd: self.fd.get(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: pidfd, events: libc::POLLIN, revents: 0 },
+        ];
+        loop {
+            if unsafe { libc::poll(pollfds.as_mut_ptr(), 2, -1) } < 0 {
+                match UnixError::last() {
+                    UnixError::Errno(libc::EINTR) => continue,
+                    error => return Err(error),
+                }
+            }
+            if pollfds[0].revents != 0 {
+                return Ok(())
+            }
+            if pollfds[1].revents != 0 {
+                return Err(UnixError::PeerDied(pid, peer_exit_status(pidfd, pid)))
+            }
+        }
+    }
+
+    /// Close our end of the channel, and replace it with a socket whose peer is already gone,
+    /// so any further receive reports the channel as closed.
+    pub fn invalidate(&self) -> Result<(),UnixError> {
+        let (_, closed_receiver) = channel()?;
+        let fd = self.fd.replace(closed_receiver.consume_fd());
+        if fd >= 0 {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open a descriptor which becomes readable once process `pid` exits.
+#[cfg(target_os = "linux")]
+fn open_pidfd(pid: u32) -> Result<c_int,UnixError> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if pidfd < 0 {
+        return Err(UnixError::last())
     }
+    Ok(pidfd as c_int)
+}
+
+/// Open a kqueue with an `EVFILT_PROC` filter for process `pid`, which becomes readable
+/// once the process exits. The event is never collected, so it stays readable.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+fn open_pidfd(pid: u32) -> Result<c_int,UnixError> {
+    unsafe {
+        let kqueue = libc::kqueue();
+        if kqueue < 0 {
+            return Err(UnixError::last())
+        }
+        libc::fcntl(kqueue, libc::F_SETFD, libc::FD_CLOEXEC);
+        let mut change: libc::kevent = mem::zeroed();
+        change.ident = pid as libc::uintptr_t;
+        change.filter = libc::EVFILT_PROC;
+        change.flags = libc::EV_ADD | libc::EV_ONESHOT;
+        change.fflags = libc::NOTE_EXIT;
+        if libc::kevent(kqueue, &change, 1, ptr::null_mut(), 0, ptr::null()) < 0 {
+            let error = UnixError::last();
+            libc::close(kqueue);
+            return Err(error)
+        }
+        Ok(kqueue)
+    }
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+fn open_pidfd(_: u32) -> Result<c_int,UnixError> {
+    Err(UnixError::Errno(libc::ENOSYS))
+}
+
+/// How the process behind `pidfd`, `pid`, exited, if it is an unreaped child of ours.
+#[cfg(target_os = "linux")]
+fn peer_exit_status(pidfd: c_int, _: u32) -> Option<ExitStatus> {
+    super::unreaped_exit_status(libc::P_PIDFD, pidfd as libc::id_t)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_exit_status(_: c_int, pid: u32) -> Option<ExitStatus> {
+    super::unreaped_exit_status(libc::P_PID, pid as libc::id_t)
+}
+
+/// Whether `fd` is readable or hung up, without waiting.
+fn is_readable(fd: c_int) -> Result<bool,UnixError> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut pollfd, 1, 0) } {
+        result if result < 0 => Err(UnixError::last()),
+        _ => Ok(pollfd.revents != 0),
+    }
+}
+
+/// Whether the other end of the socket `fd` is closed, without waiting.
+fn is_hung_up(fd: c_int) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: 0, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & libc::POLLHUP != 0 }
 }
 
 #[derive(PartialEq, Debug)]
@@ -166,6 +484,108 @@ impl OsIpcSender {
         }
     }
 
+    /// Take over a sender socket, e.g. one inherited from the parent process.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a channel socket, not owned by anything else.
+    pub unsafe fn from_raw_fd(fd: c_int) -> OsIpcSender {
+        OsIpcSender::from_fd(fd)
+    }
+
+    /// Let the children spawned by `command` inherit a duplicate of the socket, whose
+    /// number is stored in their environment variable `var_name`. The parent's copy
+    /// is closed along with `command`.
+    pub fn to_env(&self, var_name: &str, command: &mut Command) -> Result<(),Error> {
+        // Close-on-exec, so children spawned meanwhile by other threads don't get it.
+        let fd = unsafe { libc::fcntl(self.fd.0, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error())
+        }
+        let inherited = SharedFileDescriptor(fd);
+        command.env(var_name, format!("fd:{}", fd));
+        unsafe {
+            command.pre_exec(move || {
+                // In the child, between `fork()` and `exec()`.
+                if libc::fcntl(inherited.0, libc::F_SETFD, 0) < 0 {
+                    return Err(Error::last_os_error())
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Take over the socket handed down with `to_env()`.
+    ///
+    /// The environment is left alone, as changing it isn't safe while other threads
+    /// may read it. Setting close-on-exec on the socket instead keeps it from our own
+    /// children, and tells it was taken over already.
+    pub fn from_env(var_name: &str) -> Result<OsIpcSender,Error> {
+        let value = env::var(var_name).map_err(|_| {
+            Error::new(ErrorKind::NotFound, format!("no sender in ${}", var_name))
+        })?;
+        let fd = match value.strip_prefix("fd:").and_then(|fd| fd.parse::<c_int>().ok()) {
+            Some(fd) => fd,
+            None => {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("invalid sender in ${}: {}", var_name, value)))
+            }
+        };
+        let invalid = |reason| {
+            Error::new(ErrorKind::InvalidData,
+                       format!("descriptor {} in ${} {}", fd, var_name, reason))
+        };
+        // So two threads don't both take it over.
+        let _guard = FROM_ENV_LOCK.lock().unwrap();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(invalid("is not open"))
+        }
+        if flags & libc::FD_CLOEXEC != 0 {
+            return Err(invalid("was not inherited, or was taken over already"))
+        }
+        if !is_socket(fd) {
+            return Err(invalid("is not a socket"))
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error())
+        }
+        Ok(OsIpcSender::from_fd(fd))
+    }
+
+    pub fn as_raw_fd(&self) -> c_int {
+        self.fd.0
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !is_hung_up(self.fd.0)
+    }
+
+    pub fn prepare_for_inheritance(&self) -> Result<(),Error> {
+        clear_cloexec(self.fd.0)
+    }
+
+    pub fn set_qos_override(&self, _: Option<QosClass>) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Unix domain sockets don't carry a QoS class"))
+    }
+
+    pub fn downgrade(&self) -> Result<OsIpcWeakSender,Error> {
+        Ok(OsIpcWeakSender {
+            fd: Arc::downgrade(&self.fd),
+        })
+    }
+
+    /// Hand over the socket; as the descriptor may be shared with clones of
+    /// the sender, this returns a duplicate.
+    pub fn into_raw_fd(self) -> Result<c_int,UnixError> {
+        let fd = dup_cloexec(self.fd.0);
+        if fd < 0 {
+            return Err(UnixError::last())
+        }
+        Ok(fd)
+    }
+
     /// Maximum size of the kernel buffer used for transfers over this channel.
     ///
     /// Note: This is *not* the actual maximal packet size we are allowed to use...
@@ -227,8 +647,17 @@ impl OsIpcSender {
         for channel in channels.iter() {
             fds.push(channel.fd());
         }
+        // Empty regions without a store, as received in bulk, get one to be sent.
+        let mut empty_stores = Vec::new();
         for shared_memory_region in shared_memory_regions.iter() {
-            fds.push(shared_memory_region.store.fd());
+            match shared_memory_region.store.fd() {
+                fd if fd < 0 => {
+                    let store = BackingStore::new(0)?;
+                    fds.push(store.fd());
+                    empty_stores.push(store);
+                }
+                fd => fds.push(fd),
+            }
         }
 
         // `len` is the total length of the message.
@@ -389,10 +818,25 @@ impl OsIpcSender {
         Ok(())
     }
 
+    /// Like `send()`, taking ownership of `data`.
+    ///
+    /// The data is written to the socket straight from the buffer, so there's nothing to gain
+    /// from owning it here; this exists for the sake of backends that can move it instead.
+    pub fn send_vec(&self,
+                    data: Vec<u8>,
+                    channels: Vec<OsIpcChannel>,
+                    shared_memory_regions: Vec<OsIpcSharedMemory>)
+                    -> Result<(),UnixError> {
+        let result = self.send(&data, channels, shared_memory_regions);
+        pool::recycle(data);
+        result
+    }
+
     pub fn connect(name: String) -> Result<OsIpcSender,UnixError> {
+        check_unconstrained()?;
         let name = CString::new(name).unwrap();
         unsafe {
-            let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET, 0);
+            let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET | SOCK_CLOEXEC, 0);
             let (sockaddr, len) = new_sockaddr_un(name.as_ptr());
             if libc::connect(fd, &sockaddr as *const _ as *const sockaddr, len as socklen_t) < 0 {
                 return Err(UnixError::last())
@@ -403,6 +847,21 @@ impl OsIpcSender {
     }
 }
 
+/// A sender that doesn't keep the socket open, from `OsIpcSender::downgrade()`.
+#[derive(Debug, Clone)]
+pub struct OsIpcWeakSender {
+    fd: Weak<SharedFileDescriptor>,
+}
+
+impl OsIpcWeakSender {
+    pub fn upgrade(&self) -> Option<OsIpcSender> {
+        Some(OsIpcSender {
+            fd: self.fd.upgrade()?,
+            nosync_marker: PhantomData,
+        })
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum OsIpcChannel {
     Sender(OsIpcSender),
@@ -422,9 +881,19 @@ pub struct OsIpcReceiverSet {
     incrementor: RangeFrom<u64>,
     poll: Poll,
     pollfds: HashMap<Token, PollEntry, BuildHasherDefault<FnvHasher>>,
+    // The pidfds of watched peers, which are polled along with the receivers.
+    peers: HashMap<Token, PeerEntry, BuildHasherDefault<FnvHasher>>,
     events: Events
 }
 
+#[derive(Clone, Copy)]
+struct PeerEntry {
+    id: u64,
+    fd: c_int,
+    pidfd: c_int,
+    pid: u32,
+}
+
 impl Drop for OsIpcReceiverSet {
     fn drop(&mut self) {
         for &PollEntry { id: _, fd } in self.pollfds.values() {
@@ -433,6 +902,11 @@ impl Drop for OsIpcReceiverSet {
             };
             assert!(thread::panicking() || result == 0);
         }
+        for peer in self.peers.values() {
+            unsafe {
+                libc::close(peer.pidfd);
+            }
+        }
     }
 }
 
@@ -443,6 +917,7 @@ impl OsIpcReceiverSet {
             incrementor: 0..,
             poll: Poll::new()?,
             pollfds: HashMap::with_hasher(fnv),
+            peers: HashMap::with_hasher(BuildHasherDefault::default()),
             events: Events::with_capacity(10)
         })
     }
@@ -461,14 +936,59 @@ impl OsIpcReceiverSet {
                            Ready::readable(),
                            PollOpt::level())?;
         self.pollfds.insert(fd_token, poll_entry);
+        if let Some((pidfd, pid)) = receiver.peer.take() {
+            let peer_token = Token(pidfd as usize);
+            self.poll.register(&EventedFd(&pidfd),
+                               peer_token,
+                               Ready::readable(),
+                               PollOpt::level())?;
+            self.peers.insert(peer_token, PeerEntry {
+                id: last_index,
+                fd,
+                pidfd,
+                pid,
+            });
+        }
         Ok(last_index)
     }
 
+    fn remove_peer(&mut self, token: Token) {
+        if let Some(peer) = self.peers.remove(&token) {
+            self.poll.deregister(&EventedFd(&peer.pidfd)).unwrap();
+            unsafe {
+                libc::close(peer.pidfd);
+            }
+        }
+    }
+
     pub fn select(&mut self) -> Result<Vec<OsIpcSelectionResult>,UnixError> {
+        self.select_with_timeout(None)
+    }
+
+    /// Like `select()`, returning no results rather than waiting if no receiver is ready.
+    pub fn try_select(&mut self) -> Result<Vec<OsIpcSelectionResult>,UnixError> {
+        self.select_with_timeout(Some(Duration::from_millis(0)))
+    }
+
+    /// Like `select()`, returning no results if no receiver is ready within `duration`.
+    pub fn select_timeout(&mut self, duration: Duration)
+                          -> Result<Vec<OsIpcSelectionResult>,UnixError> {
+        self.select_with_timeout(Some(duration))
+    }
+
+    /// The epoll (or kqueue) descriptor of the set, readable while a receiver is ready,
+    /// for an event loop to watch.
+    pub fn as_raw_fd(&self) -> c_int {
+        self.poll.as_raw_fd()
+    }
+
+    fn select_with_timeout(&mut self, timeout: Option<Duration>)
+                           -> Result<Vec<OsIpcSelectionResult>,UnixError> {
         let mut selection_results = Vec::new();
         let mut num_events = 0;
         while num_events == 0 {
-            match self.poll.poll(&mut self.events, None) {
+            match self.poll.poll(&mut self.events, timeout) {
+                Ok(0) if timeout.is_some() => return Ok(selection_results),
                 Ok(sz) => {
                     num_events = sz;
                 },
@@ -480,7 +1000,10 @@ impl OsIpcReceiverSet {
             }
         }
 
-        for evt in self.events.iter() {
+        // Receivers closed in this round, and the peers they watched, whose events are stale.
+        let mut stale_tokens = vec![];
+        let events: Vec<_> = self.events.iter().collect();
+        for evt in events {
             let evt_token = evt.token();
             match (evt.readiness().is_readable(), self.pollfds.get(&evt_token)) {
                 (true, Some(&poll_entry)) => {
@@ -498,11 +1021,39 @@ impl OsIpcReceiverSet {
                             unsafe {
                                 libc::close(poll_entry.fd);
                             }
+                            let peer_token = self.peers.iter()
+                                                 .find(|&(_, peer)| peer.id == poll_entry.id)
+                                                 .map(|(&token, _)| token);
+                            if let Some(peer_token) = peer_token {
+                                self.remove_peer(peer_token);
+                                stale_tokens.push(peer_token);
+                            }
                             selection_results.push(OsIpcSelectionResult::ChannelClosed(poll_entry.id))
                         }
+                        // Dropped, as receiving it from the receiver would fail.
+                        Err(UnixError::QuotaExceeded(_)) => {}
                         Err(err) => return Err(err),
                     }
                 },
+                (true, None) if stale_tokens.contains(&evt_token) => {},
+                (true, None) if self.peers.contains_key(&evt_token) => {
+                    // Deliver the messages the peer left behind first; then the receiver
+                    // is closed, even if another process still holds a sender.
+                    let peer = self.peers[&evt_token];
+                    if !is_readable(peer.fd)? {
+                        let exit_status = peer_exit_status(peer.pidfd, peer.pid);
+                        self.remove_peer(evt_token);
+                        let fd_token = Token(peer.fd as usize);
+                        self.pollfds.remove(&fd_token).unwrap();
+                        self.poll.deregister(&EventedFd(&peer.fd)).unwrap();
+                        unsafe {
+                            libc::close(peer.fd);
+                        }
+                        stale_tokens.push(fd_token);
+                        selection_results.push(OsIpcSelectionResult::PeerDied(
+                                peer.id, peer.pid, exit_status));
+                    }
+                },
                 (true, None) => {
                     panic!("Readable event for unknown token: {:?}, readiness: {:?}",
                            evt_token, evt.readiness());
@@ -520,6 +1071,9 @@ impl OsIpcReceiverSet {
 pub enum OsIpcSelectionResult {
     DataReceived(u64, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
     ChannelClosed(u64),
+    /// The peer watched by the receiver with this ID exited, with its pid and exit status.
+    /// The receiver was closed and removed from the set.
+    PeerDied(u64, u32, Option<ExitStatus>),
 }
 
 impl OsIpcSelectionResult {
@@ -531,6 +1085,9 @@ impl OsIpcSelectionResult {
             OsIpcSelectionResult::ChannelClosed(id) => {
                 panic!("OsIpcSelectionResult::unwrap(): receiver ID {} was closed!", id)
             }
+            OsIpcSelectionResult::PeerDied(id, pid, _) => {
+                panic!("OsIpcSelectionResult::unwrap(): peer {} of receiver ID {} died!", pid, id)
+            }
         }
     }
 }
@@ -565,6 +1122,111 @@ impl OsOpaqueIpcChannel {
     pub fn to_receiver(&mut self) -> OsIpcReceiver {
         OsIpcReceiver::from_fd(mem::replace(&mut self.fd, -1))
     }
+
+    /// Take the channel, e.g. to send it on. Both ends are the same kind of socket,
+    /// so it doesn't matter which one this was.
+    pub fn to_channel(&mut self) -> OsIpcChannel {
+        OsIpcChannel::Receiver(self.to_receiver())
+    }
+
+    /// The opaque form of `channel`, as if it had been received in a message.
+    pub fn from_channel(channel: OsIpcChannel) -> Result<OsOpaqueIpcChannel,UnixError> {
+        let fd = match channel {
+            // Clones share the descriptor, so take a duplicate.
+            OsIpcChannel::Sender(sender) => sender.into_raw_fd()?,
+            OsIpcChannel::Receiver(receiver) => receiver.into_raw_fd(),
+        };
+        Ok(OsOpaqueIpcChannel::from_fd(fd))
+    }
+}
+
+lazy_static! {
+    // The socket paths of the one-shot servers created by `OsIpcOneShotServer::new()`,
+    // by descriptor, so they can be bound anew by `rebind_one_shot_servers()`.
+    static ref ONE_SHOT_SERVER_PATHS: Mutex<HashMap<c_int, PathBuf>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Create a socket listening at `path`.
+fn listen_at(path: &str) -> Result<c_int,UnixError> {
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET | SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(UnixError::last())
+        }
+        let (sockaddr, len) = new_sockaddr_un(CString::new(path).unwrap().as_ptr());
+        if libc::bind(fd, &sockaddr as *const _ as *const sockaddr, len as socklen_t) != 0 ||
+           libc::listen(fd, 10) != 0 {
+            let error = UnixError::last();
+            libc::close(fd);
+            return Err(error)
+        }
+        Ok(fd)
+    }
+}
+
+/// Bind the one-shot servers whose socket file is gone anew, at the same path and
+/// on the same descriptor, returning how many were; see `checkpoint::restored()`.
+pub fn rebind_one_shot_servers() -> Result<usize,Error> {
+    check_unconstrained()?;
+    let paths = ONE_SHOT_SERVER_PATHS.lock().unwrap();
+    let mut rebound = 0;
+    for (&fd, path) in paths.iter() {
+        if fs::symlink_metadata(path).is_ok() {
+            continue
+        }
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir)?;
+        }
+        let listener = listen_at(path.to_str().unwrap())?;
+        let result = unsafe { libc::dup2(listener, fd) };
+        let dup_error = Error::last_os_error();
+        unsafe {
+            libc::close(listener);
+        }
+        if result < 0 {
+            return Err(dup_error)
+        }
+        // `dup2()` clears the close-on-exec flag; not all our platforms have `dup3()`.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error())
+        }
+        rebound += 1;
+    }
+    Ok(rebound)
+}
+
+/// Create the directory `dir` for the user alone, or check that the one already there is
+/// theirs alone, so no other user can have put or take sockets there.
+fn create_private_dir(dir: &Path) -> Result<(),Error> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(ref error) if error.kind() == ErrorKind::AlreadyExists => {}
+        result => return result,
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::geteuid() } ||
+       metadata.mode() & 0o077 != 0 {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+                              format!("{} is not a private directory of the user's own",
+                                      dir.display())))
+    }
+    Ok(())
+}
+
+/// The receiver and first message of a client accepted by an `OsIpcOneShotServer`.
+type AcceptedClient = (OsIpcReceiver, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
+/// Read an integer socket option of `fd` at the `SOL_SOCKET` level.
+fn get_socket_option(fd: c_int, option: c_int) -> Result<c_int,Error> {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let result = unsafe {
+        getsockopt(fd, SOL_SOCKET, option, &mut value as *mut c_int as *mut c_void, &mut len)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(value)
 }
 
 pub struct OsIpcOneShotServer {
@@ -572,12 +1234,13 @@ pub struct OsIpcOneShotServer {
 
     // Object representing the temporary directory the socket was created in.
     // The directory is automatically deleted (along with the socket inside it)
-    // when this field is dropped.
-    _temp_dir: TempDir,
+    // when this field is dropped. Not set for sockets adopted from systemd.
+    _temp_dir: Option<TempDir>,
 }
 
 impl Drop for OsIpcOneShotServer {
     fn drop(&mut self) {
+        ONE_SHOT_SERVER_PATHS.lock().unwrap().remove(&self.fd);
         unsafe {
             let result = libc::close(self.fd);
             assert!(thread::panicking() || result == 0);
@@ -586,40 +1249,109 @@ impl Drop for OsIpcOneShotServer {
 }
 
 impl OsIpcOneShotServer {
+    pub fn as_raw_fd(&self) -> c_int {
+        self.fd
+    }
+
     pub fn new() -> Result<(OsIpcOneShotServer, String),UnixError> {
-        unsafe {
-            let fd = libc::socket(libc::AF_UNIX, SOCK_SEQPACKET, 0);
-            let temp_dir = Builder::new().tempdir().unwrap();
-            let socket_path = temp_dir.path().join("socket");
-            let path_string = socket_path.to_str().unwrap();
-
-            let (sockaddr, len) = new_sockaddr_un(CString::new(path_string).unwrap().as_ptr());
-            if libc::bind(fd, &sockaddr as *const _ as *const sockaddr, len as socklen_t) != 0 {
-                return Err(UnixError::last());
-            }
+        check_unconstrained()?;
+        let temp_dir = Builder::new().tempdir().unwrap();
+        let socket_path = temp_dir.path().join("socket");
+        let path_string = socket_path.to_str().unwrap().to_string();
+        let fd = listen_at(&path_string)?;
+        ONE_SHOT_SERVER_PATHS.lock().unwrap().insert(fd, socket_path);
+
+        Ok((OsIpcOneShotServer {
+            fd: fd,
+            _temp_dir: Some(temp_dir),
+        }, path_string))
+    }
 
-            if libc::listen(fd, 10) != 0 {
-                return Err(UnixError::last())
+    /// Adopt the listening sockets systemd passed to this process, as described in
+    /// `sd_listen_fds(3)`, in the order they were configured. Returns none if the
+    /// process wasn't socket-activated, or the sockets were adopted already.
+    ///
+    /// The environment is left alone, as changing it isn't safe while other threads
+    /// may read it: `$LISTEN_PID` names this process, so children don't adopt the
+    /// sockets. The sockets are all checked before any is adopted, so if one fails,
+    /// none is closed, and none counts as adopted.
+    pub fn from_listen_fds() -> Result<Vec<OsIpcOneShotServer>,Error> {
+        let (pid, fds) = match (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) {
+            (Ok(pid), Ok(fds)) => (pid, fds),
+            _ => return Ok(vec![]),
+        };
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(vec![])
+        }
+        // Listening sockets start right after standard input, output and error.
+        let end = fds.parse::<c_int>()
+                     .ok()
+                     .filter(|&count| count >= 0)
+                     .and_then(|count| count.checked_add(3))
+                     .ok_or_else(|| {
+                         Error::new(ErrorKind::InvalidData, format!("invalid $LISTEN_FDS: {}", fds))
+                     })?;
+        // So two callers don't both adopt the sockets.
+        let mut adopted = LISTEN_FDS_ADOPTED.lock().unwrap();
+        if *adopted {
+            return Ok(vec![])
+        }
+        for fd in 3..end {
+            if get_socket_option(fd, libc::SO_TYPE).ok() != Some(SOCK_SEQPACKET) {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("descriptor {} is not a seqpacket socket", fd)))
+            }
+            if get_socket_option(fd, libc::SO_ACCEPTCONN).unwrap_or(0) == 0 {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("descriptor {} is not listening", fd)))
+            }
+        }
+        for fd in 3..end {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(Error::last_os_error())
             }
-
-            Ok((OsIpcOneShotServer {
-                fd: fd,
-                _temp_dir: temp_dir,
-            }, path_string.to_string()))
         }
+        *adopted = true;
+        Ok((3..end).map(|fd| {
+            OsIpcOneShotServer {
+                fd,
+                _temp_dir: None,
+            }
+        }).collect())
     }
 
     pub fn accept(self) -> Result<(OsIpcReceiver,
                                    Vec<u8>,
                                    Vec<OsOpaqueIpcChannel>,
                                    Vec<OsIpcSharedMemory>),UnixError> {
+        self.accept_with_policy(|_| true)
+    }
+
+    /// Like `accept()`, closing the connections of clients whose credentials
+    /// `policy` rejects without reading from them.
+    pub fn accept_with_policy<F>(self, mut policy: F)
+                                 -> Result<AcceptedClient,UnixError>
+                                 where F: FnMut(&OsIpcPeerCredentials) -> bool {
         unsafe {
-            let sockaddr: *mut sockaddr = ptr::null_mut();
-            let sockaddr_len: *mut socklen_t = ptr::null_mut();
-            let client_fd = libc::accept(self.fd, sockaddr, sockaddr_len);
-            if client_fd < 0 {
-                return Err(UnixError::last())
-            }
+            let client_fd = loop {
+                let sockaddr: *mut sockaddr = ptr::null_mut();
+                let sockaddr_len: *mut socklen_t = ptr::null_mut();
+                let client_fd = libc::accept4(self.fd, sockaddr, sockaddr_len, SOCK_CLOEXEC);
+                if client_fd < 0 {
+                    return Err(UnixError::last())
+                }
+                let accepted = peer_credentials(client_fd).map(|credentials| policy(&credentials));
+                match accepted {
+                    Ok(true) => break client_fd,
+                    Ok(false) => {
+                        libc::close(client_fd);
+                    }
+                    Err(error) => {
+                        libc::close(client_fd);
+                        return Err(error)
+                    }
+                }
+            };
             make_socket_lingering(client_fd)?;
 
             let receiver = OsIpcReceiver::from_fd(client_fd);
@@ -629,6 +1361,60 @@ impl OsIpcOneShotServer {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn peer_credentials(sockfd: c_int) -> Result<OsIpcPeerCredentials,UnixError> {
+    let mut credentials: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as socklen_t;
+    let err = unsafe {
+        getsockopt(sockfd,
+                   SOL_SOCKET,
+                   libc::SO_PEERCRED,
+                   &mut credentials as *mut _ as *mut c_void,
+                   &mut len)
+    };
+    if err < 0 {
+        return Err(UnixError::last())
+    }
+    Ok(OsIpcPeerCredentials {
+        pid: Some(credentials.pid as u32),
+        uid: Some(credentials.uid),
+        gid: Some(credentials.gid),
+    })
+}
+
+// The BSDs don't report the pid of the peer.
+#[cfg(any(target_os = "openbsd", target_os = "freebsd"))]
+fn peer_credentials(sockfd: c_int) -> Result<OsIpcPeerCredentials,UnixError> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(sockfd, &mut uid, &mut gid) } < 0 {
+        return Err(UnixError::last())
+    }
+    Ok(OsIpcPeerCredentials {
+        pid: None,
+        uid: Some(uid),
+        gid: Some(gid),
+    })
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+fn peer_credentials(sockfd: c_int) -> Result<OsIpcPeerCredentials,UnixError> {
+    let mut ucred = ptr::null_mut();
+    if unsafe { libc::getpeerucred(sockfd, &mut ucred) } < 0 {
+        return Err(UnixError::last())
+    }
+    let credentials = unsafe {
+        OsIpcPeerCredentials {
+            pid: Some(libc::ucred_getpid(ucred) as u32),
+            uid: Some(libc::ucred_geteuid(ucred)),
+            gid: Some(libc::ucred_getegid(ucred)),
+        }
+    };
+    unsafe {
+        libc::ucred_free(ucred);
+    }
+    Ok(credentials)
+}
+
 // Make sure that the kernel doesn't return errors to readers if there's still data left after we
 // close our end.
 //
@@ -655,8 +1441,169 @@ struct BackingStore {
     fd: c_int
 }
 
+// The descriptors of all backing stores, telling shared memory regions apart from
+// other files in `diagnose()`.
+static BACKING_STORE_FDS: FdSet = FdSet::new();
+
+const FD_SET_WORDS: usize = 1024;
+
+/// A set of descriptors below `64 * FD_SET_WORDS`, one bit each, updated without
+/// locking as backing stores come and go whenever shared memory is sent or
+/// received. Higher descriptors are left out.
+struct FdSet {
+    words: [AtomicU64; FD_SET_WORDS],
+}
+
+impl FdSet {
+    const fn new() -> FdSet {
+        FdSet {
+            words: [const { AtomicU64::new(0) }; FD_SET_WORDS],
+        }
+    }
+
+    /// The word and bit of `fd`, if it fits in the set.
+    fn bit(&self, fd: c_int) -> Option<(&AtomicU64, u64)> {
+        if fd < 0 {
+            return None
+        }
+        let fd = fd as usize;
+        self.words.get(fd / 64).map(|word| (word, 1 << (fd % 64)))
+    }
+
+    fn insert(&self, fd: c_int) {
+        if let Some((word, bit)) = self.bit(fd) {
+            word.fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&self, fd: c_int) {
+        if let Some((word, bit)) = self.bit(fd) {
+            word.fetch_and(!bit, Ordering::Relaxed);
+        }
+    }
+
+    fn contains(&self, fd: c_int) -> bool {
+        self.bit(fd).is_some_and(|(word, bit)| word.load(Ordering::Relaxed) & bit != 0)
+    }
+}
+
+/// List the channels, one-shot servers and shared memory regions among the
+/// descriptors open in the process; see `diagnostics::dump()`.
+pub fn diagnose(report: &mut IpcDiagnostics) -> Result<(),Error> {
+    for fd in open_fds()? {
+        let handle = Some(i64::from(fd));
+        if BACKING_STORE_FDS.contains(fd) {
+            let mut st: libc::stat = unsafe { mem::zeroed() };
+            if unsafe { libc::fstat(fd, &mut st) } == 0 {
+                report.shared_memory_regions.push(SharedMemoryDiagnostics {
+                    handle,
+                    length: st.st_size as usize,
+                });
+            }
+            continue
+        }
+        if !is_socket(fd) || socket_option(fd, libc::SO_TYPE) != Some(SOCK_SEQPACKET) {
+            continue
+        }
+        let path = match unix_socket_path(fd, false) {
+            Ok(path) => path,
+            Err(()) => continue,
+        };
+        if socket_option(fd, libc::SO_ACCEPTCONN) == Some(1) {
+            report.servers.push(ServerDiagnostics {
+                handle,
+                name: path.unwrap_or_default(),
+            });
+            continue
+        }
+        let mut queued_bytes: c_int = 0;
+        let queued = unsafe { libc::ioctl(fd, libc::FIONREAD as _, &mut queued_bytes) } == 0;
+        report.channels.push(ChannelDiagnostics {
+            handle,
+            label: unix_socket_path(fd, true).unwrap_or(None),
+            peer_pid: peer_credentials(fd).ok().and_then(|credentials| credentials.pid),
+            queued_bytes: if queued { Some(queued_bytes as usize) } else { None },
+        });
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn open_fds() -> Result<Vec<c_int>,Error> {
+    let mut fds = vec![];
+    for entry in fs::read_dir("/proc/self/fd")? {
+        if let Some(fd) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            fds.push(fd)
+        }
+    }
+    Ok(fds)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> Result<Vec<c_int>,Error> {
+    let limit = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        limit if limit < 0 => 1024,
+        limit => cmp::min(limit, 1 << 20) as c_int,
+    };
+    Ok((0..limit).filter(|&fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0).collect())
+}
+
+fn socket_option(fd: c_int, option: c_int) -> Option<c_int> {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let result = unsafe {
+        getsockopt(fd, SOL_SOCKET, option, &mut value as *mut _ as *mut c_void, &mut len)
+    };
+    if result < 0 {
+        return None
+    }
+    Some(value)
+}
+
+/// The path the AF_UNIX socket `fd` is bound to, or that of its peer with `peer`:
+/// `Ok(None)` if unnamed, and `Err` if `fd` isn't an AF_UNIX socket.
+fn unix_socket_path(fd: c_int, peer: bool) -> Result<Option<String>,()> {
+    let mut address: sockaddr_un = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<sockaddr_un>() as socklen_t;
+    let address_pointer = &mut address as *mut _ as *mut sockaddr;
+    let result = unsafe {
+        if peer {
+            libc::getpeername(fd, address_pointer, &mut len)
+        } else {
+            libc::getsockname(fd, address_pointer, &mut len)
+        }
+    };
+    if result < 0 || address.sun_family != libc::AF_UNIX as sa_family_t {
+        return Err(())
+    }
+    let path: Vec<u8> = address.sun_path.iter()
+                                        .take_while(|&&byte| byte != 0)
+                                        .map(|&byte| byte as u8)
+                                        .collect();
+    if path.is_empty() {
+        return Ok(None)
+    }
+    Ok(Some(String::from_utf8_lossy(&path).into_owned()))
+}
+
 impl BackingStore {
-    pub fn new(length: usize) -> BackingStore {
+    /// A new store `length` bytes long; in constrained mode, one of those reserved,
+    /// failing with `ENOMEM` once none is left.
+    pub fn new(length: usize) -> Result<BackingStore,UnixError> {
+        if CONSTRAINED.load(Ordering::SeqCst) &&
+           cfg!(not(all(target_os="linux", feature="memfd"))) {
+            let mut reserved = RESERVED_BACKING_STORES.lock().unwrap();
+            let fd = match reserved.pop() {
+                Some(fd) => fd,
+                None => return Err(UnixError::Errno(libc::ENOMEM)),
+            };
+            if unsafe { libc::ftruncate(fd, length as off_t) } != 0 {
+                let error = UnixError::last();
+                reserved.push(fd);
+                return Err(error)
+            }
+            return Ok(Self::from_fd(fd))
+        }
         let count = SHM_COUNT.fetch_add(1, Ordering::Relaxed);
         let timestamp = UNIX_EPOCH.elapsed().unwrap();
         let name = CString::new(format!("/ipc-channel-shared-memory.{}.{}.{}.{}",
@@ -664,10 +1611,18 @@ impl BackingStore {
                                         timestamp.as_secs(),
                                         timestamp.subsec_nanos())).unwrap();
         let fd = create_shmem(name, length);
-        Self::from_fd(fd)
+        Ok(Self::from_fd(fd))
+    }
+
+    /// The store of an empty region, backed by no descriptor until it is sent.
+    fn empty() -> BackingStore {
+        BackingStore {
+            fd: -1,
+        }
     }
 
     pub fn from_fd(fd: c_int) -> BackingStore {
+        BACKING_STORE_FDS.insert(fd);
         BackingStore {
             fd: fd,
         }
@@ -677,6 +1632,13 @@ impl BackingStore {
         self.fd
     }
 
+    fn into_fd(self) -> c_int {
+        let fd = self.fd;
+        BACKING_STORE_FDS.remove(fd);
+        mem::forget(self);
+        fd
+    }
+
     pub unsafe fn map_file(&self, length: Option<size_t>) -> (*mut u8, size_t) {
         let length = length.unwrap_or_else(|| {
             let mut st = mem::uninitialized();
@@ -701,6 +1663,10 @@ impl BackingStore {
 
 impl Drop for BackingStore {
     fn drop(&mut self) {
+        if self.fd < 0 {
+            return
+        }
+        BACKING_STORE_FDS.remove(self.fd);
         unsafe {
             let result = libc::close(self.fd);
             assert!(thread::panicking() || result == 0);
@@ -731,7 +1697,10 @@ impl Drop for OsIpcSharedMemory {
 impl Clone for OsIpcSharedMemory {
     fn clone(&self) -> OsIpcSharedMemory {
         unsafe {
-            let store = BackingStore::from_fd(libc::dup(self.store.fd()));
+            if self.store.fd() < 0 {
+                return OsIpcSharedMemory::from_raw_parts(ptr::null_mut(), 0, BackingStore::empty())
+            }
+            let store = BackingStore::from_fd(dup_cloexec(self.store.fd()));
             let (address, _) = store.map_file(Some(self.length));
             OsIpcSharedMemory::from_raw_parts(address, self.length, store)
         }
@@ -755,6 +1724,10 @@ impl Deref for OsIpcSharedMemory {
 
     #[inline]
     fn deref(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            // Empty regions are not mapped.
+            return &[]
+        }
         unsafe {
             slice::from_raw_parts(self.ptr, self.length)
         }
@@ -771,30 +1744,176 @@ impl OsIpcSharedMemory {
         }
     }
 
-    unsafe fn from_fd(fd: c_int) -> OsIpcSharedMemory {
+    unsafe fn from_fd(fd: c_int, length: usize) -> OsIpcSharedMemory {
         let store = BackingStore::from_fd(fd);
-        let (ptr, length) = store.map_file(None);
+        let (ptr, length) = store.map_file(Some(length));
         OsIpcSharedMemory::from_raw_parts(ptr, length, store)
     }
 
     pub fn from_byte(byte: u8, length: usize) -> OsIpcSharedMemory {
+        OsIpcSharedMemory::try_from_byte(byte, length).expect(NO_RESERVED_STORE_LEFT)
+    }
+
+    /// Like `from_byte()`, failing in constrained mode once no reserved store is left.
+    pub fn try_from_byte(byte: u8, length: usize) -> Result<OsIpcSharedMemory,UnixError> {
         unsafe {
-            let store = BackingStore::new(length);
+            let store = BackingStore::new(length)?;
             let (address, _) = store.map_file(Some(length));
             for element in slice::from_raw_parts_mut(address, length) {
                 *element = byte;
             }
+            Ok(OsIpcSharedMemory::from_raw_parts(address, length, store))
+        }
+    }
+
+    /// A zeroed region whose pages are only allocated once first touched, by
+    /// whichever process maps it.
+    pub fn zeroed(length: usize) -> OsIpcSharedMemory {
+        unsafe {
+            let store = BackingStore::new(length).expect(NO_RESERVED_STORE_LEFT);
+            let (address, _) = store.map_file(Some(length));
             OsIpcSharedMemory::from_raw_parts(address, length, store)
         }
     }
 
     pub fn from_bytes(bytes: &[u8]) -> OsIpcSharedMemory {
+        OsIpcSharedMemory::try_from_bytes(bytes).expect(NO_RESERVED_STORE_LEFT)
+    }
+
+    /// Like `from_bytes()`, failing in constrained mode once no reserved store is left.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<OsIpcSharedMemory,UnixError> {
         unsafe {
-            let store = BackingStore::new(bytes.len());
+            let store = BackingStore::new(bytes.len())?;
             let (address, _) = store.map_file(Some(bytes.len()));
             ptr::copy_nonoverlapping(bytes.as_ptr(), address, bytes.len());
-            OsIpcSharedMemory::from_raw_parts(address, bytes.len(), store)
+            Ok(OsIpcSharedMemory::from_raw_parts(address, bytes.len(), store))
+        }
+    }
+
+    /// Overwrite the contents with zeros, as seen by every mapping of the region.
+    pub fn wipe(&self) {
+        if !self.ptr.is_null() {
+            unsafe {
+                super::wipe_bytes(self.ptr, self.length)
+            }
+        }
+    }
+
+    /// Every receiver maps the same backing file.
+    pub fn check_revocable() -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Release the pages of the region, so every mapping of it reads zeros
+    /// instead of the contents, without faulting on the way.
+    #[cfg(target_os = "linux")]
+    pub fn revoke(&self) -> Result<(), Error> {
+        if self.length == 0 {
+            return Ok(())
+        }
+        let result = unsafe {
+            libc::fallocate(self.store.fd(),
+                            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                            0,
+                            self.length as off_t)
+        };
+        if result < 0 {
+            return Err(Error::last_os_error())
         }
+        Ok(())
+    }
+
+    /// Without hole punching, the pages are overwritten with zeros.
+    #[cfg(not(target_os = "linux"))]
+    pub fn revoke(&self) -> Result<(), Error> {
+        self.wipe();
+        Ok(())
+    }
+
+    /// Lock the pages of this mapping into RAM, so they are never paged out,
+    /// until the mapping is dropped.
+    pub fn lock(&self) -> Result<(), Error> {
+        super::lock_bytes(self.ptr, self.length)
+    }
+
+    /// Prefer NUMA node `node` for the pages of the region, moving those already
+    /// allocated elsewhere. The policy belongs to the region, so it applies to the
+    /// pages touched through any mapping.
+    #[cfg(target_os = "linux")]
+    pub fn set_numa_node(&self, node: usize) -> Result<(), Error> {
+        const MPOL_PREFERRED: c_int = 1;
+        const MPOL_MF_MOVE: u32 = 1 << 1;
+        let bits_per_word = mem::size_of::<libc::c_ulong>() * 8;
+        let mut node_mask = [0 as libc::c_ulong; 16];
+        if node >= node_mask.len() * bits_per_word {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("no NUMA node {}", node)))
+        }
+        if self.length == 0 {
+            return Ok(())
+        }
+        node_mask[node / bits_per_word] |= 1 << (node % bits_per_word);
+        let result = unsafe {
+            libc::syscall(libc::SYS_mbind,
+                          self.ptr,
+                          self.length,
+                          MPOL_PREFERRED,
+                          node_mask.as_ptr(),
+                          // The kernel ignores the last bit.
+                          node_mask.len() * bits_per_word + 1,
+                          MPOL_MF_MOVE)
+        };
+        match result {
+            0 => Ok(()),
+            _ if Error::last_os_error().raw_os_error() == Some(libc::EINVAL) => {
+                Err(Error::new(ErrorKind::InvalidInput, format!("no NUMA node {}", node)))
+            }
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_numa_node(&self, _: usize) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "NUMA placement is only supported on Linux"))
+    }
+
+    /// The number of pages of the region allocated on each NUMA node, by node.
+    #[cfg(target_os = "linux")]
+    pub fn numa_nodes(&self) -> Result<Vec<usize>, Error> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let pages: Vec<*mut c_void> = (0..self.length).step_by(page_size).map(|offset| {
+            unsafe { self.ptr.add(offset) as *mut c_void }
+        }).collect();
+        let mut status = vec![0 as c_int; pages.len()];
+        if !pages.is_empty() {
+            // Without target nodes, `move_pages()` only reports where the pages are.
+            let result = unsafe {
+                libc::syscall(libc::SYS_move_pages,
+                              0,
+                              pages.len(),
+                              pages.as_ptr(),
+                              ptr::null::<c_int>(),
+                              status.as_mut_ptr(),
+                              0)
+            };
+            if result < 0 {
+                return Err(Error::last_os_error())
+            }
+        }
+        let mut nodes = vec![];
+        // Pages not allocated yet report a negative error number instead of a node.
+        for &node in status.iter().filter(|&&node| node >= 0) {
+            let node = node as usize;
+            if nodes.len() <= node {
+                nodes.resize(node + 1, 0);
+            }
+            nodes[node] += 1;
+        }
+        Ok(nodes)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn numa_nodes(&self) -> Result<Vec<usize>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "NUMA placement is only supported on Linux"))
     }
 }
 
@@ -802,6 +1921,11 @@ impl OsIpcSharedMemory {
 pub enum UnixError {
     Errno(c_int),
     ChannelClosed,
+    /// The watched peer process exited, with its pid and exit status if known.
+    PeerDied(u32, Option<ExitStatus>),
+    /// The shared memory regions of a message were refused, unmapped, as they would
+    /// exceed the quota.
+    QuotaExceeded(ShmQuotaExceeded),
 }
 
 impl UnixError {
@@ -827,6 +1951,12 @@ impl From<UnixError> for Error {
             UnixError::Errno(errno) => Error::from_raw_os_error(errno),
             UnixError::ChannelClosed => Error::new(ErrorKind::ConnectionReset,
                                                    "All senders for this socket closed"),
+            UnixError::PeerDied(pid, exit_status) => {
+                Error::new(ErrorKind::ConnectionAborted, PeerDied { pid, exit_status })
+            }
+            UnixError::QuotaExceeded(quota_error) => {
+                Error::new(ErrorKind::OutOfMemory, quota_error)
+            }
         }
     }
 }
@@ -835,6 +1965,12 @@ impl From<Error> for UnixError {
     fn from(e: Error) -> UnixError {
         if let Some(errno) = e.raw_os_error() {
             UnixError::Errno(errno)
+        } else if let Some(&PeerDied { pid, exit_status }) =
+                e.get_ref().and_then(|error| error.downcast_ref::<PeerDied>()) {
+            UnixError::PeerDied(pid, exit_status)
+        } else if let Some(&quota_error) =
+                e.get_ref().and_then(|error| error.downcast_ref::<ShmQuotaExceeded>()) {
+            UnixError::QuotaExceeded(quota_error)
         } else {
             assert!(e.kind() == ErrorKind::ConnectionReset);
             UnixError::ChannelClosed
@@ -848,8 +1984,16 @@ enum BlockingMode {
     Nonblocking,
 }
 
-fn recv(fd: c_int, blocking_mode: BlockingMode)
-        -> Result<(Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),UnixError> {
+/// The data, channels and shared memory regions of a received message.
+type SerializedMessage = (Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
+/// The total length of a message, along with the data, channels and shared memory regions of its
+/// first fragment.
+type FirstFragment = (usize, Vec<u8>, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>);
+
+/// Receive the first fragment of a message, which carries the header and any file descriptors.
+fn recv_first_fragment(fd: c_int, blocking_mode: BlockingMode)
+                       -> Result<FirstFragment,UnixError> {
 
     let (mut channels, mut shared_memory_regions) = (Vec::new(), Vec::new());
 
@@ -860,8 +2004,8 @@ fn recv(fd: c_int, blocking_mode: BlockingMode)
     let mut total_size = 0usize;
     let mut main_data_buffer;
     unsafe {
-        // Allocate a buffer without initialising the memory.
-        main_data_buffer = Vec::with_capacity(OsIpcSender::get_max_fragment_size());
+        // Get a buffer from the pool, without initialising the memory.
+        main_data_buffer = pool::take(OsIpcSender::get_max_fragment_size());
         main_data_buffer.set_len(OsIpcSender::get_max_fragment_size());
 
         let mut iovec = [
@@ -877,25 +2021,112 @@ fn recv(fd: c_int, blocking_mode: BlockingMode)
         let mut cmsg = UnixCmsg::new(&mut iovec);
 
         let bytes_read = cmsg.recv(fd, blocking_mode)?;
-        main_data_buffer.set_len(bytes_read - mem::size_of_val(&total_size));
-
-        let cmsg_fds = CMSG_DATA(cmsg.cmsg_buffer) as *const c_int;
-        let cmsg_length = cmsg.msghdr.msg_controllen;
-        let channel_length = if cmsg_length == 0 {
-            0
-        } else {
-            (cmsg.cmsg_len() - CMSG_ALIGN(mem::size_of::<cmsghdr>())) / mem::size_of::<c_int>()
-        };
-        for index in 0..channel_length {
-            let fd = *cmsg_fds.offset(index as isize);
+        main_data_buffer.set_len(0);
+
+        let control = slice::from_raw_parts(cmsg.msghdr.msg_control as *const u8,
+                                            cmsg.msghdr.msg_controllen as usize);
+        let mut region_fds = vec![];
+        for fd in decode_ancillary_data(control)? {
+            // Solaris can't make them close-on-exec on receipt.
+            #[cfg(target_os = "solaris")]
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
             if is_socket(fd) {
                 channels.push(OsOpaqueIpcChannel::from_fd(fd));
                 continue
             }
-            shared_memory_regions.push(OsIpcSharedMemory::from_fd(fd));
+            region_fds.push((fd, file_size(fd)));
+        }
+        // Refuse the regions before mapping them, so a misbehaving peer can't exhaust
+        // the address space with attachments beyond the quota.
+        let regions_size = region_fds.iter().fold(0usize, |total, &(_, size)| {
+            total.saturating_add(size)
+        });
+        if let Err(quota_error) = ipc::check_shared_memory_quota(regions_size) {
+            for (fd, _) in region_fds {
+                libc::close(fd);
+            }
+            return Err(UnixError::QuotaExceeded(quota_error))
+        }
+        for (fd, size) in region_fds {
+            shared_memory_regions.push(OsIpcSharedMemory::from_fd(fd, size));
         }
+        main_data_buffer.set_len(first_fragment_data_len(bytes_read, total_size)?);
     }
 
+    Ok((total_size, main_data_buffer, channels, shared_memory_regions))
+}
+
+/// The length of the data in a first fragment `bytes_read` long, including the header
+/// holding the total size of the message, `total_size`.
+///
+/// A peer may send anything, so this fails with `EBADMSG` if the fragment is too short
+/// for the header, or longer than the message.
+fn first_fragment_data_len(bytes_read: usize, total_size: usize) -> Result<usize,UnixError> {
+    match bytes_read.checked_sub(mem::size_of::<usize>()) {
+        Some(data_len) if data_len <= total_size => Ok(data_len),
+        _ => Err(UnixError::Errno(libc::EBADMSG)),
+    }
+}
+
+/// The file descriptors passed in `control`, the control messages received with
+/// `recvmsg()`, failing with `EBADMSG` if they are malformed.
+fn decode_ancillary_data(control: &[u8]) -> Result<Vec<c_int>,UnixError> {
+    let header_len = CMSG_LEN(0);
+    let mut fds = Vec::new();
+    let mut offset = 0;
+    while control.len() - offset >= mem::size_of::<cmsghdr>() {
+        let header = unsafe {
+            ptr::read_unaligned(control.as_ptr().add(offset) as *const cmsghdr)
+        };
+        let len = header.cmsg_len as usize;
+        if len < header_len || len > control.len() - offset {
+            return Err(UnixError::Errno(libc::EBADMSG))
+        }
+        if header.cmsg_level == SOL_SOCKET && header.cmsg_type == SCM_RIGHTS {
+            let data = &control[offset + header_len..offset + len];
+            fds.extend(data.chunks_exact(mem::size_of::<c_int>()).map(|chunk| {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(chunk);
+                c_int::from_ne_bytes(bytes)
+            }));
+        }
+        offset = cmp::min(offset + CMSG_ALIGN(len), control.len());
+    }
+    Ok(fds)
+}
+
+/// The decoding done when receiving, over byte slices rather than sockets.
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    use libc::c_int;
+    use std::io::Error;
+    use std::mem;
+    use super::{UnixError, first_fragment_data_len};
+
+    /// Decode the first fragment of a message, header included, into the total size of
+    /// the message and the data in the fragment.
+    pub fn decode_first_fragment(fragment: &[u8]) -> Result<(usize, &[u8]),Error> {
+        let mut header = [0; mem::size_of::<usize>()];
+        if fragment.len() < header.len() {
+            return Err(UnixError::Errno(libc::EBADMSG).into())
+        }
+        header.copy_from_slice(&fragment[..mem::size_of::<usize>()]);
+        let total_size = usize::from_ne_bytes(header);
+        first_fragment_data_len(fragment.len(), total_size)?;
+        Ok((total_size, &fragment[mem::size_of::<usize>()..]))
+    }
+
+    /// The numbers of the file descriptors passed in the control messages `control`,
+    /// without taking them over.
+    pub fn decode_ancillary_data(control: &[u8]) -> Result<Vec<c_int>,Error> {
+        Ok(super::decode_ancillary_data(control)?)
+    }
+}
+
+fn recv(fd: c_int, blocking_mode: BlockingMode) -> Result<SerializedMessage,UnixError> {
+    let (total_size, mut main_data_buffer, mut channels, shared_memory_regions) =
+        recv_first_fragment(fd, blocking_mode)?;
+
     if total_size == main_data_buffer.len() {
         // Fast path: no fragments.
         return Ok((main_data_buffer, channels, shared_memory_regions))
@@ -905,43 +2136,93 @@ fn recv(fd: c_int, blocking_mode: BlockingMode)
     //
     // The initial fragment carries the receive end of a dedicated channel
     // through which all the remaining fragments will be coming in.
-    let dedicated_rx = channels.pop().unwrap().to_receiver();
+    let dedicated_rx = match channels.pop() {
+        Some(mut channel) => channel.to_receiver(),
+        None => return Err(UnixError::Errno(libc::EBADMSG)),
+    };
 
     // Extend the buffer to hold the entire message, without initialising the memory.
     let len = main_data_buffer.len();
     main_data_buffer.reserve_exact(total_size - len);
 
     // Receive followup fragments directly into the main buffer.
-    while main_data_buffer.len() < total_size {
-        let write_pos = main_data_buffer.len();
-        let end_pos = cmp::min(write_pos + OsIpcSender::fragment_size(*SYSTEM_SENDBUF_SIZE),
-                               total_size);
-        let result = unsafe {
-            assert!(end_pos <= main_data_buffer.capacity());
-            main_data_buffer.set_len(end_pos);
-
-            // Integer underflow could make the following code unsound...
-            assert!(end_pos >= write_pos);
-
-            // Note: we always use blocking mode for followup fragments,
-            // to make sure that once we start receiving a multi-fragment message,
-            // we don't abort in the middle of it...
-            let result = libc::recv(dedicated_rx.fd.get(),
-                                    main_data_buffer[write_pos..].as_mut_ptr() as *mut c_void,
-                                    end_pos - write_pos,
-                                    0);
-            main_data_buffer.set_len(write_pos + cmp::max(result, 0) as usize);
-            result
+    unsafe {
+        recv_followup_fragments(dedicated_rx.fd.get(),
+                                main_data_buffer.as_mut_ptr().add(len),
+                                total_size - len)?;
+        main_data_buffer.set_len(total_size);
+    }
+
+    Ok((main_data_buffer, channels, shared_memory_regions))
+}
+
+/// Like `recv()`, but reassembles the message in a freshly mapped shared memory region.
+///
+/// Only the first fragment goes through an intermediate buffer; the followup fragments,
+/// i.e. virtually all of a large message, are received straight into the region.
+fn recv_bulk(fd: c_int, blocking_mode: BlockingMode)
+             -> Result<(OsIpcSharedMemory, Vec<OsOpaqueIpcChannel>, Vec<OsIpcSharedMemory>),
+                       UnixError> {
+    let (total_size, first_fragment, mut channels, shared_memory_regions) =
+        recv_first_fragment(fd, blocking_mode)?;
+
+    if total_size == 0 {
+        pool::recycle(first_fragment);
+        let region = unsafe {
+            OsIpcSharedMemory::from_raw_parts(ptr::null_mut(), 0, BackingStore::empty())
+        };
+        return Ok((region, channels, shared_memory_regions))
+    }
+    let region = unsafe {
+        let store = BackingStore::new(total_size)?;
+        let (address, _) = store.map_file(Some(total_size));
+        OsIpcSharedMemory::from_raw_parts(address, total_size, store)
+    };
+
+    unsafe {
+        ptr::copy_nonoverlapping(first_fragment.as_ptr(), region.ptr, first_fragment.len());
+    }
+    let first_fragment_len = first_fragment.len();
+    pool::recycle(first_fragment);
+    if total_size > first_fragment_len {
+        let dedicated_rx = match channels.pop() {
+            Some(mut channel) => channel.to_receiver(),
+            None => return Err(UnixError::Errno(libc::EBADMSG)),
         };
+        unsafe {
+            recv_followup_fragments(dedicated_rx.fd.get(),
+                                    region.ptr.add(first_fragment_len),
+                                    total_size - first_fragment_len)?;
+        }
+    }
+
+    Ok((region, channels, shared_memory_regions))
+}
+
+/// Receive the `len` bytes of followup fragments from the dedicated channel `fd` into `buffer`.
+unsafe fn recv_followup_fragments(fd: c_int, buffer: *mut u8, len: usize)
+                                  -> Result<(),UnixError> {
+    let mut write_pos = 0;
+    while write_pos < len {
+        let end_pos = cmp::min(write_pos + OsIpcSender::fragment_size(*SYSTEM_SENDBUF_SIZE), len);
+
+        // Note: we always use blocking mode for followup fragments,
+        // to make sure that once we start receiving a multi-fragment message,
+        // we don't abort in the middle of it...
+        let result = libc::recv(fd,
+                                buffer.add(write_pos) as *mut c_void,
+                                end_pos - write_pos,
+                                0);
 
         if result == 0 {
             return Err(UnixError::ChannelClosed)
         } else if result < 0 {
             return Err(UnixError::last())
         };
+        write_pos += result as usize;
     }
 
-    Ok((main_data_buffer, channels, shared_memory_regions))
+    Ok(())
 }
 
 // https://github.com/servo/ipc-channel/issues/192
@@ -962,6 +2243,7 @@ fn create_shmem(name: CString, length: usize) -> c_int {
     unsafe {
         // NB: the FreeBSD man page for shm_unlink states that it requires
         // write permissions, but testing shows that read-write is required.
+        // The descriptor is close-on-exec, as for all shared memory objects.
         let fd = libc::shm_open(name.as_ptr(),
                                 libc::O_CREAT | libc::O_RDWR | libc::O_EXCL,
                                 0o600);
@@ -975,7 +2257,7 @@ fn create_shmem(name: CString, length: usize) -> c_int {
 #[cfg(all(feature="memfd", target_os="linux"))]
 fn create_shmem(name: CString, length: usize) -> c_int {
     unsafe {
-        let fd = memfd_create(name.as_ptr(), 0);
+        let fd = memfd_create(name.as_ptr(), libc::MFD_CLOEXEC as usize);
         assert!(fd >= 0);
         assert!(libc::ftruncate(fd, length as off_t) == 0);
         fd
@@ -1015,7 +2297,7 @@ impl UnixCmsg {
             }
         }
 
-        let result = recvmsg(fd, &mut self.msghdr, 0);
+        let result = recvmsg(fd, &mut self.msghdr, RECV_FLAGS);
         let result = if result > 0 {
             Ok(result as usize)
         } else if result == 0 {
@@ -1031,9 +2313,29 @@ impl UnixCmsg {
         }
         result
     }
+}
 
-    unsafe fn cmsg_len(&self) -> size_t {
-        (*(self.msghdr.msg_control as *const cmsghdr)).cmsg_len as size_t
+/// Duplicate `fd`, close-on-exec, returning -1 on failure like `dup()`.
+fn dup_cloexec(fd: c_int) -> c_int {
+    unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) }
+}
+
+/// Let processes `exec()`ed from now on inherit `fd`.
+fn clear_cloexec(fd: c_int) -> Result<(),Error> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+/// The size of the file `fd` refers to, 0 if it can't be told.
+fn file_size(fd: c_int) -> usize {
+    unsafe {
+        let mut st: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut st) != 0 {
+            return 0
+        }
+        st.st_size as usize
     }
 }
 
@@ -1065,11 +2367,19 @@ unsafe fn CMSG_DATA(cmsg: *mut cmsghdr) -> *mut c_void {
             mem::size_of::<cmsghdr>()) as isize) as *mut c_void
 }
 
+#[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
 #[allow(non_snake_case)]
 fn CMSG_ALIGN(length: size_t) -> size_t {
     (length + mem::size_of::<size_t>() - 1) & !(mem::size_of::<size_t>() - 1)
 }
 
+// Control message data is only `int`-aligned there.
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+#[allow(non_snake_case)]
+fn CMSG_ALIGN(length: size_t) -> size_t {
+    (length + mem::size_of::<c_int>() - 1) & !(mem::size_of::<c_int>() - 1)
+}
+
 #[allow(non_snake_case)]
 fn CMSG_SPACE(length: size_t) -> size_t {
     CMSG_ALIGN(length) + CMSG_ALIGN(mem::size_of::<cmsghdr>())
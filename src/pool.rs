@@ -0,0 +1,209 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Keeping channels to a peer established ahead of time, so taking one costs no
+//! handshake.
+//!
+//! An [IpcChannelPool] keeps a number of connections ready, made by a function
+//! run on a background thread, and makes a new one whenever one is taken. Most
+//! pools hold senders whose receivers were already handed to the peer over an
+//! acceptor channel, as made by [IpcChannelPool::connected_to]; with
+//! [IpcChannelPool::new], connections can be anything, e.g. the channels of a
+//! one-shot server handshake.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::ipc::{self, IpcReceiver};
+//! # use ipc_channel::pool::IpcChannelPool;
+//! let (acceptor, connections) = ipc::channel::<IpcReceiver<String>>().unwrap();
+//! let pool = IpcChannelPool::connected_to(acceptor, 2).unwrap();
+//!
+//! let sender = pool.get().unwrap();
+//! sender.send("Hello".to_owned()).unwrap();
+//! let receiver = connections.recv().unwrap();
+//! assert_eq!(receiver.recv().unwrap(), "Hello");
+//! ```
+//!
+//! [IpcChannelPool]: struct.IpcChannelPool.html
+//! [IpcChannelPool::connected_to]: struct.IpcChannelPool.html#method.connected_to
+//! [IpcChannelPool::new]: struct.IpcChannelPool.html#method.new
+
+use ipc::{self, IpcReceiver, IpcSender};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use threads;
+
+/// Connections made ahead of time on a background thread, to be taken one at a time.
+///
+/// The thread is named with the `pool` role, as set with [threads::set_thread_config].
+/// Dropping the pool waits for a connection being made to complete, and drops
+/// the connections not taken.
+///
+/// [threads::set_thread_config]: ../threads/fn.set_thread_config.html
+pub struct IpcChannelPool<C> {
+    shared: Arc<Shared<C>>,
+    filler: Option<JoinHandle<()>>,
+}
+
+struct Shared<C> {
+    state: Mutex<State<C>>,
+    changed: Condvar,
+}
+
+struct State<C> {
+    ready: VecDeque<C>,
+    size: usize,
+    // Why the last connection failed, until someone that found the pool empty is told.
+    error: Option<Error>,
+    stopping: bool,
+}
+
+impl<C> Shared<C> {
+    fn lock(&self) -> MutexGuard<'_, State<C>> {
+        self.state.lock().unwrap()
+    }
+
+    fn fill<F>(&self, mut connect: F) where F: FnMut() -> Result<C, Error> {
+        let mut state = self.lock();
+        loop {
+            if state.stopping {
+                return
+            }
+            if state.ready.len() >= state.size || state.error.is_some() {
+                state = self.changed.wait(state).unwrap();
+                continue
+            }
+            drop(state);
+            let result = connect();
+            state = self.lock();
+            match result {
+                Ok(connection) => state.ready.push_back(connection),
+                Err(error) => state.error = Some(error),
+            }
+            self.changed.notify_all();
+        }
+    }
+}
+
+impl<C> IpcChannelPool<C> where C: Send + 'static {
+    /// Keep `size` connections made by `connect` ready.
+    ///
+    /// When `connect` fails, the pool stops making connections until the error is
+    /// returned from [get].
+    ///
+    /// [get]: #method.get
+    pub fn new<F>(size: usize, connect: F) -> Result<IpcChannelPool<C>, Error>
+                  where F: FnMut() -> Result<C, Error> + Send + 'static {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                ready: VecDeque::with_capacity(size),
+                size,
+                error: None,
+                stopping: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let filler_shared = shared.clone();
+        let (started_sender, started_receiver) = mpsc::channel();
+        let filler = threads::builder("pool").spawn(move || {
+            let result = threads::configure_current_thread();
+            let started = result.is_ok();
+            started_sender.send(result).unwrap();
+            if started {
+                filler_shared.fill(connect)
+            }
+        })?;
+        started_receiver.recv().unwrap()?;
+        Ok(IpcChannelPool {
+            shared,
+            filler: Some(filler),
+        })
+    }
+}
+
+impl<C> IpcChannelPool<C> {
+    /// Take a ready connection, waiting for one to be made if there are none.
+    ///
+    /// Fails with the error of the last attempt at making one, if that failed.
+    pub fn get(&self) -> Result<C, Error> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(connection) = state.ready.pop_front() {
+                self.shared.changed.notify_all();
+                return Ok(connection)
+            }
+            if let Some(error) = state.error.take() {
+                self.shared.changed.notify_all();
+                return Err(error)
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Take a ready connection, if there is one.
+    pub fn try_get(&self) -> Option<C> {
+        let connection = self.shared.lock().ready.pop_front();
+        if connection.is_some() {
+            self.shared.changed.notify_all();
+        }
+        connection
+    }
+
+    /// The number of connections ready to be taken.
+    pub fn available(&self) -> usize {
+        self.shared.lock().ready.len()
+    }
+
+    /// Keep `size` connections ready from now on. Connections beyond that are
+    /// kept until taken.
+    pub fn resize(&self, size: usize) {
+        self.shared.lock().size = size;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<T> IpcChannelPool<IpcSender<T>>
+        where T: for<'de> Deserialize<'de> + Serialize + Send + 'static {
+    /// Keep `size` senders ready, whose receivers were sent to the peer on `acceptor`.
+    ///
+    /// The pool fails with `ErrorKind::NotConnected` once the peer's receiving end
+    /// of `acceptor` is gone.
+    pub fn connected_to(acceptor: IpcSender<IpcReceiver<T>>, size: usize)
+                        -> Result<IpcChannelPool<IpcSender<T>>, Error> {
+        IpcChannelPool::new(size, move || {
+            let (sender, receiver) = ipc::channel()?;
+            acceptor.send(receiver).map_err(|error| Error::new(ErrorKind::NotConnected, error))?;
+            Ok(sender)
+        })
+    }
+}
+
+impl<C> Drop for IpcChannelPool<C> {
+    fn drop(&mut self) {
+        self.shared.lock().stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(filler) = self.filler.take() {
+            let _ = filler.join();
+        }
+    }
+}
+
+impl<C> Debug for IpcChannelPool<C> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcChannelPool")
+                 .field("available", &self.available())
+                 .finish()
+    }
+}
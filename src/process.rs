@@ -0,0 +1,113 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for spawning child processes connected to the parent over a channel.
+//!
+//! The parent calls [spawn], which sets up an [IpcOneShotServer] and passes its
+//! name to the child in the `IPC_CHANNEL_BOOTSTRAP` environment variable.
+//! The child calls [bootstrap_sender] to connect, and sends a first message,
+//! typically holding the channels the parent will use to talk to it:
+//!
+//! ```no_run
+//! # use ipc_channel::ipc::{self, IpcSender};
+//! # use ipc_channel::process;
+//! # use std::process::Command;
+//! # use std::time::Duration;
+//! // In the parent:
+//! let (child, _, requests): (_, _, IpcSender<String>) =
+//!     process::spawn(Command::new("child"), Duration::from_secs(5)).unwrap();
+//! requests.send("Hello".to_owned()).unwrap();
+//!
+//! // In the child:
+//! let (requests, requests_rx) = ipc::channel::<String>().unwrap();
+//! process::bootstrap_sender().unwrap().send(requests).unwrap();
+//! ```
+//!
+//! Note that this requires a real multi-process backend: it doesn't work with
+//! the `force-inprocess` feature.
+//!
+//! [spawn]: fn.spawn.html
+//! [bootstrap_sender]: fn.bootstrap_sender.html
+//! [IpcOneShotServer]: ../ipc/struct.IpcOneShotServer.html
+
+use crossbeam_channel;
+use ipc::{IpcOneShotServer, IpcReceiver, IpcSender};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::process::{Child, Command};
+use threads;
+use std::time::Duration;
+
+/// Name of the environment variable holding the bootstrap server name.
+pub const BOOTSTRAP_ENV_VAR: &str = "IPC_CHANNEL_BOOTSTRAP";
+
+/// Spawn `command`, and wait up to `timeout` for the child to connect back
+/// with [bootstrap_sender] and send its first message.
+///
+/// Returns the child, the receiver connected to the child's bootstrap sender,
+/// and the first message. If the child doesn't connect in time, it is killed
+/// and the call fails with `ErrorKind::TimedOut`.
+///
+/// [bootstrap_sender]: fn.bootstrap_sender.html
+pub fn spawn<T>(mut command: Command, timeout: Duration)
+                -> Result<(Child, IpcReceiver<T>, T), Error>
+                where T: for<'de> Deserialize<'de> + Serialize + Send + 'static {
+    let (server, name) = IpcOneShotServer::<T>::new()?;
+    let mut child = command.env(BOOTSTRAP_ENV_VAR, &name).spawn()?;
+
+    let (result_sender, result_receiver) = crossbeam_channel::bounded(1);
+    let acceptor = threads::builder("acceptor").spawn(move || {
+        let result = match threads::configure_current_thread() {
+            Ok(()) => server.accept(),
+            Err(error) => Err(error.into()),
+        };
+        let _ = result_sender.send(result);
+    })?;
+    match result_receiver.recv_timeout(timeout) {
+        Ok(result) => {
+            let _ = acceptor.join();
+            match result {
+                Ok((receiver, first_message)) => Ok((child, receiver, first_message)),
+                Err(error) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    Err(Error::new(ErrorKind::ConnectionAborted, error))
+                }
+            }
+        }
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            // Unblock the acceptor thread by connecting in place of the child. If that
+            // fails, the child connected before it was killed, so the connection it
+            // left behind closes and the acceptor returns without our help.
+            let connected = IpcSender::<T>::connect(name).is_ok();
+            if connected || result_receiver.recv_timeout(timeout).is_ok() {
+                let _ = acceptor.join();
+            }
+            Err(Error::new(ErrorKind::TimedOut, "child process did not connect in time"))
+        }
+    }
+}
+
+/// In a child started with [spawn], connect to the parent.
+///
+/// The first message sent on the returned sender is handed to the parent by
+/// [spawn]; further messages arrive on the receiver it returns.
+///
+/// Fails with `ErrorKind::NotFound` if the process wasn't started with [spawn].
+///
+/// [spawn]: fn.spawn.html
+pub fn bootstrap_sender<T>() -> Result<IpcSender<T>, Error> where T: Serialize {
+    let name = env::var(BOOTSTRAP_ENV_VAR).map_err(|_| {
+        Error::new(ErrorKind::NotFound, "process was not spawned with a bootstrap server")
+    })?;
+    IpcSender::connect(name)
+}
@@ -0,0 +1,129 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bounding the rate a sender sends at, so one producer can't flood a receiver
+//! shared with others.
+//!
+//! A [RateLimitedSender], from [IpcSender::rate_limited], sends at most `rate`
+//! messages per second on average, and up to `burst` messages at once after being
+//! idle, as with a token bucket holding `burst` tokens, refilled at `rate` tokens
+//! per second. Once the bucket is empty, [send] waits for the next token, and
+//! [try_send] fails:
+//!
+//! ```
+//! # use ipc_channel::ipc;
+//! let (tx, rx) = ipc::channel().unwrap();
+//! let tx = tx.rate_limited(10, 2);
+//! tx.try_send(1).unwrap();
+//! tx.try_send(2).unwrap();
+//! assert!(tx.try_send(3).is_err());
+//! // Waits a tenth of a second.
+//! tx.send(3).unwrap();
+//! # assert_eq!(rx.recv().unwrap(), 1);
+//! ```
+//!
+//! [RateLimitedSender]: struct.RateLimitedSender.html
+//! [IpcSender::rate_limited]: ../ipc/struct.IpcSender.html#method.rate_limited
+//! [send]: struct.RateLimitedSender.html#method.send
+//! [try_send]: struct.RateLimitedSender.html#method.try_send
+
+use bincode;
+use ipc::IpcSender;
+use serde::Serialize;
+use std::cmp;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The token bucket, kept as the time the bucket will be full again if nothing else
+/// is sent, which needs no periodic refills.
+#[derive(Debug)]
+struct Bucket {
+    /// Time to refill one token.
+    interval: Duration,
+    /// Time to refill all tokens but one.
+    tolerance: Duration,
+    full_at: Instant,
+}
+
+impl Bucket {
+    /// Take a token, or return how long to wait for the next one.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let full_at = cmp::max(self.full_at, now);
+        let refill = full_at - now;
+        if refill > self.tolerance {
+            return Err(refill - self.tolerance)
+        }
+        self.full_at = full_at + self.interval;
+        Ok(())
+    }
+}
+
+/// A sender limited to a rate of messages, from [IpcSender::rate_limited].
+///
+/// Clones share the limit, so it applies to the producer however many clones it uses.
+///
+/// [IpcSender::rate_limited]: ../ipc/struct.IpcSender.html#method.rate_limited
+#[derive(Debug)]
+pub struct RateLimitedSender<T> where T: Serialize {
+    sender: IpcSender<T>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl<T> RateLimitedSender<T> where T: Serialize {
+    pub(crate) fn new(sender: IpcSender<T>, rate: u32, burst: u32) -> RateLimitedSender<T> {
+        assert!(rate > 0, "rate limit of zero messages per second");
+        assert!(burst > 0, "burst of zero messages");
+        let interval = Duration::from_secs(1) / rate;
+        RateLimitedSender {
+            sender,
+            bucket: Arc::new(Mutex::new(Bucket {
+                interval,
+                tolerance: interval * (burst - 1),
+                full_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Send `data`, waiting for the rate limit to allow it first.
+    pub fn send(&self, data: T) -> Result<(), bincode::Error> {
+        loop {
+            let result = self.bucket.lock().unwrap().take(Instant::now());
+            match result {
+                Ok(()) => return self.sender.send(data),
+                Err(wait) => thread::sleep(wait),
+            }
+        }
+    }
+
+    /// Send `data` if the rate limit allows it right away, and fail with
+    /// `ErrorKind::WouldBlock` otherwise, without sending it.
+    pub fn try_send(&self, data: T) -> Result<(), bincode::Error> {
+        let result = self.bucket.lock().unwrap().take(Instant::now());
+        if result.is_err() {
+            return Err(Error::new(ErrorKind::WouldBlock, "rate limit exceeded").into())
+        }
+        self.sender.send(data)
+    }
+
+    /// Take back the sender, without a limit.
+    pub fn into_inner(self) -> IpcSender<T> {
+        self.sender
+    }
+}
+
+impl<T> Clone for RateLimitedSender<T> where T: Serialize {
+    fn clone(&self) -> RateLimitedSender<T> {
+        RateLimitedSender {
+            sender: self.sender.clone(),
+            bucket: self.bucket.clone(),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The bookkeeping of the receiver sets and routes alive in the process, which
+//! `diagnostics::dump()` reports on, and a forked child cleans up after.
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+use std::{collections::HashSet, os::unix::io::RawFd};
+use std::sync::{Mutex, MutexGuard};
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+#[derive(Default)]
+pub(crate) struct Registry {
+    /// The number of `IpcReceiverSet`s alive, including those of routers.
+    pub receiver_sets: usize,
+    /// The receivers of all routers whose channels are still open.
+    pub routed_receivers: usize,
+    /// The receivers held by router threads, which a forked child closes, as the
+    /// threads that would have closed them were left behind in the parent.
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    pub router_fds: HashSet<RawFd>,
+}
+
+/// Lock the registry; keep it locked no longer than needed.
+pub(crate) fn lock() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap()
+}
@@ -0,0 +1,309 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Fixed-capacity channels that drop the oldest unread message when full.
+//!
+//! This is meant for telemetry and metrics streams, where the latest data matters
+//! more than completeness: a send never blocks waiting for the receiver and never
+//! grows the queue; instead, once `capacity` messages are waiting,
+//! each new message overwrites the oldest one.
+//!
+//! The messages live in a shared memory ring, so the endpoints can be sent to
+//! other processes like regular channels. Messages are limited to a fixed size
+//! chosen when creating the channel, and cannot embed channels or shared memory.
+//! A sender sent to another process only counts once received, so keep another
+//! sender alive until then if the receiver must not see the ring closed.
+//!
+//! Ring channels are unavailable on macOS, where [received shared memory] doesn't
+//! see the other end's later writes, and on illumos and Solaris, which lack a wait
+//! on shared memory.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::ring;
+//! let (tx, rx) = ring::channel(2, 64).unwrap();
+//! for sample in 0..5u32 {
+//!     tx.send(sample).unwrap();
+//! }
+//! assert_eq!(rx.recv().unwrap(), 3);
+//! assert_eq!(rx.recv().unwrap(), 4);
+//! assert_eq!(rx.dropped_count(), 3);
+//! ```
+//!
+//! [received shared memory]: ../ipc/struct.IpcSharedMemory.html#sharing-between-processes
+
+use bincode;
+use ipc::{self, IpcSharedMemory};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sync::{IpcCondvar, IpcMutex};
+use words::{load_bytes, slot_words, store_bytes, WORD_SIZE};
+
+// Layout of the header preceding the slots, in 64-bit words.
+const HEAD: usize = 0;
+const TAIL: usize = 1;
+const DROPPED: usize = 2;
+const SENDERS: usize = 3;
+const CAPACITY: usize = 4;
+const SLOT_WORDS: usize = 5;
+const HEADER_WORDS: usize = 6;
+
+/// Create a ring channel holding up to `capacity` messages,
+/// each serializing to at most `max_message_size` bytes.
+/// Fails with `ErrorKind::OutOfMemory` rather than exceed the [shared memory quota].
+///
+/// [shared memory quota]: ../ipc/fn.set_shared_memory_quota.html
+pub fn channel<T>(capacity: usize, max_message_size: usize)
+                  -> Result<(IpcRingSender<T>, IpcRingReceiver<T>), Error>
+                  where T: for<'de> Deserialize<'de> + Serialize {
+    if capacity == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "ring capacity must not be zero"))
+    }
+    let slot_words = slot_words(max_message_size);
+    let size = capacity.checked_mul(slot_words)
+                       .and_then(|words| words.checked_add(HEADER_WORDS))
+                       .and_then(|words| words.checked_mul(WORD_SIZE))
+                       .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "ring too large"))?;
+    let ring = Ring {
+        shared_memory: IpcSharedMemory::try_from_byte(0, size)?,
+        mutex: IpcMutex::new(),
+        condvar: IpcCondvar::new(),
+    };
+    ring.header()[CAPACITY].store(capacity as u64, Ordering::Relaxed);
+    ring.header()[SLOT_WORDS].store(slot_words as u64, Ordering::Relaxed);
+    ring.header()[SENDERS].store(1, Ordering::Relaxed);
+    Ok((IpcRingSender {
+        ring: ring.clone(),
+        phantom: PhantomData,
+    }, IpcRingReceiver {
+        ring,
+        phantom: PhantomData,
+    }))
+}
+
+#[derive(Clone)]
+struct Ring {
+    shared_memory: IpcSharedMemory,
+    mutex: IpcMutex,
+    condvar: IpcCondvar,
+}
+
+impl Ring {
+    fn header(&self) -> &[AtomicU64] {
+        self.shared_memory.atomic_u64s(0, HEADER_WORDS).expect("invalid ring header")
+    }
+
+    fn slot(&self, index: u64) -> &[AtomicU64] {
+        let header = self.header();
+        let capacity = header[CAPACITY].load(Ordering::Relaxed);
+        let slot_words = header[SLOT_WORDS].load(Ordering::Relaxed) as usize;
+        let offset = (HEADER_WORDS + (index % capacity) as usize * slot_words) * WORD_SIZE;
+        self.shared_memory.atomic_u64s(offset, slot_words).expect("invalid ring slot")
+    }
+
+    fn max_message_size(&self) -> usize {
+        (self.header()[SLOT_WORDS].load(Ordering::Relaxed) as usize - 1) * WORD_SIZE
+    }
+
+    /// Store a message in the next slot; the caller must hold the lock.
+    fn write(&self, bytes: &[u8]) {
+        let header = self.header();
+        let head = header[HEAD].load(Ordering::Relaxed);
+        let tail = header[TAIL].load(Ordering::Relaxed);
+        if head - tail == header[CAPACITY].load(Ordering::Relaxed) {
+            header[TAIL].store(tail + 1, Ordering::Relaxed);
+            header[DROPPED].fetch_add(1, Ordering::Relaxed);
+        }
+        store_bytes(self.slot(head), bytes);
+        header[HEAD].store(head + 1, Ordering::Relaxed);
+    }
+
+    /// Take the oldest message, if any; the caller must hold the lock.
+    fn read(&self) -> Option<Vec<u8>> {
+        let header = self.header();
+        let tail = header[TAIL].load(Ordering::Relaxed);
+        if tail == header[HEAD].load(Ordering::Relaxed) {
+            return None
+        }
+        let bytes = load_bytes(self.slot(tail));
+        header[TAIL].store(tail + 1, Ordering::Relaxed);
+        Some(bytes)
+    }
+}
+
+impl Serialize for Ring {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.shared_memory, &self.mutex, &self.condvar).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ring {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (shared_memory, mutex, condvar) = Deserialize::deserialize(deserializer)?;
+        Ok(Ring {
+            shared_memory,
+            mutex,
+            condvar,
+        })
+    }
+}
+
+/// Sending end of a [ring channel].
+///
+/// [ring channel]: fn.channel.html
+pub struct IpcRingSender<T> where T: Serialize {
+    ring: Ring,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcRingSender<T> where T: Serialize {
+    /// Send a message, overwriting the oldest unread one if the ring is full.
+    ///
+    /// Fails if the serialized message is larger than the ring's maximum message size,
+    /// or if it embeds channels or shared memory (these are dropped).
+    pub fn send(&self, data: T) -> Result<(), bincode::Error> {
+        let mut bytes = Vec::new();
+        ipc::serialize_plain(&data, &mut bytes)?;
+        if bytes.len() > self.ring.max_message_size() {
+            return Err(Error::new(ErrorKind::InvalidInput, "message too large for ring").into())
+        }
+        let _guard = self.ring.mutex.lock();
+        self.ring.write(&bytes);
+        self.ring.condvar.notify_all();
+        Ok(())
+    }
+}
+
+impl<T> Clone for IpcRingSender<T> where T: Serialize {
+    fn clone(&self) -> IpcRingSender<T> {
+        self.ring.header()[SENDERS].fetch_add(1, Ordering::Relaxed);
+        IpcRingSender {
+            ring: self.ring.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for IpcRingSender<T> where T: Serialize {
+    fn drop(&mut self) {
+        let _guard = self.ring.mutex.lock();
+        self.ring.header()[SENDERS].fetch_sub(1, Ordering::Relaxed);
+        self.ring.condvar.notify_all();
+    }
+}
+
+impl<T> Debug for IpcRingSender<T> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcRingSender").finish()
+    }
+}
+
+impl<T> Serialize for IpcRingSender<T> where T: Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.ring.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcRingSender<T> where T: Serialize {
+    /// The received sender counts as a live sender from here on: one in transit
+    /// doesn't keep the ring open, so the receiver reports it as closed if the
+    /// last sender is dropped before a sent one is received.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let ring: Ring = Deserialize::deserialize(deserializer)?;
+        ring.header()[SENDERS].fetch_add(1, Ordering::Relaxed);
+        Ok(IpcRingSender {
+            ring,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// Receiving end of a [ring channel].
+///
+/// There should only be one receiver per ring: sending the receiver to another
+/// process doesn't invalidate the original, but both would compete for messages.
+///
+/// [ring channel]: fn.channel.html
+pub struct IpcRingReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    ring: Ring,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcRingReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// Blocking receive of the oldest message still in the ring.
+    ///
+    /// Fails with `ErrorKind::ConnectionReset` once the ring is empty
+    /// and all senders are gone.
+    pub fn recv(&self) -> Result<T, bincode::Error> {
+        let mut guard = self.ring.mutex.lock();
+        loop {
+            if let Some(bytes) = self.ring.read() {
+                drop(guard);
+                return bincode::deserialize(&bytes)
+            }
+            if self.ring.header()[SENDERS].load(Ordering::Relaxed) == 0 {
+                return Err(Error::new(ErrorKind::ConnectionReset,
+                                      "All senders for this ring closed").into())
+            }
+            guard = self.ring.condvar.wait(guard);
+        }
+    }
+
+    /// Non-blocking receive.
+    ///
+    /// Fails with `ErrorKind::WouldBlock` if the ring is empty,
+    /// or `ErrorKind::ConnectionReset` if all senders are gone as well.
+    pub fn try_recv(&self) -> Result<T, bincode::Error> {
+        let guard = self.ring.mutex.lock();
+        match self.ring.read() {
+            Some(bytes) => {
+                drop(guard);
+                bincode::deserialize(&bytes)
+            }
+            None if self.ring.header()[SENDERS].load(Ordering::Relaxed) == 0 => {
+                Err(Error::new(ErrorKind::ConnectionReset,
+                               "All senders for this ring closed").into())
+            }
+            None => Err(Error::new(ErrorKind::WouldBlock, "ring is empty").into()),
+        }
+    }
+
+    /// Total number of messages overwritten before they could be received.
+    pub fn dropped_count(&self) -> u64 {
+        self.ring.header()[DROPPED].load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Debug for IpcRingReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcRingReceiver")
+                 .field("dropped_count", &self.dropped_count())
+                 .finish()
+    }
+}
+
+impl<T> Serialize for IpcRingReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.ring.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcRingReceiver<T>
+                              where T: for<'dde> Deserialize<'dde> + Serialize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcRingReceiver {
+            ring: Deserialize::deserialize(deserializer)?,
+            phantom: PhantomData,
+        })
+    }
+}
@@ -7,45 +7,386 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+use std::{cell::RefCell, os::unix::io::{AsRawFd, RawFd}, sync::MutexGuard};
 
-use crossbeam_channel::{self, Receiver, Sender};
+use bincode;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+          target_os = "windows", target_os = "openbsd", target_os = "freebsd",
+          target_os = "illumos", target_os = "solaris"))]
+use libc;
+use crossbeam_channel::{self, Receiver, Sender, TrySendError};
 use ipc::OpaqueIpcReceiver;
-use ipc::{self, IpcReceiver, IpcReceiverSet, IpcSelectionResult, IpcSender, OpaqueIpcMessage};
+use ipc::{self, DeadLetter, DeadLetterReason, IpcReceiver, IpcReceiverSet, IpcSelectionResult};
+use ipc::{IpcSender, OpaqueIpcMessage};
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+use registry::Registry;
+use registry;
 use serde::{Deserialize, Serialize};
+use tempfile;
+use threads;
 
 lazy_static! {
-    pub static ref ROUTER: RouterProxy = RouterProxy::new();
+    pub static ref ROUTER: RouterProxy = {
+        ROUTER_STARTED.store(true, Ordering::Release);
+        let config = ROUTER_THREAD_CONFIG.lock().unwrap().clone();
+        RouterProxy::with_thread_config(config).expect("failed to start the router thread")
+    };
+    static ref ROUTER_THREAD_CONFIG: Mutex<RouterThreadConfig> =
+        Mutex::new(RouterThreadConfig::default());
+}
+
+// Whether the global `ROUTER` was started, so fork handling doesn't start it needlessly.
+static ROUTER_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+thread_local! {
+    static FORK_GUARD: RefCell<Option<ForkGuard>> = const { RefCell::new(None) }
+}
+
+/// The locks held across a `fork()`.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+struct ForkGuard {
+    comm: Option<MutexGuard<'static, RouterProxyComm>>,
+    registry: MutexGuard<'static, Registry>,
+}
+
+/// Note that a router thread now holds the receiver `fd`, or no longer does.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+fn track_fd(fd: RawFd, held: bool) {
+    let router_fds = &mut registry::lock().router_fds;
+    if held {
+        router_fds.insert(fd);
+    } else {
+        router_fds.remove(&fd);
+    }
 }
 
 pub struct RouterProxy {
     comm: Mutex<RouterProxyComm>,
+    error_handler: Arc<Mutex<Option<RouterErrorHandler>>>,
+    dead_letters: Arc<Mutex<Option<Sender<DeadLetter>>>>,
+    next_timer_id: AtomicU64,
 }
 
 impl RouterProxy {
     pub fn new() -> RouterProxy {
-        let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
-        let (wakeup_sender, wakeup_receiver) = ipc::channel().unwrap();
-        thread::spawn(move || Router::new(msg_receiver, wakeup_receiver).run());
-        RouterProxy {
-            comm: Mutex::new(RouterProxyComm {
-                msg_sender: msg_sender,
-                wakeup_sender: wakeup_sender,
-            }),
-        }
+        RouterProxy::with_thread_config(RouterThreadConfig::default()).unwrap()
+    }
+
+    /// Start a router whose thread is set up according to `config`.
+    ///
+    /// Fails if the thread can't be spawned, or its priority can't be set. The thread
+    /// exits, dropping its routes, once the proxy is dropped.
+    pub fn with_thread_config(config: RouterThreadConfig) -> Result<RouterProxy, Error> {
+        Ok(RouterProxy {
+            comm: Mutex::new(RouterProxyComm::start(&config)?),
+            error_handler: Arc::new(Mutex::new(None)),
+            dead_letters: Arc::new(Mutex::new(None)),
+            next_timer_id: AtomicU64::new(0),
+        })
     }
 
     pub fn add_route(&self, receiver: OpaqueIpcReceiver, callback: RouterHandler) {
+        self.send_route(receiver, callback, None)
+    }
+
+    /// Like [add_route], also calling `on_close` on the router thread
+    /// once all senders of `receiver` are gone and the route is removed.
+    ///
+    /// [add_route]: #method.add_route
+    pub fn add_route_with_close_handler(
+        &self,
+        receiver: OpaqueIpcReceiver,
+        callback: RouterHandler,
+        on_close: RouterCloseHandler,
+    ) {
+        self.send_route(receiver, callback, Some(on_close))
+    }
+
+    fn send_route(
+        &self,
+        receiver: OpaqueIpcReceiver,
+        callback: RouterHandler,
+        on_close: Option<RouterCloseHandler>,
+    ) {
+        let comm = self.comm.lock().unwrap();
+        comm.msg_sender
+            .send(RouterMsg::AddRoute(receiver, callback, on_close))
+            .unwrap();
+        comm.wakeup_sender.send(()).unwrap();
+    }
+
+    /// Route an `IpcReceiver<T>`, calling `callback` with each message deserialized.
+    ///
+    /// Messages that fail to deserialize are passed to the handler set with
+    /// [set_error_handler], or dropped if there is none.
+    ///
+    /// [set_error_handler]: #method.set_error_handler
+    pub fn add_typed_route<T>(&self, receiver: IpcReceiver<T>, callback: TypedRouterHandler<T>)
+    where
+        T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
+    {
+        let error_handler = self.error_handler.clone();
+        self.add_typed_route_with_error_handler(
+            receiver,
+            callback,
+            Box::new(move |message, error| {
+                if let Some(ref mut handler) = *error_handler.lock().unwrap() {
+                    handler(message, error)
+                }
+            }),
+        )
+    }
+
+    /// Like [add_typed_route], passing the messages that fail to deserialize
+    /// to `error_handler` instead.
+    ///
+    /// [add_typed_route]: #method.add_typed_route
+    pub fn add_typed_route_with_error_handler<T>(
+        &self,
+        receiver: IpcReceiver<T>,
+        mut callback: TypedRouterHandler<T>,
+        mut error_handler: RouterErrorHandler,
+    ) where
+        T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
+    {
+        self.add_route(
+            receiver.to_opaque(),
+            Box::new(move |message| match message.deserialize_or_return() {
+                Ok(value) => callback(value),
+                Err((message, error)) => error_handler(message, error),
+            }),
+        )
+    }
+
+    /// Like [add_route], collapsing the messages that arrive in quick succession
+    /// according to `policy`: the first message after a quiet period opens a window,
+    /// and once it is over, `callback` gets the messages received meanwhile as one.
+    /// A window still open when the channel is closed is delivered right away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// # use ipc_channel::router::{CoalescePolicy, ROUTER};
+    /// # use std::sync::mpsc;
+    /// # use std::time::Duration;
+    /// let (tx, rx) = ipc::channel::<u32>().unwrap();
+    /// let (progress_sender, progress_receiver) = mpsc::channel();
+    /// ROUTER.add_typed_coalescing_route(rx, Box::new(move |progress| {
+    ///     progress_sender.send(progress).unwrap()
+    /// }), CoalescePolicy::KeepLatest(Duration::from_millis(50)));
+    /// for progress in 0..100 {
+    ///     tx.send(progress).unwrap();
+    /// }
+    /// drop(tx);
+    /// assert_eq!(progress_receiver.iter().last(), Some(99));
+    /// ```
+    ///
+    /// [add_route]: #method.add_route
+    pub fn add_coalescing_route(
+        &self,
+        receiver: OpaqueIpcReceiver,
+        callback: RouterHandler,
+        policy: CoalescePolicy<OpaqueIpcMessage>,
+    ) {
+        let window = policy.window();
+        self.send_coalescing_route(receiver, window, coalescing_handler(Some, callback, policy))
+    }
+
+    /// Like [add_coalescing_route], for an `IpcReceiver<T>`: the messages are
+    /// deserialized as they arrive, for `CoalescePolicy::Merge` to combine them.
+    /// Messages that fail to deserialize are handled as with [add_typed_route].
+    ///
+    /// [add_coalescing_route]: #method.add_coalescing_route
+    /// [add_typed_route]: #method.add_typed_route
+    pub fn add_typed_coalescing_route<T>(
+        &self,
+        receiver: IpcReceiver<T>,
+        callback: TypedRouterHandler<T>,
+        policy: CoalescePolicy<T>,
+    ) where
+        T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
+    {
+        let error_handler = self.error_handler.clone();
+        let window = policy.window();
+        let decode = move |message: OpaqueIpcMessage| match message.deserialize_or_return() {
+            Ok(value) => Some(value),
+            Err((message, error)) => {
+                if let Some(ref mut handler) = *error_handler.lock().unwrap() {
+                    handler(message, error)
+                }
+                None
+            },
+        };
+        let handler = coalescing_handler(decode, callback, policy);
+        self.send_coalescing_route(receiver.to_opaque(), window, handler)
+    }
+
+    fn send_coalescing_route(
+        &self,
+        receiver: OpaqueIpcReceiver,
+        window: Duration,
+        handler: CoalescingHandler,
+    ) {
         let comm = self.comm.lock().unwrap();
         comm.msg_sender
-            .send(RouterMsg::AddRoute(receiver, callback))
+            .send(RouterMsg::AddCoalescingRoute(receiver, window, handler))
             .unwrap();
         comm.wakeup_sender.send(()).unwrap();
     }
 
+    /// Call `callback` on the router thread once `delay` is over, between the
+    /// messages of the routes.
+    ///
+    /// The callback runs late if a route's handler is still running by then; it
+    /// must not block, or it holds up every route meanwhile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::router::ROUTER;
+    /// # use std::sync::mpsc;
+    /// # use std::time::Duration;
+    /// let (fired_sender, fired_receiver) = mpsc::channel();
+    /// ROUTER.add_timer(Duration::from_millis(10), Box::new(move || {
+    ///     fired_sender.send(()).unwrap()
+    /// }));
+    /// fired_receiver.recv().unwrap();
+    /// ```
+    pub fn add_timer(&self, delay: Duration, callback: RouterTimerHandler) -> TimerId {
+        self.send_timer(delay, None, callback)
+    }
+
+    /// Like [add_timer], calling `callback` every `period` until the timer is
+    /// cancelled. Calls that would be due while a previous one is late are skipped.
+    ///
+    /// [add_timer]: #method.add_timer
+    pub fn add_periodic_timer(&self, period: Duration, callback: RouterTimerHandler) -> TimerId {
+        self.send_timer(period, Some(period), callback)
+    }
+
+    /// Stop the timer `id`. Its callback may run once more if it is already due.
+    pub fn cancel_timer(&self, id: TimerId) {
+        self.send_msg(RouterMsg::CancelTimer(id))
+    }
+
+    fn send_timer(
+        &self,
+        delay: Duration,
+        period: Option<Duration>,
+        callback: RouterTimerHandler,
+    ) -> TimerId {
+        let id = TimerId(self.next_timer_id.fetch_add(1, Ordering::Relaxed));
+        let timer = Timer {
+            deadline: Instant::now() + delay,
+            period,
+            handler: callback,
+        };
+        self.send_msg(RouterMsg::AddTimer(id, timer));
+        id
+    }
+
+    fn send_msg(&self, msg: RouterMsg) {
+        let comm = self.comm.lock().unwrap();
+        comm.msg_sender.send(msg).unwrap();
+        comm.wakeup_sender.send(()).unwrap();
+    }
+
+    /// Set the handler for messages that fail to deserialize on typed routes
+    /// without an error handler of their own, replacing the previous one.
+    ///
+    /// The handler runs on the router thread; it must not call `set_error_handler` itself.
+    pub fn set_error_handler(&self, handler: RouterErrorHandler) {
+        *self.error_handler.lock().unwrap() = Some(handler);
+    }
+
+    /// Send the messages that routes to channels can't deliver to `dead_letters`,
+    /// rather than drop them, replacing the previous dead letter sender.
+    ///
+    /// This covers the routes added with `route_ipc_receiver_to_*`, once their
+    /// receiver is gone, or when their queue is full with `OverflowPolicy::Error`.
+    /// Messages dropped to make room with `OverflowPolicy::DropOldest` were already
+    /// deserialized, and aren't sent.
+    pub fn set_dead_letter_sender(&self, dead_letters: Sender<DeadLetter>) {
+        *self.dead_letters.lock().unwrap() = Some(dead_letters);
+    }
+
+    /// Add a typed route handing the messages to `deliver`, which hands back the values
+    /// it couldn't deliver; those go to the dead letters.
+    fn add_delivering_route<T, F>(&self, receiver: IpcReceiver<T>, mut deliver: F)
+    where
+        T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
+        F: FnMut(T) -> Result<(), T> + Send + 'static,
+    {
+        let error_handler = self.error_handler.clone();
+        let dead_letters = self.dead_letters.clone();
+        self.add_route(
+            receiver.to_opaque(),
+            Box::new(move |message| match message.deserialize_or_return() {
+                Ok(value) => {
+                    let value = match deliver(value) {
+                        Ok(()) => return,
+                        Err(value) => value,
+                    };
+                    if let Some(ref dead_letters) = *dead_letters.lock().unwrap() {
+                        // The message was taken apart for the value, so make it anew.
+                        if let Ok(message) = OpaqueIpcMessage::from_value(&value) {
+                            drop(dead_letters.send(DeadLetter {
+                                reason: DeadLetterReason::Undeliverable,
+                                message,
+                            }))
+                        }
+                    }
+                },
+                Err((message, error)) => {
+                    if let Some(ref mut handler) = *error_handler.lock().unwrap() {
+                        handler(message, error)
+                    }
+                },
+            }),
+        )
+    }
+
     /// A convenience function to route an `IpcReceiver<T>` to an existing `Sender<T>`.
+    ///
+    /// This is a typed route: see [add_typed_route] for messages that fail to deserialize.
+    /// Once the receiving end is gone, messages go to the [dead letters].
+    ///
+    /// [add_typed_route]: #method.add_typed_route
+    /// [dead letters]: #method.set_dead_letter_sender
     pub fn route_ipc_receiver_to_crossbeam_sender<T>(
         &self,
         ipc_receiver: IpcReceiver<T>,
@@ -53,10 +394,9 @@ impl RouterProxy {
     ) where
         T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
     {
-        self.add_route(
-            ipc_receiver.to_opaque(),
-            Box::new(move |message| drop(crossbeam_sender.send(message.to::<T>().unwrap()))),
-        )
+        self.add_delivering_route(ipc_receiver, move |message| {
+            crossbeam_sender.send(message).map_err(|error| error.0)
+        })
     }
 
     /// A convenience function to route an `IpcReceiver<T>` to a `Receiver<T>`: the most common
@@ -72,6 +412,173 @@ impl RouterProxy {
         self.route_ipc_receiver_to_crossbeam_sender(ipc_receiver, crossbeam_sender);
         crossbeam_receiver
     }
+
+    /// Like [route_ipc_receiver_to_new_crossbeam_receiver], keeping at most `backlog`
+    /// messages in memory: those arriving beyond are written to a temporary file,
+    /// to be read back in order once the receiver gets to them.
+    ///
+    /// Only plain messages are written out: those embedding channels or shared memory
+    /// regions are always kept in memory, as are all messages if writing fails.
+    /// Once the receiver is gone, messages go to the [dead letters].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ipc_channel::ipc;
+    /// # use ipc_channel::router::ROUTER;
+    /// let (tx, rx) = ipc::channel::<u32>().unwrap();
+    /// let spilling_rx = ROUTER.route_ipc_receiver_to_new_spilling_receiver(rx, 1).unwrap();
+    /// for value in 0..3 {
+    ///     tx.send(value).unwrap();
+    /// }
+    /// drop(tx);
+    /// assert_eq!(spilling_rx.iter().collect::<Vec<_>>(), [0, 1, 2]);
+    /// ```
+    ///
+    /// [route_ipc_receiver_to_new_crossbeam_receiver]:
+    /// #method.route_ipc_receiver_to_new_crossbeam_receiver
+    /// [dead letters]: #method.set_dead_letter_sender
+    pub fn route_ipc_receiver_to_new_spilling_receiver<T>(
+        &self,
+        ipc_receiver: IpcReceiver<T>,
+        backlog: usize,
+    ) -> Result<SpillingReceiver<T>, Error>
+    where
+        T: for<'de> Deserialize<'de> + Serialize,
+    {
+        let shared = Arc::new(SpoolShared {
+            spool: Mutex::new(Spool {
+                entries: VecDeque::new(),
+                in_memory: 0,
+                spilled: 0,
+                backlog,
+                file: tempfile::tempfile()?,
+                read_offset: 0,
+                write_offset: 0,
+                receiver_alive: true,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let spool = shared.clone();
+        let closing_spool = shared.clone();
+        let dead_letters = self.dead_letters.clone();
+        self.add_route_with_close_handler(
+            ipc_receiver.to_opaque(),
+            Box::new(move |message| {
+                if let Some(message) = spool.push(message) {
+                    if let Some(ref dead_letters) = *dead_letters.lock().unwrap() {
+                        drop(dead_letters.send(DeadLetter {
+                            reason: DeadLetterReason::Undeliverable,
+                            message,
+                        }))
+                    }
+                }
+            }),
+            Box::new(move || {
+                closing_spool.spool.lock().unwrap().closed = true;
+                closing_spool.changed.notify_all();
+            }),
+        );
+        Ok(SpillingReceiver {
+            shared,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Like [route_ipc_receiver_to_new_crossbeam_receiver], queueing at most `capacity`
+    /// messages, and handling the others according to `policy`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `policy` is `OverflowPolicy::DropOldest` and `capacity` is 0.
+    ///
+    /// [route_ipc_receiver_to_new_crossbeam_receiver]:
+    /// #method.route_ipc_receiver_to_new_crossbeam_receiver
+    pub fn route_ipc_receiver_to_new_bounded_crossbeam_receiver<T>(
+        &self,
+        ipc_receiver: IpcReceiver<T>,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Receiver<T>
+    where
+        T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
+    {
+        assert!(capacity > 0 || policy != OverflowPolicy::DropOldest,
+                "OverflowPolicy::DropOldest needs room for a message");
+        let (crossbeam_sender, crossbeam_receiver) = crossbeam_channel::bounded(capacity);
+        let mut crossbeam_sender = Some(crossbeam_sender);
+        // Only used to make room, as the router can't tell when the route's receiver is gone.
+        let oldest_receiver = crossbeam_receiver.clone();
+        self.add_delivering_route(ipc_receiver, move |mut message| {
+            let sender = match crossbeam_sender {
+                Some(ref sender) => sender,
+                None => return Err(message),
+            };
+            match policy {
+                OverflowPolicy::Block => sender.send(message).map_err(|error| error.0),
+                OverflowPolicy::DropOldest => {
+                    while let Err(TrySendError::Full(returned)) = sender.try_send(message) {
+                        drop(oldest_receiver.try_recv());
+                        message = returned;
+                    }
+                    Ok(())
+                },
+                OverflowPolicy::Error => match sender.try_send(message) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(message)) => {
+                        crossbeam_sender = None;
+                        Err(message)
+                    },
+                    Err(TrySendError::Disconnected(message)) => Err(message),
+                },
+            }
+        });
+        crossbeam_receiver
+    }
+
+    /// Like [route_ipc_receiver_to_new_bounded_crossbeam_receiver], with a
+    /// `std::sync::mpsc::sync_channel`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `policy` is `OverflowPolicy::DropOldest`: std receivers can't be
+    /// shared with the router, so it can't make room.
+    ///
+    /// [route_ipc_receiver_to_new_bounded_crossbeam_receiver]:
+    /// #method.route_ipc_receiver_to_new_bounded_crossbeam_receiver
+    pub fn route_ipc_receiver_to_new_sync_receiver<T>(
+        &self,
+        ipc_receiver: IpcReceiver<T>,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> mpsc::Receiver<T>
+    where
+        T: for<'de> Deserialize<'de> + Serialize + Send + 'static,
+    {
+        assert!(policy != OverflowPolicy::DropOldest,
+                "std sync channels don't support OverflowPolicy::DropOldest");
+        let (sync_sender, sync_receiver) = mpsc::sync_channel(capacity);
+        let mut sync_sender = Some(sync_sender);
+        self.add_delivering_route(ipc_receiver, move |message| {
+            let sender = match sync_sender {
+                Some(ref sender) => sender,
+                None => return Err(message),
+            };
+            if policy == OverflowPolicy::Block {
+                return sender.send(message).map_err(|error| error.0)
+            }
+            match sender.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(mpsc::TrySendError::Full(message)) => {
+                    sync_sender = None;
+                    Err(message)
+                },
+                Err(mpsc::TrySendError::Disconnected(message)) => Err(message),
+            }
+        });
+        sync_receiver
+    }
 }
 
 struct RouterProxyComm {
@@ -79,28 +586,339 @@ struct RouterProxyComm {
     wakeup_sender: IpcSender<()>,
 }
 
+impl RouterProxyComm {
+    /// Spawn a router thread, and return the means to talk to it.
+    fn start(config: &RouterThreadConfig) -> Result<RouterProxyComm, Error> {
+        let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
+        let (wakeup_sender, wakeup_receiver) = ipc::channel()?;
+        let mut builder = match config.name {
+            Some(ref name) => thread::Builder::new().name(name.clone()),
+            None => threads::builder("router"),
+        };
+        if let Some(stack_size) = config.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let priority = config.priority;
+        let (started_sender, started_receiver) = crossbeam_channel::bounded(1);
+        builder.spawn(move || {
+            let result = threads::configure_current_thread().and_then(|()| match priority {
+                Some(priority) => set_current_thread_priority(priority),
+                None => Ok(()),
+            });
+            let started = result.is_ok();
+            started_sender.send(result).unwrap();
+            if started {
+                Router::new(msg_receiver, wakeup_receiver).run()
+            }
+        })?;
+        started_receiver.recv().unwrap()?;
+        Ok(RouterProxyComm {
+            msg_sender: msg_sender,
+            wakeup_sender: wakeup_sender,
+        })
+    }
+}
+
+/// How the thread of a router is spawned.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::router::{RouterProxy, RouterThreadConfig};
+/// let config = RouterThreadConfig::new().name("ipc-router").stack_size(256 * 1024);
+/// let router = RouterProxy::with_thread_config(config).unwrap();
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouterThreadConfig {
+    name: Option<String>,
+    stack_size: Option<usize>,
+    priority: Option<ThreadPriority>,
+}
+
+impl RouterThreadConfig {
+    /// A thread named as set with [threads::set_thread_config], with the default
+    /// stack size and priority.
+    ///
+    /// [threads::set_thread_config]: ../threads/fn.set_thread_config.html
+    pub fn new() -> RouterThreadConfig {
+        RouterThreadConfig::default()
+    }
+
+    /// Name the thread `name`, rather than after the global [name prefix].
+    ///
+    /// [name prefix]: ../threads/struct.ThreadConfig.html#method.name_prefix
+    pub fn name<S: Into<String>>(mut self, name: S) -> RouterThreadConfig {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Give the thread a stack of `bytes`, rather than the standard library's default.
+    pub fn stack_size(mut self, bytes: usize) -> RouterThreadConfig {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Set the scheduling priority of the thread once it starts.
+    pub fn priority(mut self, priority: ThreadPriority) -> RouterThreadConfig {
+        self.priority = Some(priority);
+        self
+    }
+}
+
+/// The OS scheduling priority of a thread. Each kind only applies on some platforms;
+/// elsewhere, setting it fails with `ErrorKind::Unsupported`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadPriority {
+    /// A nice value, from -20, the most favourable, to 19. Linux and Android only,
+    /// where it applies to the thread alone. Lowering it usually needs privileges.
+    Nice(i32),
+    /// A quality of service class, on macOS and iOS.
+    QualityOfService(QosClass),
+    /// A Windows thread priority, `THREAD_PRIORITY_*`, from -15 to 15.
+    Windows(i32),
+}
+
+/// The quality of service classes of macOS and iOS, from the most urgent to the least.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QosClass {
+    UserInteractive,
+    UserInitiated,
+    Default,
+    Utility,
+    Background,
+}
+
+/// Make the router thread of the global [ROUTER] follow `config`.
+///
+/// This must be called before anything uses the global router; it fails with
+/// `ErrorKind::AlreadyExists` otherwise. If the thread can't be set up as
+/// configured, the first use of the global router panics.
+///
+/// [ROUTER]: struct.ROUTER.html
+pub fn set_router_thread_config(config: RouterThreadConfig) -> Result<(), Error> {
+    let mut global_config = ROUTER_THREAD_CONFIG.lock().unwrap();
+    if ROUTER_STARTED.load(Ordering::Acquire) {
+        return Err(Error::new(ErrorKind::AlreadyExists, "the global router is already running"))
+    }
+    *global_config = config;
+    Ok(())
+}
+
+fn set_current_thread_priority(priority: ThreadPriority) -> Result<(), Error> {
+    match priority {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        ThreadPriority::Nice(nice) => {
+            // Linux applies nice values to the thread with the given ID, not the whole process.
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+                return Err(Error::last_os_error())
+            }
+            Ok(())
+        }
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        ThreadPriority::QualityOfService(class) => {
+            let class = match class {
+                QosClass::UserInteractive => libc::qos_class_t::QOS_CLASS_USER_INTERACTIVE,
+                QosClass::UserInitiated => libc::qos_class_t::QOS_CLASS_USER_INITIATED,
+                QosClass::Default => libc::qos_class_t::QOS_CLASS_DEFAULT,
+                QosClass::Utility => libc::qos_class_t::QOS_CLASS_UTILITY,
+                QosClass::Background => libc::qos_class_t::QOS_CLASS_BACKGROUND,
+            };
+            match unsafe { libc::pthread_set_qos_class_self_np(class, 0) } {
+                0 => Ok(()),
+                errno => Err(Error::from_raw_os_error(errno)),
+            }
+        }
+        #[cfg(target_os = "windows")]
+        ThreadPriority::Windows(priority) => {
+            extern "system" {
+                fn GetCurrentThread() -> *mut libc::c_void;
+                fn SetThreadPriority(thread: *mut libc::c_void, priority: libc::c_int) -> i32;
+            }
+            if unsafe { SetThreadPriority(GetCurrentThread(), priority) } == 0 {
+                return Err(Error::last_os_error())
+            }
+            Ok(())
+        }
+        _ => Err(Error::new(ErrorKind::Unsupported,
+                            format!("{:?} is not supported on this platform", priority))),
+    }
+}
+
+/// Hold the locks of the routers across a `fork()`,
+/// so the child doesn't inherit them locked by a thread that doesn't exist there.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub(crate) fn prepare_fork() {
+    let comm = if ROUTER_STARTED.load(Ordering::Acquire) {
+        Some(ROUTER.comm.lock().unwrap())
+    } else {
+        None
+    };
+    let registry = registry::lock();
+    FORK_GUARD.with(|fork_guard| *fork_guard.borrow_mut() = Some(ForkGuard { comm, registry }));
+}
+
+/// Release the locks taken by `prepare_fork()`.
+///
+/// In the child, the router threads didn't survive the fork: close the receivers
+/// of their routes, which are only serviced in the parent, and start a new thread
+/// for the global router, so routes added from now on are serviced.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+pub(crate) fn finish_fork(in_child: bool) {
+    let guard = FORK_GUARD.with(|fork_guard| fork_guard.borrow_mut().take());
+    let (comm, mut registry) = match guard {
+        Some(ForkGuard { comm, registry }) => (comm, registry),
+        None => (None, registry::lock()),
+    };
+    if !in_child {
+        return
+    }
+    for fd in registry.router_fds.drain() {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    // The threads of the routers, and so their routes, were left behind in the parent.
+    registry.routed_receivers = 0;
+    drop(registry);
+    if ROUTER_STARTED.load(Ordering::Acquire) {
+        let mut comm = comm.unwrap_or_else(|| ROUTER.comm.lock().unwrap());
+        let config = ROUTER_THREAD_CONFIG.lock().unwrap();
+        // The previous wakeup sender is dropped, and closed, with the old means of talking
+        // to the router.
+        *comm = RouterProxyComm::start(&config).expect("failed to restart the router thread");
+    }
+}
+
 struct Router {
     msg_receiver: Receiver<RouterMsg>,
     msg_wakeup_id: u64,
     ipc_receiver_set: IpcReceiverSet,
     handlers: HashMap<u64, RouterHandler>,
+    close_handlers: HashMap<u64, RouterCloseHandler>,
+    coalescing_routes: HashMap<u64, CoalescingRoute>,
+    timers: HashMap<TimerId, Timer>,
+    /// The receivers of the router, by receiver ID, for forked children to close.
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    fds: HashMap<u64, RawFd>,
+}
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let routes = self.handlers.len() + self.coalescing_routes.len();
+        registry::lock().routed_receivers -= routes;
+        #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                        target_os = "openbsd",
+                                                        target_os = "freebsd",
+                                                        target_os = "illumos",
+                                                        target_os = "solaris")))]
+        for (_, fd) in self.fds.drain() {
+            track_fd(fd, false);
+        }
+    }
+}
+
+struct CoalescingRoute {
+    window: Duration,
+    /// When the open window is over, if any.
+    deadline: Option<Instant>,
+    handler: CoalescingHandler,
 }
 
 impl Router {
     fn new(msg_receiver: Receiver<RouterMsg>, wakeup_receiver: IpcReceiver<()>) -> Router {
         let mut ipc_receiver_set = IpcReceiverSet::new().unwrap();
+        #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                        target_os = "openbsd",
+                                                        target_os = "freebsd",
+                                                        target_os = "illumos",
+                                                        target_os = "solaris")))]
+        let wakeup_fd = wakeup_receiver.as_raw_fd();
         let msg_wakeup_id = ipc_receiver_set.add(wakeup_receiver).unwrap();
+        #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                        target_os = "openbsd",
+                                                        target_os = "freebsd",
+                                                        target_os = "illumos",
+                                                        target_os = "solaris")))]
+        track_fd(wakeup_fd, true);
         Router {
             msg_receiver: msg_receiver,
             msg_wakeup_id: msg_wakeup_id,
             ipc_receiver_set: ipc_receiver_set,
             handlers: HashMap::new(),
+            close_handlers: HashMap::new(),
+            coalescing_routes: HashMap::new(),
+            timers: HashMap::new(),
+            #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                            target_os = "openbsd",
+                                                            target_os = "freebsd",
+                                                            target_os = "illumos",
+                                                            target_os = "solaris")))]
+            fds: iter::once((msg_wakeup_id, wakeup_fd)).collect(),
+        }
+    }
+
+    /// Add the receiver of a route to the set, returning its ID.
+    fn add_receiver(&mut self, receiver: OpaqueIpcReceiver) -> u64 {
+        #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                        target_os = "openbsd",
+                                                        target_os = "freebsd",
+                                                        target_os = "illumos",
+                                                        target_os = "solaris")))]
+        let fd = receiver.as_raw_fd();
+        let id = self.ipc_receiver_set.add_opaque(receiver).unwrap();
+        registry::lock().routed_receivers += 1;
+        #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                        target_os = "openbsd",
+                                                        target_os = "freebsd",
+                                                        target_os = "illumos",
+                                                        target_os = "solaris")))]
+        {
+            track_fd(fd, true);
+            self.fds.insert(id, fd);
+        }
+        id
+    }
+
+    /// Note that the receiver `id`, closed, was removed from the set.
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    fn forget_fd(&mut self, id: u64) {
+        if let Some(fd) = self.fds.remove(&id) {
+            track_fd(fd, false);
         }
     }
 
     fn run(&mut self) {
         loop {
-            let results = match self.ipc_receiver_set.select() {
+            let timer_deadlines = self.timers.values().map(|timer| timer.deadline);
+            let deadline = self.coalescing_routes.values()
+                                                 .filter_map(|route| route.deadline)
+                                                 .chain(timer_deadlines)
+                                                 .min();
+            let results = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    self.ipc_receiver_set.select_timeout(timeout)
+                },
+                None => self.ipc_receiver_set.select(),
+            };
+            let results = match results {
                 Ok(results) => results,
                 Err(_) => break,
             };
@@ -108,25 +926,338 @@ impl Router {
                 match result {
                     IpcSelectionResult::MessageReceived(id, _) if id == self.msg_wakeup_id =>
                         match self.msg_receiver.recv().unwrap() {
-                            RouterMsg::AddRoute(receiver, handler) => {
-                                let new_receiver_id =
-                                    self.ipc_receiver_set.add_opaque(receiver).unwrap();
+                            RouterMsg::AddRoute(receiver, handler, on_close) => {
+                                let new_receiver_id = self.add_receiver(receiver);
                                 self.handlers.insert(new_receiver_id, handler);
+                                if let Some(on_close) = on_close {
+                                    self.close_handlers.insert(new_receiver_id, on_close);
+                                }
+                            },
+                            RouterMsg::AddCoalescingRoute(receiver, window, handler) => {
+                                let new_receiver_id = self.add_receiver(receiver);
+                                self.coalescing_routes.insert(new_receiver_id, CoalescingRoute {
+                                    window,
+                                    deadline: None,
+                                    handler,
+                                });
+                            },
+                            RouterMsg::AddTimer(id, timer) => {
+                                self.timers.insert(id, timer);
+                            },
+                            RouterMsg::CancelTimer(id) => {
+                                self.timers.remove(&id);
                             },
                         },
-                    IpcSelectionResult::MessageReceived(id, message) =>
-                        self.handlers.get_mut(&id).unwrap()(message),
+                    IpcSelectionResult::MessageReceived(id, message) => {
+                        if let Some(route) = self.coalescing_routes.get_mut(&id) {
+                            (route.handler)(Some(message));
+                            if route.deadline.is_none() {
+                                route.deadline = Some(Instant::now() + route.window);
+                            }
+                        } else {
+                            self.handlers.get_mut(&id).unwrap()(message)
+                        }
+                    },
+                    // The proxy is gone, so no routes can be added nor timers set anymore.
+                    IpcSelectionResult::ChannelClosed(id) if id == self.msg_wakeup_id => return,
                     IpcSelectionResult::ChannelClosed(id) => {
+                        registry::lock().routed_receivers -= 1;
+                        // Routes aren't told why their channel closed.
+                        self.ipc_receiver_set.take_peer_death(id);
+                        #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                                        target_os = "openbsd",
+                                                                        target_os = "freebsd",
+                                                                        target_os = "illumos",
+                                                                        target_os = "solaris")))]
+                        self.forget_fd(id);
+                        if let Some(mut route) = self.coalescing_routes.remove(&id) {
+                            (route.handler)(None);
+                            continue
+                        }
                         self.handlers.remove(&id).unwrap();
+                        if let Some(on_close) = self.close_handlers.remove(&id) {
+                            on_close();
+                        }
                     },
                 }
             }
+            let now = Instant::now();
+            for route in self.coalescing_routes.values_mut() {
+                if route.deadline.iter().any(|&deadline| deadline <= now) {
+                    route.deadline = None;
+                    (route.handler)(None);
+                }
+            }
+            self.fire_timers(now);
+        }
+    }
+
+    fn fire_timers(&mut self, now: Instant) {
+        let due: Vec<TimerId> = self.timers.iter()
+                                    .filter(|&(_, timer)| timer.deadline <= now)
+                                    .map(|(&id, _)| id)
+                                    .collect();
+        for id in due {
+            let mut timer = self.timers.remove(&id).unwrap();
+            (timer.handler)();
+            if let Some(period) = timer.period {
+                timer.deadline += period;
+                if timer.deadline <= now {
+                    timer.deadline = now + period;
+                }
+                self.timers.insert(id, timer);
+            }
         }
     }
 }
 
 enum RouterMsg {
-    AddRoute(OpaqueIpcReceiver, RouterHandler),
+    AddRoute(OpaqueIpcReceiver, RouterHandler, Option<RouterCloseHandler>),
+    AddCoalescingRoute(OpaqueIpcReceiver, Duration, CoalescingHandler),
+    AddTimer(TimerId, Timer),
+    CancelTimer(TimerId),
+}
+
+/// Identifies a timer added with `RouterProxy::add_timer` or
+/// `RouterProxy::add_periodic_timer`, to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+pub type RouterTimerHandler = Box<dyn FnMut() + Send>;
+
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+    handler: RouterTimerHandler,
+}
+
+/// Receiving end of a route added with `RouterProxy::route_ipc_receiver_to_new_spilling_receiver`.
+pub struct SpillingReceiver<T> {
+    shared: Arc<SpoolShared>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> SpillingReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// Blocking receive. Fails with `ErrorKind::ConnectionReset` once all senders
+    /// are gone and all messages were received.
+    pub fn recv(&self) -> Result<T, bincode::Error> {
+        let mut spool = self.shared.spool.lock().unwrap();
+        while spool.entries.is_empty() && !spool.closed {
+            spool = self.shared.changed.wait(spool).unwrap();
+        }
+        spool.pop()?.to()
+    }
+
+    /// Non-blocking receive, failing with `ErrorKind::WouldBlock` if no message is queued.
+    pub fn try_recv(&self) -> Result<T, bincode::Error> {
+        self.shared.spool.lock().unwrap().pop()?.to()
+    }
+
+    /// An iterator over the messages, ending once all senders are gone, or on an error.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        iter::from_fn(move || self.recv().ok())
+    }
+
+    /// The number of queued messages written out to the spool file.
+    pub fn spilled(&self) -> usize {
+        self.shared.spool.lock().unwrap().spilled
+    }
+}
+
+impl<T> Drop for SpillingReceiver<T> {
+    fn drop(&mut self) {
+        let mut spool = self.shared.spool.lock().unwrap();
+        spool.receiver_alive = false;
+        spool.entries.clear();
+    }
+}
+
+struct SpoolShared {
+    spool: Mutex<Spool>,
+    changed: Condvar,
+}
+
+impl SpoolShared {
+    /// Queue a message, handing it back if the receiver is gone.
+    fn push(&self, message: OpaqueIpcMessage) -> Option<OpaqueIpcMessage> {
+        let mut spool = self.spool.lock().unwrap();
+        if !spool.receiver_alive {
+            return Some(message)
+        }
+        let entry = if spool.in_memory < spool.backlog {
+            SpoolEntry::InMemory(message)
+        } else {
+            spool.spill(message)
+        };
+        if let SpoolEntry::InMemory(_) = entry {
+            spool.in_memory += 1;
+        }
+        spool.entries.push_back(entry);
+        self.changed.notify_all();
+        None
+    }
+}
+
+/// The messages of a spilling route, in order; past the backlog, only the lengths of
+/// the messages written out to the file are kept in memory.
+struct Spool {
+    entries: VecDeque<SpoolEntry>,
+    in_memory: usize,
+    spilled: usize,
+    backlog: usize,
+    file: File,
+    read_offset: u64,
+    write_offset: u64,
+    receiver_alive: bool,
+    closed: bool,
+}
+
+enum SpoolEntry {
+    InMemory(OpaqueIpcMessage),
+    /// The length of the message data, and whether it starts with a metadata header.
+    Spilled(usize, bool),
+}
+
+impl Spool {
+    fn spill(&mut self, message: OpaqueIpcMessage) -> SpoolEntry {
+        let written = match message.plain_data() {
+            Some(data) => {
+                let offset = self.write_offset;
+                self.file.seek(SeekFrom::Start(offset))
+                    .and_then(|_| self.file.write_all(data))
+                    .map(|()| data.len())
+            }
+            None => return SpoolEntry::InMemory(message),
+        };
+        match written {
+            Ok(length) => {
+                self.write_offset += length as u64;
+                self.spilled += 1;
+                let metadata = message.has_metadata();
+                message.recycle();
+                SpoolEntry::Spilled(length, metadata)
+            }
+            Err(_) => SpoolEntry::InMemory(message),
+        }
+    }
+
+    fn pop(&mut self) -> Result<OpaqueIpcMessage, bincode::Error> {
+        match self.entries.pop_front() {
+            Some(SpoolEntry::InMemory(message)) => {
+                self.in_memory -= 1;
+                Ok(message)
+            }
+            Some(SpoolEntry::Spilled(length, metadata)) => {
+                let mut data = vec![0; length];
+                let offset = self.read_offset;
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut data)?;
+                self.read_offset += length as u64;
+                self.spilled -= 1;
+                if self.spilled == 0 {
+                    // Start over, so the file doesn't grow over the life of the route.
+                    self.read_offset = 0;
+                    self.write_offset = 0;
+                    drop(self.file.set_len(0));
+                }
+                Ok(OpaqueIpcMessage::from_plain_data(data, metadata))
+            }
+            None if self.closed => {
+                Err(Error::new(ErrorKind::ConnectionReset, "all senders dropped").into())
+            }
+            None => Err(Error::new(ErrorKind::WouldBlock, "no message queued").into()),
+        }
+    }
+}
+
+/// What a bounded route does with a message that arrives while its queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room. This blocks the router thread, and so every other route, meanwhile.
+    Block,
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+    /// Drop the message and stop routing: the receiver reports disconnection
+    /// once it has taken the messages already queued. The message, and the
+    /// following ones, go to the router's dead letters, if it has any.
+    Error,
 }
 
 pub type RouterHandler = Box<FnMut(OpaqueIpcMessage) + Send>;
+
+/// How a coalescing route collapses the messages received within a window.
+pub enum CoalescePolicy<T> {
+    /// Only deliver the last message received within each window of this duration.
+    KeepLatest(Duration),
+    /// Combine the messages received within each window of this duration with the
+    /// closure, which gets the messages combined so far and the next one.
+    Merge(Duration, Box<dyn FnMut(T, T) -> T + Send>),
+}
+
+impl<T> CoalescePolicy<T> {
+    fn window(&self) -> Duration {
+        match *self {
+            CoalescePolicy::KeepLatest(window) | CoalescePolicy::Merge(window, _) => window,
+        }
+    }
+}
+
+impl<T> Debug for CoalescePolicy<T> {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CoalescePolicy::KeepLatest(window) => {
+                formatter.debug_tuple("KeepLatest").field(&window).finish()
+            }
+            CoalescePolicy::Merge(window, _) => {
+                formatter.debug_tuple("Merge").field(&window).finish()
+            }
+        }
+    }
+}
+
+/// Handler of a coalescing route on the router thread: folds in each message
+/// received, and delivers the result when called with `None` once the window is over.
+type CoalescingHandler = Box<dyn FnMut(Option<OpaqueIpcMessage>) + Send>;
+
+/// A handler folding in the messages `decode` accepts according to `policy`.
+fn coalescing_handler<T, D>(
+    mut decode: D,
+    mut callback: Box<dyn FnMut(T) + Send>,
+    policy: CoalescePolicy<T>,
+) -> CoalescingHandler
+where
+    T: Send + 'static,
+    D: FnMut(OpaqueIpcMessage) -> Option<T> + Send + 'static,
+{
+    let mut merge = match policy {
+        CoalescePolicy::KeepLatest(_) => None,
+        CoalescePolicy::Merge(_, merge) => Some(merge),
+    };
+    let mut pending = None;
+    Box::new(move |message| match message {
+        Some(message) => {
+            if let Some(value) = decode(message) {
+                pending = Some(match (pending.take(), merge.as_mut()) {
+                    (Some(merged), Some(merge)) => merge(merged, value),
+                    _ => value,
+                });
+            }
+        },
+        None => {
+            if let Some(value) = pending.take() {
+                callback(value)
+            }
+        },
+    })
+}
+
+/// Callback run when a route added with `RouterProxy::add_route_with_close_handler`
+/// is removed, as its channel was closed.
+pub type RouterCloseHandler = Box<dyn FnOnce() + Send>;
+
+/// Callback of a route added with `RouterProxy::add_typed_route`.
+pub type TypedRouterHandler<T> = Box<dyn FnMut(T) + Send>;
+
+/// Callback for messages that fail to deserialize on a typed route:
+/// gets the raw message, and the error.
+pub type RouterErrorHandler = Box<dyn FnMut(OpaqueIpcMessage, bincode::Error) + Send>;
@@ -0,0 +1,303 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Deterministic simulated channels with virtual time, for unit testing protocol logic.
+//!
+//! Enabled with the `test-support` feature. All channels of a [Simulation] live
+//! in the current thread and share a virtual clock. Each message is delivered
+//! after a latency drawn from a generator seeded when creating the simulation,
+//! so the interleaving of messages on different channels varies with the seed,
+//! but is the same in every run with the same seed. Messages on one channel
+//! always arrive in order, as with real channels.
+//!
+//! Nothing ever sleeps: when a receiver waits, the clock jumps to the next
+//! delivery on its channel, or to the end of its timeout. This makes timeout
+//! paths as quick to test as the others.
+//!
+//! Messages are serialized with bincode on the way, so values that fail to
+//! serialize or deserialize fail here too. They can't embed channels or
+//! shared memory.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::sim::Simulation;
+//! # use std::time::Duration;
+//! let sim = Simulation::new(7);
+//! let (request_tx, request_rx) = sim.channel::<u32>();
+//! let (response_tx, response_rx) = sim.channel::<u32>();
+//!
+//! request_tx.send(20).unwrap();
+//! let request = request_rx.recv().unwrap();
+//! response_tx.send(request + 1).unwrap();
+//! assert_eq!(response_rx.recv().unwrap(), 21);
+//!
+//! // Nobody answers this time, so the timeout expires, without any real waiting.
+//! let start = sim.now();
+//! assert!(response_rx.recv_timeout(Duration::from_secs(30)).is_err());
+//! assert_eq!(sim.now() - start, Duration::from_secs(30));
+//! ```
+//!
+//! [Simulation]: struct.Simulation.html
+
+use bincode;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// A set of simulated channels sharing a virtual clock and a seeded scheduler.
+#[derive(Clone)]
+pub struct Simulation {
+    world: Rc<RefCell<World>>,
+}
+
+struct World {
+    now: Duration,
+    rng: SplitMix64,
+    min_latency: Duration,
+    max_latency: Duration,
+    next_channel_id: u64,
+    channels: HashMap<u64, SimChannel>,
+}
+
+#[derive(Default)]
+struct SimChannel {
+    // Messages in flight, with their delivery times, which never decrease.
+    queue: VecDeque<(Duration, Vec<u8>)>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+/// SplitMix64, so schedules don't change with the version of the `rand` crate.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Simulation {
+    /// Create a simulation, with message latencies between 0 and 1 ms.
+    pub fn new(seed: u64) -> Simulation {
+        Simulation {
+            world: Rc::new(RefCell::new(World {
+                now: Duration::from_secs(0),
+                rng: SplitMix64(seed),
+                min_latency: Duration::from_secs(0),
+                max_latency: Duration::from_millis(1),
+                next_channel_id: 0,
+                channels: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Draw the latencies of messages sent from now on between `min` and `max`.
+    pub fn set_latency(&self, min: Duration, max: Duration) {
+        assert!(min <= max, "minimum latency exceeds the maximum");
+        let mut world = self.world.borrow_mut();
+        world.min_latency = min;
+        world.max_latency = max;
+    }
+
+    /// Create a simulated channel.
+    pub fn channel<T>(&self) -> (SimSender<T>, SimReceiver<T>)
+                      where T: for<'de> Deserialize<'de> + Serialize {
+        let mut world = self.world.borrow_mut();
+        let id = world.next_channel_id;
+        world.next_channel_id += 1;
+        world.channels.insert(id, SimChannel {
+            senders: 1,
+            receiver_alive: true,
+            ..SimChannel::default()
+        });
+        let sender = SimSender {
+            world: self.world.clone(),
+            id,
+            phantom: PhantomData,
+        };
+        let receiver = SimReceiver {
+            world: self.world.clone(),
+            id,
+            phantom: PhantomData,
+        };
+        (sender, receiver)
+    }
+
+    /// Virtual time elapsed since the simulation was created.
+    pub fn now(&self) -> Duration {
+        self.world.borrow().now
+    }
+
+    /// Let virtual time pass, e.g. to simulate a slow computation or a sleep.
+    pub fn advance(&self, duration: Duration) {
+        self.world.borrow_mut().now += duration;
+    }
+
+    /// The number of messages in flight on all channels.
+    pub fn pending(&self) -> usize {
+        self.world.borrow().channels.values().map(|channel| channel.queue.len()).sum()
+    }
+}
+
+impl World {
+    fn latency(&mut self) -> Duration {
+        let range = self.max_latency - self.min_latency;
+        let range_nanos = range.as_secs() * 1_000_000_000 + u64::from(range.subsec_nanos());
+        let offset = match range_nanos.checked_add(1) {
+            Some(modulus) => self.rng.next() % modulus,
+            None => self.rng.next(),
+        };
+        self.min_latency + Duration::from_nanos(offset)
+    }
+}
+
+/// Sending end of a simulated channel.
+pub struct SimSender<T> {
+    world: Rc<RefCell<World>>,
+    id: u64,
+    phantom: PhantomData<T>,
+}
+
+impl<T> SimSender<T> where T: Serialize {
+    /// Send a message, to be delivered after a random latency.
+    ///
+    /// Fails with `ErrorKind::ConnectionReset` if the receiver was dropped.
+    pub fn send(&self, value: T) -> Result<(), bincode::Error> {
+        let data = bincode::serialize(&value)?;
+        let mut world = self.world.borrow_mut();
+        let delivery = world.now + world.latency();
+        let channel = world.channels.get_mut(&self.id).unwrap();
+        if !channel.receiver_alive {
+            return Err(Error::new(ErrorKind::ConnectionReset, "receiver dropped").into())
+        }
+        // Keep the channel in order.
+        let delivery = match channel.queue.back() {
+            Some(&(last, _)) => cmp::max(last, delivery),
+            None => delivery,
+        };
+        channel.queue.push_back((delivery, data));
+        Ok(())
+    }
+}
+
+impl<T> Clone for SimSender<T> {
+    fn clone(&self) -> SimSender<T> {
+        self.world.borrow_mut().channels.get_mut(&self.id).unwrap().senders += 1;
+        SimSender {
+            world: self.world.clone(),
+            id: self.id,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for SimSender<T> {
+    fn drop(&mut self) {
+        let mut world = self.world.borrow_mut();
+        let closed = {
+            let channel = world.channels.get_mut(&self.id).unwrap();
+            channel.senders -= 1;
+            channel.senders == 0 && !channel.receiver_alive
+        };
+        if closed {
+            world.channels.remove(&self.id);
+        }
+    }
+}
+
+/// Receiving end of a simulated channel.
+pub struct SimReceiver<T> {
+    world: Rc<RefCell<World>>,
+    id: u64,
+    phantom: PhantomData<T>,
+}
+
+impl<T> SimReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// Receive the next message, advancing the clock to its delivery if needed.
+    ///
+    /// As nothing else can send while this waits, this fails with
+    /// `ErrorKind::WouldBlock` rather than wait forever if no message is in flight,
+    /// and with `ErrorKind::ConnectionReset` if all senders were dropped too.
+    pub fn recv(&self) -> Result<T, bincode::Error> {
+        self.receive(None)
+    }
+
+    /// Receive a message that has already been delivered,
+    /// failing with `ErrorKind::WouldBlock` if there is none.
+    pub fn try_recv(&self) -> Result<T, bincode::Error> {
+        self.receive(Some(Duration::from_secs(0)))
+    }
+
+    /// Receive the next message if it is delivered within `timeout`, advancing the clock
+    /// to its delivery; otherwise, advance the clock by `timeout` and fail with
+    /// `ErrorKind::TimedOut`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, bincode::Error> {
+        self.receive(Some(timeout))
+    }
+
+    fn receive(&self, timeout: Option<Duration>) -> Result<T, bincode::Error> {
+        let data = {
+            let mut world = self.world.borrow_mut();
+            let now = world.now;
+            let deadline = timeout.map(|timeout| now + timeout);
+            let (delivery, senders) = {
+                let channel = &world.channels[&self.id];
+                (channel.queue.front().map(|&(delivery, _)| delivery), channel.senders)
+            };
+            match delivery {
+                Some(delivery) if deadline.iter().all(|&deadline| delivery <= deadline) => {
+                    world.now = cmp::max(now, delivery);
+                    world.channels.get_mut(&self.id).unwrap().queue.pop_front().unwrap().1
+                }
+                _ if senders == 0 && delivery.is_none() => {
+                    return Err(Error::new(ErrorKind::ConnectionReset, "all senders dropped")
+                                   .into())
+                }
+                _ => {
+                    return Err(match timeout {
+                        None => Error::new(ErrorKind::WouldBlock, "no message in flight"),
+                        Some(timeout) if timeout == Duration::from_secs(0) => {
+                            Error::new(ErrorKind::WouldBlock, "no message delivered yet")
+                        }
+                        Some(timeout) => {
+                            world.now = now + timeout;
+                            Error::new(ErrorKind::TimedOut, "timed out")
+                        }
+                    }.into())
+                }
+            }
+        };
+        bincode::deserialize(&data)
+    }
+}
+
+impl<T> Drop for SimReceiver<T> {
+    fn drop(&mut self) {
+        let mut world = self.world.borrow_mut();
+        let closed = {
+            let channel = world.channels.get_mut(&self.id).unwrap();
+            channel.receiver_alive = false;
+            channel.queue.clear();
+            channel.senders == 0
+        };
+        if closed {
+            world.channels.remove(&self.id);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Publishing a frequently updated, fixed-size value to other processes without
+//! sending any messages.
+//!
+//! A [Publisher] keeps the value in shared memory, guarded by a sequence lock:
+//! [publish] bumps a counter before and after writing the value, and [Subscriber]s
+//! reading meanwhile see the counter change and read again. So readers never see a
+//! torn value, and never hold up the publisher, however many there are. This suits
+//! statistics and timings updated every frame; for values that need serializing, or
+//! receivers waiting for changes, see [ipc::watch].
+//!
+//! The value is copied as is, so it must be [Pod]. Subscribers can be cloned and
+//! sent to other processes; there is only ever one publisher. If its process dies in
+//! the middle of publishing, readers spin forever.
+//!
+//! Shared state is unavailable on macOS, where [received shared memory] doesn't see
+//! the publisher's later writes.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::ipc;
+//! # use ipc_channel::state::{Publisher, Subscriber};
+//! let mut publisher = Publisher::new([0u64; 2]);
+//! let (tx, rx) = ipc::channel().unwrap();
+//! tx.send(publisher.subscriber()).unwrap();
+//! let subscriber: Subscriber<[u64; 2]> = rx.recv().unwrap();
+//!
+//! publisher.publish([16, 60]);
+//! assert_eq!(subscriber.read(), [16, 60]);
+//! assert_eq!(subscriber.version(), 1);
+//! ```
+//!
+//! [Publisher]: struct.Publisher.html
+//! [publish]: struct.Publisher.html#method.publish
+//! [Subscriber]: struct.Subscriber.html
+//! [ipc::watch]: ../ipc/fn.watch.html
+//! [Pod]: trait.Pod.html
+//! [received shared memory]: ../ipc/struct.IpcSharedMemory.html#sharing-between-processes
+
+use ipc::IpcSharedMemory;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp;
+use std::fmt::{self, Debug, Formatter};
+use std::hint;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{self, AtomicU64, Ordering};
+use words::WORD_SIZE;
+
+/// Types that can be copied between processes byte for byte.
+///
+/// # Safety
+///
+/// Implementors must not contain padding, whose bytes would be read uninitialized,
+/// and every bit pattern must be a valid value, as with `bytemuck::Pod`: any process
+/// holding the shared memory can write to it, so a value read isn't necessarily one
+/// that was published. They also shouldn't contain pointers or references, which are
+/// meaningless in other processes.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    }
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T, const N: usize> Pod for [T; N] where T: Pod {}
+
+/// Number of words after the sequence counter holding a `T`.
+fn value_words<T>() -> usize {
+    mem::size_of::<T>().div_ceil(WORD_SIZE)
+}
+
+/// The shared memory holding the sequence counter, then the value.
+#[derive(Clone)]
+struct Shared<T> {
+    shared_memory: IpcSharedMemory,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Shared<T> where T: Pod {
+    fn new(shared_memory: IpcSharedMemory) -> Option<Shared<T>> {
+        if shared_memory.len() != (1 + value_words::<T>()) * WORD_SIZE {
+            return None
+        }
+        Some(Shared {
+            shared_memory,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Counts the writes started and completed: odd while one is in progress.
+    fn sequence(&self) -> &AtomicU64 {
+        &self.words()[0]
+    }
+
+    fn words(&self) -> &[AtomicU64] {
+        self.shared_memory.atomic_u64s(0, 1 + value_words::<T>()).expect("invalid shared state")
+    }
+
+    /// Copy `value` in, bumping the counter around it; only the publisher may call this.
+    fn write(&self, value: &T) {
+        let sequence = self.sequence().load(Ordering::Relaxed);
+        self.sequence().store(sequence + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        self.store(value);
+        self.sequence().store(sequence + 2, Ordering::Release);
+    }
+
+    fn store(&self, value: &T) {
+        let bytes = unsafe {
+            slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        for (word, chunk) in self.words()[1..].iter().zip(bytes.chunks(WORD_SIZE)) {
+            let mut buffer = [0; WORD_SIZE];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            word.store(u64::from_ne_bytes(buffer), Ordering::Relaxed);
+        }
+    }
+
+    /// Copy the value out, along with the number of writes before it.
+    fn read(&self) -> (T, u64) {
+        let mut value = MaybeUninit::<T>::uninit();
+        loop {
+            let before = self.sequence().load(Ordering::Acquire);
+            if before & 1 == 0 {
+                let destination = value.as_mut_ptr() as *mut u8;
+                for (index, word) in self.words()[1..].iter().enumerate() {
+                    let buffer = word.load(Ordering::Relaxed).to_ne_bytes();
+                    let offset = index * WORD_SIZE;
+                    let len = cmp::min(WORD_SIZE, mem::size_of::<T>() - offset);
+                    unsafe {
+                        ptr::copy_nonoverlapping(buffer.as_ptr(), destination.add(offset), len);
+                    }
+                }
+                atomic::fence(Ordering::Acquire);
+                if self.sequence().load(Ordering::Relaxed) == before {
+                    // No write overlapped the copy, so it holds a published value.
+                    return (unsafe { value.assume_init() }, before / 2)
+                }
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+/// The writing end of a [shared state](index.html).
+///
+/// Publishers can't be cloned or sent to other processes, so writes never overlap.
+pub struct Publisher<T> where T: Pod {
+    shared: Shared<T>,
+}
+
+impl<T> Publisher<T> where T: Pod {
+    /// Create the shared memory, holding `initial_value`.
+    pub fn new(initial_value: T) -> Publisher<T> {
+        let size = (1 + value_words::<T>()) * WORD_SIZE;
+        let shared = Shared::new(IpcSharedMemory::from_byte(0, size)).unwrap();
+        // Nobody can read yet, and the initial value doesn't count as a publication.
+        shared.store(&initial_value);
+        Publisher { shared }
+    }
+
+    /// Replace the value readers see, without waiting for them.
+    pub fn publish(&mut self, value: T) {
+        self.shared.write(&value);
+    }
+
+    /// A reader of the values published from now on, to keep or send to other processes.
+    pub fn subscriber(&self) -> Subscriber<T> {
+        Subscriber {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Debug for Publisher<T> where T: Pod {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("Publisher").finish()
+    }
+}
+
+/// A reading end of a [shared state](index.html), from [Publisher::subscriber].
+///
+/// [Publisher::subscriber]: struct.Publisher.html#method.subscriber
+#[derive(Clone)]
+pub struct Subscriber<T> where T: Pod {
+    shared: Shared<T>,
+}
+
+impl<T> Subscriber<T> where T: Pod {
+    /// The value last published, or the initial value if none was.
+    ///
+    /// This only waits for a write in progress to complete.
+    pub fn read(&self) -> T {
+        self.shared.read().0
+    }
+
+    /// The number of values published so far, e.g. to tell whether the value changed
+    /// since it was last read.
+    pub fn version(&self) -> u64 {
+        self.shared.read().1
+    }
+
+    /// The value last published, along with the [version] it made.
+    ///
+    /// [version]: #method.version
+    pub fn read_with_version(&self) -> (T, u64) {
+        self.shared.read()
+    }
+}
+
+impl<T> Debug for Subscriber<T> where T: Pod {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("Subscriber")
+                 .field("version", &self.version())
+                 .finish()
+    }
+}
+
+impl<T> Serialize for Subscriber<T> where T: Pod {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.shared.shared_memory.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Subscriber<T> where T: Pod {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let shared_memory = IpcSharedMemory::deserialize(deserializer)?;
+        let shared = Shared::new(shared_memory).ok_or_else(|| {
+            de::Error::custom("shared state of the wrong size for the type")
+        })?;
+        Ok(Subscriber { shared })
+    }
+}
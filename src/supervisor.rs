@@ -0,0 +1,344 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Keeping a child process running, re-establishing its channels when it is respawned.
+//!
+//! A [Supervisor] spawns the child with [process::spawn], and restarts it with
+//! exponential backoff whenever it exits. Each time, the child connects back with
+//! [process::bootstrap_sender] and sends its first message anew, which typically
+//! holds the senders the parent talks to it with. [ReconnectingIpcSender]s
+//! handed out by the supervisor pick such a sender from the latest first
+//! message, so they keep working across restarts:
+//!
+//! ```no_run
+//! # use ipc_channel::ipc::IpcSender;
+//! # use ipc_channel::supervisor::{Supervisor, SupervisorConfig};
+//! # use std::process::Command;
+//! let supervisor = Supervisor::start(|| Command::new("worker"), SupervisorConfig::new())
+//!     .unwrap();
+//! let requests = supervisor.sender(|requests: &IpcSender<String>| requests.clone());
+//! requests.send("Hello".to_owned()).unwrap();
+//! ```
+//!
+//! Messages that were still queued for a child when it died are lost. As with
+//! [process::spawn], this doesn't work with the `force-inprocess` feature.
+//!
+//! [Supervisor]: struct.Supervisor.html
+//! [ReconnectingIpcSender]: struct.ReconnectingIpcSender.html
+//! [process::spawn]: ../process/fn.spawn.html
+//! [process::bootstrap_sender]: ../process/fn.bootstrap_sender.html
+
+use bincode;
+use ipc::{IpcReceiver, IpcSender};
+use process;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::io::{Error, ErrorKind};
+use std::process::{Child, Command};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use threads;
+use std::time::{Duration, Instant};
+
+/// How a [Supervisor] spawns and restarts its child.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::supervisor::SupervisorConfig;
+/// # use std::time::Duration;
+/// let config = SupervisorConfig::new()
+///     .backoff(Duration::from_millis(50), Duration::from_secs(10))
+///     .max_restarts(5);
+/// ```
+///
+/// [Supervisor]: struct.Supervisor.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SupervisorConfig {
+    connect_timeout: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_restarts: Option<u32>,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> SupervisorConfig {
+        SupervisorConfig {
+            connect_timeout: Duration::from_secs(10),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: None,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    /// Wait 10 s for each child to connect, back off from 100 ms to 30 s
+    /// between restarts, and restart forever.
+    pub fn new() -> SupervisorConfig {
+        SupervisorConfig::default()
+    }
+
+    /// Consider a child that doesn't connect back within `timeout` to have failed;
+    /// see [process::spawn].
+    ///
+    /// [process::spawn]: ../process/fn.spawn.html
+    pub fn connect_timeout(mut self, timeout: Duration) -> SupervisorConfig {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Wait `initial` before the first restart, doubling the wait after each
+    /// failed restart, up to `max`. A child that ran for `max` or longer is
+    /// considered to have started fine, so the next restart waits `initial` again.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> SupervisorConfig {
+        assert!(initial <= max, "initial backoff exceeds the maximum");
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Give up after restarting the child `restarts` times.
+    pub fn max_restarts(mut self, restarts: u32) -> SupervisorConfig {
+        self.max_restarts = Some(restarts);
+        self
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    changed: Condvar,
+}
+
+struct State<T> {
+    // The first message of the running child, if any.
+    bootstrap: Option<T>,
+    // Incremented whenever a child connects, so senders know when to switch.
+    generation: u64,
+    restarts: u32,
+    gave_up: bool,
+    stopping: bool,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Owns a child process started with [process::spawn], restarting it when it exits.
+///
+/// Dropping the supervisor kills the child.
+///
+/// [process::spawn]: ../process/fn.spawn.html
+pub struct Supervisor<T> {
+    shared: Arc<Shared<T>>,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl<T> Supervisor<T> where T: for<'de> Deserialize<'de> + Serialize + Send + 'static {
+    /// Spawn the command returned by `make_command`, waiting for the child to connect,
+    /// and keep it running from a background thread, calling `make_command`
+    /// again for each restart.
+    ///
+    /// Fails as [process::spawn] does if the first child can't be started.
+    ///
+    /// [process::spawn]: ../process/fn.spawn.html
+    pub fn start<F>(mut make_command: F, config: SupervisorConfig) -> Result<Supervisor<T>, Error>
+                    where F: FnMut() -> Command + Send + 'static {
+        let (child, receiver, bootstrap) = process::spawn(make_command(), config.connect_timeout)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                bootstrap: Some(bootstrap),
+                generation: 1,
+                restarts: 0,
+                gave_up: false,
+                stopping: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let monitor_shared = shared.clone();
+        let (started_sender, started_receiver) = mpsc::channel();
+        let monitor = threads::builder("supervisor").spawn(move || {
+            let mut child = child;
+            let result = threads::configure_current_thread();
+            let started = result.is_ok();
+            started_sender.send(result).unwrap();
+            if started {
+                monitor_shared.monitor(child, receiver, make_command, config)
+            } else {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        })?;
+        started_receiver.recv().unwrap()?;
+        Ok(Supervisor {
+            shared,
+            monitor: Some(monitor),
+        })
+    }
+}
+
+impl<T> Supervisor<T> {
+    /// A sender to the running child, picked by `select` from the first message
+    /// of each child.
+    pub fn sender<M, F>(&self, select: F) -> ReconnectingIpcSender<T, M>
+                        where M: Serialize, F: Fn(&T) -> IpcSender<M> + Send + Sync + 'static {
+        ReconnectingIpcSender {
+            shared: self.shared.clone(),
+            select: Arc::new(select),
+            current: Mutex::new(None),
+        }
+    }
+
+    /// The number of times the child was restarted.
+    pub fn restarts(&self) -> u32 {
+        self.shared.lock().restarts
+    }
+
+    /// Whether the supervisor gave up restarting the child, after reaching the
+    /// [maximum number of restarts].
+    ///
+    /// [maximum number of restarts]: struct.SupervisorConfig.html#method.max_restarts
+    pub fn gave_up(&self) -> bool {
+        self.shared.lock().gave_up
+    }
+}
+
+impl<T> Drop for Supervisor<T> {
+    fn drop(&mut self) {
+        self.shared.lock().stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(monitor) = self.monitor.take() {
+            let _ = monitor.join();
+        }
+    }
+}
+
+impl<T> Shared<T> where T: for<'de> Deserialize<'de> + Serialize + Send + 'static {
+    fn monitor<F>(&self,
+                  child: Child,
+                  receiver: IpcReceiver<T>,
+                  mut make_command: F,
+                  config: SupervisorConfig)
+                  where F: FnMut() -> Command {
+        // The receiver is kept so the child can go on using its bootstrap sender.
+        let mut running = (child, receiver);
+        let mut backoff = config.initial_backoff;
+        let mut started = Instant::now();
+        loop {
+            if !self.wait_for_exit(&mut running.0) {
+                return
+            }
+            if started.elapsed() >= config.max_backoff {
+                backoff = config.initial_backoff;
+            }
+            // Spawn the replacement, retrying spawns that fail with the same backoff.
+            loop {
+                {
+                    let mut state = self.lock();
+                    if config.max_restarts.iter().any(|&max| state.restarts >= max) {
+                        state.gave_up = true;
+                        self.changed.notify_all();
+                        return
+                    }
+                    state = self.changed.wait_timeout(state, backoff).unwrap().0;
+                    if state.stopping {
+                        return
+                    }
+                    state.restarts += 1;
+                }
+                backoff = cmp::min(backoff * 2, config.max_backoff);
+                started = Instant::now();
+                if let Ok((new_child, receiver, bootstrap)) =
+                        process::spawn(make_command(), config.connect_timeout) {
+                    running = (new_child, receiver);
+                    let mut state = self.lock();
+                    state.bootstrap = Some(bootstrap);
+                    state.generation += 1;
+                    self.changed.notify_all();
+                    break
+                }
+            }
+        }
+    }
+
+    /// Wait for `child` to exit, returning false if the supervisor is stopping instead,
+    /// after killing the child.
+    fn wait_for_exit(&self, child: &mut Child) -> bool {
+        let mut state = self.lock();
+        loop {
+            if state.stopping {
+                let _ = child.kill();
+                let _ = child.wait();
+                return false
+            }
+            if let Ok(Some(_)) = child.try_wait() {
+                state.bootstrap = None;
+                return true
+            }
+            state = self.changed.wait_timeout(state, Duration::from_millis(20)).unwrap().0;
+        }
+    }
+}
+
+/// Picks the sender to a child from its first message.
+type Selector<T, M> = Arc<dyn Fn(&T) -> IpcSender<M> + Send + Sync>;
+
+/// A sender to the child of a [Supervisor], which switches over to each new child.
+///
+/// While the child is being restarted, sending waits for the new child to connect.
+/// Once the supervisor gives up or is dropped, sending fails with
+/// `ErrorKind::NotConnected`.
+///
+/// [Supervisor]: struct.Supervisor.html
+pub struct ReconnectingIpcSender<T, M> where M: Serialize {
+    shared: Arc<Shared<T>>,
+    select: Selector<T, M>,
+    // The sender to the current child, with the generation it belongs to.
+    current: Mutex<Option<(u64, IpcSender<M>)>>,
+}
+
+impl<T, M> ReconnectingIpcSender<T, M> where M: Serialize {
+    /// Send `data` to the running child, waiting for it to be restarted if needed.
+    ///
+    /// Fails if the child died before receiving it; the next call goes to its replacement.
+    pub fn send(&self, data: M) -> Result<(), bincode::Error> {
+        let mut current = self.current.lock().unwrap();
+        {
+            let mut state = self.shared.lock();
+            loop {
+                if state.stopping || state.gave_up {
+                    return Err(Error::new(ErrorKind::NotConnected,
+                                          "supervised child is not running").into())
+                }
+                if let Some(ref bootstrap) = state.bootstrap {
+                    let generation = current.as_ref().map(|&(generation, _)| generation);
+                    if generation != Some(state.generation) {
+                        *current = Some((state.generation, (self.select)(bootstrap)));
+                    }
+                    break
+                }
+                state = self.shared.changed.wait(state).unwrap();
+            }
+        }
+        current.as_ref().unwrap().1.send(data)
+    }
+}
+
+impl<T, M> Clone for ReconnectingIpcSender<T, M> where M: Serialize {
+    fn clone(&self) -> ReconnectingIpcSender<T, M> {
+        ReconnectingIpcSender {
+            shared: self.shared.clone(),
+            select: self.select.clone(),
+            current: Mutex::new(None),
+        }
+    }
+}
@@ -0,0 +1,528 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Synchronization primitives that work across process boundaries.
+//!
+//! Each primitive keeps its state in a small [IpcSharedMemory] region, so it
+//! can be cloned within a process and sent over an [IpcSender] to other
+//! processes; all copies refer to the same underlying lock.
+//!
+//! Blocking is implemented with the process-shared futexes of Linux and OpenBSD,
+//! and the shared `_umtx_op` waits of FreeBSD. The in-process backend parks
+//! waiting threads on a condition variable keyed by the address of the lock word.
+//!
+//! The module is unavailable on macOS, which sends shared memory regions as
+//! copy-on-write copies rather than sharing them, and on illumos and Solaris,
+//! which have no wait primitive keyed on shared memory.
+//!
+//! Note that the primitives cannot detect that another process died while
+//! holding a lock: the lock then stays held forever.
+//!
+//! [IpcSharedMemory]: ../ipc/struct.IpcSharedMemory.html
+//! [IpcSender]: ../ipc/struct.IpcSender.html
+
+use ipc::IpcSharedMemory;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock shared between processes.
+///
+/// Unlike `std::sync::Mutex`, `IpcMutex` does not own the data it protects:
+/// it is typically used to guard access to an [IpcSharedMemory] region.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::ipc;
+/// # use ipc_channel::sync::IpcMutex;
+/// let mutex = IpcMutex::new();
+/// let (tx, rx) = ipc::channel().unwrap();
+/// tx.send(mutex.clone()).unwrap();
+/// let received_mutex: IpcMutex = rx.recv().unwrap();
+///
+/// let guard = mutex.lock();
+/// assert!(received_mutex.try_lock().is_none());
+/// drop(guard);
+/// assert!(received_mutex.try_lock().is_some());
+/// ```
+///
+/// [IpcSharedMemory]: ../ipc/struct.IpcSharedMemory.html
+#[derive(Clone)]
+pub struct IpcMutex {
+    shared_memory: IpcSharedMemory,
+}
+
+impl IpcMutex {
+    /// Create a new, unlocked mutex.
+    pub fn new() -> IpcMutex {
+        IpcMutex {
+            shared_memory: IpcSharedMemory::from_byte(0, 4),
+        }
+    }
+
+    fn state(&self) -> &AtomicU32 {
+        &self.shared_memory.atomic_u32s(0, 1).expect("IpcMutex: invalid shared memory")[0]
+    }
+
+    /// Acquire the lock, blocking until it is available.
+    pub fn lock(&self) -> IpcMutexGuard<'_> {
+        let state = self.state();
+        let mut current = compare_exchange(state, UNLOCKED, LOCKED);
+        if current != UNLOCKED {
+            if current != CONTENDED {
+                current = state.swap(CONTENDED, Ordering::Acquire);
+            }
+            while current != UNLOCKED {
+                futex::wait(state, CONTENDED);
+                current = state.swap(CONTENDED, Ordering::Acquire);
+            }
+        }
+        IpcMutexGuard { mutex: self }
+    }
+
+    /// Attempt to acquire the lock without blocking.
+    pub fn try_lock(&self) -> Option<IpcMutexGuard<'_>> {
+        if compare_exchange(self.state(), UNLOCKED, LOCKED) == UNLOCKED {
+            Some(IpcMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn unlock(&self) {
+        let state = self.state();
+        if state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex::wake(state, 1);
+        }
+    }
+}
+
+impl Serialize for IpcMutex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.shared_memory.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpcMutex {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcMutex {
+            shared_memory: Deserialize::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl Default for IpcMutex {
+    fn default() -> IpcMutex {
+        IpcMutex::new()
+    }
+}
+
+impl Debug for IpcMutex {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcMutex")
+                 .field("locked", &(self.state().load(Ordering::Relaxed) != UNLOCKED))
+                 .finish()
+    }
+}
+
+/// RAII guard returned by [IpcMutex::lock]; the lock is released on drop.
+///
+/// [IpcMutex::lock]: struct.IpcMutex.html#method.lock
+#[derive(Debug)]
+pub struct IpcMutexGuard<'a> {
+    mutex: &'a IpcMutex,
+}
+
+impl<'a> Drop for IpcMutexGuard<'a> {
+    fn drop(&mut self) {
+        self.mutex.unlock()
+    }
+}
+
+/// A condition variable shared between processes, used together with an
+/// [IpcMutex].
+///
+/// As with `std::sync::Condvar`, spurious wakeups are possible, so waits
+/// should be performed in a loop checking the actual condition.
+///
+/// [IpcMutex]: struct.IpcMutex.html
+#[derive(Clone)]
+pub struct IpcCondvar {
+    shared_memory: IpcSharedMemory,
+}
+
+impl IpcCondvar {
+    /// Create a new condition variable.
+    pub fn new() -> IpcCondvar {
+        IpcCondvar {
+            shared_memory: IpcSharedMemory::from_byte(0, 4),
+        }
+    }
+
+    fn sequence(&self) -> &AtomicU32 {
+        &self.shared_memory.atomic_u32s(0, 1).expect("IpcCondvar: invalid shared memory")[0]
+    }
+
+    /// Release the lock held by `guard`, block until notified, and re-acquire
+    /// the lock before returning.
+    pub fn wait<'a>(&self, guard: IpcMutexGuard<'a>) -> IpcMutexGuard<'a> {
+        let sequence = self.sequence();
+        let current = sequence.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        futex::wait(sequence, current);
+        mutex.lock()
+    }
+
+    /// Wake up one process or thread blocked in [wait].
+    ///
+    /// [wait]: #method.wait
+    pub fn notify_one(&self) {
+        let sequence = self.sequence();
+        sequence.fetch_add(1, Ordering::Release);
+        futex::wake(sequence, 1);
+    }
+
+    /// Wake up all processes and threads blocked in [wait].
+    ///
+    /// [wait]: #method.wait
+    pub fn notify_all(&self) {
+        let sequence = self.sequence();
+        sequence.fetch_add(1, Ordering::Release);
+        futex::wake(sequence, i32::MAX);
+    }
+}
+
+impl Serialize for IpcCondvar {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.shared_memory.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpcCondvar {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcCondvar {
+            shared_memory: Deserialize::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl Default for IpcCondvar {
+    fn default() -> IpcCondvar {
+        IpcCondvar::new()
+    }
+}
+
+impl Debug for IpcCondvar {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcCondvar").finish()
+    }
+}
+
+/// A counting semaphore shared between processes.
+///
+/// Like the other primitives of this module, it isn't available on macOS, illumos
+/// and Solaris; on Windows, where channels don't leave the process, it only
+/// synchronizes the threads of one process.
+///
+/// # Examples
+///
+/// ```
+/// # use ipc_channel::sync::IpcSemaphore;
+/// let semaphore = IpcSemaphore::new(1);
+/// semaphore.acquire();
+/// assert!(!semaphore.try_acquire());
+/// semaphore.release();
+/// assert!(semaphore.try_acquire());
+/// ```
+#[derive(Clone)]
+pub struct IpcSemaphore {
+    shared_memory: IpcSharedMemory,
+}
+
+impl IpcSemaphore {
+    /// Create a new semaphore holding `count` permits.
+    pub fn new(count: u32) -> IpcSemaphore {
+        let semaphore = IpcSemaphore {
+            shared_memory: IpcSharedMemory::from_byte(0, 4),
+        };
+        semaphore.count().store(count, Ordering::Release);
+        semaphore
+    }
+
+    fn count(&self) -> &AtomicU32 {
+        &self.shared_memory.atomic_u32s(0, 1).expect("IpcSemaphore: invalid shared memory")[0]
+    }
+
+    /// Take a permit, blocking until one is available.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            futex::wait(self.count(), 0);
+        }
+    }
+
+    /// Take a permit if one is available, without blocking.
+    pub fn try_acquire(&self) -> bool {
+        let count = self.count();
+        let mut current = count.load(Ordering::Relaxed);
+        while current != 0 {
+            match count.compare_exchange_weak(current, current - 1,
+                                              Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(previous) => current = previous,
+            }
+        }
+        false
+    }
+
+    /// Return a permit, waking up one waiter if any.
+    pub fn release(&self) {
+        let count = self.count();
+        count.fetch_add(1, Ordering::Release);
+        futex::wake(count, 1);
+    }
+}
+
+impl Serialize for IpcSemaphore {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.shared_memory.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpcSemaphore {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcSemaphore {
+            shared_memory: Deserialize::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl Debug for IpcSemaphore {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcSemaphore")
+                 .field("count", &self.count().load(Ordering::Relaxed))
+                 .finish()
+    }
+}
+
+// Layout of an `IpcBarrier` region, in 32-bit words.
+const BARRIER_PARTIES: usize = 0;
+const BARRIER_ARRIVED: usize = 1;
+const BARRIER_GENERATION: usize = 2;
+
+/// A barrier letting a fixed number of participants, possibly in different
+/// processes, wait for each other.
+///
+/// As with `std::sync::Barrier`, the barrier can be reused once all
+/// participants went through it. It is available on the same platforms as
+/// [IpcSemaphore].
+///
+/// [IpcSemaphore]: struct.IpcSemaphore.html
+#[derive(Clone)]
+pub struct IpcBarrier {
+    shared_memory: IpcSharedMemory,
+}
+
+impl IpcBarrier {
+    /// Create a barrier blocking until `parties` participants called [wait].
+    ///
+    /// [wait]: #method.wait
+    pub fn new(parties: u32) -> IpcBarrier {
+        let barrier = IpcBarrier {
+            shared_memory: IpcSharedMemory::from_byte(0, 12),
+        };
+        barrier.words()[BARRIER_PARTIES].store(parties, Ordering::Release);
+        barrier
+    }
+
+    fn words(&self) -> &[AtomicU32] {
+        self.shared_memory.atomic_u32s(0, 3).expect("IpcBarrier: invalid shared memory")
+    }
+
+    /// Block until all participants reached the barrier.
+    ///
+    /// Returns `true` for exactly one of the participants (the last one to arrive).
+    pub fn wait(&self) -> bool {
+        let words = self.words();
+        let generation = words[BARRIER_GENERATION].load(Ordering::Acquire);
+        let arrived = words[BARRIER_ARRIVED].fetch_add(1, Ordering::AcqRel) + 1;
+        if arrived >= words[BARRIER_PARTIES].load(Ordering::Relaxed) {
+            words[BARRIER_ARRIVED].store(0, Ordering::Relaxed);
+            words[BARRIER_GENERATION].fetch_add(1, Ordering::Release);
+            futex::wake(&words[BARRIER_GENERATION], i32::MAX);
+            return true
+        }
+        while words[BARRIER_GENERATION].load(Ordering::Acquire) == generation {
+            futex::wait(&words[BARRIER_GENERATION], generation);
+        }
+        false
+    }
+}
+
+impl Serialize for IpcBarrier {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.shared_memory.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpcBarrier {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(IpcBarrier {
+            shared_memory: Deserialize::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl Debug for IpcBarrier {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcBarrier")
+                 .field("parties", &self.words()[BARRIER_PARTIES].load(Ordering::Relaxed))
+                 .finish()
+    }
+}
+
+fn compare_exchange(state: &AtomicU32, current: u32, new: u32) -> u32 {
+    match state.compare_exchange(current, new, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(previous) | Err(previous) => previous,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod futex {
+    use libc;
+    use std::ptr;
+    use std::sync::atomic::AtomicU32;
+
+    /// Block while `*word == expected`, until woken by `wake()`.
+    ///
+    /// Note: this deliberately doesn't use `FUTEX_PRIVATE_FLAG`,
+    /// as the word may be mapped in several processes.
+    pub fn wait(word: &AtomicU32, expected: u32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex,
+                          word as *const AtomicU32,
+                          libc::FUTEX_WAIT,
+                          expected,
+                          ptr::null::<libc::timespec>());
+        }
+    }
+
+    pub fn wake(word: &AtomicU32, count: i32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE, count);
+        }
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod futex {
+    use libc;
+    use std::ptr;
+    use std::sync::atomic::AtomicU32;
+
+    /// Block while `*word == expected`, until woken by `wake()`.
+    ///
+    /// Note: this deliberately doesn't use the `_PRIVATE` operations,
+    /// as the word may be mapped in several processes.
+    pub fn wait(word: &AtomicU32, expected: u32) {
+        unsafe {
+            libc::_umtx_op(word as *const AtomicU32 as *mut libc::c_void,
+                           libc::UMTX_OP_WAIT_UINT,
+                           expected as libc::c_ulong,
+                           ptr::null_mut(),
+                           ptr::null_mut());
+        }
+    }
+
+    pub fn wake(word: &AtomicU32, count: i32) {
+        unsafe {
+            libc::_umtx_op(word as *const AtomicU32 as *mut libc::c_void,
+                           libc::UMTX_OP_WAKE,
+                           count as libc::c_ulong,
+                           ptr::null_mut(),
+                           ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod futex {
+    use libc;
+    use std::ptr;
+    use std::sync::atomic::AtomicU32;
+
+    /// Block while `*word == expected`, until woken by `wake()`.
+    ///
+    /// Note: this deliberately doesn't use `FUTEX_PRIVATE_FLAG`,
+    /// as the word may be mapped in several processes.
+    pub fn wait(word: &AtomicU32, expected: u32) {
+        unsafe {
+            libc::futex(word as *const AtomicU32 as *mut u32,
+                        libc::FUTEX_WAIT,
+                        expected as libc::c_int,
+                        ptr::null(),
+                        ptr::null_mut());
+        }
+    }
+
+    pub fn wake(word: &AtomicU32, count: i32) {
+        unsafe {
+            libc::futex(word as *const AtomicU32 as *mut u32,
+                        libc::FUTEX_WAKE,
+                        count,
+                        ptr::null(),
+                        ptr::null_mut());
+        }
+    }
+}
+
+/// The in-process backend: every copy of a region is the same memory, so waiters
+/// can park on a condition variable picked by the address of the word.
+#[cfg(not(any(target_os = "linux", target_os = "android",
+              target_os = "freebsd", target_os = "openbsd")))]
+mod futex {
+    use std::sync::{Condvar, Mutex};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const BUCKETS: usize = 64;
+
+    lazy_static! {
+        static ref PARKING_LOT: Vec<(Mutex<()>, Condvar)> =
+            (0..BUCKETS).map(|_| (Mutex::new(()), Condvar::new())).collect();
+    }
+
+    fn bucket(word: &AtomicU32) -> &'static (Mutex<()>, Condvar) {
+        &PARKING_LOT[(word as *const AtomicU32 as usize >> 2) % BUCKETS]
+    }
+
+    /// Block while `*word == expected`, until woken by `wake()`.
+    ///
+    /// The value is checked with the bucket locked, and `wake()` takes the lock
+    /// before notifying, so a wake-up between the check and the wait isn't lost.
+    pub fn wait(word: &AtomicU32, expected: u32) {
+        let (ref mutex, ref condvar) = *bucket(word);
+        let guard = mutex.lock().unwrap();
+        if word.load(Ordering::Acquire) == expected {
+            drop(condvar.wait(guard).unwrap());
+        }
+    }
+
+    /// Words sharing a bucket share its condition variable, so this wakes every
+    /// waiter of the bucket: as with futexes, callers must expect spurious wake-ups.
+    pub fn wake(word: &AtomicU32, _: i32) {
+        let (ref mutex, ref condvar) = *bucket(word);
+        let _guard = mutex.lock().unwrap();
+        condvar.notify_all();
+    }
+}
@@ -7,7 +7,15 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use bincode;
+use bytes::{self, ByteBuf};
+use capture::{self, CaptureFrame, CaptureReader, Direction};
 use crossbeam_channel::{self, Sender};
+use delta::{self, DeltaReceiver};
+use dispatcher::{DispatchId, Dispatcher};
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+use double_buffer;
+use events::{self, Endpoint, IpcEvent};
 #[cfg(not(any(
     feature = "force-inprocess",
     target_os = "windows",
@@ -15,7 +23,18 @@ use crossbeam_channel::{self, Sender};
     target_os = "ios"
 )))]
 use ipc::IpcReceiver;
-use ipc::{self, IpcReceiverSet, IpcSender, IpcSharedMemory};
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+use fork;
+use hmac::{HmacKey, Sha256};
+use ipc::{self, BincodeConfig, ChannelOptions, DeadLetterReason, IpcRawChannel, IpcReceiverSet};
+use ipc::IpcSender;
+use ipc::{TypedReceiverSet, TypedSelectionResult};
+use ipc::IpcSharedMemory;
+use ipc::SequenceError;
 #[cfg(not(any(
     feature = "force-inprocess",
     target_os = "windows",
@@ -23,7 +42,27 @@ use ipc::{self, IpcReceiverSet, IpcSender, IpcSharedMemory};
     target_os = "ios"
 )))]
 use libc;
-use router::ROUTER;
+use mux;
+use named_lock::IpcNamedLock;
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+use ring;
+use router::{OverflowPolicy, QosClass, ROUTER, RouterProxy};
+#[cfg(feature = "test-support")]
+use sim::Simulation;
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+use state::{Publisher, Subscriber};
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+use sync::{IpcBarrier, IpcCondvar, IpcMutex, IpcSemaphore};
+use oneshot::IpcOneshotSender;
+use pool::IpcChannelPool;
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+use watch::IpcWatchSender;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cell::RefCell;
 use std::iter;
@@ -34,7 +73,8 @@ use std::iter;
     target_os = "ios"
 )))]
 use std::ptr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 use std::thread;
 
 #[cfg(feature = "async")]
@@ -54,7 +94,23 @@ use ipc::IpcOneShotServer;
     target_os = "android",
     target_os = "ios"
 )))]
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+)))]
+use process;
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+)))]
+use std::{env, process::Stdio, time::Duration};
 
 #[cfg(not(any(
     feature = "force-inprocess",
@@ -74,6 +130,21 @@ pub unsafe fn fork<F: FnOnce()>(child_func: F) -> libc::pid_t {
     }
 }
 
+/// A command re-running this test binary with only the test `test_name`, for tests
+/// whose other half must run in a process of its own.
+#[cfg(any(feature = "test-support", not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+))))]
+fn child_command(test_name: &str) -> std::process::Command {
+    let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+    command.args(["--exact", &format!("test::{}", test_name), "--quiet"])
+           .stdout(std::process::Stdio::null());
+    command
+}
+
 #[cfg(not(any(
     feature = "force-inprocess",
     target_os = "windows",
@@ -109,6 +180,533 @@ fn simple() {
     assert_eq!(person, received_person);
 }
 
+#[test]
+fn small_and_large_messages() {
+    // Around the size from which messages are serialized on the heap.
+    let (tx, rx) = ipc::channel().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    for len in (0..300).chain(4000..4200) {
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        tx.send((data.clone(), sub_tx.clone())).unwrap();
+        let (received_data, received_tx): (Vec<u8>, IpcSender<usize>) = rx.recv().unwrap();
+        assert_eq!(received_data, data);
+        received_tx.send(len).unwrap();
+        assert_eq!(sub_rx.recv().unwrap(), len);
+    }
+}
+
+#[test]
+fn channel_with_options() {
+    use ipc::ChannelOptions;
+
+    let options = ChannelOptions::new().send_buffer(1024 * 1024).recv_buffer(1024 * 1024);
+    let (tx, rx) = ipc::channel_with_options(options).unwrap();
+    let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+    for _ in 0..4 {
+        tx.send(data.clone()).unwrap();
+    }
+    for _ in 0..4 {
+        assert_eq!(rx.recv().unwrap(), data);
+    }
+
+    let (tx, rx) = ipc::bytes_channel_with_options(ChannelOptions::new().recv_buffer(4096))
+        .unwrap();
+    tx.send(&data).unwrap();
+    assert_eq!(rx.recv().unwrap(), data);
+
+    // Packets are cut to the default send buffer size, so it can't shrink.
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    {
+        let error = ipc::bytes_channel_with_options(ChannelOptions::new().send_buffer(1))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn receive_buffer_pool() {
+    let before = ipc::receive_buffer_pool_stats();
+    let (tx, rx) = ipc::channel().unwrap();
+    let data = vec![7u8; 1000];
+    for _ in 0..100 {
+        tx.send(data.clone()).unwrap();
+        assert_eq!(rx.recv().unwrap(), data);
+    }
+    let after = ipc::receive_buffer_pool_stats();
+    assert!(after.hits > before.hits);
+    assert!(after.hit_rate() > 0.0 && after.hit_rate() <= 1.0);
+}
+
+#[test]
+fn custom_buffer_allocator() {
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocated: AtomicUsize,
+        released: AtomicUsize,
+    }
+
+    impl ipc::BufferAllocator for CountingAllocator {
+        fn allocate(&self, capacity: usize) -> Vec<u8> {
+            self.allocated.fetch_add(1, Ordering::SeqCst);
+            Vec::with_capacity(capacity)
+        }
+
+        fn release(&self, buffer: Vec<u8>) {
+            self.released.fetch_add(1, Ordering::SeqCst);
+            drop(buffer)
+        }
+    }
+
+    let allocator = Arc::new(CountingAllocator::default());
+    ipc::set_buffer_allocator(Some(allocator.clone()));
+    // Larger than the buffers the receive buffer pool keeps, so transactions, which
+    // allocate their buffers whole, can't take them from the pool.
+    let data = vec![7u8; 2 * 1024 * 1024];
+    let (tx, rx) = metadata_channel();
+    let sent = data.clone();
+    let sender = thread::spawn(move || {
+        let mut transaction = tx.transaction();
+        transaction.push(sent).unwrap();
+        transaction.commit().unwrap()
+    });
+    assert_eq!(rx.recv().unwrap(), data);
+    sender.join().unwrap();
+    ipc::set_buffer_allocator(None);
+    assert!(allocator.allocated.load(Ordering::SeqCst) >= 1);
+    assert!(allocator.released.load(Ordering::SeqCst) >= 1);
+}
+
+#[test]
+fn bincode_config() {
+    let config = BincodeConfig::new().varint_encoding().big_endian();
+    let (tx, rx) = ipc::channel::<(u64, String)>().unwrap();
+    let (tx, rx) = (tx.with_bincode_config(config), rx.with_bincode_config(config));
+    tx.send((300, "varint".to_owned())).unwrap();
+    assert_eq!(rx.recv().unwrap(), (300, "varint".to_owned()));
+
+    // 3 bytes for 300, then 1 for the string length.
+    tx.send((300, "varint".to_owned())).unwrap();
+    assert_eq!(rx.recv_raw().unwrap().data, b"\xfb\x01\x2c\x06varint");
+
+    tx.send((300, "varint".to_owned())).unwrap();
+    let mut message = rx.recv_ref().unwrap();
+    let (number, string): (u64, &str) = message.get().unwrap();
+    assert_eq!((number, string), (300, "varint"));
+
+    let limited = BincodeConfig::new().limit(16);
+    let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    let tx = tx.with_bincode_config(limited);
+    assert!(tx.send(vec![0; 32]).is_err());
+    let tx = tx.with_bincode_config(BincodeConfig::new());
+    let rx = rx.with_bincode_config(limited);
+    tx.send(vec![0; 32]).unwrap();
+    assert!(rx.recv().is_err());
+    tx.send(vec![0; 4]).unwrap();
+    assert_eq!(rx.recv().unwrap(), [0; 4]);
+}
+
+#[test]
+fn send_with_writer() {
+    let (tx, rx) = ipc::channel::<(String, Vec<u8>, IpcSharedMemory)>().unwrap();
+    let shmem = IpcSharedMemory::from_byte(3, 64);
+    let body = vec![9; 512];
+    tx.send_with(|writer| {
+        writer.serialize("parts")?;
+        // A length-prefixed sequence written by hand.
+        std::io::Write::write_all(writer, &(body.len() as u64).to_le_bytes())?;
+        std::io::Write::write_all(writer, &body)?;
+        assert_eq!(writer.written(), 13 + 8 + 512);
+        writer.serialize(&shmem)
+    }).unwrap();
+    let (name, received_body, received_shmem) = rx.recv().unwrap();
+    assert_eq!((&*name, received_body), ("parts", body));
+    assert_eq!(received_shmem, shmem);
+
+    let (tx, rx) = ipc::channel::<(u64, u64)>().unwrap();
+    let tx = tx.with_bincode_config(BincodeConfig::new().limit(12));
+    assert!(tx.send_with(|writer| {
+        writer.serialize(&1u64)?;
+        writer.serialize(&2u64)
+    }).is_err());
+    assert!(tx.send_with(|_| Err(Box::new(bincode::ErrorKind::SizeLimit))).is_err());
+    tx.with_bincode_config(BincodeConfig::new()).send((1, 2)).unwrap();
+    assert_eq!(rx.recv().unwrap(), (1, 2));
+}
+
+/// A channel whose messages carry the metadata header.
+fn metadata_channel<T>() -> (IpcSender<T>, ipc::IpcReceiver<T>)
+                        where T: for<'de> Deserialize<'de> + Serialize {
+    ipc::channel_with_options(ChannelOptions::new().metadata_header()).unwrap()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn hmac_sha256() {
+    let mut hash = Sha256::new();
+    hash.update(b"abc");
+    assert_eq!(hex(&hash.finish()),
+               "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    let mut hash = Sha256::new();
+    hash.update(b"abcdbcdecdefdefgefghfghighijhij");
+    hash.update(b"kijkljklmklmnlmnomnopnopq");
+    assert_eq!(hex(&hash.finish()),
+               "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+
+    // RFC 4231, test cases 1 and 6.
+    assert_eq!(hex(&HmacKey::new(&[0x0b; 20]).sign(b"Hi There")),
+               "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+    let data = b"Test Using Larger Than Block-Size Key - Hash Key First";
+    assert_eq!(hex(&HmacKey::new(&[0xaa; 131]).sign(data)),
+               "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+
+    // The attachments are covered by the tags of messages.
+    let key = HmacKey::new(b"secret");
+    let tag = key.sign_message(b"data", 1, vec![64]);
+    assert_ne!(tag, key.sign_message(b"data", 0, vec![64]));
+    assert_ne!(tag, key.sign_message(b"data", 1, vec![32]));
+    assert_ne!(tag, key.sign_message(b"data", 1, vec![]));
+    let mut message = b"data".to_vec();
+    message.extend_from_slice(&tag);
+    assert!(key.open(&mut message.clone(), 1, vec![32]).is_err());
+    key.open(&mut message, 1, vec![64]).unwrap();
+    assert_eq!(message, b"data");
+}
+
+#[test]
+fn hmac_keyed_channel() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let rx = rx.with_hmac_key(b"secret");
+    let keyed_tx = tx.clone().with_hmac_key(b"secret");
+    let large = vec![7u8; 10000];
+    keyed_tx.send(large.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), large);
+    keyed_tx.send_local(vec![1]).unwrap();
+    assert_eq!(rx.recv_local().unwrap(), [1]);
+
+    tx.send(vec![2]).unwrap();
+    let error = rx.recv().unwrap_err();
+    match *error {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData)
+        }
+        ref error => panic!("unexpected error: {:?}", error),
+    }
+    tx.clone().with_hmac_key(b"wrong").send(vec![3]).unwrap();
+    assert!(rx.recv().is_err());
+
+    keyed_tx.send(vec![5]).unwrap();
+    assert_eq!(rx.recv().unwrap(), [5]);
+}
+
+#[test]
+fn hmac_keyed_receiver_set() {
+    let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    let keyed_tx = tx.clone().with_hmac_key(b"secret");
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx.with_hmac_key(b"secret")).unwrap();
+    tx.send(vec![1]).unwrap();
+    keyed_tx.send(vec![2]).unwrap();
+    let mut received = vec![];
+    while received.is_empty() {
+        received = rx_set.select().unwrap();
+    }
+    assert_eq!(received.len(), 1);
+    let (id, message) = received.remove(0).unwrap();
+    assert_eq!((id, message.to::<Vec<u8>>().unwrap()), (rx_id, vec![2]));
+
+    // The router checks the messages of a keyed receiver too.
+    let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    let keyed_tx = tx.clone().with_hmac_key(b"secret");
+    let routed_rx =
+        ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx.with_hmac_key(b"secret"));
+    tx.send(vec![3]).unwrap();
+    keyed_tx.send(vec![4]).unwrap();
+    assert_eq!(routed_rx.recv().unwrap(), [4]);
+    keyed_tx.send(vec![5]).unwrap();
+    assert_eq!(routed_rx.recv().unwrap(), [5]);
+}
+
+#[cfg(not(feature = "force-inprocess"))]
+#[test]
+fn one_shot_server_policy() {
+    let (server, name) = ipc::IpcOneShotServer::new().unwrap();
+    let rejected_tx = IpcSender::connect(name.clone()).unwrap();
+    rejected_tx.send(1).unwrap();
+    let tx = IpcSender::connect(name).unwrap();
+    tx.send(2).unwrap();
+
+    let mut peers = vec![];
+    let (_, value): (_, u32) = server.accept_with_policy(|peer| {
+        peers.push(*peer);
+        peers.len() > 1
+    }).unwrap();
+    assert_eq!(value, 2);
+    assert_eq!(peers.len(), 2);
+    #[cfg(all(not(feature = "force-inprocess"), target_os = "linux"))]
+    assert_eq!(peers[1].pid(), Some(std::process::id()));
+    #[cfg(all(not(feature = "force-inprocess"), target_os = "linux"))]
+    assert_eq!(peers[1].uid(), Some(unsafe { libc::getuid() }));
+}
+
+#[cfg(feature = "force-inprocess")]
+#[test]
+fn one_shot_server_policy() {
+    let (server, name) = ipc::IpcOneShotServer::<u32>::new().unwrap();
+    let tx = IpcSender::connect(name).unwrap();
+    tx.send(1).unwrap();
+
+    let mut asked = 0;
+    match *server.accept_with_policy(|_| {
+        asked += 1;
+        false
+    }).unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied)
+        }
+        ref error => panic!("unexpected error {:?}", error),
+    }
+    assert_eq!(asked, 1);
+    assert!(tx.send(2).is_err());
+}
+
+#[test]
+fn sensitive_shared_memory() {
+    let secret = IpcSharedMemory::new_sensitive(b"hunter2");
+    assert!(secret.is_sensitive());
+    assert!(!format!("{:?}", secret).contains("104"));
+    drop(secret.clone());
+    assert_eq!(&secret[..], b"hunter2");
+
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(secret.clone()).unwrap();
+    drop(secret);
+    let received: IpcSharedMemory = rx.recv().unwrap();
+    assert!(received.is_sensitive());
+    assert_eq!(&received[..], b"hunter2");
+    assert!(!IpcSharedMemory::from_bytes(b"public").is_sensitive());
+
+    // The copies of failed sends are wiped, leaving the region and later copies be.
+    let (tx, rx) = ipc::channel::<(IpcSharedMemory, Vec<u8>)>().unwrap();
+    let tx = tx.with_bincode_config(BincodeConfig::default().limit(64));
+    assert!(tx.send((received.clone(), vec![0; 100])).is_err());
+    let mut transaction = tx.transaction();
+    transaction.push((received.clone(), vec![])).unwrap();
+    drop(transaction);
+    tx.send((received.clone(), vec![])).unwrap();
+    assert_eq!(&rx.recv().unwrap().0[..], b"hunter2");
+    assert_eq!(&received[..], b"hunter2");
+}
+
+#[test]
+fn locked_shared_memory() {
+    let region = IpcSharedMemory::from_byte(7, 4096);
+    match region.lock() {
+        Ok(()) => {}
+        // The sandbox may not let us lock any memory.
+        Err(ref error) if error.kind() == std::io::ErrorKind::PermissionDenied => {}
+        #[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+                  target_os = "ios"))]
+        Err(ref error) if error.kind() == std::io::ErrorKind::Unsupported => {}
+        Err(error) => panic!("unexpected error: {}", error),
+    }
+    assert!(region.iter().all(|byte| *byte == 7));
+}
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+)))]
+#[test]
+fn shared_memory_quota() {
+    // The quota is per process, so exercise it in a child, leaving the other tests alone.
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            let results_tx = IpcSender::connect(name).unwrap();
+            let base = ipc::shared_memory_in_use();
+            ipc::set_shared_memory_quota(Some(base + 1024));
+            let region = IpcSharedMemory::try_from_byte(1, 1000).unwrap();
+            let in_use = ipc::shared_memory_in_use() - base;
+            let refused = IpcSharedMemory::try_from_bytes(&[2; 100]).unwrap_err();
+
+            let (tx, rx) = ipc::channel().unwrap();
+            tx.send(IpcSharedMemory::from_byte(3, 100)).unwrap();
+            let error = rx.recv().unwrap_err();
+            let received_refused = ipc::ShmQuotaExceeded::from_error(&error).is_some();
+            drop(region);
+            tx.send(IpcSharedMemory::from_byte(3, 100)).unwrap();
+            let received: IpcSharedMemory = rx.recv().unwrap();
+            results_tx.send((in_use, (refused.requested, refused.in_use - base),
+                             received_refused, received[..] == [3; 100])).unwrap();
+        })
+    };
+    let (_, results): (_, (usize, (usize, usize), bool, bool)) = server.accept().unwrap();
+    child_pid.wait();
+    assert_eq!(results, (1000, (100, 1000), true, true));
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn constrained_mode() {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    // The mode is per process, so enter it in a child.
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            let results_tx = IpcSender::connect(name).unwrap();
+            let (tx, rx) = ipc::channel::<IpcSharedMemory>().unwrap();
+            ipc::enter_constrained_mode(2).unwrap();
+
+            let server_refused = match IpcOneShotServer::<()>::new() {
+                Err(error) => error.kind() == std::io::ErrorKind::PermissionDenied,
+                Ok(_) => false,
+            };
+            // Stand-ins for descriptors inherited across `exec()`.
+            let tx = IpcSender::<IpcSharedMemory>::from_raw_fd(tx.into_raw_fd());
+            let rx = IpcReceiver::<IpcSharedMemory>::from_raw_fd(rx.into_raw_fd());
+            tx.send(IpcSharedMemory::from_bytes(b"reserved")).unwrap();
+            let first = rx.recv().unwrap();
+            tx.send(IpcSharedMemory::from_byte(7, 3)).unwrap();
+            let second = rx.recv().unwrap();
+            // Both reserved objects are used up, unless memfds need none.
+            let exhausted = IpcSharedMemory::try_from_byte(0, 1).is_err();
+            results_tx.send((server_refused,
+                             first[..] == b"reserved"[..],
+                             second[..] == [7; 3],
+                             exhausted))
+                      .unwrap();
+        })
+    };
+    let (_, results): (_, (bool, bool, bool, bool)) = server.accept().unwrap();
+    child_pid.wait();
+    let memfd = cfg!(all(feature = "memfd", target_os = "linux"));
+    assert_eq!(results, (true, true, true, !memfd));
+}
+
+#[test]
+fn capture() {
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    capture::start_capture(SharedBuffer(buffer.clone()), 4).unwrap();
+    let (tx, rx) = metadata_channel();
+    tx.send((0x01020304u32, IpcSharedMemory::from_byte(0, 5))).unwrap();
+    let _: (u32, IpcSharedMemory) = rx.recv().unwrap();
+    assert!(capture::stop_capture().is_some());
+
+    // Other tests may have sent messages meanwhile.
+    let capture = buffer.lock().unwrap().clone();
+    let reader = CaptureReader::new(&capture[..]).unwrap();
+    assert_eq!(reader.snapshot_length(), 4);
+    let frames: Vec<CaptureFrame> = reader.map(Result::unwrap)
+                                          .filter(|frame| frame.sender_id == tx.sender_id())
+                                          .collect();
+    assert_eq!(frames.len(), 2);
+    assert_eq!((frames[0].direction, frames[1].direction), (Direction::Sent, Direction::Received));
+    for frame in &frames {
+        assert_eq!(frame.pid, std::process::id());
+        assert_eq!(frame.sequence, 0);
+        assert_eq!(frame.payload_length, 12);
+        assert_eq!(frame.payload, [4, 3, 2, 1]);
+        assert_eq!(frame.shared_memory_lengths, [5]);
+    }
+    assert!(CaptureReader::new(&b"not a capture"[..]).is_err());
+
+    // A forged captured length beyond the snapshot length is refused, not allocated.
+    let mut forged = capture[..16].to_vec();
+    let mut frame = [0; 48];
+    frame[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    frame[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+    forged.extend_from_slice(&frame);
+    let error = CaptureReader::new(&forged[..]).unwrap().next().unwrap().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn simulation() {
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    fn schedule(seed: u64) -> Vec<char> {
+        let sim = Simulation::new(seed);
+        sim.set_latency(Duration::from_millis(1), Duration::from_millis(50));
+        let (a_tx, a_rx) = sim.channel();
+        let (b_tx, b_rx) = sim.channel();
+        for i in 0..8 {
+            a_tx.send(i).unwrap();
+            b_tx.send(i).unwrap();
+        }
+        let mut order = vec![];
+        let (mut next_a, mut next_b) = (0, 0);
+        while sim.pending() > 0 {
+            // Take whichever channel has a message delivered first.
+            sim.advance(Duration::from_millis(1));
+            while let Ok(i) = a_rx.try_recv() {
+                assert_eq!(i, next_a);
+                next_a += 1;
+                order.push('a');
+            }
+            while let Ok(i) = b_rx.try_recv() {
+                assert_eq!(i, next_b);
+                next_b += 1;
+                order.push('b');
+            }
+        }
+        assert!(sim.now() <= Duration::from_millis(51));
+        order
+    }
+
+    assert_eq!(schedule(1), schedule(1));
+    assert!((2..10).any(|seed| schedule(seed) != schedule(1)));
+
+    let sim = Simulation::new(0);
+    let (tx, rx) = sim.channel::<String>();
+    assert_eq!(bincode_io_kind(rx.recv_timeout(Duration::from_secs(60)).unwrap_err()),
+               ErrorKind::TimedOut);
+    assert_eq!(sim.now(), Duration::from_secs(60));
+    assert_eq!(bincode_io_kind(rx.try_recv().unwrap_err()), ErrorKind::WouldBlock);
+    tx.send("late".to_owned()).unwrap();
+    assert_eq!(rx.recv().unwrap(), "late");
+    drop(tx);
+    assert_eq!(bincode_io_kind(rx.recv().unwrap_err()), ErrorKind::ConnectionReset);
+}
+
+#[cfg(feature = "test-support")]
+fn bincode_io_kind(error: bincode::Error) -> std::io::ErrorKind {
+    match *error {
+        bincode::ErrorKind::Io(ref error) => error.kind(),
+        _ => panic!("not an I/O error: {}", error),
+    }
+}
+
 #[test]
 fn embedded_senders() {
     let person = ("Patrick Walton".to_owned(), 29);
@@ -190,102 +788,958 @@ fn select() {
     }
 }
 
-#[cfg(not(any(
-    feature = "force-inprocess",
-    target_os = "windows",
-    target_os = "android",
-    target_os = "ios"
-)))]
 #[test]
-fn cross_process_embedded_senders() {
+fn try_select() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx).unwrap();
+    assert!(rx_set.try_select().unwrap().is_empty());
+
     let person = ("Patrick Walton".to_owned(), 29);
-    let (server0, server0_name) = IpcOneShotServer::new().unwrap();
-    let (server2, server2_name) = IpcOneShotServer::new().unwrap();
-    let child_pid = unsafe {
-        fork(|| {
-            let (tx1, rx1): (IpcSender<Person>, IpcReceiver<Person>) = ipc::channel().unwrap();
-            let tx0 = IpcSender::connect(server0_name).unwrap();
-            tx0.send(tx1).unwrap();
-            rx1.recv().unwrap();
-            let tx2: IpcSender<Person> = IpcSender::connect(server2_name).unwrap();
-            tx2.send(person.clone()).unwrap();
-        })
+    tx.send(person.clone()).unwrap();
+    #[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                    target_os = "openbsd",
+                                                    target_os = "freebsd",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris")))]
+    {
+        use std::os::unix::io::AsRawFd;
+        let mut pollfd = libc::pollfd { fd: rx_set.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 1000) }, 1);
+    }
+    let results = rx_set.try_select().unwrap();
+    assert_eq!(results.len(), 1);
+    let (received_id, received_data) = results.into_iter().next().unwrap().unwrap();
+    assert_eq!(received_id, rx_id);
+    assert_eq!(received_data.to::<Person>().unwrap(), person);
+    assert!(rx_set.try_select().unwrap().is_empty());
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn select_priorities() {
+    let (control_tx, control_rx) = ipc::channel::<u32>().unwrap();
+    let (bulk_tx, bulk_rx) = ipc::channel::<u32>().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let control_id = rx_set.add_with_priority(control_rx, 1).unwrap();
+    let bulk_id = rx_set.add(bulk_rx).unwrap();
+    let select = |rx_set: &mut IpcReceiverSet| -> Vec<(u64, u32)> {
+        rx_set.select().unwrap().into_iter().map(|result| {
+            let (id, message) = result.unwrap();
+            (id, message.to().unwrap())
+        }).collect()
     };
-    let (_, tx1): (_, IpcSender<Person>) = server0.accept().unwrap();
-    tx1.send(person.clone()).unwrap();
-    let (_, received_person): (_, Person) = server2.accept().unwrap();
-    child_pid.wait();
-    assert_eq!(received_person, person);
+
+    // Pending bulk data is held back while control messages are pending.
+    bulk_tx.send(1).unwrap();
+    control_tx.send(2).unwrap();
+    assert_eq!(select(&mut rx_set), [(control_id, 2)]);
+    assert_eq!(select(&mut rx_set), [(bulk_id, 1)]);
+
+    // Unless it was held back for long enough.
+    rx_set.set_starvation_limit(Some(1));
+    bulk_tx.send(3).unwrap();
+    control_tx.send(4).unwrap();
+    assert_eq!(select(&mut rx_set), [(control_id, 4)]);
+    control_tx.send(5).unwrap();
+    assert_eq!(select(&mut rx_set), [(control_id, 5), (bulk_id, 3)]);
+    assert!(rx_set.try_select().unwrap().is_empty());
 }
 
 #[test]
-fn router_simple() {
-    let person = ("Patrick Walton".to_owned(), 29);
+fn select_timeout() {
+    use std::time::{Duration, Instant};
+
     let (tx, rx) = ipc::channel().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx).unwrap();
+    let start = Instant::now();
+    assert!(rx_set.select_timeout(Duration::from_millis(50)).unwrap().is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    let person = ("Patrick Walton".to_owned(), 29);
     tx.send(person.clone()).unwrap();
+    let results = rx_set.select_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(results.len(), 1);
+    let (received_id, received_data) = results.into_iter().next().unwrap().unwrap();
+    assert_eq!(received_id, rx_id);
+    assert_eq!(received_data.to::<Person>().unwrap(), person);
+}
 
-    let (callback_fired_sender, callback_fired_receiver) = crossbeam_channel::unbounded::<Person>();
-    ROUTER.add_route(
-        rx.to_opaque(),
-        Box::new(move |person| {
-            callback_fired_sender.send(person.to().unwrap()).unwrap();
-        }),
-    );
-    let received_person = callback_fired_receiver.recv().unwrap();
-    assert_eq!(received_person, person);
+#[test]
+fn recv_ref() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel::<u32>().unwrap();
+    tx.send(("Patrick Walton".to_owned(), vec![1u8, 2, 3], sub_tx)).unwrap();
+    let mut message = rx.recv_ref().unwrap();
+    assert_eq!(message.metadata().unwrap().sequence(), 0);
+    let (name, bytes, sub_tx): (&str, &[u8], IpcSender<u32>) = message.get().unwrap();
+    assert_eq!(name, "Patrick Walton");
+    assert_eq!(bytes, &[1, 2, 3]);
+    sub_tx.send(29).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), 29);
 }
 
 #[test]
-fn router_routing_to_new_crossbeam_receiver() {
+fn local_messages() {
     let person = ("Patrick Walton".to_owned(), 29);
     let (tx, rx) = ipc::channel().unwrap();
-    tx.send(person.clone()).unwrap();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
 
-    let crossbeam_receiver = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx);
-    let received_person = crossbeam_receiver.recv().unwrap();
+    tx.send_local((person.clone(), sub_tx)).unwrap();
+    let (received_person, sub_tx): (Person, IpcSender<Person>) = rx.recv_local().unwrap();
     assert_eq!(received_person, person);
+    sub_tx.send_local(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
+
+    sub_tx.send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv_local().unwrap(), person);
+
+    let (tx, rx) = ipc::channel::<String>().unwrap();
+    tx.send_local("Hello".to_owned()).unwrap();
+    assert_eq!(rx.cast::<Vec<u8>>().try_recv_local().unwrap(), b"Hello");
+
+    let (tx, rx) = ipc::channel::<Person>().unwrap();
+    let crossbeam_rx = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx);
+    tx.send_local(person.clone()).unwrap();
+    assert_eq!(crossbeam_rx.recv().unwrap(), person);
+
+    // Serializing fails on sending, or where the value is received serialized.
+    let (tx, rx) = ipc::channel::<String>().unwrap();
+    let tx = tx.with_bincode_config(BincodeConfig::new().limit(4));
+    let error = tx.send_local("Too long".to_owned()).and_then(|()| rx.recv()).unwrap_err();
+    assert!(matches!(*error, bincode::ErrorKind::SizeLimit));
 }
 
 #[test]
-fn router_multiplexing() {
-    let person = ("Patrick Walton".to_owned(), 29);
-    let (tx0, rx0) = ipc::channel().unwrap();
-    tx0.send(person.clone()).unwrap();
-    let (tx1, rx1) = ipc::channel().unwrap();
-    tx1.send(person.clone()).unwrap();
+fn raw_messages() {
+    let (tx, rx) = metadata_channel::<Person>();
+    let (sub_tx, sub_rx) = ipc::channel::<Person>().unwrap();
+    let (other_tx, other_rx) = ipc::channel::<u32>().unwrap();
+    let bytes = [1, 2, 3, 4];
+    tx.send_raw(&bytes,
+                vec![IpcRawChannel::Receiver(sub_rx.to_opaque()),
+                     IpcRawChannel::Sender(other_tx.to_opaque())],
+                vec![IpcSharedMemory::from_bytes(&bytes)]).unwrap();
+    let message = rx.recv_raw().unwrap();
+    assert_eq!(message.data, bytes);
+    assert_eq!(message.metadata.sequence(), 0);
+    assert_eq!(&message.shared_memory_regions[0][..], &bytes);
+    let mut channels = message.channels.into_iter();
+    let sub_rx = channels.next().unwrap().to_receiver().to::<Person>();
+    let other_tx = channels.next().unwrap().to_sender().to::<u32>();
+    other_tx.send(29).unwrap();
+    assert_eq!(other_rx.recv().unwrap(), 29);
 
-    let crossbeam_rx_0 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx0);
-    let crossbeam_rx_1 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx1);
-    let received_person_0 = crossbeam_rx_0.recv().unwrap();
-    let received_person_1 = crossbeam_rx_1.recv().unwrap();
-    assert_eq!(received_person_0, person);
-    assert_eq!(received_person_1, person);
+    // A raw message holding a valid serialization can be received as usual.
+    let person = ("Patrick Walton".to_owned(), 29);
+    tx.send_raw(&bincode::serialize(&person).unwrap(), vec![], vec![]).unwrap();
+    let (received_person, metadata) = rx.recv_with_metadata().unwrap();
+    assert_eq!(received_person, person);
+    assert_eq!(metadata.sequence(), 1);
+    sub_tx.send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
 }
 
 #[test]
-fn router_multithreaded_multiplexing() {
-    let person = ("Patrick Walton".to_owned(), 29);
+fn forward_opaque_messages() {
+    type Envelope = (IpcSender<u32>, IpcSharedMemory);
 
-    let person_for_thread = person.clone();
-    let (tx0, rx0) = ipc::channel().unwrap();
-    thread::spawn(move || tx0.send(person_for_thread).unwrap());
-    let person_for_thread = person.clone();
-    let (tx1, rx1) = ipc::channel().unwrap();
-    thread::spawn(move || tx1.send(person_for_thread).unwrap());
+    let (tx, rx) = metadata_channel::<Envelope>();
+    let (forward_tx, forward_rx) = metadata_channel::<Envelope>();
+    let forward_tx = forward_tx.with_hmac_key(b"secret");
+    let forward_rx = forward_rx.with_hmac_key(b"secret");
+    let (reply_tx, reply_rx) = ipc::channel().unwrap();
+    tx.send((reply_tx, IpcSharedMemory::from_byte(7, 64))).unwrap();
 
-    let crossbeam_rx_0 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx0);
-    let crossbeam_rx_1 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx1);
-    let received_person_0 = crossbeam_rx_0.recv().unwrap();
-    let received_person_1 = crossbeam_rx_1.recv().unwrap();
-    assert_eq!(received_person_0, person);
-    assert_eq!(received_person_1, person);
+    // The broker holds on to the message without knowing its type.
+    let message = rx.recv_opaque().unwrap();
+    assert_eq!(message.metadata().unwrap().sender_id(), tx.sender_id());
+    forward_tx.forward(message).unwrap();
+
+    let ((reply_tx, region), metadata) = forward_rx.recv_with_metadata().unwrap();
+    assert_eq!((metadata.sender_id(), metadata.sequence()), (tx.sender_id(), 0));
+    assert_eq!(&region[..], &[7; 64][..]);
+    reply_tx.send(29).unwrap();
+    assert_eq!(reply_rx.recv().unwrap(), 29);
+    assert!(rx.try_recv_opaque().is_err());
 }
 
+#[cfg(not(all(target_os = "macos", not(feature = "force-inprocess"))))]
 #[test]
-fn router_drops_callbacks_on_sender_shutdown() {
-    struct Dropper {
-        sender: Sender<i32>,
-    }
+fn receiver_duplicate() {
+    let (tx, rx) = ipc::channel::<String>().unwrap();
+    let tx = tx.with_hmac_key(b"secret");
+    let rx = rx.with_hmac_key(b"secret");
+    let other_rx = rx.try_duplicate().unwrap();
+    tx.send("first".to_owned()).unwrap();
+    tx.send("second".to_owned()).unwrap();
+    assert_eq!(other_rx.recv().unwrap(), "first");
+    assert_eq!(rx.recv().unwrap(), "second");
+    drop(tx);
+    assert!(rx.recv().is_err());
+    assert!(other_rx.recv().is_err());
+}
+
+#[cfg(all(target_os = "macos", not(feature = "force-inprocess")))]
+#[test]
+fn receiver_duplicate_unsupported() {
+    let (_tx, rx) = ipc::channel::<String>().unwrap();
+    assert_eq!(rx.try_duplicate().unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn cast_channels() {
+    let (tx, rx) = metadata_channel::<Vec<u8>>();
+    tx.send(b"bytes".to_vec()).unwrap();
+    let sender_id = tx.sender_id();
+    let tx = tx.cast::<String>();
+    assert_eq!(tx.sender_id(), sender_id);
+    tx.send("string".to_owned()).unwrap();
+    let (bytes, metadata) = rx.recv_with_metadata().unwrap();
+    assert_eq!((&bytes[..], metadata.sequence()), (&b"bytes"[..], 0));
+    let (bytes, metadata) = rx.recv_with_metadata().unwrap();
+    assert_eq!((&bytes[..], metadata.sequence()), (&b"string"[..], 1));
+
+    let rx = rx.cast_unchecked::<String>();
+    tx.cast_unchecked::<Vec<u8>>().send(vec![0xff]).unwrap();
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn metadata_header_is_opt_in() {
+    use std::time::Duration;
+
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx, rx) = ipc::channel::<Person>().unwrap();
+    tx.send(person.clone()).unwrap();
+    let message = rx.recv_raw().unwrap();
+    assert_eq!(message.data, bincode::serialize(&person).unwrap());
+    assert_eq!(message.metadata, Default::default());
+    assert!(tx.send_with_ttl(person.clone(), Duration::from_secs(60)).is_err());
+    assert!(tx.transaction().commit().is_ok());
+    let mut transaction = tx.transaction();
+    transaction.push(person.clone()).unwrap();
+    assert!(transaction.commit().is_err());
+
+    // Forwarding between the layouts adds or drops the header.
+    let (metadata_tx, metadata_rx) = metadata_channel::<Person>();
+    tx.send(person.clone()).unwrap();
+    metadata_tx.forward(rx.recv_opaque().unwrap()).unwrap();
+    let (received_person, metadata) = metadata_rx.recv_with_metadata().unwrap();
+    assert_eq!((received_person, metadata.sender_id()), (person.clone(), metadata_tx.sender_id()));
+    metadata_tx.send(person.clone()).unwrap();
+    tx.forward(metadata_rx.recv_opaque().unwrap()).unwrap();
+    assert_eq!(rx.recv_raw().unwrap().data, bincode::serialize(&person).unwrap());
+}
+
+#[test]
+fn sender_ids() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx0, rx) = metadata_channel();
+    let tx1 = tx0.clone();
+    assert!(tx0.sender_id() != tx1.sender_id());
+
+    tx1.send(person.clone()).unwrap();
+    tx0.send(person.clone()).unwrap();
+    let (received_person, metadata) = rx.recv_with_metadata().unwrap();
+    assert_eq!(received_person, person);
+    assert_eq!(metadata.sender_id(), tx1.sender_id());
+    let (_, metadata) = rx.try_recv_with_metadata().unwrap();
+    assert_eq!(metadata.sender_id(), tx0.sender_id());
+
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(tx0.clone()).unwrap();
+    let transferred_tx: IpcSender<Person> = super_rx.recv().unwrap();
+    let transferred_tx = transferred_tx.with_bincode_config(BincodeConfig::new().metadata_header());
+    assert!(transferred_tx.sender_id() != tx0.sender_id());
+    transferred_tx.send(person.clone()).unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    rx_set.add(rx).unwrap();
+    let (_, message) = rx_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    assert_eq!(message.metadata().unwrap().sender_id(), transferred_tx.sender_id());
+    let received_person: Person = message.to().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn sequence_checking() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx0, mut rx) = metadata_channel();
+    let tx1 = tx0.clone();
+    rx.set_sequence_checking(true);
+    tx0.send(person.clone()).unwrap();
+    tx1.send(person.clone()).unwrap();
+    tx0.send(person.clone()).unwrap();
+    let sequences: Vec<_> = (0..3)
+        .map(|_| rx.recv_with_metadata().unwrap().1.sequence())
+        .collect();
+    assert_eq!(sequences, vec![0, 0, 1]);
+
+    // Skip a message while checking is disabled, so a gap shows up afterwards.
+    rx.set_sequence_checking(false);
+    tx0.send(person.clone()).unwrap();
+    tx0.send(person.clone()).unwrap();
+    assert_eq!(rx.recv().unwrap(), person);
+    rx.set_sequence_checking(true);
+    let error = rx.recv().unwrap_err();
+    assert_eq!(
+        SequenceError::from_error(&error),
+        Some(&SequenceError::Gap {
+            sender_id: tx0.sender_id(),
+            expected: 0,
+            received: 3,
+        })
+    );
+    let (received_person, metadata) = rx.recv_with_metadata().unwrap();
+    assert_eq!(received_person, person);
+    assert_eq!(metadata.sequence(), 3);
+    tx0.send(person.clone()).unwrap();
+    assert_eq!(rx.try_recv().unwrap(), person);
+
+    drop((tx0, tx1));
+    let error = rx.recv().unwrap_err();
+    assert_eq!(SequenceError::from_error(&error), None);
+}
+
+#[test]
+fn transaction() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx, mut rx) = metadata_channel::<(Person, Option<IpcSender<Person>>)>();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    rx.set_sequence_checking(true);
+    tx.send((person.clone(), None)).unwrap();
+    let mut transaction = tx.transaction();
+    transaction.push((person.clone(), None)).unwrap();
+    transaction.push((person.clone(), Some(sub_tx))).unwrap();
+    assert_eq!(transaction.len(), 2);
+    transaction.commit().unwrap();
+    tx.transaction().commit().unwrap();
+    let mut transaction = tx.transaction();
+    transaction.push((person.clone(), None)).unwrap();
+    drop(transaction);
+    tx.send((person.clone(), None)).unwrap();
+
+    let received: Vec<_> = (0..4).map(|_| rx.recv_with_metadata().unwrap()).collect();
+    assert_eq!(received.iter().map(|&(_, metadata)| metadata.sequence()).collect::<Vec<_>>(),
+               vec![0, 1, 2, 3]);
+    assert!(received.iter().all(|&((ref received_person, _), _)| *received_person == person));
+    let sender = received.into_iter().filter_map(|((_, sender), _)| sender).next().unwrap();
+    sender.send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
+
+    // Receiver sets hand out the messages of a transaction one by one.
+    let (tx, rx) = metadata_channel();
+    let mut transaction = tx.transaction();
+    transaction.push(1u32).unwrap();
+    transaction.push(2u32).unwrap();
+    transaction.commit().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    rx_set.add(rx).unwrap();
+    let numbers: Vec<u32> = rx_set.select().unwrap().into_iter().map(|result| {
+        result.unwrap().1.to().unwrap()
+    }).collect();
+    assert_eq!(numbers, vec![1, 2]);
+}
+
+#[test]
+fn try_recv_timeout() {
+    use std::time::{Duration, Instant};
+    let (tx, rx) = ipc::channel().unwrap();
+    let start = Instant::now();
+    let error = rx.try_recv_timeout(Duration::from_millis(50)).unwrap_err();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    match *error {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::TimedOut)
+        }
+        _ => panic!("unexpected error {:?}", error),
+    }
+    let thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        tx.send(42u32).unwrap();
+    });
+    assert_eq!(rx.try_recv_timeout(Duration::from_secs(10)).unwrap(), 42);
+    thread.join().unwrap();
+}
+
+#[test]
+fn ack_redelivery() {
+    use std::time::Duration;
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let rx = rx.with_acks(Duration::from_millis(100));
+    let other_rx = rx.clone();
+    for i in 0..3 {
+        tx.send(i).unwrap();
+    }
+
+    // Acknowledged messages are done with.
+    assert_eq!(rx.recv().unwrap().ack(), 0);
+    // Dropped deliveries are handed out again right away, to any clone.
+    let delivery = rx.recv().unwrap();
+    assert_eq!((*delivery, delivery.attempt()), (1, 1));
+    drop(delivery);
+    let delivery = other_rx.try_recv().unwrap();
+    assert_eq!((*delivery, delivery.attempt()), (1, 2));
+    assert_eq!(delivery.ack(), 1);
+
+    // Deliveries not acknowledged in time are handed out again, even after the
+    // senders are gone.
+    let delivery = rx.recv().unwrap();
+    assert_eq!(*delivery, 2);
+    drop(tx);
+    assert_eq!(rx.unacked_count(), 1);
+    let redelivery = other_rx.recv().unwrap();
+    assert_eq!((*redelivery, redelivery.attempt()), (2, 2));
+    assert_eq!(delivery.ack(), 2);
+    assert_eq!(rx.unacked_count(), 1);
+    assert_eq!(redelivery.ack(), 2);
+    assert_eq!(rx.unacked_count(), 0);
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn ack_redelivery_while_receiving() {
+    use std::time::{Duration, Instant};
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let rx = rx.with_acks(Duration::from_secs(30));
+    tx.send(0).unwrap();
+    let delivery = rx.recv().unwrap();
+
+    // A clone waiting on the channel doesn't hold up messages handed out again.
+    let waiting_rx = rx.clone();
+    let thread = thread::spawn(move || waiting_rx.recv().unwrap().ack());
+    thread::sleep(Duration::from_millis(100));
+    drop(delivery);
+    let start = Instant::now();
+    let redelivery = rx.recv().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!((*redelivery, redelivery.attempt()), (0, 2));
+    assert_eq!(redelivery.ack(), 0);
+
+    tx.send(1).unwrap();
+    assert_eq!(thread.join().unwrap(), 1);
+}
+
+#[test]
+fn receiver_map_filter() {
+    let (tx, rx) = ipc::channel::<(u32, String)>().unwrap();
+    let names = rx.map(|(_, name)| name);
+    tx.send((1, "one".to_owned())).unwrap();
+    assert_eq!(names.recv().unwrap(), "one");
+    assert!(names.try_recv().is_err());
+
+    let odd = names.into_inner().filter(|&(number, _)| number % 2 == 1);
+    for number in 2..6 {
+        tx.send((number, number.to_string())).unwrap();
+    }
+    assert_eq!(odd.recv().unwrap(), (3, "3".to_owned()));
+    assert_eq!(odd.try_recv().unwrap(), (5, "5".to_owned()));
+    assert!(odd.try_recv().is_err());
+    drop(tx);
+    assert!(odd.recv().is_err());
+}
+
+#[test]
+fn sender_with() {
+    let (tx, rx) = ipc::channel::<(u32, String)>().unwrap();
+    let tx = tx.with(|name: String| (name.len() as u32, name));
+    let tx2 = tx.clone();
+    tx.send("Hello".to_owned()).unwrap();
+    tx2.send("Hi".to_owned()).unwrap();
+    assert_eq!(rx.recv().unwrap(), (5, "Hello".to_owned()));
+    assert_eq!(rx.recv().unwrap(), (2, "Hi".to_owned()));
+    let tx = tx.into_inner();
+    tx.send((0, String::new())).unwrap();
+    assert_eq!(rx.recv().unwrap(), (0, String::new()));
+}
+
+#[test]
+fn sender_rate_limited() {
+    use std::time::{Duration, Instant};
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let tx = tx.rate_limited(20, 3);
+    let tx2 = tx.clone();
+    for i in 0..3 {
+        tx.try_send(i).unwrap();
+    }
+    // Clones share the limit.
+    match *tx2.try_send(3).unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock)
+        }
+        ref error => panic!("unexpected error {}", error),
+    }
+    let start = Instant::now();
+    tx2.send(3).unwrap();
+    tx.send(4).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(90));
+    for i in 0..5 {
+        assert_eq!(rx.recv().unwrap(), i);
+    }
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn message_expiry() {
+    use std::time::Duration;
+
+    let (tx, mut rx) = metadata_channel::<u32>();
+    let (expired_sender, expired_receiver) = crossbeam_channel::unbounded();
+    rx.set_expiry_handler(Box::new(move |metadata| {
+        expired_sender.send(metadata.sequence()).unwrap()
+    }));
+    rx.set_sequence_checking(true);
+    // A TTL of zero has run out by the time the message is received.
+    tx.send_with_ttl(1, Duration::from_secs(0)).unwrap();
+    tx.send(2).unwrap();
+    tx.send_with_ttl(3, Duration::from_secs(60)).unwrap();
+    assert_eq!(rx.recv().unwrap(), 2);
+    let (value, metadata) = rx.recv_with_metadata().unwrap();
+    assert_eq!((value, metadata.sequence()), (3, 2));
+    assert!(metadata.expires_at().is_some() && !metadata.is_expired());
+    tx.send_with_ttl(4, Duration::from_secs(0)).unwrap();
+    assert!(rx.try_recv().is_err());
+    assert_eq!(rx.expired_count(), 2);
+    assert_eq!(expired_receiver.try_iter().collect::<Vec<_>>(), vec![0, 3]);
+
+    // Receiver sets hand expired messages over, for the application to check.
+    tx.send_with_ttl(5, Duration::from_secs(0)).unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    rx_set.add(rx).unwrap();
+    let (_, message) = rx_set.select().unwrap().into_iter().next().unwrap().unwrap();
+    assert!(message.metadata().unwrap().is_expired());
+    assert_eq!(message.to::<u32>().unwrap(), 5);
+}
+
+#[test]
+fn dead_letters() {
+    use std::time::Duration;
+
+    let (dead_letter_sender, dead_letter_receiver) = crossbeam_channel::unbounded();
+    let (tx, rx) = metadata_channel::<String>();
+    let mut rx = rx.with_bincode_config(BincodeConfig::new().limit(64).metadata_header());
+    rx.set_dead_letter_sender(dead_letter_sender);
+    tx.send_with_ttl("expired".to_owned(), Duration::from_secs(0)).unwrap();
+    tx.send("x".repeat(100)).unwrap();
+    tx.send("delivered".to_owned()).unwrap();
+    assert!(rx.recv().is_err());
+    assert_eq!(rx.recv().unwrap(), "delivered");
+    assert_eq!(rx.expired_count(), 1);
+
+    let dead_letters: Vec<_> = dead_letter_receiver.try_iter().map(|dead_letter| {
+        let sequence = dead_letter.message.metadata().unwrap().sequence();
+        (dead_letter.reason, sequence, dead_letter.message.to::<String>().unwrap().len())
+    }).collect();
+    assert_eq!(dead_letters, vec![(DeadLetterReason::Expired, 0, 7),
+                                  (DeadLetterReason::LimitExceeded, 1, 100)]);
+}
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+)))]
+#[test]
+fn cross_process_embedded_senders() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (server0, server0_name) = IpcOneShotServer::new().unwrap();
+    let (server2, server2_name) = IpcOneShotServer::new().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            let (tx1, rx1): (IpcSender<Person>, IpcReceiver<Person>) = ipc::channel().unwrap();
+            let tx0 = IpcSender::connect(server0_name).unwrap();
+            tx0.send(tx1).unwrap();
+            rx1.recv().unwrap();
+            let tx2: IpcSender<Person> = IpcSender::connect(server2_name).unwrap();
+            tx2.send(person.clone()).unwrap();
+        })
+    };
+    let (_, tx1): (_, IpcSender<Person>) = server0.accept().unwrap();
+    tx1.send(person.clone()).unwrap();
+    let (_, received_person): (_, Person) = server2.accept().unwrap();
+    child_pid.wait();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn router_simple() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(person.clone()).unwrap();
+
+    let (callback_fired_sender, callback_fired_receiver) = crossbeam_channel::unbounded::<Person>();
+    ROUTER.add_route(
+        rx.to_opaque(),
+        Box::new(move |person| {
+            callback_fired_sender.send(person.to().unwrap()).unwrap();
+        }),
+    );
+    let received_person = callback_fired_receiver.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn router_routing_to_new_crossbeam_receiver() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(person.clone()).unwrap();
+
+    let crossbeam_receiver = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx);
+    let received_person = crossbeam_receiver.recv().unwrap();
+    assert_eq!(received_person, person);
+}
+
+#[test]
+fn router_routing_to_bounded_receivers() {
+    use std::time::Duration;
+
+    fn queued(messages: u32) -> ipc::IpcReceiver<u32> {
+        let (tx, rx) = ipc::channel().unwrap();
+        for message in 0..messages {
+            tx.send(message).unwrap();
+        }
+        rx
+    }
+
+    // Once the IPC senders are gone, routes are removed, dropping their senders.
+    // Unless they block, give the routes time to take all messages before receiving.
+    let router = RouterProxy::new();
+    let blocking = router.route_ipc_receiver_to_new_bounded_crossbeam_receiver(
+        queued(5), 1, OverflowPolicy::Block);
+    assert_eq!(blocking.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    let dropping = router.route_ipc_receiver_to_new_bounded_crossbeam_receiver(
+        queued(5), 2, OverflowPolicy::DropOldest);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(dropping.iter().collect::<Vec<_>>(), vec![3, 4]);
+    let failing = router.route_ipc_receiver_to_new_bounded_crossbeam_receiver(
+        queued(5), 2, OverflowPolicy::Error);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(failing.iter().collect::<Vec<_>>(), vec![0, 1]);
+
+    let blocking = router.route_ipc_receiver_to_new_sync_receiver(
+        queued(5), 1, OverflowPolicy::Block);
+    assert_eq!(blocking.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    let failing = router.route_ipc_receiver_to_new_sync_receiver(
+        queued(5), 2, OverflowPolicy::Error);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(failing.iter().collect::<Vec<_>>(), vec![0, 1]);
+}
+
+#[test]
+fn router_dead_letters() {
+    let router = RouterProxy::new();
+    let (dead_letter_sender, dead_letter_receiver) = crossbeam_channel::unbounded();
+    router.set_dead_letter_sender(dead_letter_sender);
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (crossbeam_sender, crossbeam_receiver) = crossbeam_channel::unbounded();
+    router.route_ipc_receiver_to_crossbeam_sender(rx, crossbeam_sender);
+    tx.send(1).unwrap();
+    assert_eq!(crossbeam_receiver.recv().unwrap(), 1);
+    drop(crossbeam_receiver);
+    tx.send(2).unwrap();
+    let dead_letter = dead_letter_receiver.recv().unwrap();
+    assert_eq!(dead_letter.reason, DeadLetterReason::Undeliverable);
+    assert_eq!(dead_letter.message.to::<u32>().unwrap(), 2);
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    for message in 0..3 {
+        tx.send(message).unwrap();
+    }
+    let bounded = router.route_ipc_receiver_to_new_bounded_crossbeam_receiver(
+        rx, 1, OverflowPolicy::Error);
+    let undelivered: Vec<u32> = (0..2).map(|_| {
+        dead_letter_receiver.recv().unwrap().message.to().unwrap()
+    }).collect();
+    assert_eq!(undelivered, vec![1, 2]);
+    assert_eq!(bounded.recv().unwrap(), 0);
+
+    // Undeliverable messages keep their channels and shared memory regions.
+    let (tx, rx) = ipc::channel::<(IpcSharedMemory, IpcSender<u32>)>().unwrap();
+    let (crossbeam_sender, crossbeam_receiver) = crossbeam_channel::unbounded();
+    router.route_ipc_receiver_to_crossbeam_sender(rx, crossbeam_sender);
+    drop(crossbeam_receiver);
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    tx.send((IpcSharedMemory::from_bytes(&[1, 2, 3]), sub_tx)).unwrap();
+    let dead_letter = dead_letter_receiver.recv().unwrap();
+    let (region, sub_tx) =
+        dead_letter.message.to::<(IpcSharedMemory, IpcSender<u32>)>().unwrap();
+    assert_eq!(&*region, &[1, 2, 3]);
+    sub_tx.send(4).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), 4);
+}
+
+#[test]
+fn router_spilling_receiver() {
+    let router = RouterProxy::new();
+    let (tx, rx) = ipc::channel::<(u32, Option<IpcSender<u32>>)>().unwrap();
+    let spilling_rx = router.route_ipc_receiver_to_new_spilling_receiver(rx, 2).unwrap();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    for value in 0..5 {
+        tx.send((value, None)).unwrap();
+    }
+    // Channels can't be written out, so this is kept in memory, past the backlog.
+    tx.send((5, Some(sub_tx))).unwrap();
+    tx.send((6, None)).unwrap();
+    while spilling_rx.spilled() < 4 {
+        thread::yield_now();
+    }
+    let values: Vec<_> = (0..5).map(|_| spilling_rx.recv().unwrap().0).collect();
+    assert_eq!(values, vec![0, 1, 2, 3, 4]);
+    let (value, embedded_tx) = spilling_rx.recv().unwrap();
+    assert_eq!(value, 5);
+    embedded_tx.unwrap().send(42).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), 42);
+    assert_eq!(spilling_rx.recv().unwrap().0, 6);
+    assert_eq!(spilling_rx.spilled(), 0);
+    assert!(spilling_rx.try_recv().is_err());
+
+    // Spilling starts over once the file was read back.
+    tx.send((7, None)).unwrap();
+    tx.send((8, None)).unwrap();
+    tx.send((9, None)).unwrap();
+    drop(tx);
+    let values: Vec<_> = spilling_rx.iter().map(|(value, _)| value).collect();
+    assert_eq!(values, vec![7, 8, 9]);
+}
+
+#[test]
+fn router_thread_config() {
+    use router::{QosClass, RouterThreadConfig, ThreadPriority};
+
+    let config = RouterThreadConfig::new().name("test-router").stack_size(128 * 1024);
+    let router = RouterProxy::with_thread_config(config).unwrap();
+    let (tx, rx) = ipc::channel::<()>().unwrap();
+    let (name_sender, name_receiver) = crossbeam_channel::unbounded();
+    router.add_route(rx.to_opaque(), Box::new(move |_| {
+        name_sender.send(thread::current().name().map(|name| name.to_owned())).unwrap();
+    }));
+    tx.send(()).unwrap();
+    assert_eq!(name_receiver.recv().unwrap(), Some("test-router".to_owned()));
+
+    if !cfg!(any(target_os = "macos", target_os = "ios")) {
+        let config = RouterThreadConfig::new()
+            .priority(ThreadPriority::QualityOfService(QosClass::Utility));
+        let error = RouterProxy::with_thread_config(config).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn router_thread_nice() {
+    use router::{RouterThreadConfig, ThreadPriority};
+
+    // Raising the nice value needs no privileges.
+    let config = RouterThreadConfig::new().priority(ThreadPriority::Nice(19));
+    let router = RouterProxy::with_thread_config(config).unwrap();
+    let (tx, rx) = ipc::channel::<()>().unwrap();
+    let (nice_sender, nice_receiver) = crossbeam_channel::unbounded();
+    router.add_route(rx.to_opaque(), Box::new(move |_| {
+        let nice = unsafe {
+            libc::getpriority(libc::PRIO_PROCESS, libc::syscall(libc::SYS_gettid) as libc::id_t)
+        };
+        nice_sender.send(nice).unwrap();
+    }));
+    tx.send(()).unwrap();
+    assert_eq!(nice_receiver.recv().unwrap(), 19);
+}
+
+#[cfg(all(not(feature = "force-inprocess"), target_os = "linux"))]
+#[test]
+fn internal_thread_config() {
+    use threads::{self, ThreadConfig};
+
+    assert_eq!(threads::set_thread_config(ThreadConfig::new().affinity(&[])).unwrap_err().kind(),
+               std::io::ErrorKind::InvalidInput);
+
+    // The configuration is global, and affects the threads other tests spawn, so
+    // try it out in a child of its own.
+    let child_pid = unsafe {
+        fork(|| {
+            let result = std::panic::catch_unwind(|| {
+                let cpu = {
+                    let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+                    assert_eq!(libc::sched_getaffinity(0, std::mem::size_of_val(&cpu_set),
+                                                       &mut cpu_set),
+                               0);
+                    (0..libc::CPU_SETSIZE as usize).find(|&cpu| libc::CPU_ISSET(cpu, &cpu_set))
+                                                   .unwrap()
+                };
+                threads::set_thread_config(ThreadConfig::new().name_prefix("test-ipc-")
+                                                              .affinity(&[cpu]))
+                    .unwrap();
+                let router = RouterProxy::new();
+
+                let (tx, rx) = ipc::channel::<()>().unwrap();
+                let (thread_sender, thread_receiver) = crossbeam_channel::unbounded();
+                router.add_route(rx.to_opaque(), Box::new(move |_| {
+                    let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+                    libc::sched_getaffinity(0, std::mem::size_of_val(&cpu_set), &mut cpu_set);
+                    let name = thread::current().name().map(|name| name.to_owned());
+                    thread_sender.send((name, libc::CPU_COUNT(&cpu_set))).unwrap();
+                }));
+                tx.send(()).unwrap();
+                assert_eq!(thread_receiver.recv().unwrap(),
+                           (Some("test-ipc-router".to_owned()), 1));
+            });
+            libc::_exit(if result.is_ok() { 0 } else { 1 });
+        })
+    };
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(child_pid, &mut status, 0) }, child_pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+            "the child failed with status {:#x}", status);
+}
+
+#[test]
+#[should_panic(expected = "DropOldest")]
+fn router_sync_receiver_drop_oldest() {
+    let (_tx, rx) = ipc::channel::<u32>().unwrap();
+    ROUTER.route_ipc_receiver_to_new_sync_receiver(rx, 1, OverflowPolicy::DropOldest);
+}
+
+#[test]
+fn router_typed_route_errors() {
+    let router = RouterProxy::new();
+    let (fallback_sender, fallback_receiver) = crossbeam_channel::unbounded();
+    router.set_error_handler(Box::new(move |message, _| {
+        fallback_sender.send(message.to::<String>().unwrap()).unwrap();
+    }));
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (value_sender, value_receiver) = crossbeam_channel::unbounded();
+    let (error_sender, error_receiver) = crossbeam_channel::unbounded();
+    router.add_typed_route_with_error_handler(
+        rx,
+        Box::new(move |value| value_sender.send(value).unwrap()),
+        Box::new(move |message, error| {
+            error_sender.send((message.to::<u16>().unwrap(), error.to_string())).unwrap();
+        }),
+    );
+    tx.send(42).unwrap();
+    assert_eq!(value_receiver.recv().unwrap(), 42);
+    tx.clone().cast_unchecked::<u16>().send(7).unwrap();
+    let (message, _) = error_receiver.recv().unwrap();
+    assert_eq!(message, 7);
+    tx.send(43).unwrap();
+    assert_eq!(value_receiver.recv().unwrap(), 43);
+    assert!(fallback_receiver.try_recv().is_err());
+
+    let (tx, rx) = ipc::channel::<(u64, u64)>().unwrap();
+    let crossbeam_rx = router.route_ipc_receiver_to_new_crossbeam_receiver(rx);
+    tx.clone().cast_unchecked::<String>().send("drift".to_owned()).unwrap();
+    assert_eq!(fallback_receiver.recv().unwrap(), "drift");
+    tx.send((1, 2)).unwrap();
+    assert_eq!(crossbeam_rx.recv().unwrap(), (1, 2));
+}
+
+#[test]
+fn router_coalescing_routes() {
+    use router::CoalescePolicy;
+    use std::time::Duration;
+
+    let router = RouterProxy::new();
+    let window = Duration::from_millis(200);
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (latest_sender, latest_receiver) = crossbeam_channel::unbounded();
+    router.add_coalescing_route(
+        rx.to_opaque(),
+        Box::new(move |message| latest_sender.send(message.to::<u32>().unwrap()).unwrap()),
+        CoalescePolicy::KeepLatest(window),
+    );
+    for value in 0..10 {
+        tx.send(value).unwrap();
+    }
+    assert_eq!(latest_receiver.recv().unwrap(), 9);
+    assert!(latest_receiver.recv_timeout(window * 2).is_err());
+    tx.send(10).unwrap();
+    assert_eq!(latest_receiver.recv().unwrap(), 10);
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (sum_sender, sum_receiver) = crossbeam_channel::unbounded();
+    router.add_typed_coalescing_route(
+        rx,
+        Box::new(move |sum| sum_sender.send(sum).unwrap()),
+        CoalescePolicy::Merge(window, Box::new(|sum, value| sum + value)),
+    );
+    for value in 1..5 {
+        tx.send(value).unwrap();
+    }
+    assert_eq!(sum_receiver.recv().unwrap(), 10);
+    // Closing the channel delivers the open window right away.
+    tx.send(5).unwrap();
+    drop(tx);
+    assert_eq!(sum_receiver.recv_timeout(window / 2).unwrap(), 5);
+    assert!(sum_receiver.recv().is_err());
+}
+
+#[test]
+fn router_close_handler() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (message_sender, message_receiver) = crossbeam_channel::unbounded();
+    let (closed_sender, closed_receiver) = crossbeam_channel::unbounded();
+    ROUTER.add_route_with_close_handler(
+        rx.to_opaque(),
+        Box::new(move |message| message_sender.send(message.to::<u32>().unwrap()).unwrap()),
+        Box::new(move || closed_sender.send(()).unwrap()),
+    );
+    tx.send(1).unwrap();
+    assert_eq!(message_receiver.recv().unwrap(), 1);
+    assert!(closed_receiver.try_recv().is_err());
+    drop(tx);
+    closed_receiver.recv().unwrap();
+    // The route's callback was dropped along with the route.
+    assert!(message_receiver.recv().is_err());
+}
+
+#[test]
+fn router_multiplexing() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx0, rx0) = ipc::channel().unwrap();
+    tx0.send(person.clone()).unwrap();
+    let (tx1, rx1) = ipc::channel().unwrap();
+    tx1.send(person.clone()).unwrap();
+
+    let crossbeam_rx_0 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx0);
+    let crossbeam_rx_1 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx1);
+    let received_person_0 = crossbeam_rx_0.recv().unwrap();
+    let received_person_1 = crossbeam_rx_1.recv().unwrap();
+    assert_eq!(received_person_0, person);
+    assert_eq!(received_person_1, person);
+}
+
+#[test]
+fn router_multithreaded_multiplexing() {
+    let person = ("Patrick Walton".to_owned(), 29);
+
+    let person_for_thread = person.clone();
+    let (tx0, rx0) = ipc::channel().unwrap();
+    thread::spawn(move || tx0.send(person_for_thread).unwrap());
+    let person_for_thread = person.clone();
+    let (tx1, rx1) = ipc::channel().unwrap();
+    thread::spawn(move || tx1.send(person_for_thread).unwrap());
+
+    let crossbeam_rx_0 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx0);
+    let crossbeam_rx_1 = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(rx1);
+    let received_person_0 = crossbeam_rx_0.recv().unwrap();
+    let received_person_1 = crossbeam_rx_1.recv().unwrap();
+    assert_eq!(received_person_0, person);
+    assert_eq!(received_person_1, person);
+}
+
+#[test]
+fn router_drops_callbacks_on_sender_shutdown() {
+    struct Dropper {
+        sender: Sender<i32>,
+    }
 
     impl Drop for Dropper {
         fn drop(&mut self) {
@@ -347,18 +1801,695 @@ fn router_big_data() {
 }
 
 #[test]
-fn shared_memory() {
-    let person = ("Patrick Walton".to_owned(), 29);
-    let person_and_shared_memory = (person, IpcSharedMemory::from_byte(0xba, 1024 * 1024));
-    let (tx, rx) = ipc::channel().unwrap();
-    tx.send(person_and_shared_memory.clone()).unwrap();
-    let received_person_and_shared_memory = rx.recv().unwrap();
-    assert_eq!(received_person_and_shared_memory, person_and_shared_memory);
-    assert!(person_and_shared_memory.1.iter().all(|byte| *byte == 0xba));
-    assert!(received_person_and_shared_memory
-        .1
-        .iter()
-        .all(|byte| *byte == 0xba));
+fn shared_memory() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let person_and_shared_memory = (person, IpcSharedMemory::from_byte(0xba, 1024 * 1024));
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(person_and_shared_memory.clone()).unwrap();
+    let received_person_and_shared_memory = rx.recv().unwrap();
+    assert_eq!(received_person_and_shared_memory, person_and_shared_memory);
+    assert!(person_and_shared_memory.1.iter().all(|byte| *byte == 0xba));
+    assert!(received_person_and_shared_memory
+        .1
+        .iter()
+        .all(|byte| *byte == 0xba));
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+#[test]
+fn shared_memory_atomics() {
+    let shmem = IpcSharedMemory::from_byte(0, 64);
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(shmem.clone()).unwrap();
+    let received_shmem: IpcSharedMemory = rx.recv().unwrap();
+
+    let counters = shmem.atomic_u32s(0, 4).unwrap();
+    let received_counters = received_shmem.atomic_u32s(0, 4).unwrap();
+    counters[1].fetch_add(5, Ordering::SeqCst);
+    received_counters[1].fetch_add(2, Ordering::SeqCst);
+    assert_eq!(counters[1].load(Ordering::SeqCst), 7);
+    assert_eq!(received_counters[1].load(Ordering::SeqCst), 7);
+
+    let flag = received_shmem.atomic_u64s(56, 1).unwrap();
+    flag[0].store(u64::MAX, Ordering::SeqCst);
+    assert!(shmem[56..].iter().all(|byte| *byte == 0xff));
+}
+
+#[test]
+fn shared_memory_racy_copy() {
+    let shmem = IpcSharedMemory::from_byte(0, 64);
+    let counters = shmem.atomic_u32s(0, 16).unwrap();
+    counters[3].store(7, Ordering::SeqCst);
+    let copy = shmem.racy_copy();
+    counters[3].store(8, Ordering::SeqCst);
+    assert_eq!(copy.atomic_u32s(12, 1).unwrap()[0].load(Ordering::SeqCst), 7);
+    assert!(!copy.is_sensitive());
+
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(copy).unwrap();
+    let received_copy: IpcSharedMemory = rx.recv().unwrap();
+    assert_eq!(&received_copy[12..16], &7u32.to_ne_bytes());
+    assert_eq!(shmem.atomic_u32s(12, 1).unwrap()[0].load(Ordering::SeqCst), 8);
+
+    assert!(IpcSharedMemory::new_sensitive(b"secret").racy_copy().is_sensitive());
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+#[test]
+fn shared_memory_revocation() {
+    let shmem = IpcSharedMemory::from_byte(0x5a, 3 * 4096);
+    let (tx, rx) = ipc::channel().unwrap();
+    let mut grant = shmem.send_revocable(&tx).unwrap();
+    let received: IpcSharedMemory = rx.recv().unwrap();
+    let received_clone = received.clone();
+    assert_eq!(received, shmem);
+
+    grant.region().unwrap().atomic_u32s(4096, 1).unwrap()[0].store(7, Ordering::SeqCst);
+    assert_eq!(received_clone.atomic_u32s(4096, 1).unwrap()[0].load(Ordering::SeqCst), 7);
+    shmem.atomic_u32s(0, 1).unwrap()[0].store(8, Ordering::SeqCst);
+    assert_eq!(received[0], 0x5a);
+
+    grant.revoke().unwrap();
+    assert!(grant.is_revoked() && grant.region().is_none());
+    assert!(received.iter().chain(received_clone.iter()).all(|&byte| byte == 0));
+    assert_eq!(received.len(), 3 * 4096);
+    assert_eq!(shmem[4], 0x5a);
+    grant.revoke().unwrap();
+}
+
+#[cfg(all(not(feature = "force-inprocess"), target_os = "macos"))]
+#[test]
+fn shared_memory_revocation_unsupported() {
+    let shmem = IpcSharedMemory::from_byte(0x5a, 4096);
+    let (tx, rx) = ipc::channel().unwrap();
+    match *shmem.send_revocable(&tx).unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::Unsupported)
+        }
+        ref error => panic!("unexpected error {}", error),
+    }
+    assert!(rx.try_recv().is_err());
+}
+
+#[cfg(all(not(feature = "force-inprocess"), target_os = "linux"))]
+#[test]
+fn shared_memory_numa_placement() {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let shmem = IpcSharedMemory::zeroed(16 * page_size);
+    assert_eq!(shmem.numa_nodes().unwrap().iter().sum::<usize>(), 0);
+    let shmem = shmem.with_numa_node(0).unwrap();
+    for &page in &[0, 5] {
+        shmem.atomic_u32s(page * page_size, 1).unwrap()[0].store(1, Ordering::SeqCst);
+    }
+    assert_eq!(shmem.numa_nodes().unwrap().iter().sum::<usize>(), 2);
+    assert_eq!(shmem.iter().filter(|&&byte| byte != 0).count(), 2);
+
+    for &node in &[1000, usize::MAX] {
+        match shmem.racy_copy().with_numa_node(node) {
+            Err(error) => assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("placed shared memory on NUMA node {}", node),
+        }
+    }
+}
+
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+          target_os = "ios", target_os = "macos"))]
+#[test]
+fn shared_memory_numa_placement_unsupported() {
+    let shmem = IpcSharedMemory::zeroed(4096);
+    assert!(shmem.iter().all(|&byte| byte == 0));
+    assert_eq!(shmem.numa_nodes().unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+    match shmem.with_numa_node(0) {
+        Err(error) => assert_eq!(error.kind(), std::io::ErrorKind::Unsupported),
+        Ok(_) => panic!("placed in-process shared memory on a NUMA node"),
+    }
+}
+
+#[test]
+fn shared_memory_atomics_checks() {
+    let shmem = IpcSharedMemory::from_byte(0, 64);
+    assert!(shmem.atomic_u64s(0, 8).is_some());
+    assert!(shmem.atomic_u64s(8, 8).is_none());
+    assert!(shmem.atomic_u32s(60, 2).is_none());
+    assert!(shmem.atomic_u32s(usize::MAX, 2).is_none());
+    assert!(shmem.atomic_u32s(0, 0).unwrap().is_empty());
+    let base = shmem.as_ptr() as usize;
+    for offset in 1..4 {
+        assert_eq!(shmem.atomic_u32s(offset, 1).is_some(), (base + offset) & 3 == 0);
+    }
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+#[test]
+fn ipc_mutex_and_condvar() {
+    let mutex = IpcMutex::new();
+    let condvar = IpcCondvar::new();
+    let counter = IpcSharedMemory::from_byte(0, 4);
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send((mutex.clone(), condvar.clone(), counter.clone())).unwrap();
+
+    let thread = thread::spawn(move || {
+        let (mutex, condvar, counter): (IpcMutex, IpcCondvar, IpcSharedMemory) =
+            rx.recv().unwrap();
+        let value = &counter.atomic_u32s(0, 1).unwrap()[0];
+        for _ in 0..100 {
+            let _guard = mutex.lock();
+            let current = value.load(Ordering::Relaxed);
+            thread::yield_now();
+            value.store(current + 1, Ordering::Relaxed);
+        }
+        let _guard = mutex.lock();
+        condvar.notify_all();
+    });
+
+    let value = &counter.atomic_u32s(0, 1).unwrap()[0];
+    for _ in 0..100 {
+        let _guard = mutex.lock();
+        let current = value.load(Ordering::Relaxed);
+        thread::yield_now();
+        value.store(current + 1, Ordering::Relaxed);
+    }
+    let mut guard = mutex.lock();
+    while value.load(Ordering::Relaxed) != 200 {
+        guard = condvar.wait(guard);
+    }
+    drop(guard);
+    thread.join().unwrap();
+}
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "illumos",
+    target_os = "solaris"
+)))]
+#[test]
+fn cross_process_ipc_mutex() {
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            let tx: IpcSender<(IpcMutex, IpcCondvar, IpcSharedMemory)> =
+                IpcSender::connect(name).unwrap();
+            let mutex = IpcMutex::new();
+            let condvar = IpcCondvar::new();
+            let flag = IpcSharedMemory::from_byte(0, 4);
+            let mut guard = mutex.lock();
+            tx.send((mutex.clone(), condvar.clone(), flag.clone())).unwrap();
+            while flag.atomic_u32s(0, 1).unwrap()[0].load(Ordering::Relaxed) == 0 {
+                guard = condvar.wait(guard);
+            }
+        })
+    };
+    let (_, (mutex, condvar, flag)): (_, (IpcMutex, IpcCondvar, IpcSharedMemory)) =
+        server.accept().unwrap();
+    {
+        let _guard = mutex.lock();
+        flag.atomic_u32s(0, 1).unwrap()[0].store(1, Ordering::Relaxed);
+        condvar.notify_one();
+    }
+    child_pid.wait();
+    assert!(mutex.try_lock().is_some());
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+#[test]
+fn ipc_semaphore_and_barrier() {
+    let semaphore = IpcSemaphore::new(2);
+    let barrier = IpcBarrier::new(3);
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send((semaphore.clone(), barrier.clone())).unwrap();
+    let (received_semaphore, received_barrier): (IpcSemaphore, IpcBarrier) = rx.recv().unwrap();
+    assert!(received_semaphore.try_acquire());
+    semaphore.acquire();
+    assert!(!semaphore.try_acquire());
+
+    let threads: Vec<_> = (0..2).map(|_| {
+        let semaphore = received_semaphore.clone();
+        let barrier = received_barrier.clone();
+        thread::spawn(move || {
+            let leader = barrier.wait();
+            semaphore.release();
+            leader
+        })
+    }).collect();
+    let leader = barrier.wait();
+    semaphore.acquire();
+    semaphore.acquire();
+    let leaders = threads.into_iter()
+                         .map(|thread| thread.join().unwrap())
+                         .fold(leader as u32, |count, leader| count + leader as u32);
+    assert_eq!(leaders, 1);
+}
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "illumos",
+    target_os = "solaris"
+)))]
+#[test]
+fn cross_process_ipc_barrier() {
+    let (server, name) = IpcOneShotServer::new().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            let tx: IpcSender<(IpcBarrier, IpcSemaphore)> = IpcSender::connect(name).unwrap();
+            let barrier = IpcBarrier::new(2);
+            let semaphore = IpcSemaphore::new(0);
+            tx.send((barrier.clone(), semaphore.clone())).unwrap();
+            barrier.wait();
+            semaphore.release();
+        })
+    };
+    let (_, (barrier, semaphore)): (_, (IpcBarrier, IpcSemaphore)) = server.accept().unwrap();
+    barrier.wait();
+    semaphore.acquire();
+    child_pid.wait();
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd")))]
+#[test]
+fn watch_peer() {
+    let (tx, mut rx) = ipc::channel::<u32>().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            tx.send(7).unwrap();
+            libc::_exit(3);
+        })
+    };
+    rx.watch_peer(child_pid as u32).unwrap();
+    // The message sent before exiting comes first, although `tx` keeps the channel open.
+    assert_eq!(rx.recv().unwrap(), 7);
+    let error = rx.recv().unwrap_err();
+    let peer_died = ipc::PeerDied::from_error(&error).unwrap();
+    assert_eq!(peer_died.pid, child_pid as u32);
+    assert_eq!(peer_died.exit_status.unwrap().code(), Some(3));
+    assert!(ipc::PeerDied::from_error(&rx.try_recv().unwrap_err()).is_some());
+    child_pid.wait();
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd")))]
+#[test]
+fn receiver_set_watch_peer() {
+    let (tx, mut rx) = ipc::channel::<u32>().unwrap();
+    let child_pid = unsafe {
+        fork(|| {
+            tx.send(7).unwrap();
+        })
+    };
+    rx.watch_peer(child_pid as u32).unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx).unwrap();
+    let mut results = vec![];
+    while results.len() < 2 {
+        results.extend(rx_set.select().unwrap());
+    }
+    match results.remove(0) {
+        ipc::IpcSelectionResult::MessageReceived(id, message) => {
+            assert_eq!((id, message.to::<u32>().unwrap()), (rx_id, 7))
+        }
+        _ => panic!("message lost"),
+    }
+    // The receiver is closed, although `tx` keeps the channel open.
+    match results.remove(0) {
+        ipc::IpcSelectionResult::ChannelClosed(id) => assert_eq!(id, rx_id),
+        _ => panic!("unexpected result"),
+    }
+    let peer_died = rx_set.take_peer_death(rx_id).unwrap();
+    assert_eq!(peer_died.pid, child_pid as u32);
+    assert!(peer_died.exit_status.unwrap().success());
+    assert!(rx_set.take_peer_death(rx_id).is_none());
+    child_pid.wait();
+    drop(tx);
+}
+
+// Mach ports don't survive `fork()`, so on macOS the peer can't send anything.
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "macos")))]
+#[test]
+fn watch_silent_peer() {
+    let (_tx, mut rx) = ipc::channel::<u32>().unwrap();
+    let (_set_tx, mut set_rx) = ipc::channel::<u32>().unwrap();
+    // The child waits for the pipe to close, so it is watched before it exits.
+    let mut pipe = [0; 2];
+    assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+    let child_pid = unsafe {
+        fork(|| {
+            libc::close(pipe[1]);
+            libc::read(pipe[0], [0u8; 1].as_mut_ptr() as *mut libc::c_void, 1);
+            libc::_exit(5);
+        })
+    };
+    rx.watch_peer(child_pid as u32).unwrap();
+    set_rx.watch_peer(child_pid as u32).unwrap();
+    unsafe {
+        libc::close(pipe[0]);
+        libc::close(pipe[1]);
+    }
+    let error = rx.recv().unwrap_err();
+    let peer_died = ipc::PeerDied::from_error(&error).unwrap();
+    assert_eq!(peer_died.pid, child_pid as u32);
+    assert_eq!(peer_died.exit_status.unwrap().code(), Some(5));
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(set_rx).unwrap();
+    match rx_set.select().unwrap().remove(0) {
+        ipc::IpcSelectionResult::ChannelClosed(id) => assert_eq!(id, rx_id),
+        _ => panic!("unexpected result"),
+    }
+    assert_eq!(rx_set.take_peer_death(rx_id).unwrap().pid, child_pid as u32);
+    child_pid.wait();
+}
+
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+          target_os = "ios"))]
+#[test]
+fn watch_peer_unsupported() {
+    let (_tx, mut rx) = ipc::channel::<u32>().unwrap();
+    let error = rx.watch_peer(std::process::id()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn fork_hooks() {
+    use std::os::unix::io::AsRawFd;
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (result_tx, result_rx) = ipc::channel().unwrap();
+    let (route_tx, route_rx) = ipc::channel::<u32>().unwrap();
+    // The descriptor may be reused in the child, so tell it by its inode.
+    let inode = |fd| unsafe {
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) == 0 { Some(stat.st_ino) } else { None }
+    };
+    let route_fd = route_rx.as_raw_fd();
+    let route_inode = inode(route_fd);
+    let routed = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(route_rx);
+    // Wait for the route to be added, so the router thread holds the receiver.
+    route_tx.send(0).unwrap();
+    assert_eq!(routed.recv().unwrap(), 0);
+    fork::prepare();
+    let child_pid = unsafe {
+        fork(|| {
+            fork::child();
+            let route_closed = inode(route_fd) != route_inode;
+            fork::invalidate(&rx).unwrap();
+            let (child_tx, child_rx) = ipc::channel::<u32>().unwrap();
+            let child_routed = ROUTER.route_ipc_receiver_to_new_crossbeam_receiver(child_rx);
+            child_tx.send(2).unwrap();
+            result_tx.send((rx.recv().is_err(), child_routed.recv().unwrap(), route_closed))
+                     .unwrap();
+        })
+    };
+    fork::parent();
+    assert_eq!(result_rx.recv().unwrap(), (true, 2, true));
+    tx.send(1).unwrap();
+    assert_eq!(rx.recv().unwrap(), 1);
+    route_tx.send(3).unwrap();
+    assert_eq!(routed.recv().unwrap(), 3);
+    child_pid.wait();
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+#[test]
+fn ring_channel_drops_oldest() {
+    let (tx, rx) = ring::channel(3, 32).unwrap();
+    assert!(rx.try_recv().is_err());
+    for i in 0..10u32 {
+        tx.send((i, format!("sample {}", i))).unwrap();
+    }
+    assert_eq!(rx.dropped_count(), 7);
+    assert_eq!(rx.recv().unwrap(), (7, "sample 7".to_owned()));
+    assert_eq!(rx.try_recv().unwrap(), (8, "sample 8".to_owned()));
+    tx.send((10, "sample 10".to_owned())).unwrap();
+    assert_eq!(rx.recv().unwrap(), (9, "sample 9".to_owned()));
+    assert_eq!(rx.recv().unwrap(), (10, "sample 10".to_owned()));
+    assert!(tx.send((11, "x".repeat(64))).is_err());
+
+    let tx_clone = tx.clone();
+    drop(tx);
+    tx_clone.send((12, String::new())).unwrap();
+    drop(tx_clone);
+    assert_eq!(rx.recv().unwrap(), (12, String::new()));
+    assert!(rx.recv().is_err());
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+#[test]
+fn ring_channel_rejects_attachments() {
+    let (tx, _rx) = ring::channel(1, 64).unwrap();
+    let (sub_tx, _sub_rx) = ipc::channel::<()>().unwrap();
+    assert!(tx.send(sub_tx).is_err());
+    assert!(ring::channel::<()>(0, 64).is_err());
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+#[test]
+fn ring_channel_transfer() {
+    let (tx, rx) = ring::channel::<u64>(4, 8).unwrap();
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(tx).unwrap();
+    let tx: ring::IpcRingSender<u64> = super_rx.recv().unwrap();
+    let thread = thread::spawn(move || {
+        for i in 0..100 {
+            tx.send(i).unwrap();
+        }
+    });
+    let mut last = None;
+    while let Ok(value) = rx.recv() {
+        assert!(last.is_none_or(|last| value > last));
+        last = Some(value);
+    }
+    assert_eq!(last, Some(99));
+    thread.join().unwrap();
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+#[test]
+fn state_reads_are_not_torn() {
+    let mut publisher = Publisher::new([0u64; 16]);
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(publisher.subscriber()).unwrap();
+    let subscriber: Subscriber<[u64; 16]> = rx.recv().unwrap();
+    assert_eq!(subscriber.read_with_version(), ([0; 16], 0));
+
+    let publishing = thread::spawn(move || {
+        for i in 1..=10_000 {
+            publisher.publish([i; 16]);
+        }
+    });
+    let mut last_version = 0;
+    while last_version < 10_000 {
+        let (value, version) = subscriber.read_with_version();
+        assert!(value.iter().all(|&word| word == value[0]));
+        assert_eq!(value[0], version);
+        assert!(version >= last_version);
+        last_version = version;
+    }
+    publishing.join().unwrap();
+
+    // Subscribers check the region fits the type.
+    let (tx, rx) = ipc::channel().unwrap();
+    tx.send(subscriber).unwrap();
+    assert!(rx.recv_opaque().unwrap().to::<Subscriber<[u64; 4]>>().is_err());
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+#[test]
+fn watch_latest_value() {
+    let (tx, rx) = ipc::watch("initial".to_owned(), 32).unwrap();
+    assert!(!rx.has_changed());
+    assert_eq!(rx.borrow().unwrap(), "initial");
+    let other_rx = rx.clone();
+    tx.send("first".to_owned()).unwrap();
+    tx.send("second".to_owned()).unwrap();
+    assert!(rx.has_changed());
+    rx.changed().unwrap();
+    assert_eq!(rx.borrow().unwrap(), "second");
+    assert!(!rx.has_changed());
+    assert!(other_rx.has_changed());
+    assert!(tx.send("x".repeat(64)).is_err());
+
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(tx).unwrap();
+    let tx: IpcWatchSender<String> = super_rx.recv().unwrap();
+    let thread = thread::spawn(move || {
+        tx.send("third".to_owned()).unwrap();
+    });
+    rx.changed().unwrap();
+    assert_eq!(rx.borrow().unwrap(), "third");
+    thread.join().unwrap();
+    assert!(rx.changed().is_err());
+}
+
+#[test]
+fn oneshot() {
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (tx, rx) = ipc::oneshot().unwrap();
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(tx).unwrap();
+    let tx: IpcOneshotSender<Person> = super_rx.recv().unwrap();
+    let thread = thread::spawn(move || tx.send(person.clone()).unwrap());
+    assert_eq!(rx.recv().unwrap(), ("Patrick Walton".to_owned(), 29));
+    thread.join().unwrap();
+
+    let (tx, rx) = ipc::oneshot::<Person>().unwrap();
+    drop(tx);
+    assert!(rx.recv().is_err());
+
+    let (tx, _rx) = ipc::oneshot::<Person>().unwrap();
+    assert!(bincode::serialize_into(Vec::new(), &tx).is_ok());
+    assert!(bincode::serialize_into(Vec::new(), &tx).is_err());
+    assert!(tx.send(("Pat".to_owned(), 1)).is_err());
+
+    // Channels with a size limit serialize the sender once too.
+    let (tx, rx) = ipc::oneshot::<Person>().unwrap();
+    let limited = BincodeConfig::new().limit(64);
+    super_tx.with_bincode_config(limited).send(tx).unwrap();
+    let tx: IpcOneshotSender<Person> = super_rx.recv().unwrap();
+    tx.send(("Pat".to_owned(), 2)).unwrap();
+    assert_eq!(rx.recv().unwrap(), ("Pat".to_owned(), 2));
+
+    let (tx, rx) = ipc::oneshot().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    tx.send(sub_tx).unwrap();
+    let sub_tx: IpcSender<u32> = rx.recv().unwrap();
+    sub_tx.send(7).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), 7);
+}
+
+#[test]
+fn mux_sub_channels() {
+    let (tx, rx) = mux::channel().unwrap();
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(tx).unwrap();
+    let tx: mux::MuxSender = super_rx.recv().unwrap();
+
+    let person = ("Patrick Walton".to_owned(), 29);
+    let (sub_tx, sub_rx) = ipc::channel().unwrap();
+    let people = tx.sub_sender::<(Person, IpcSender<Person>)>(1);
+    let numbers = tx.sub_sender::<u32>(2);
+    let numbers_rx = rx.sub_receiver::<u32>(2);
+    assert!(numbers_rx.try_recv().is_err());
+    for i in 0..10 {
+        numbers.send(i).unwrap();
+    }
+    people.send((person.clone(), sub_tx)).unwrap();
+
+    let thread = thread::spawn(move || {
+        (0..10).map(|_| numbers_rx.recv().unwrap()).collect::<Vec<_>>()
+    });
+    let (received_person, sender) = rx.sub_receiver::<(Person, IpcSender<Person>)>(1)
+                                      .recv()
+                                      .unwrap();
+    assert_eq!(received_person, person);
+    sender.send(person.clone()).unwrap();
+    assert_eq!(sub_rx.recv().unwrap(), person);
+    assert_eq!(thread.join().unwrap(), (0..10).collect::<Vec<_>>());
+
+    drop((tx, people, numbers));
+    assert!(rx.sub_receiver::<u32>(3).recv().is_err());
+}
+
+#[test]
+fn mux_flow_control() {
+    let (tx, rx) = mux::channel_with_flow_control(4).unwrap();
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(tx).unwrap();
+    let tx: mux::MuxSender = super_rx.recv().unwrap();
+
+    let chatty = tx.sub_sender::<u32>(1);
+    let quiet = tx.sub_sender::<u32>(2);
+    for i in 0..4 {
+        chatty.send(i).unwrap();
+    }
+    assert!(!chatty.poll_ready().unwrap());
+    assert!(quiet.poll_ready().unwrap());
+    quiet.send(42).unwrap();
+    assert_eq!(rx.sub_receiver::<u32>(2).recv().unwrap(), 42);
+    assert!(!chatty.poll_ready().unwrap());
+
+    let chatty_rx = rx.sub_receiver::<u32>(1);
+    let thread = thread::spawn(move || {
+        for i in 4..10 {
+            chatty.send(i).unwrap();
+        }
+    });
+    assert_eq!((0..10).map(|_| chatty_rx.recv().unwrap()).collect::<Vec<_>>(),
+               (0..10).collect::<Vec<_>>());
+    thread.join().unwrap();
+}
+
+#[test]
+fn mux_transaction() {
+    let (tx, rx) = mux::channel_with_flow_control(2).unwrap();
+    let names_rx = rx.sub_receiver::<String>(1);
+    let numbers_rx = rx.sub_receiver::<u32>(2);
+    let mut transaction = tx.transaction();
+    transaction.push(2, 29u32).unwrap();
+    transaction.push(1, "Patrick Walton".to_owned()).unwrap();
+    transaction.push(2, 30u32).unwrap();
+    let error = transaction.push(2, 31u32).unwrap_err();
+    match *error {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput)
+        }
+        _ => panic!("unexpected error {:?}", error),
+    }
+    transaction.commit().unwrap();
+    assert!(!tx.sub_sender::<u32>(2).poll_ready().unwrap());
+
+    // Receiving for one sub-channel queues the whole transaction.
+    assert_eq!(names_rx.recv().unwrap(), "Patrick Walton");
+    assert_eq!(numbers_rx.try_recv().unwrap(), 29);
+    assert_eq!(numbers_rx.try_recv().unwrap(), 30);
+}
+
+#[test]
+fn mux_congestion_handler() {
+    let (tx, rx) = mux::channel_with_flow_control(2).unwrap();
+    let sub_tx = tx.sub_sender::<u32>(1);
+    let (event_tx, event_rx) = crossbeam_channel::unbounded();
+    sub_tx.set_congestion_handler(Box::new(move |congested| event_tx.send(congested).unwrap()));
+
+    sub_tx.send(1).unwrap();
+    assert!(event_rx.try_recv().is_err());
+    sub_tx.send(2).unwrap();
+    assert!(event_rx.recv().unwrap());
+    assert!(!sub_tx.poll_ready().unwrap());
+
+    assert_eq!(rx.sub_receiver::<u32>(1).recv().unwrap(), 1);
+    assert!(!event_rx.recv().unwrap());
+    assert!(sub_tx.poll_ready().unwrap());
+    sub_tx.send(3).unwrap();
+    assert!(event_rx.recv().unwrap());
 }
 
 #[test]
@@ -401,6 +2532,76 @@ fn try_recv() {
     assert!(rx.try_recv().is_err());
 }
 
+#[test]
+fn nonblocking_recv() {
+    let (tx, mut rx) = ipc::channel::<u32>().unwrap();
+    rx.set_nonblocking(true);
+    match *rx.recv().unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock)
+        }
+        ref error => panic!("unexpected error {}", error),
+    }
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    assert_eq!(rx.recv().unwrap(), 1);
+    assert_eq!(rx.recv_opaque().unwrap().to::<u32>().unwrap(), 2);
+    assert!(rx.recv_opaque().is_err());
+
+    rx.set_nonblocking(false);
+    let sender = thread::spawn(move || {
+        thread::sleep(std::time::Duration::from_millis(50));
+        tx.send(3).unwrap();
+    });
+    assert_eq!(rx.recv().unwrap(), 3);
+    sender.join().unwrap();
+}
+
+#[test]
+fn sender_connectivity() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let other_tx = tx.clone();
+    assert!(tx.is_connected());
+    assert_ne!(rx.sender_count(), Some(0));
+    #[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+              target_os = "ios"))]
+    assert_eq!(rx.sender_count(), Some(2));
+
+    // A receiver in a message not yet received keeps the channel connected.
+    let (super_tx, super_rx) = ipc::channel().unwrap();
+    super_tx.send(rx).unwrap();
+    assert!(tx.is_connected());
+    let rx = super_rx.recv().unwrap();
+
+    drop(tx);
+    assert_ne!(rx.sender_count(), Some(0));
+    drop(other_tx);
+    assert_eq!(rx.sender_count(), Some(0));
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    drop(rx);
+    assert!(!tx.is_connected());
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+#[test]
+fn weak_sender() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let weak_tx = tx.downgrade().unwrap();
+    let other_tx = weak_tx.clone().upgrade().unwrap();
+    assert_ne!(other_tx.sender_id(), tx.sender_id());
+    other_tx.send(1).unwrap();
+    drop((tx, other_tx));
+    assert_eq!(rx.recv().unwrap(), 1);
+    match *rx.recv().unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset)
+        }
+        ref error => panic!("unexpected error {}", error),
+    }
+    assert!(weak_tx.upgrade().is_none());
+}
+
 #[test]
 fn multiple_paths_to_a_sender() {
     let person = ("Patrick Walton".to_owned(), 29);
@@ -435,6 +2636,52 @@ fn bytes() {
     assert_eq!(&bytes, &received_bytes[..]);
 }
 
+#[cfg(all(not(feature = "force-inprocess"), target_os = "macos"))]
+#[test]
+fn out_of_line_threshold() {
+    ipc::set_out_of_line_threshold(16);
+    let bytes: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let (tx, rx) = ipc::bytes_channel().unwrap();
+    tx.send(&bytes).unwrap();
+    assert_eq!(rx.recv().unwrap(), bytes);
+    tx.send(&bytes[..8]).unwrap();
+    assert_eq!(rx.recv().unwrap(), &bytes[..8]);
+    ipc::set_out_of_line_threshold(usize::MAX);
+}
+
+#[test]
+fn bytes_send_vec() {
+    let (tx, rx) = ipc::bytes_channel().unwrap();
+    tx.send_vec(vec![1, 2, 3, 4, 5, 6, 7]).unwrap();
+    assert_eq!(rx.recv().unwrap(), [1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn bytes_bulk() {
+    let (tx, rx) = ipc::bytes_channel().unwrap();
+    let small = [1, 2, 3, 4, 5, 6, 7];
+    tx.send(&small[..]).unwrap();
+    assert_eq!(&*rx.recv_bulk().unwrap(), &small[..]);
+    tx.send(&[]).unwrap();
+    let empty = rx.recv_bulk().unwrap();
+    assert!(empty.is_empty());
+    // An empty region can still be cloned and sent on.
+    let (region_tx, region_rx) = ipc::channel().unwrap();
+    region_tx.send(empty.clone()).unwrap();
+    let empty: IpcSharedMemory = region_rx.recv().unwrap();
+    assert!(empty.is_empty());
+
+    let large: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let thread = {
+        let large = large.clone();
+        thread::spawn(move || tx.send(&large).unwrap())
+    };
+    let received = rx.recv_bulk().unwrap();
+    thread.join().unwrap();
+    assert_eq!(received.len(), large.len());
+    assert!(*received == large[..]);
+}
+
 #[test]
 fn embedded_bytes_receivers() {
     let (sub_tx, sub_rx) = ipc::bytes_channel().unwrap();
@@ -447,6 +2694,495 @@ fn embedded_bytes_receivers() {
     assert_eq!(&bytes, &received_bytes[..]);
 }
 
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+)))]
+#[test]
+fn spawn_with_bootstrap() {
+    if env::var(process::BOOTSTRAP_ENV_VAR).is_ok() {
+        // Running as the child spawned below.
+        let (tx, rx) = ipc::channel::<String>().unwrap();
+        let bootstrap_tx = process::bootstrap_sender().unwrap();
+        bootstrap_tx.send(tx).unwrap();
+        let request = rx.recv().unwrap();
+        bootstrap_tx.send(IpcSender::connect(request).unwrap()).unwrap();
+        return
+    }
+
+    let command = child_command("spawn_with_bootstrap");
+    let (mut child, rx, tx): (_, IpcReceiver<IpcSender<String>>, _) =
+        process::spawn(command, Duration::from_secs(30)).unwrap();
+    let (server, name) = IpcOneShotServer::<String>::new().unwrap();
+    tx.send(name).unwrap();
+    let child_tx = rx.recv().unwrap();
+    child_tx.send("Hello".to_owned()).unwrap();
+    let (_, greeting) = server.accept().unwrap();
+    assert_eq!(greeting, "Hello");
+    assert!(child.wait().unwrap().success());
+
+    let command = child_command("no_such_test");
+    let error = process::spawn::<()>(command, Duration::from_millis(500)).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+}
+
+#[cfg(not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+)))]
+#[test]
+fn supervisor_restarts_child() {
+    use supervisor::{Supervisor, SupervisorConfig};
+
+    if env::var(process::BOOTSTRAP_ENV_VAR).is_ok() {
+        // Running as the supervised child: echo requests, exiting on 0.
+        let (tx, rx) = ipc::channel::<(u32, IpcSender<u32>)>().unwrap();
+        let bootstrap_tx = process::bootstrap_sender().unwrap();
+        bootstrap_tx.send(tx).unwrap();
+        while let Ok((request, reply_tx)) = rx.recv() {
+            if request == 0 {
+                std::process::exit(1);
+            }
+            reply_tx.send(request).unwrap();
+        }
+        return
+    }
+
+    let config = SupervisorConfig::new().backoff(Duration::from_millis(10), Duration::from_secs(1))
+                                        .max_restarts(1);
+    let supervisor = Supervisor::start(|| child_command("supervisor_restarts_child"), config)
+                                .unwrap();
+    let requests = supervisor.sender(|tx: &IpcSender<(u32, IpcSender<u32>)>| tx.clone());
+    let (reply_tx, reply_rx) = ipc::channel().unwrap();
+    requests.send((1, reply_tx.clone())).unwrap();
+    assert_eq!(reply_rx.recv().unwrap(), 1);
+
+    requests.send((0, reply_tx.clone())).unwrap();
+    while supervisor.restarts() == 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // This waits for the new child to connect.
+    requests.clone().send((2, reply_tx.clone())).unwrap();
+    assert_eq!(reply_rx.recv().unwrap(), 2);
+
+    requests.send((0, reply_tx.clone())).unwrap();
+    while !supervisor.gave_up() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    match *requests.send((3, reply_tx)).unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => assert_eq!(error.kind(), ErrorKind::NotConnected),
+        ref error => panic!("unexpected error {}", error),
+    }
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris",
+                                                target_os = "macos")))]
+#[test]
+fn sender_to_env() {
+    const VAR_NAME: &str = "IPC_CHANNEL_TEST_SENDER";
+    if let Ok(value) = env::var(VAR_NAME) {
+        // Running as one of the children spawned below.
+        match IpcSender::<(String, bool)>::from_env(VAR_NAME) {
+            Ok(tx) => {
+                let taken_twice = IpcSender::<(String, bool)>::from_env(VAR_NAME).is_ok();
+                tx.send(("Hello".to_owned(), taken_twice)).unwrap();
+            }
+            Err(error) => assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", value),
+        }
+        return
+    }
+
+    let (tx, rx) = ipc::channel::<(String, bool)>().unwrap();
+    let mut command = child_command("sender_to_env");
+    tx.to_env(VAR_NAME, &mut command).unwrap();
+    let mut child = command.spawn().unwrap();
+    // On macOS, the sender is served until the command is dropped, so the child could
+    // take it twice.
+    let (_, taken_twice) = rx.recv().unwrap();
+    drop((command, tx));
+    assert_eq!(taken_twice, cfg!(target_os = "macos"));
+    assert!(child.wait().unwrap().success());
+    // The child's copies were the last ones.
+    assert!(rx.recv().is_err());
+
+    // Standard input is no socket.
+    for value in &["fd:none", "fd:0", "mach:"] {
+        let status = child_command("sender_to_env")
+            .env(VAR_NAME, value)
+            .stdin(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "{}", value);
+    }
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn descriptors_are_close_on_exec() {
+    use std::os::unix::io::{AsRawFd, RawFd};
+    fn close_on_exec(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) & libc::FD_CLOEXEC != 0 }
+    }
+
+    let (tx, rx) = ipc::channel::<IpcSender<u32>>().unwrap();
+    let (inner_tx, inner_rx) = ipc::channel::<u32>().unwrap();
+    assert!(close_on_exec(tx.as_raw_fd()) && close_on_exec(rx.as_raw_fd()));
+    let duplicate_rx = inner_rx.try_duplicate().unwrap();
+    assert!(close_on_exec(duplicate_rx.as_raw_fd()));
+    tx.send(inner_tx).unwrap();
+    let received_tx = rx.recv().unwrap();
+    assert!(close_on_exec(received_tx.as_raw_fd()));
+
+    let (server, name) = IpcOneShotServer::<()>::new().unwrap();
+    assert!(close_on_exec(server.as_raw_fd()));
+    let server_tx = IpcSender::<()>::connect(name).unwrap();
+    assert!(close_on_exec(server_tx.as_raw_fd()));
+    server_tx.send(()).unwrap();
+    let (server_rx, ()) = server.accept().unwrap();
+    assert!(close_on_exec(server_rx.as_raw_fd()));
+
+    received_tx.prepare_for_inheritance().unwrap();
+    inner_rx.prepare_for_inheritance().unwrap();
+    assert!(!close_on_exec(received_tx.as_raw_fd()) && !close_on_exec(inner_rx.as_raw_fd()));
+    received_tx.send(1).unwrap();
+    assert_eq!(inner_rx.recv().unwrap(), 1);
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn checkpoint_rebinds_one_shot_servers() {
+    use checkpoint;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    let (server, name) = IpcOneShotServer::<u32>::new().unwrap();
+    // As if restored on a machine without the socket file, or its directory.
+    fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    assert!(IpcSender::<u32>::connect(name.clone()).is_err());
+
+    let (tx, rx) = ipc::channel().unwrap();
+    assert!(checkpoint::restored().unwrap() >= 1);
+    assert!(unsafe { libc::fcntl(server.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC != 0);
+    IpcSender::connect(name.clone()).unwrap().send(7).unwrap();
+    let (_, first) = server.accept().unwrap();
+    assert_eq!(first, 7);
+    tx.send(8).unwrap();
+    assert_eq!(rx.recv().unwrap(), 8);
+
+    // The directory is only reused if no other user could have put sockets there.
+    let (server, name) = IpcOneShotServer::<u32>::new().unwrap();
+    let dir = Path::new(&name).parent().unwrap();
+    fs::remove_file(&name).unwrap();
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o733)).unwrap();
+    let error = checkpoint::restored().unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).unwrap();
+    assert!(checkpoint::restored().unwrap() >= 1);
+    IpcSender::connect(name.clone()).unwrap().send(9).unwrap();
+    assert_eq!(server.accept().unwrap().1, 9);
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn diagnostics_dump() {
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    tx.send(vec![1; 100]).unwrap();
+    let (server, name) = IpcOneShotServer::<()>::new().unwrap();
+    let connected = IpcSender::<()>::connect(name.clone()).unwrap();
+    let shmem = IpcSharedMemory::from_byte(0, 12345);
+    let receiver_set = IpcReceiverSet::new().unwrap();
+
+    let report = ipc::diagnostics::dump().unwrap();
+    assert_eq!(report.pid, std::process::id());
+    let channel = |fd: RawFd| {
+        report.channels.iter().find(|channel| channel.handle == Some(fd.into()))
+    };
+    let received = channel(rx.as_raw_fd()).unwrap();
+    assert!(received.queued_bytes.unwrap() > 100);
+    assert_eq!((received.peer_pid, &received.label), (Some(std::process::id()), &None));
+    assert_eq!(channel(connected.as_raw_fd()).unwrap().label.as_ref(), Some(&name));
+    assert!(report.servers.iter().any(|server| server.name == name));
+    assert!(report.shared_memory_regions.iter().any(|region| region.length == 12345));
+    assert!(report.shared_memory_in_use >= shmem.len());
+    assert!(report.receiver_sets >= 1);
+    assert!(report.to_string().contains(&format!("at {}", name)));
+
+    drop((server, receiver_set));
+    let report = ipc::diagnostics::dump().unwrap();
+    assert!(report.servers.iter().all(|server| server.name != name));
+}
+
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+          target_os = "ios"))]
+#[test]
+fn diagnostics_dump_in_process() {
+    let (server, name) = ipc::IpcOneShotServer::<()>::new().unwrap();
+    let report = ipc::diagnostics::dump().unwrap();
+    assert!(report.servers.iter().any(|server| server.name == name && server.handle.is_none()));
+    assert!(report.channels.is_empty() && report.shared_memory_regions.is_empty());
+    drop(server);
+}
+
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+          target_os = "ios", target_os = "macos"))]
+#[test]
+fn sender_to_env_unsupported() {
+    let (tx, _rx) = ipc::channel::<u32>().unwrap();
+    let mut command = std::process::Command::new("true");
+    let error = tx.to_env("IPC_CHANNEL_TEST_SENDER", &mut command).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    let error = tx.prepare_for_inheritance().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[cfg(all(not(feature = "force-inprocess"), any(target_os = "linux",
+                                                target_os = "openbsd",
+                                                target_os = "freebsd",
+                                                target_os = "illumos",
+                                                target_os = "solaris")))]
+#[test]
+fn one_shot_server_from_listen_fds() {
+    use std::os::unix::process::CommandExt;
+
+    const VAR_NAME: &str = "IPC_CHANNEL_TEST_LISTEN_FDS";
+    if env::var(VAR_NAME).is_ok() {
+        // Running as the child spawned below, with the socket as descriptor 3.
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        for &(fds, kind) in &[("-1", ErrorKind::InvalidData),
+                              ("2147483647", ErrorKind::InvalidData),
+                              ("2", ErrorKind::InvalidInput)] {
+            env::set_var("LISTEN_FDS", fds);
+            let error = IpcOneShotServer::<String>::from_listen_fds().err().unwrap();
+            assert_eq!(error.kind(), kind, "{}", fds);
+        }
+        // The socket was left open, and can still be adopted.
+        assert!(unsafe { libc::fcntl(3, libc::F_GETFD) } >= 0);
+        env::set_var("LISTEN_FDS", "1");
+        let mut servers = IpcOneShotServer::<String>::from_listen_fds().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert!(IpcOneShotServer::<String>::from_listen_fds().unwrap().is_empty());
+        let (_, value) = servers.pop().unwrap().accept().unwrap();
+        assert_eq!(value, "Hello");
+        return
+    }
+
+    // Not socket-activated.
+    assert!(IpcOneShotServer::<String>::from_listen_fds().unwrap().is_empty());
+
+    let path = env::temp_dir().join(format!("ipc-channel-listen-fds-{}", std::process::id()));
+    let path_string = path.to_str().unwrap().to_owned();
+    let listener = unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0);
+        assert!(fd >= 0);
+        let mut sockaddr: libc::sockaddr_un = std::mem::zeroed();
+        sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dest, &byte) in sockaddr.sun_path.iter_mut().zip(path_string.as_bytes()) {
+            *dest = byte as libc::c_char;
+        }
+        let len = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+        assert_eq!(libc::bind(fd, &sockaddr as *const _ as *const libc::sockaddr, len), 0);
+        assert_eq!(libc::listen(fd, 1), 0);
+        fd
+    };
+
+    let mut command = child_command("one_shot_server_from_listen_fds");
+    command.env(VAR_NAME, "1");
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(listener, 3) < 0 || libc::fcntl(3, libc::F_SETFD, 0) < 0 {
+                return Err(Error::last_os_error())
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    let tx = IpcSender::connect(path_string).unwrap();
+    tx.send("Hello".to_owned()).unwrap();
+    let status = child.wait().unwrap();
+    unsafe {
+        libc::close(listener);
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert!(status.success());
+}
+
+#[cfg(all(feature = "test-support", not(any(
+    feature = "force-inprocess",
+    target_os = "windows",
+    target_os = "android",
+    target_os = "ios"
+))))]
+#[test]
+fn test_group() {
+    use testing::{self, TestGroup};
+
+    if env::var(testing::MEMBER_ENV_VAR).is_ok() {
+        // Running as one of the children spawned below.
+        let member = testing::join_group().unwrap();
+        match member.name() {
+            "server" => {
+                let (tx, rx) = ipc::channel::<String>().unwrap();
+                member.publish("requests", tx).unwrap();
+                member.barrier().unwrap();
+                assert_eq!(rx.recv().unwrap(), "Hello");
+                let parent_tx: IpcSender<String> = member.look_up("replies").unwrap();
+                parent_tx.send("Done".to_owned()).unwrap();
+            }
+            "client" => {
+                let requests: IpcSender<String> = member.look_up("requests").unwrap();
+                member.barrier().unwrap();
+                requests.send("Hello".to_owned()).unwrap();
+            }
+            _ => panic!("child failed"),
+        }
+        return
+    }
+
+    let command = || {
+        let mut command = child_command("test_group");
+        command.stderr(Stdio::null());
+        command
+    };
+    let mut group = TestGroup::new(Duration::from_secs(30));
+    group.spawn("client", command()).unwrap();
+    group.spawn("server", command()).unwrap();
+    let (tx, rx) = ipc::channel::<String>().unwrap();
+    group.publish("replies", tx);
+    let requests: IpcSender<String> = group.look_up("requests").unwrap();
+    group.barrier().unwrap();
+    assert_eq!(rx.recv().unwrap(), "Done");
+    drop(requests);
+    group.join().unwrap();
+
+    // A child panicking before the barrier fails it, with the panic message.
+    let mut group = TestGroup::new(Duration::from_secs(30));
+    group.spawn("panicking", command()).unwrap();
+    let error = group.barrier().unwrap_err();
+    assert!(error.to_string().contains("child failed"), "{}", error);
+
+    let group = TestGroup::new(Duration::from_millis(100));
+    let error = group.look_up::<String>("nothing").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert_eq!(testing::join_group().unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[cfg(all(feature = "evented", not(feature = "force-inprocess"), any(target_os = "linux",
+                                                                     target_os = "openbsd",
+                                                                     target_os = "freebsd",
+                                                                     target_os = "illumos",
+                                                                     target_os = "solaris")))]
+#[test]
+fn evented_receiver_and_server() {
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+
+    let poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    let (server, name) = IpcOneShotServer::<IpcSender<u32>>::new().unwrap();
+    poll.register(&server, Token(0), Ready::readable(), PollOpt::level()).unwrap();
+    poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+    assert!(events.is_empty());
+
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    IpcSender::connect(name).unwrap().send(tx).unwrap();
+    poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+    let tokens: Vec<Token> = events.iter().map(|event| event.token()).collect();
+    assert_eq!(tokens, [Token(0)]);
+    poll.deregister(&server).unwrap();
+    let (_, tx) = server.accept().unwrap();
+
+    poll.register(&rx, Token(1), Ready::readable(), PollOpt::edge()).unwrap();
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+    let tokens: Vec<Token> = events.iter().map(|event| event.token()).collect();
+    assert_eq!(tokens, [Token(1)]);
+    assert_eq!(rx.try_recv().unwrap(), 1);
+    assert_eq!(rx.try_recv().unwrap(), 2);
+    assert!(rx.try_recv().is_err());
+
+    // Losing the last sender makes the receiver readable too.
+    drop(tx);
+    poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(events.iter().next().unwrap().token(), Token(1));
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+#[test]
+fn connect_to_dropped_one_shot_server() {
+    let (server, name) = ipc::IpcOneShotServer::<u32>::new().unwrap();
+    drop(server);
+    let error = ipc::IpcSender::<u32>::connect(name).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+    let (server, name) = ipc::IpcOneShotServer::<u32>::new().unwrap();
+    let tx = ipc::IpcSender::connect(name.clone()).unwrap();
+    tx.send(1).unwrap();
+    let (_, value) = server.accept().unwrap();
+    assert_eq!(value, 1);
+    assert!(ipc::IpcSender::<u32>::connect(name).is_err());
+}
+
+#[cfg(all(feature = "test-support", any(feature = "force-inprocess", target_os = "windows",
+                                        target_os = "android", target_os = "ios")))]
+#[test]
+fn inprocess_reset() {
+    // Resetting would break the one-shot servers of tests running concurrently, so
+    // this runs in a process of its own.
+    const VAR_NAME: &str = "IPC_CHANNEL_TEST_INPROCESS_RESET";
+    if std::env::var(VAR_NAME).is_err() {
+        let status = child_command("inprocess_reset")
+            .env(VAR_NAME, "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return
+    }
+
+    let (server, name) = ipc::IpcOneShotServer::<u32>::new().unwrap();
+    std::mem::forget(server);
+    let (server, other_name) = ipc::IpcOneShotServer::<u32>::new().unwrap();
+    ipc::inprocess::reset();
+    let error = ipc::IpcSender::<u32>::connect(name).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    assert!(ipc::IpcSender::<u32>::connect(other_name).is_err());
+    assert!(server.accept().is_err());
+}
+
+#[cfg(any(feature = "force-inprocess", target_os = "windows", target_os = "android",
+          target_os = "ios", target_os = "macos"))]
+#[test]
+fn one_shot_server_from_listen_fds_unsupported() {
+    let error = match ipc::IpcOneShotServer::<u32>::from_listen_fds() {
+        Ok(_) => panic!("adopted sockets from systemd"),
+        Err(error) => error,
+    };
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+}
+
 #[test]
 fn test_so_linger() {
     let (sender, receiver) = ipc::channel().unwrap();
@@ -516,6 +3252,34 @@ fn transfer_closed_sender() {
     let _transferred_tx = main_rx.recv().unwrap();
 }
 
+#[cfg(feature = "async")]
+#[test]
+fn test_receiver_set_stream() {
+    let (tx, rx) = ipc::channel().unwrap();
+    let mut rx_set = IpcReceiverSet::new().unwrap();
+    let rx_id = rx_set.add(rx).unwrap();
+    let mut stream = rx_set.into_stream();
+    assert!(stream.poll().unwrap().is_not_ready());
+    tx.send(29).unwrap();
+    drop(tx);
+    let mut results = vec![];
+    while results.len() < 2 {
+        if let Async::Ready(Some(result)) = stream.poll().unwrap() {
+            results.push(result)
+        }
+    }
+    match results.remove(0) {
+        ipc::IpcSelectionResult::MessageReceived(id, message) => {
+            assert_eq!((id, message.to::<u32>().unwrap()), (rx_id, 29))
+        }
+        _ => panic!("message lost"),
+    }
+    match results.remove(0) {
+        ipc::IpcSelectionResult::ChannelClosed(id) => assert_eq!(id, rx_id),
+        _ => panic!("unexpected result"),
+    }
+}
+
 #[cfg(feature = "async")]
 #[test]
 fn test_bytes_receiver_stream() {
@@ -525,3 +3289,329 @@ fn test_bytes_receiver_stream() {
     tx.send(payload).unwrap();
     assert_eq!(rx.poll().unwrap(), Async::Ready(Some(payload.to_vec())));
 }
+
+#[test]
+fn channel_pool_replenishes() {
+    let (acceptor, connections) = ipc::channel::<ipc::IpcReceiver<u32>>().unwrap();
+    let pool = IpcChannelPool::connected_to(acceptor, 2).unwrap();
+    for message in 0..5 {
+        pool.get().unwrap().send(message).unwrap();
+        assert_eq!(connections.recv().unwrap().recv().unwrap(), message);
+    }
+    // Two connections are made ahead of the next `get()`, then the pool is full.
+    let ready: Vec<_> = (0..2).map(|_| connections.recv().unwrap()).collect();
+    while pool.available() < 2 {
+        thread::yield_now();
+    }
+    assert!(connections.try_recv_timeout(std::time::Duration::from_millis(100)).is_err());
+
+    drop((ready, connections));
+    pool.get().unwrap();
+    pool.get().unwrap();
+    assert_eq!(pool.get().unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn byte_buf_encoding() {
+    let pixels: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    let encoded = bincode::serialize(&ByteBuf::from(pixels.clone())).unwrap();
+    assert_eq!(encoded, bincode::serialize(&pixels).unwrap());
+    let mut serialized = vec![];
+    bytes::serialize(&pixels, &mut bincode::Serializer::new(&mut serialized,
+                                                            bincode::DefaultOptions::new()))
+        .unwrap();
+    let mut deserializer = bincode::Deserializer::from_slice(&serialized,
+                                                             bincode::DefaultOptions::new());
+    assert_eq!(bytes::deserialize::<Vec<u8>, _>(&mut deserializer).unwrap(), pixels);
+
+    let (tx, rx) = ipc::channel::<Vec<u8>>().unwrap();
+    tx.cast::<ByteBuf>().send(ByteBuf::from(pixels.clone())).unwrap();
+    let received = rx.recv().unwrap();
+    assert_eq!(received, pixels);
+}
+
+#[test]
+fn lifecycle_events() {
+    // Other tests run meanwhile, so only keep the events of this thread.
+    let test_thread = thread::current().id();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let recorder = seen.clone();
+    let subscription = events::subscribe(move |event| {
+        if thread::current().id() == test_thread {
+            recorder.lock().unwrap().push(event.clone());
+        }
+    });
+
+    let (tx, rx) = ipc::channel::<IpcSender<u32>>().unwrap();
+    let (sub_tx, sub_rx) = ipc::channel::<u32>().unwrap();
+    let ids = (tx.sender_id(), sub_tx.sender_id());
+    tx.send(sub_tx).unwrap();
+    drop(rx.recv().unwrap());
+    assert!(sub_rx.recv().is_err());
+    drop(IpcSharedMemory::from_bytes(b"abc"));
+    let (server, name) = ipc::IpcOneShotServer::<u32>::new().unwrap();
+    IpcSender::connect(name.clone()).unwrap().send(5).unwrap();
+    server.accept().unwrap();
+    assert!(events::unsubscribe(subscription));
+    assert!(!events::unsubscribe(subscription));
+
+    assert_eq!(*seen.lock().unwrap(), vec![
+        IpcEvent::ChannelCreated { sender_id: Some(ids.0) },
+        IpcEvent::ChannelCreated { sender_id: Some(ids.1) },
+        IpcEvent::ChannelTransferred { direction: Direction::Sent, endpoint: Endpoint::Sender },
+        IpcEvent::ChannelTransferred { direction: Direction::Received, endpoint: Endpoint::Sender },
+        IpcEvent::ChannelClosed,
+        IpcEvent::SharedMemoryMapped { length: 3 },
+        IpcEvent::SharedMemoryUnmapped { length: 3 },
+        IpcEvent::ServerRegistered { name: Some(name.clone()) },
+        IpcEvent::ServerAccepted { name: Some(name) },
+    ]);
+}
+
+#[cfg(not(all(not(feature = "force-inprocess"), target_os = "macos")))]
+#[test]
+fn double_buffer_frames_are_not_torn() {
+    let (mut producer, mut reader) = double_buffer::channel(4096).unwrap();
+    assert_eq!(reader.front(), b"");
+    let thread = thread::spawn(move || {
+        for frame in 1..=200u32 {
+            producer.send(&[frame as u8; 4096]).unwrap();
+        }
+        assert!(producer.send(&[0; 4097]).is_err());
+    });
+    let mut generation = 0;
+    while generation < 200 {
+        let frame = reader.recv().unwrap().to_vec();
+        assert!(reader.generation() > generation);
+        generation = reader.generation();
+        assert_eq!(frame, vec![generation as u8; 4096]);
+    }
+    thread.join().unwrap();
+    assert!(reader.try_recv().is_err());
+}
+
+#[test]
+fn typed_receiver_set() {
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Count(u32),
+        Flag(bool),
+    }
+    let (count_tx, count_rx) = ipc::channel().unwrap();
+    let (flag_tx, flag_rx) = ipc::channel::<bool>().unwrap();
+    let mut set = TypedReceiverSet::new().unwrap();
+    let count_id = set.add(count_rx, Event::Count).unwrap();
+    let flag_id = set.add_with_priority(flag_rx, 1, Event::Flag).unwrap();
+
+    count_tx.send(3).unwrap();
+    flag_tx.send(true).unwrap();
+    let mut events = vec![];
+    while events.len() < 2 {
+        events.extend(set.select().unwrap().into_iter().map(|result| match result {
+            TypedSelectionResult::MessageReceived(id, event) => (id, event),
+            result => panic!("unexpected result: {:?}", result),
+        }));
+    }
+    events.sort_by_key(|&(id, _)| id);
+    let mut expected = vec![(count_id, Event::Count(3)), (flag_id, Event::Flag(true))];
+    expected.sort_by_key(|&(id, _)| id);
+    assert_eq!(events, expected);
+
+    // Not a valid bool.
+    let flag_tx = flag_tx.cast_unchecked::<u32>();
+    flag_tx.send(7).unwrap();
+    match set.select().unwrap().remove(0) {
+        TypedSelectionResult::DeserializationFailed(id, _) => assert_eq!(id, flag_id),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    drop(count_tx);
+    match set.select().unwrap().remove(0) {
+        TypedSelectionResult::ChannelClosed(id) => assert_eq!(id, count_id),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn router_timers() {
+    let router = RouterProxy::new();
+    let (fired_sender, fired_receiver) = crossbeam_channel::unbounded();
+    let once_sender = fired_sender.clone();
+    router.add_timer(std::time::Duration::from_millis(20), Box::new(move || {
+        once_sender.send("once").unwrap()
+    }));
+    let cancelled_sender = fired_sender.clone();
+    let cancelled = router.add_timer(std::time::Duration::from_millis(50), Box::new(move || {
+        cancelled_sender.send("cancelled").unwrap()
+    }));
+    router.cancel_timer(cancelled);
+    let periodic = router.add_periodic_timer(std::time::Duration::from_millis(5),
+                                             Box::new(move || {
+        fired_sender.send("periodic").unwrap()
+    }));
+
+    // Timers interleave with routed messages.
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let crossbeam_rx = router.route_ipc_receiver_to_new_crossbeam_receiver(rx);
+    tx.send(1).unwrap();
+    assert_eq!(crossbeam_rx.recv().unwrap(), 1);
+
+    let mut periodic_calls = 0;
+    let mut once_calls = 0;
+    while periodic_calls < 5 || once_calls == 0 {
+        match fired_receiver.recv().unwrap() {
+            "periodic" => periodic_calls += 1,
+            "once" => once_calls += 1,
+            other => panic!("unexpected timer: {}", other),
+        }
+    }
+    router.cancel_timer(periodic);
+    // Wait past the cancelled timer's deadline; one last periodic call may be due.
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert!(fired_receiver.try_iter().all(|timer| timer == "periodic"));
+    assert!(fired_receiver.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+}
+
+#[test]
+fn named_lock_elects_one_holder() {
+    let name = format!("ipc-channel-test-{}", std::process::id());
+    let lock = IpcNamedLock::try_lock(&name).unwrap().unwrap();
+    assert_eq!(lock.name(), name);
+    assert!(IpcNamedLock::try_lock(&name).unwrap().is_none());
+    assert_eq!(IpcNamedLock::published(&name).unwrap(), None);
+
+    lock.publish("first server").unwrap();
+    lock.publish("server").unwrap();
+    assert_eq!(IpcNamedLock::published(&name).unwrap(), Some("server".to_owned()));
+
+    // Dropping the lock lets another holder take over, with nothing published yet.
+    drop(lock);
+    let lock = IpcNamedLock::try_lock(&name).unwrap().unwrap();
+    assert_eq!(IpcNamedLock::published(&name).unwrap(), None);
+    drop(lock);
+
+    for invalid in &["", ".hidden", "../escape", "with space"] {
+        assert_eq!(IpcNamedLock::try_lock(invalid).unwrap_err().kind(),
+                   std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn named_lock_refuses_symbolic_links() {
+    let name = format!("ipc-channel-test-link-{}", std::process::id());
+    let directory = std::env::var_os("XDG_RUNTIME_DIR").map(std::path::PathBuf::from)
+                                                       .unwrap_or_else(std::env::temp_dir);
+    let path = directory.join(format!("ipc-channel-{}.lock", name));
+    let target = directory.join(format!("ipc-channel-{}.target", name));
+    std::os::unix::fs::symlink(&target, &path).unwrap();
+    let error = IpcNamedLock::try_lock(&name).unwrap_err();
+    let published_error = IpcNamedLock::published(&name).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(published_error.kind(), std::io::ErrorKind::PermissionDenied);
+    // The link wasn't followed.
+    assert!(!target.exists());
+}
+
+#[test]
+fn qos_override() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let supported = match tx.set_qos_override(Some(QosClass::UserInteractive)) {
+        Ok(()) => true,
+        Err(error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+            false
+        }
+    };
+    assert_eq!(supported, cfg!(all(target_os = "macos", not(feature = "force-inprocess"))));
+
+    // Messages are delivered the same either way, from clones too.
+    let cloned_tx = tx.clone();
+    cloned_tx.send(1).unwrap();
+    assert_eq!(tx.set_qos_override(None).is_ok(), supported);
+    tx.send(2).unwrap();
+    assert_eq!(rx.recv().unwrap(), 1);
+    assert_eq!(rx.recv().unwrap(), 2);
+}
+
+#[test]
+fn dispatcher_steals_work() {
+    let (tx, rx) = ipc::channel::<u32>().unwrap();
+    let (release_sender, release_receiver) = crossbeam_channel::unbounded::<()>();
+    let handled = Arc::new(Mutex::new(vec![]));
+    let handler_handled = handled.clone();
+    let dispatcher = Dispatcher::new(rx, 2, move |id, value| {
+        assert_eq!(u64::from(id), value as u64);
+        match value {
+            // Holds up the first worker, whose queue the other one takes over.
+            0 => release_receiver.recv().unwrap(),
+            7 => panic!("handler failure"),
+            _ => {}
+        }
+        handler_handled.lock().unwrap().push(value);
+    }).unwrap();
+
+    for value in 0..10 {
+        tx.send(value).unwrap();
+    }
+    for id in 1..10 {
+        assert!(dispatcher.wait(DispatchId::from(id)));
+    }
+    assert!(!dispatcher.is_complete(DispatchId::from(0)));
+    assert_eq!(dispatcher.in_flight(), 1);
+    release_sender.send(()).unwrap();
+    dispatcher.wait_idle();
+    assert_eq!(dispatcher.received(), 10);
+    assert_eq!(dispatcher.panicked(), 1);
+    let mut values = handled.lock().unwrap().clone();
+    values.sort();
+    assert_eq!(values, [0, 1, 2, 3, 4, 5, 6, 8, 9]);
+
+    drop(tx);
+    assert!(!dispatcher.wait(DispatchId::from(10)));
+    assert!(dispatcher.is_closed());
+}
+
+#[test]
+fn delta_channel() {
+    let (tx, mut rx) = delta::channel::<(String, Vec<u32>)>().unwrap();
+    let mut tx = tx.with_snapshot_interval(2);
+    let mut state = ("frame".to_owned(), vec![7; 10_000]);
+    tx.send(&state).unwrap();
+    let snapshot_len = tx.last_frame_len();
+    assert_eq!(rx.recv().unwrap(), state);
+
+    // Same length, then a shifted encoding.
+    state.1[5_000] = 8;
+    tx.send(&state).unwrap();
+    assert!(tx.last_frame_len() < 100);
+    assert_eq!(rx.recv().unwrap(), state);
+    state.0.push_str(" 3");
+    tx.send(&state).unwrap();
+    assert!(tx.last_frame_len() < 100);
+    assert_eq!(rx.recv().unwrap(), state);
+    // The interval is up.
+    tx.send(&state).unwrap();
+    assert_eq!(tx.last_frame_len(), snapshot_len + 2);
+    assert_eq!(rx.recv().unwrap(), state);
+    assert_eq!(rx.generation(), Some(4));
+
+    // A receiver sent elsewhere lost the previous value, and asks for a snapshot.
+    let (transfer_tx, transfer_rx) = ipc::channel::<DeltaReceiver<(String, Vec<u32>)>>().unwrap();
+    transfer_tx.send(rx).unwrap();
+    let mut rx = transfer_rx.recv().unwrap();
+    state.1[0] = 9;
+    tx.send(&state).unwrap();
+    assert!(tx.last_frame_len() < 100);
+    match *rx.try_recv().unwrap_err() {
+        bincode::ErrorKind::Io(ref error) => {
+            assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock)
+        }
+        ref error => panic!("unexpected error {}", error),
+    }
+    state.1[1] = 9;
+    tx.send(&state).unwrap();
+    assert_eq!(tx.last_frame_len(), snapshot_len + 2);
+    assert_eq!(rx.recv().unwrap(), state);
+    assert_eq!(rx.generation(), Some(6));
+}
@@ -0,0 +1,414 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Coordinating the child processes of multi-process tests, without sleeping to get
+//! past startup races.
+//!
+//! Enabled with the `test-support` feature. The test creates a [TestGroup], and
+//! spawns the children with [TestGroup::spawn], each under a name. Each child calls
+//! [join_group] first thing, which connects it to the parent. From then on:
+//!
+//! - [barrier]s block the parent and all children until all of them reached it,
+//! - a [publish]ed sender can be [look]ed up by name by the parent and any child,
+//!   waiting for it to be published,
+//! - a panic in a child is reported to the parent, which fails with the panic
+//!   message, instead of waiting for a child that will never get there.
+//!
+//! Everything waits up to the timeout given to [TestGroup::new], and fails with
+//! `ErrorKind::TimedOut` after that, so a child that hangs fails the test instead of
+//! hanging it too:
+//!
+//! ```no_run
+//! # use ipc_channel::ipc::{self, IpcSender};
+//! # use ipc_channel::testing::{self, TestGroup};
+//! # use std::process::Command;
+//! # use std::time::Duration;
+//! // In the test:
+//! let mut group = TestGroup::new(Duration::from_secs(30));
+//! group.spawn("server", Command::new("server")).unwrap();
+//! group.spawn("client", Command::new("client")).unwrap();
+//! group.barrier().unwrap();
+//! group.join().unwrap();
+//!
+//! // In the server:
+//! let member = testing::join_group().unwrap();
+//! let (tx, rx) = ipc::channel::<String>().unwrap();
+//! member.publish("requests", tx).unwrap();
+//! member.barrier().unwrap();
+//! assert_eq!(rx.recv().unwrap(), "Hello");
+//!
+//! // In the client:
+//! let member = testing::join_group().unwrap();
+//! let requests: IpcSender<String> = member.look_up("requests").unwrap();
+//! member.barrier().unwrap();
+//! requests.send("Hello".to_owned()).unwrap();
+//! ```
+//!
+//! Children are spawned with [process::spawn], so as with it, this doesn't work with
+//! the `force-inprocess` feature.
+//!
+//! [TestGroup]: struct.TestGroup.html
+//! [TestGroup::new]: struct.TestGroup.html#method.new
+//! [TestGroup::spawn]: struct.TestGroup.html#method.spawn
+//! [join_group]: fn.join_group.html
+//! [barrier]: struct.TestMember.html#method.barrier
+//! [publish]: struct.TestMember.html#method.publish
+//! [look]: struct.TestMember.html#method.look_up
+//! [process::spawn]: ../process/fn.spawn.html
+
+use bincode;
+use ipc::{self, IpcReceiver, IpcSender, OpaqueIpcSender};
+use process;
+use router::ROUTER;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::panic;
+use std::process::{Child, Command};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the environment variable holding a child's name and the group timeout,
+/// in milliseconds, as `name:timeout`.
+pub const MEMBER_ENV_VAR: &str = "IPC_CHANNEL_TEST_MEMBER";
+
+/// A sender published under a name.
+type Publication = (String, OpaqueIpcSender);
+
+/// A look-up of a name, with the sender to answer with the published sender.
+type LookUp = (String, IpcSender<OpaqueIpcSender>);
+
+/// What a child sends the parent on connecting: the receiver of barrier arrivals,
+/// the sender of barrier releases, and the receivers of panic messages,
+/// publications and look-ups.
+type Hello = (
+    IpcReceiver<()>,
+    IpcSender<()>,
+    IpcReceiver<String>,
+    IpcReceiver<Publication>,
+    IpcReceiver<LookUp>,
+);
+
+/// The senders published by the parent and the children, kept in the parent.
+#[derive(Default)]
+struct Registry {
+    state: Mutex<RegistryState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    published: HashMap<String, OpaqueIpcSender>,
+    /// Children waiting for a name to be published.
+    waiting: HashMap<String, Vec<IpcSender<OpaqueIpcSender>>>,
+}
+
+impl Registry {
+    fn publish(&self, name: String, sender: OpaqueIpcSender) {
+        let mut state = self.state.lock().unwrap();
+        for reply in state.waiting.remove(&name).unwrap_or_default() {
+            // The child may have given up waiting already.
+            let _ = reply.send(sender.clone());
+        }
+        state.published.insert(name, sender);
+        self.changed.notify_all();
+    }
+
+    fn look_up_for_child(&self, name: String, reply: IpcSender<OpaqueIpcSender>) {
+        let mut state = self.state.lock().unwrap();
+        match state.published.get(&name) {
+            Some(sender) => {
+                let _ = reply.send(sender.clone());
+            }
+            None => state.waiting.entry(name).or_default().push(reply),
+        }
+    }
+
+    fn look_up(&self, name: &str, deadline: Instant) -> Result<OpaqueIpcSender, Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(sender) = state.published.get(name) {
+                return Ok(sender.clone())
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(not_published(name))
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// Unwrap the I/O errors of failed sends and receives.
+fn io_error(error: bincode::Error) -> Error {
+    match *error {
+        bincode::ErrorKind::Io(error) => error,
+        _ => Error::new(ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+fn not_published(name: &str) -> Error {
+    Error::new(ErrorKind::TimedOut, format!("nothing was published as {:?} in time", name))
+}
+
+/// The parent of a multi-process test, coordinating the children it spawned.
+///
+/// Dropping the group kills the children that are still running.
+pub struct TestGroup {
+    timeout: Duration,
+    children: Vec<GroupChild>,
+    registry: Arc<Registry>,
+}
+
+struct GroupChild {
+    name: String,
+    child: Child,
+    arrivals: IpcReceiver<()>,
+    releases: IpcSender<()>,
+    panics: IpcReceiver<String>,
+}
+
+impl GroupChild {
+    /// An error for the child having gone away, with its panic message if it panicked.
+    fn failure(&self, what: &str) -> Error {
+        let message = match self.panics.try_recv() {
+            Ok(message) => format!("child {} panicked: {}", self.name, message),
+            Err(_) => format!("child {} {}", self.name, what),
+        };
+        Error::new(ErrorKind::ConnectionAborted, message)
+    }
+
+    fn timed_out(&self, what: &str) -> Error {
+        Error::new(ErrorKind::TimedOut, format!("child {} did not {} in time", self.name, what))
+    }
+}
+
+impl TestGroup {
+    /// Create a group whose children have up to `timeout` to connect, and whose
+    /// barriers, look-ups and [join] wait up to `timeout`.
+    ///
+    /// [join]: #method.join
+    pub fn new(timeout: Duration) -> TestGroup {
+        TestGroup {
+            timeout,
+            children: vec![],
+            registry: Arc::new(Registry::default()),
+        }
+    }
+
+    /// Spawn `command` as the child called `name`, and wait for it to call [join_group].
+    ///
+    /// [join_group]: fn.join_group.html
+    pub fn spawn(&mut self, name: &str, mut command: Command) -> Result<(), Error> {
+        let timeout_ms = self.timeout.as_secs() * 1000 + u64::from(self.timeout.subsec_millis());
+        command.env(MEMBER_ENV_VAR, format!("{}:{}", name, timeout_ms));
+        let (child, _, hello): (_, IpcReceiver<Hello>, _) =
+            process::spawn(command, self.timeout)?;
+        let (arrivals, releases, panics, publications, look_ups) = hello;
+
+        let registry = self.registry.clone();
+        ROUTER.add_typed_route(publications, Box::new(move |(name, sender)| {
+            registry.publish(name, sender)
+        }));
+        let registry = self.registry.clone();
+        ROUTER.add_typed_route(look_ups, Box::new(move |(name, reply)| {
+            registry.look_up_for_child(name, reply)
+        }));
+
+        self.children.push(GroupChild {
+            name: name.to_owned(),
+            child,
+            arrivals,
+            releases,
+            panics,
+        });
+        Ok(())
+    }
+
+    /// Wait for all children to reach the barrier, and let them through.
+    ///
+    /// Fails with `ErrorKind::ConnectionAborted` if a child exits or panics first, with
+    /// the panic message if there is one, and with `ErrorKind::TimedOut` if a child
+    /// doesn't get there in time.
+    pub fn barrier(&self) -> Result<(), Error> {
+        let deadline = Instant::now() + self.timeout;
+        for child in &self.children {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if let Err(error) = child.arrivals.try_recv_timeout(wait).map_err(io_error) {
+                return Err(match error.kind() {
+                    ErrorKind::TimedOut => child.timed_out("reach the barrier"),
+                    _ => child.failure("exited before reaching the barrier"),
+                })
+            }
+        }
+        for child in &self.children {
+            child.releases.send(()).map_err(|_| child.failure("exited at the barrier"))?;
+        }
+        Ok(())
+    }
+
+    /// Publish `sender` under `name`, for the children to look up.
+    pub fn publish<T>(&self, name: &str, sender: IpcSender<T>) where T: Serialize {
+        self.registry.publish(name.to_owned(), sender.to_opaque())
+    }
+
+    /// Look up the sender published under `name` by a child, waiting for it to be
+    /// published.
+    pub fn look_up<T>(&self, name: &str) -> Result<IpcSender<T>, Error>
+                      where T: for<'de> Deserialize<'de> + Serialize {
+        let sender = self.registry.look_up(name, Instant::now() + self.timeout)?;
+        Ok(sender.to())
+    }
+
+    /// Wait for all children to exit, failing if one of them panicked, exited with
+    /// an error, or is still running after the timeout, in which case it is killed.
+    pub fn join(mut self) -> Result<(), Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut result = Ok(());
+        for mut child in self.children.drain(..) {
+            let status = loop {
+                match child.child.try_wait()? {
+                    Some(status) => break Some(status),
+                    None if Instant::now() >= deadline => break None,
+                    None => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            let error = match status {
+                Some(status) if status.success() => continue,
+                Some(status) => child.failure(&format!("exited with {}", status)),
+                None => {
+                    let _ = child.child.kill();
+                    let _ = child.child.wait();
+                    child.timed_out("exit")
+                }
+            };
+            if result.is_ok() {
+                result = Err(error);
+            }
+        }
+        result
+    }
+}
+
+impl Debug for TestGroup {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        let names: Vec<&str> = self.children.iter().map(|child| &*child.name).collect();
+        formatter.debug_struct("TestGroup")
+                 .field("timeout", &self.timeout)
+                 .field("children", &names)
+                 .finish()
+    }
+}
+
+impl Drop for TestGroup {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.child.kill();
+            let _ = child.child.wait();
+        }
+    }
+}
+
+/// A child process of a [TestGroup], from [join_group].
+///
+/// [TestGroup]: struct.TestGroup.html
+/// [join_group]: fn.join_group.html
+pub struct TestMember {
+    name: String,
+    timeout: Duration,
+    arrivals: IpcSender<()>,
+    releases: IpcReceiver<()>,
+    publications: IpcSender<Publication>,
+    look_ups: IpcSender<LookUp>,
+}
+
+/// In a child spawned with [TestGroup::spawn], connect to the parent, and report
+/// panics to it from then on.
+///
+/// Fails with `ErrorKind::NotFound` if this process wasn't spawned by a group.
+///
+/// [TestGroup::spawn]: struct.TestGroup.html#method.spawn
+pub fn join_group() -> Result<TestMember, Error> {
+    let value = env::var(MEMBER_ENV_VAR).map_err(|_| {
+        Error::new(ErrorKind::NotFound, "process was not spawned by a test group")
+    })?;
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid {}", MEMBER_ENV_VAR));
+    let split = value.rfind(':').ok_or_else(invalid)?;
+    let timeout_ms = value[split + 1..].parse().map_err(|_| invalid())?;
+
+    let (arrivals, arrivals_rx) = ipc::channel()?;
+    let (releases_tx, releases) = ipc::channel()?;
+    let (panics, panics_rx) = ipc::channel::<String>()?;
+    let (publications, publications_rx) = ipc::channel()?;
+    let (look_ups, look_ups_rx) = ipc::channel()?;
+    let hello: Hello = (arrivals_rx, releases_tx, panics_rx, publications_rx, look_ups_rx);
+    process::bootstrap_sender()?.send(hello).map_err(io_error)?;
+
+    let panics = Mutex::new(panics);
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = panics.lock().map(|panics| panics.send(info.to_string()));
+        previous_hook(info)
+    }));
+
+    Ok(TestMember {
+        name: value[..split].to_owned(),
+        timeout: Duration::from_millis(timeout_ms),
+        arrivals,
+        releases,
+        publications,
+        look_ups,
+    })
+}
+
+impl TestMember {
+    /// The name the parent spawned this child under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Wait for the parent and all other children to reach the barrier.
+    ///
+    /// Fails if the parent doesn't let us through in time, e.g. because another
+    /// child never gets there, or if the parent is gone.
+    pub fn barrier(&self) -> Result<(), Error> {
+        self.arrivals.send(()).map_err(io_error)?;
+        self.releases.try_recv_timeout(self.timeout).map_err(io_error)
+    }
+
+    /// Publish `sender` under `name`, for the parent and the other children to look up.
+    pub fn publish<T>(&self, name: &str, sender: IpcSender<T>) -> Result<(), Error>
+                      where T: Serialize {
+        self.publications.send((name.to_owned(), sender.to_opaque())).map_err(io_error)
+    }
+
+    /// Look up the sender published under `name`, waiting for it to be published.
+    pub fn look_up<T>(&self, name: &str) -> Result<IpcSender<T>, Error>
+                      where T: for<'de> Deserialize<'de> + Serialize {
+        let (reply, reply_rx) = ipc::channel()?;
+        self.look_ups.send((name.to_owned(), reply)).map_err(io_error)?;
+        match reply_rx.try_recv_timeout(self.timeout).map_err(io_error) {
+            Ok(sender) => Ok(sender.to()),
+            Err(ref error) if error.kind() == ErrorKind::TimedOut => Err(not_published(name)),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Debug for TestMember {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("TestMember")
+                 .field("name", &self.name)
+                 .field("timeout", &self.timeout)
+                 .finish()
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Settings shared by the threads this crate spawns: router threads, the monitor
+//! threads of supervisors, the threads [process::spawn] accepts children with,
+//! those filling channel pools, and those of dispatchers.
+//!
+//! Each thread is named after the [name prefix] followed by its role: `router`,
+//! `supervisor`, `acceptor`, `pool`, `dispatcher` or `dispatcher-worker`, so they can
+//! be told apart in debuggers and profilers.
+//! With an [affinity], they only run on the given CPUs, e.g. to keep them away
+//! from latency-critical cores.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ipc_channel::threads::{self, ThreadConfig};
+//! threads::set_thread_config(ThreadConfig::new().name_prefix("myapp-ipc-")).unwrap();
+//! ```
+//!
+//! [process::spawn]: ../process/fn.spawn.html
+//! [name prefix]: struct.ThreadConfig.html#method.name_prefix
+//! [affinity]: struct.ThreadConfig.html#method.affinity
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use libc;
+use std::io::{Error, ErrorKind};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+use std::sync::Mutex;
+use std::thread;
+
+lazy_static! {
+    static ref THREAD_CONFIG: Mutex<ThreadConfig> = Mutex::new(ThreadConfig::default());
+}
+
+/// How the threads of this crate are set up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadConfig {
+    name_prefix: String,
+    affinity: Option<Vec<usize>>,
+}
+
+impl Default for ThreadConfig {
+    fn default() -> ThreadConfig {
+        ThreadConfig {
+            name_prefix: "ipc-".to_owned(),
+            affinity: None,
+        }
+    }
+}
+
+impl ThreadConfig {
+    /// Threads named `ipc-` followed by their role, running on any CPU.
+    pub fn new() -> ThreadConfig {
+        ThreadConfig::default()
+    }
+
+    /// Name threads `prefix` followed by their role.
+    pub fn name_prefix<S: Into<String>>(mut self, prefix: S) -> ThreadConfig {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    /// Only run threads on the CPUs with these indices. Linux and Android only.
+    pub fn affinity(mut self, cpus: &[usize]) -> ThreadConfig {
+        self.affinity = Some(cpus.to_vec());
+        self
+    }
+}
+
+/// Set up the threads spawned from now on according to `config`.
+///
+/// Fails with `ErrorKind::InvalidInput` if the affinity has no CPU, or CPUs
+/// beyond what the OS supports, and with `ErrorKind::Unsupported` if it is set
+/// on platforms other than Linux and Android. Threads that can't be pinned to
+/// those CPUs when they start, e.g. as all of them are offline, make spawning
+/// them fail: routers and supervisors fail to start, and [process::spawn] fails
+/// with `ErrorKind::ConnectionAborted`.
+///
+/// [process::spawn]: ../process/fn.spawn.html
+pub fn set_thread_config(config: ThreadConfig) -> Result<(), Error> {
+    if let Some(ref cpus) = config.affinity {
+        check_affinity(cpus)?;
+    }
+    *THREAD_CONFIG.lock().unwrap() = config;
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn check_affinity(cpus: &[usize]) -> Result<(), Error> {
+    let cpu_set_size = mem::size_of::<libc::cpu_set_t>() * 8;
+    if cpus.is_empty() || cpus.iter().any(|&cpu| cpu >= cpu_set_size) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("affinity must list CPUs below {}", cpu_set_size)))
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn check_affinity(_: &[usize]) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Unsupported, "thread affinity is not supported on this platform"))
+}
+
+/// A builder for a thread with the given role, named according to the configuration.
+pub(crate) fn builder(role: &str) -> thread::Builder {
+    let name = format!("{}{}", THREAD_CONFIG.lock().unwrap().name_prefix, role);
+    thread::Builder::new().name(name)
+}
+
+/// Set up the current thread, which was just spawned, according to the configuration.
+pub(crate) fn configure_current_thread() -> Result<(), Error> {
+    let affinity = THREAD_CONFIG.lock().unwrap().affinity.clone();
+    match affinity {
+        Some(cpus) => set_current_thread_affinity(&cpus),
+        None => Ok(()),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_current_thread_affinity(cpus: &[usize]) -> Result<(), Error> {
+    unsafe {
+        let mut cpu_set: libc::cpu_set_t = mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut cpu_set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpu_set) != 0 {
+            return Err(Error::last_os_error())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_current_thread_affinity(cpus: &[usize]) -> Result<(), Error> {
+    check_affinity(cpus)
+}
@@ -0,0 +1,257 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Endpoints of watch channels, created with [ipc::watch].
+//!
+//! A sender sent to another process only counts once received, so keep another
+//! sender alive until then if receivers must not see the channel closed.
+//! Watch channels are unavailable on macOS, where [received shared memory] doesn't
+//! see the sender's later writes, and on illumos and Solaris.
+//!
+//! [ipc::watch]: ../ipc/fn.watch.html
+//! [received shared memory]: ../ipc/struct.IpcSharedMemory.html#sharing-between-processes
+
+use bincode;
+use ipc::{self, IpcSharedMemory};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sync::{IpcCondvar, IpcMutex};
+use words::{self, WORD_SIZE};
+
+// Layout of the header preceding the value, in 64-bit words.
+const VERSION: usize = 0;
+const SENDERS: usize = 1;
+const SLOT_WORDS: usize = 2;
+const HEADER_WORDS: usize = 3;
+
+pub(crate) fn channel<T>(initial_value: T, max_value_size: usize)
+                         -> Result<(IpcWatchSender<T>, IpcWatchReceiver<T>), bincode::Error>
+                         where T: for<'de> Deserialize<'de> + Serialize {
+    let slot_words = words::slot_words(max_value_size);
+    let size = slot_words.checked_add(HEADER_WORDS)
+                         .and_then(|words| words.checked_mul(WORD_SIZE))
+                         .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "value too large"))?;
+    let shared = Shared {
+        shared_memory: IpcSharedMemory::try_from_byte(0, size)?,
+        mutex: IpcMutex::new(),
+        condvar: IpcCondvar::new(),
+    };
+    shared.header()[SLOT_WORDS].store(slot_words as u64, Ordering::Relaxed);
+    shared.header()[SENDERS].store(1, Ordering::Relaxed);
+    let sender = IpcWatchSender {
+        shared: shared.clone(),
+        phantom: PhantomData,
+    };
+    sender.send(initial_value)?;
+    Ok((sender, IpcWatchReceiver {
+        seen_version: Cell::new(shared.header()[VERSION].load(Ordering::Relaxed)),
+        shared,
+        phantom: PhantomData,
+    }))
+}
+
+#[derive(Clone)]
+struct Shared {
+    shared_memory: IpcSharedMemory,
+    mutex: IpcMutex,
+    condvar: IpcCondvar,
+}
+
+impl Shared {
+    fn header(&self) -> &[AtomicU64] {
+        self.shared_memory.atomic_u64s(0, HEADER_WORDS).expect("invalid watch header")
+    }
+
+    fn slot(&self) -> &[AtomicU64] {
+        let slot_words = self.header()[SLOT_WORDS].load(Ordering::Relaxed) as usize;
+        self.shared_memory.atomic_u64s(HEADER_WORDS * WORD_SIZE, slot_words)
+                          .expect("invalid watch slot")
+    }
+
+    fn version(&self) -> u64 {
+        self.header()[VERSION].load(Ordering::Relaxed)
+    }
+}
+
+impl Serialize for Shared {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.shared_memory, &self.mutex, &self.condvar).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Shared {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (shared_memory, mutex, condvar) = Deserialize::deserialize(deserializer)?;
+        Ok(Shared {
+            shared_memory,
+            mutex,
+            condvar,
+        })
+    }
+}
+
+/// Publishing end of a [watch channel].
+///
+/// [watch channel]: ../ipc/fn.watch.html
+pub struct IpcWatchSender<T> where T: Serialize {
+    shared: Shared,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcWatchSender<T> where T: Serialize {
+    /// Publish a new value, replacing the current one.
+    ///
+    /// Fails if the serialized value is larger than the maximum value size,
+    /// or if it embeds channels or shared memory (these are dropped).
+    pub fn send(&self, value: T) -> Result<(), bincode::Error> {
+        let mut bytes = Vec::new();
+        ipc::serialize_plain(&value, &mut bytes)?;
+        let slot = self.shared.slot();
+        if bytes.len() > (slot.len() - 1) * WORD_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "value too large for watch").into())
+        }
+        let _guard = self.shared.mutex.lock();
+        words::store_bytes(slot, &bytes);
+        self.shared.header()[VERSION].fetch_add(1, Ordering::Relaxed);
+        self.shared.condvar.notify_all();
+        Ok(())
+    }
+}
+
+impl<T> Clone for IpcWatchSender<T> where T: Serialize {
+    fn clone(&self) -> IpcWatchSender<T> {
+        self.shared.header()[SENDERS].fetch_add(1, Ordering::Relaxed);
+        IpcWatchSender {
+            shared: self.shared.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for IpcWatchSender<T> where T: Serialize {
+    fn drop(&mut self) {
+        let _guard = self.shared.mutex.lock();
+        self.shared.header()[SENDERS].fetch_sub(1, Ordering::Relaxed);
+        self.shared.condvar.notify_all();
+    }
+}
+
+impl<T> Debug for IpcWatchSender<T> where T: Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcWatchSender").finish()
+    }
+}
+
+impl<T> Serialize for IpcWatchSender<T> where T: Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.shared.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcWatchSender<T> where T: Serialize {
+    /// The received sender counts as a live sender from here on, not while in transit.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let shared: Shared = Deserialize::deserialize(deserializer)?;
+        shared.header()[SENDERS].fetch_add(1, Ordering::Relaxed);
+        Ok(IpcWatchSender {
+            shared,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// Observing end of a [watch channel].
+///
+/// Any number of receivers can watch the same value: receivers can be cloned,
+/// and sent to other processes. Each one keeps track of the latest version it has seen.
+///
+/// [watch channel]: ../ipc/fn.watch.html
+pub struct IpcWatchReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    shared: Shared,
+    seen_version: Cell<u64>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> IpcWatchReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    /// Get the most recently published value, marking it as seen.
+    pub fn borrow(&self) -> Result<T, bincode::Error> {
+        let guard = self.shared.mutex.lock();
+        let bytes = words::load_bytes(self.shared.slot());
+        self.seen_version.set(self.shared.version());
+        drop(guard);
+        bincode::deserialize(&bytes)
+    }
+
+    /// Whether a value was published since this receiver last saw one.
+    pub fn has_changed(&self) -> bool {
+        self.shared.version() != self.seen_version.get()
+    }
+
+    /// Block until a value is published that this receiver hasn't seen yet.
+    ///
+    /// Intermediate values published while the receiver is not looking are skipped:
+    /// use [borrow] afterwards to get the latest one.
+    /// Fails with `ErrorKind::ConnectionReset` if all senders are gone
+    /// and there is no unseen value.
+    ///
+    /// [borrow]: #method.borrow
+    pub fn changed(&self) -> Result<(), bincode::Error> {
+        let mut guard = self.shared.mutex.lock();
+        loop {
+            if self.has_changed() {
+                return Ok(())
+            }
+            if self.shared.header()[SENDERS].load(Ordering::Relaxed) == 0 {
+                return Err(Error::new(ErrorKind::ConnectionReset,
+                                      "All senders for this watch closed").into())
+            }
+            guard = self.shared.condvar.wait(guard);
+        }
+    }
+}
+
+impl<T> Clone for IpcWatchReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn clone(&self) -> IpcWatchReceiver<T> {
+        IpcWatchReceiver {
+            shared: self.shared.clone(),
+            seen_version: self.seen_version.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Debug for IpcWatchReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_struct("IpcWatchReceiver")
+                 .field("seen_version", &self.seen_version.get())
+                 .finish()
+    }
+}
+
+impl<T> Serialize for IpcWatchReceiver<T> where T: for<'de> Deserialize<'de> + Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        (&self.shared, self.seen_version.get()).serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for IpcWatchReceiver<T>
+                              where T: for<'dde> Deserialize<'dde> + Serialize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let (shared, seen_version) = Deserialize::deserialize(deserializer)?;
+        Ok(IpcWatchReceiver {
+            shared,
+            seen_version: Cell::new(seen_version),
+            phantom: PhantomData,
+        })
+    }
+}
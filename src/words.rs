@@ -0,0 +1,55 @@
+// Copyright 2019 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Byte strings stored in shared memory as 64-bit atomic words, as used by the
+//! [ring], [watch], [state] and [double_buffer] channels.
+//!
+//! [ring]: ../ring/index.html
+//! [watch]: ../watch/index.html
+//! [state]: ../state/index.html
+//! [double_buffer]: ../double_buffer/index.html
+
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const WORD_SIZE: usize = mem::size_of::<u64>();
+
+/// Number of words needed for a slot holding up to `max_size` bytes,
+/// as used by `store_bytes()` and `load_bytes()`.
+pub fn slot_words(max_size: usize) -> usize {
+    // Each slot starts with a word holding the length.
+    1 + max_size.div_ceil(WORD_SIZE)
+}
+
+/// Copy `bytes` into a slot of shared memory words.
+pub fn store_bytes(slot: &[AtomicU64], bytes: &[u8]) {
+    slot[0].store(bytes.len() as u64, Ordering::Relaxed);
+    for (word, chunk) in slot[1..].iter().zip(bytes.chunks(WORD_SIZE)) {
+        let mut buffer = [0; WORD_SIZE];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        word.store(u64::from_ne_bytes(buffer), Ordering::Relaxed);
+    }
+}
+
+/// Copy the bytes stored with `store_bytes()` out of a slot.
+#[cfg(not(all(not(feature = "force-inprocess"), any(target_os = "macos",
+                                                    target_os = "illumos",
+                                                    target_os = "solaris"))))]
+pub fn load_bytes(slot: &[AtomicU64]) -> Vec<u8> {
+    let len = slot[0].load(Ordering::Relaxed) as usize;
+    let mut bytes = Vec::with_capacity(len);
+    for word in &slot[1..] {
+        if bytes.len() >= len {
+            break
+        }
+        bytes.extend_from_slice(&word.load(Ordering::Relaxed).to_ne_bytes());
+    }
+    bytes.truncate(len);
+    bytes
+}